@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+use dsrp_core::client_handler::{ClientHandler, ClientOperation, HandshakeProgress};
+use dsrp_core::handshake::{HandshakeRequest, HandshakeResponse};
+use dsrp_core::messages::{ChannelId, ChannelRevocationReason, ConnectionFailureReason, ConnectionId, RequestId, ServerMessage};
+use futures::future::{abortable, AbortHandle};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use crate::config::{RemotePort, Tunnel};
+use crate::sockets;
+
+/// Socket activity that needs to be run through the client handler
+pub enum Event {
+    HandshakeResponse {
+        response: HandshakeResponse,
+    },
+
+    ServerMessage {
+        message: ServerMessage,
+    },
+
+    ServerDisconnected,
+
+    LocalTcpDataReceived {
+        channel: ChannelId,
+        connection: ConnectionId,
+        data: Vec<u8>,
+    },
+
+    LocalTcpConnectionEstablished {
+        channel: ChannelId,
+        connection: ConnectionId,
+    },
+
+    LocalTcpConnectionHalfClosed {
+        channel: ChannelId,
+        connection: ConnectionId,
+    },
+
+    LocalTcpConnectionClosed {
+        channel: ChannelId,
+        connection: ConnectionId,
+    },
+
+    LocalTcpConnectionFailed {
+        channel: ChannelId,
+        connection: ConnectionId,
+        reason: ConnectionFailureReason,
+    },
+
+    LocalUdpDataReceived {
+        channel: ChannelId,
+        connection: ConnectionId,
+        data: Vec<u8>,
+    },
+
+    LocalUdpConnectionFailed {
+        channel: ChannelId,
+        connection: ConnectionId,
+        reason: ConnectionFailureReason,
+    },
+
+    Tick,
+}
+
+/// How a session with the DSRP server ended, which decides whether and how soon to reconnect
+pub enum SessionOutcome {
+    /// The connection was lost after the server accepted the client
+    Lost(HandshakeRequest),
+
+    /// The server was reached but didn't accept the client for now, such as while it's shutting
+    /// down, so reconnecting should keep backing off
+    Unavailable(HandshakeRequest),
+
+    /// The server turned the client away, such as for a bad auth secret or protocol version,
+    /// and there's no point reconnecting
+    Rejected,
+}
+
+struct LocalSocket {
+    task: AbortHandle,
+
+    /// Cleared once the write side of the socket has been shut down
+    outbound: Option<mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+/// Owns the client handler and carries out the operations it returns against real sockets.
+/// All socket activity is funneled through a single event queue so the handler is only ever
+/// touched from one place.  The agent outlives individual connections to the DSRP server, so
+/// its tunnels are restored whenever it reconnects.
+pub struct Agent {
+    handler: ClientHandler,
+    tunnels: Vec<Tunnel>,
+    events: mpsc::UnboundedSender<Event>,
+
+    /// Set while there's a connection to the DSRP server
+    server: Option<mpsc::UnboundedSender<Vec<u8>>>,
+
+    /// Set once the tunnels have been registered for the first time, after which they're
+    /// restored by the handler instead
+    tunnels_registered: bool,
+
+    /// Set once the server accepted the handshake of the current session
+    handshake_accepted: bool,
+    handshake_rejected: bool,
+    pending_registrations: HashMap<RequestId, usize>,
+    channel_tunnels: HashMap<ChannelId, usize>,
+    tcp_connections: HashMap<ConnectionId, LocalSocket>,
+    udp_connections: HashMap<ConnectionId, LocalSocket>,
+}
+
+impl Agent {
+    pub fn new(handler: ClientHandler, tunnels: Vec<Tunnel>, events: mpsc::UnboundedSender<Event>) -> Self {
+        Agent {
+            handler,
+            tunnels,
+            events,
+            server: None,
+            tunnels_registered: false,
+            handshake_accepted: false,
+            handshake_rejected: false,
+            pending_registrations: HashMap::new(),
+            channel_tunnels: HashMap::new(),
+            tcp_connections: HashMap::new(),
+            udp_connections: HashMap::new(),
+        }
+    }
+
+    /// Relays traffic over a connection to the DSRP server until the connection is lost.  Local
+    /// connections that went through it are closed, and how the session ended is returned along
+    /// with the handshake to reconnect with, unless the server rejected the client.
+    pub async fn run_session<S>(&mut self,
+                                stream: S,
+                                handshake: HandshakeRequest,
+                                events: &mut mpsc::UnboundedReceiver<Event>) -> SessionOutcome
+        where S: AsyncRead + AsyncWrite + Send + 'static {
+
+        self.handshake_accepted = false;
+
+        let (reader, writer) = tokio::io::split(stream);
+        let (server, server_receiver) = mpsc::unbounded_channel();
+        let _ = server.send(handshake.into_bytes());
+        tokio::spawn(sockets::write_outbound(writer, server_receiver));
+        self.server = Some(server);
+
+        // Server events get their own queue, so anything still queued from a dead connection
+        // is dropped along with it instead of leaking into the next one
+        let (server_events, mut server_event_receiver) = mpsc::unbounded_channel();
+        let (reader_future, reader) = abortable(sockets::read_dsrp_server(reader, server_events));
+        tokio::spawn(reader_future);
+
+        while self.server.is_some() {
+            let event = tokio::select! {
+                Some(event) = server_event_receiver.recv() => event,
+                Some(event) = events.recv() => event,
+                else => break,
+            };
+
+            self.handle_event(event);
+        }
+
+        reader.abort();
+        self.server = None;
+
+        let (operations, handshake) = self.handler.server_connection_lost();
+        self.perform_operations(operations);
+        if self.handshake_rejected {
+            SessionOutcome::Rejected
+        } else if self.handshake_accepted {
+            SessionOutcome::Lost(handshake)
+        } else {
+            SessionOutcome::Unavailable(handshake)
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::HandshakeResponse {response} => {
+                match self.handler.handle_handshake_response(response) {
+                    Ok(HandshakeProgress::Accepted) => {
+                        println!("Handshake accepted by the DSRP server");
+                        self.handshake_accepted = true;
+                        let operations = self.handler.restore_registrations();
+                        self.perform_operations(operations);
+                        if !self.tunnels_registered {
+                            self.register_tunnels();
+                            self.tunnels_registered = true;
+                        }
+                    },
+
+                    Ok(HandshakeProgress::SendChallengeAnswer {answer}) => {
+                        self.send_to_server(answer.into_bytes());
+                    },
+
+                    Ok(HandshakeProgress::Rejected {reason}) => {
+                        println!("DSRP server rejected the handshake: {}", reason);
+                        self.handshake_rejected = true;
+                    },
+
+                    Ok(HandshakeProgress::ServerShuttingDown) => {
+                        println!("DSRP server is shutting down and could not accept the handshake");
+                    },
+
+                    Err(error) => {
+                        // Nothing more can be done without a valid handshake, so hang up
+                        println!("Failed to handle handshake response: {}", error);
+                        self.server = None;
+                    },
+                }
+            },
+
+            Event::ServerMessage {message} => {
+                match self.handler.handle_server_message(message) {
+                    Ok(operations) => self.perform_operations(operations),
+                    Err(error) => println!("Failed to handle message from the DSRP server: {}", error),
+                }
+            },
+
+            Event::ServerDisconnected => {
+                println!("Disconnected from the DSRP server");
+                self.server = None;
+            },
+
+            Event::Tick => {
+                let operations = self.handler.tick(Instant::now());
+                self.perform_operations(operations);
+            },
+
+            Event::LocalTcpDataReceived {channel, connection, data} |
+            Event::LocalUdpDataReceived {channel, connection, data} => {
+                // Data for connections the server has already closed or expired is dropped
+                if let Ok(message) = self.handler.send_data(channel, connection, data) {
+                    self.send_to_server(message.into_bytes());
+                }
+            },
+
+            Event::LocalTcpConnectionEstablished {channel, connection} => {
+                // Releases anything the remote peer sent while the connection was being opened.
+                // Connections the server closed before this point have already been removed.
+                if let Ok(operations) = self.handler.local_connection_established(channel, connection) {
+                    self.perform_operations(operations);
+                }
+            },
+
+            Event::LocalTcpConnectionHalfClosed {channel, connection} => {
+                if let Ok(operations) = self.handler.local_connection_half_closed(channel, connection) {
+                    self.perform_operations(operations);
+                }
+            },
+
+            Event::LocalTcpConnectionClosed {channel, connection} => {
+                // Connections the server closed have already been removed
+                if let Ok(message) = self.handler.local_connection_closed(channel, connection) {
+                    // The reader may still be running when it was the write side that failed
+                    if let Some(socket) = self.tcp_connections.remove(&connection) {
+                        socket.task.abort();
+                    }
+
+                    self.send_to_server(message.into_bytes());
+                }
+            },
+
+            Event::LocalTcpConnectionFailed {channel, connection, reason} => {
+                if let Ok(message) = self.handler.local_connection_failed(channel, connection, reason) {
+                    self.tcp_connections.remove(&connection);
+                    self.send_to_server(message.into_bytes());
+                }
+            },
+
+            Event::LocalUdpConnectionFailed {channel, connection, reason} => {
+                // Connections the server expired have already been removed
+                if let Ok(message) = self.handler.local_connection_failed(channel, connection, reason) {
+                    self.udp_connections.remove(&connection);
+                    self.send_to_server(message.into_bytes());
+                }
+            },
+        }
+    }
+
+    fn register_tunnels(&mut self) {
+        for index in 0..self.tunnels.len() {
+            self.register_tunnel(index);
+        }
+    }
+
+    fn register_tunnel(&mut self, index: usize) {
+        let tunnel = &self.tunnels[index];
+        let connection_type = tunnel.connection_type.clone();
+        let (request, message) = match tunnel.remote_port {
+            RemotePort::Specific(port) => {
+                self.handler.request_registration(connection_type, port, tunnel.bind_address)
+            },
+
+            RemotePort::Any(ref range) => {
+                self.handler.request_any_port_registration(connection_type, range.clone(), tunnel.bind_address)
+            },
+        };
+
+        self.pending_registrations.insert(request, index);
+        self.send_to_server(message.into_bytes());
+    }
+
+    fn send_to_server(&self, bytes: Vec<u8>) {
+        if let Some(server) = &self.server {
+            let _ = server.send(bytes);
+        }
+    }
+
+    fn channel_target(&self, channel: ChannelId) -> Option<SocketAddr> {
+        self.channel_tunnels.get(&channel).map(|index| self.tunnels[*index].target)
+    }
+
+    /// Fails a connection announced on a channel that isn't tied to any tunnel, since there's
+    /// nowhere to relay it to, so the server can clean up its side
+    fn report_untargeted_connection(&mut self, channel: ChannelId, connection: ConnectionId) {
+        println!("No tunnel is relaying traffic for channel {:?}, failing connection {:?}", channel, connection);
+        if let Ok(message) = self.handler.local_connection_failed(channel, connection, ConnectionFailureReason::Other) {
+            self.send_to_server(message.into_bytes());
+        }
+    }
+
+    fn perform_operations(&mut self, operations: Vec<ClientOperation>) {
+        for operation in operations {
+            self.perform_operation(operation);
+        }
+    }
+
+    fn perform_operation(&mut self, operation: ClientOperation) {
+        match operation {
+            ClientOperation::NotifyChannelOpened {registered_by_request, opened_channel, port} => {
+                if let Some(index) = self.pending_registrations.remove(&registered_by_request) {
+                    let target = self.tunnels[index].target;
+                    println!("Channel {:?} opened on port {}, relaying its traffic to {}", opened_channel, port, target);
+                    self.channel_tunnels.insert(opened_channel, index);
+                }
+            },
+
+            ClientOperation::NotifyRegistrationFailed {request, cause} => {
+                if let Some(index) = self.pending_registrations.remove(&request) {
+                    println!("Registration for {} was rejected: {:?}", self.tunnels[index].target, cause);
+                }
+            },
+
+            ClientOperation::NotifyChannelClosed {channel} => {
+                if let Some(index) = self.channel_tunnels.remove(&channel) {
+                    println!("Channel {:?} relaying traffic to {} was closed", channel, self.tunnels[index].target);
+                }
+            },
+
+            ClientOperation::NotifyChannelRevoked {channel, reason: ChannelRevocationReason::ServerShuttingDown} => {
+                // The handler restores the channel once the agent reconnects, so the tunnel stays
+                // tied to it until then
+                if let Some(index) = self.channel_tunnels.get(&channel) {
+                    println!("Channel {:?} relaying traffic to {} was closed for the DSRP server's shutdown",
+                             channel, self.tunnels[*index].target);
+                }
+            },
+
+            ClientOperation::NotifyChannelRevoked {channel, reason} => {
+                // The tunnel is still wanted, so try to get the port back
+                if let Some(index) = self.channel_tunnels.remove(&channel) {
+                    println!("Channel {:?} relaying traffic to {} was revoked by the DSRP server: {:?}",
+                             channel, self.tunnels[index].target, reason);
+
+                    self.register_tunnel(index);
+                }
+            },
+
+            ClientOperation::NotifyChannelRestored {previous_channel, restored_channel, port, same_port} => {
+                if let Some(index) = self.channel_tunnels.remove(&previous_channel) {
+                    let target = self.tunnels[index].target;
+                    if same_port {
+                        println!("Channel {:?} restored on port {}, relaying its traffic to {}", restored_channel, port, target);
+                    } else {
+                        println!("Channel {:?} restored on different port {}, relaying its traffic to {}", restored_channel, port, target);
+                    }
+
+                    self.channel_tunnels.insert(restored_channel, index);
+                }
+            },
+
+            ClientOperation::NotifyChannelRestorationFailed {previous_channel, cause} => {
+                if let Some(index) = self.channel_tunnels.remove(&previous_channel) {
+                    println!("Channel relaying traffic to {} could not be restored: {:?}", self.tunnels[index].target, cause);
+                }
+            },
+
+            ClientOperation::NotifyChannelRestorationDelayed {previous_channel, cause, retry_at} => {
+                // The tunnel stays tied to the previous channel until the handler asks for it again
+                if let Some(index) = self.channel_tunnels.get(&previous_channel) {
+                    let remaining = retry_at.saturating_duration_since(Instant::now());
+                    println!("Channel relaying traffic to {} could not be restored yet ({:?}), trying again in {:.1}s",
+                             self.tunnels[*index].target, cause, remaining.as_secs_f64());
+                }
+            },
+
+            ClientOperation::NotifyServerUnresponsive => {
+                println!("DSRP server stopped responding");
+                self.server = None;
+            },
+
+            ClientOperation::NotifyServerShuttingDown {deadline} => {
+                // Once the server closes the connection the usual reconnect logic takes over
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                println!("DSRP server is shutting down, its channels will be closed in {:.1}s", remaining.as_secs_f64());
+            },
+
+            ClientOperation::SendMessageToServer {message} => {
+                self.send_to_server(message.into_bytes());
+            },
+
+            ClientOperation::CreateTcpConnectionForChannel {channel, new_connection} => {
+                let target = match self.channel_target(channel) {
+                    Some(x) => x,
+                    None => return self.report_untargeted_connection(channel, new_connection),
+                };
+
+                let (outbound, outbound_receiver) = mpsc::unbounded_channel();
+                let connection_future = sockets::run_local_tcp_connection(target,
+                                                                          channel,
+                                                                          new_connection,
+                                                                          outbound_receiver,
+                                                                          self.events.clone());
+
+                let (future, task) = abortable(connection_future);
+                tokio::spawn(future);
+                self.tcp_connections.insert(new_connection, LocalSocket {task, outbound: Some(outbound)});
+            },
+
+            ClientOperation::CloseTcpConnection {channel: _, connection} => {
+                // Dropping the outbound sender lets queued data get flushed before the write
+                // side is shut down, while aborting the task releases the rest of the socket
+                if let Some(socket) = self.tcp_connections.remove(&connection) {
+                    socket.task.abort();
+                }
+            },
+
+            ClientOperation::CreateUdpConnectionForChannel {channel, new_connection, peer_address: _} => {
+                // Each remote peer gets its own local socket so replies from the application
+                // server can be routed back to the right peer
+                let target = match self.channel_target(channel) {
+                    Some(x) => x,
+                    None => return self.report_untargeted_connection(channel, new_connection),
+                };
+
+                let (outbound, outbound_receiver) = mpsc::unbounded_channel();
+                let socket_future = sockets::run_local_udp_socket(target,
+                                                                  channel,
+                                                                  new_connection,
+                                                                  outbound_receiver,
+                                                                  self.events.clone());
+
+                let (future, task) = abortable(socket_future);
+                tokio::spawn(future);
+                self.udp_connections.insert(new_connection, LocalSocket {task, outbound: Some(outbound)});
+            },
+
+            ClientOperation::CloseUdpConnection {channel: _, connection} => {
+                if let Some(socket) = self.udp_connections.remove(&connection) {
+                    socket.task.abort();
+                }
+            },
+
+            ClientOperation::ShutdownTcpConnectionWrite {channel: _, connection} => {
+                // Dropping the outbound sender lets queued data get flushed before the write
+                // side is shut down, while the task keeps relaying what the application sends
+                if let Some(socket) = self.tcp_connections.get_mut(&connection) {
+                    socket.outbound = None;
+                }
+            },
+
+            ClientOperation::RelayRemotePacket {channel: _, connection, peer_address, data} => {
+                let socket = match (connection, peer_address) {
+                    (Some(connection), Some(_)) => self.udp_connections.get(&connection),
+                    (Some(connection), None) => self.tcp_connections.get(&connection),
+                    (None, _) => None,
+                };
+
+                if let Some(outbound) = socket.and_then(|socket| socket.outbound.as_ref()) {
+                    let _ = outbound.send(data);
+                }
+            },
+        }
+    }
+}
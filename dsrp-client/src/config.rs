@@ -0,0 +1,187 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use dsrp_core::messages::ConnectionType;
+use crate::tls::FINGERPRINT_LENGTH;
+
+const USAGE: &str = "Usage: dsrp-client [--server <address:port>] [--auth-secret <secret>] \
+                     [--tcp <remote port>[@<bind ip>]:<local address:port>]... \
+                     [--udp <remote port>[@<bind ip>]:<local address:port>]... \
+                     [--tls-ca <ca.pem>] [--tls-fingerprint <sha256 hex>] [--tls-server-name <name>] \
+                     [--tls-cert <cert.pem> --tls-key <key.pem>]\n\
+                     where <remote port> is a port, 'any', or a start-end range for the server to pick from, \
+                     and <bind ip> is the server address to bind it on (ipv6 addresses in brackets)";
+
+/// A port to register on the DSRP server along with the local application server its
+/// traffic should be relayed to
+pub struct Tunnel {
+    pub connection_type: ConnectionType,
+    pub remote_port: RemotePort,
+
+    /// Which of the DSRP server's addresses the port is bound on, or its default when `None`
+    pub bind_address: Option<IpAddr>,
+
+    pub target: SocketAddr,
+}
+
+/// Which port a tunnel should be registered on
+pub enum RemotePort {
+    Specific(u16),
+
+    /// The DSRP server picks a free port, optionally from within the range
+    Any(Option<RangeInclusive<u16>>),
+}
+
+/// Settings the DSRP client is started with
+pub struct ClientConfig {
+    /// Address of the DSRP server to connect to
+    pub server_address: SocketAddr,
+
+    /// Shared secret used to answer the DSRP server's authentication challenge
+    pub auth_secret: Option<Vec<u8>>,
+
+    pub tunnels: Vec<Tunnel>,
+
+    /// PEM file of CA certificates the DSRP server's certificate is verified against
+    pub tls_ca_path: Option<PathBuf>,
+
+    /// SHA-256 fingerprint the DSRP server's certificate must match
+    pub tls_fingerprint: Option<[u8; FINGERPRINT_LENGTH]>,
+
+    /// Name the DSRP server's certificate is verified for when checking it against the CAs
+    pub tls_server_name: Option<String>,
+
+    /// PEM file holding the certificate chain presented to DSRP servers that require clients
+    /// to identify themselves
+    pub tls_cert_path: Option<PathBuf>,
+
+    /// PEM file holding the private key for the client certificate
+    pub tls_key_path: Option<PathBuf>,
+}
+
+impl ClientConfig {
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut config = ClientConfig {
+            server_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 6142),
+            auth_secret: None,
+            tunnels: Vec::new(),
+            tls_ca_path: None,
+            tls_fingerprint: None,
+            tls_server_name: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--server" => config.server_address = parse_value(&arg, args.next())?,
+                "--auth-secret" => {
+                    let secret: String = parse_value(&arg, args.next())?;
+                    if secret.is_empty() {
+                        return Err(format!("Auth secret can not be empty\n{}", USAGE));
+                    }
+
+                    config.auth_secret = Some(secret.into_bytes());
+                },
+
+                "--tls-ca" => config.tls_ca_path = Some(parse_value(&arg, args.next())?),
+                "--tls-fingerprint" => config.tls_fingerprint = Some(parse_fingerprint(&arg, args.next())?),
+                "--tls-server-name" => config.tls_server_name = Some(parse_value(&arg, args.next())?),
+                "--tls-cert" => config.tls_cert_path = Some(parse_value(&arg, args.next())?),
+                "--tls-key" => config.tls_key_path = Some(parse_value(&arg, args.next())?),
+                "--tcp" => config.tunnels.push(parse_tunnel(&arg, args.next(), ConnectionType::Tcp)?),
+                "--udp" => config.tunnels.push(parse_tunnel(&arg, args.next(), ConnectionType::Udp)?),
+                "--help" | "-h" => return Err(USAGE.to_owned()),
+                x => return Err(format!("Unknown argument '{}'\n{}", x, USAGE)),
+            }
+        }
+
+        if config.tunnels.is_empty() {
+            return Err(format!("At least one tcp or udp tunnel must be specified\n{}", USAGE));
+        }
+
+        if config.tls_ca_path.is_some() && config.tls_server_name.is_none() {
+            return Err(format!("--tls-server-name is required when verifying against --tls-ca\n{}", USAGE));
+        }
+
+        if config.tls_cert_path.is_some() != config.tls_key_path.is_some() {
+            return Err(format!("--tls-cert and --tls-key must be specified together\n{}", USAGE));
+        }
+
+        if config.tls_cert_path.is_some() && !config.uses_tls() {
+            return Err(format!("--tls-cert requires --tls-ca or --tls-fingerprint to verify the server\n{}", USAGE));
+        }
+
+        Ok(config)
+    }
+
+    /// Tls is used to connect to the DSRP server whenever there's a way to verify it
+    pub fn uses_tls(&self) -> bool {
+        self.tls_ca_path.is_some() || self.tls_fingerprint.is_some()
+    }
+}
+
+fn parse_tunnel(name: &str, value: Option<String>, connection_type: ConnectionType) -> Result<Tunnel, String> {
+    let value: String = parse_value(name, value)?;
+    let invalid = || format!("Invalid value '{}' for {}, expected <remote port>[@<bind ip>]:<local address:port>", value, name);
+
+    // Ipv6 bind addresses are bracketed, so the remote side ends at the first colon after them
+    let remote_end = match (value.find("@["), value.find("]:")) {
+        (Some(start), Some(end)) if start < end => end + 1,
+        _ => value.find(':').ok_or_else(invalid)?,
+    };
+
+    let (remote, target) = (&value[..remote_end], &value[remote_end + 1..]);
+    let mut remote_parts = remote.splitn(2, '@');
+    let remote_port = remote_parts.next().and_then(parse_remote_port).ok_or_else(invalid)?;
+    let bind_address = match remote_parts.next() {
+        Some(address) => Some(address.trim_start_matches('[').trim_end_matches(']').parse().map_err(|_| invalid())?),
+        None => None,
+    };
+
+    let target = target.parse().map_err(|_| invalid())?;
+    Ok(Tunnel {connection_type, remote_port, bind_address, target})
+}
+
+fn parse_remote_port(value: &str) -> Option<RemotePort> {
+    if value == "any" {
+        return Some(RemotePort::Any(None));
+    }
+
+    let mut bounds = value.splitn(2, '-');
+    let start = bounds.next()?.parse().ok()?;
+    match bounds.next() {
+        None => Some(RemotePort::Specific(start)),
+        Some(end) => {
+            let end = end.parse().ok()?;
+            if start > end {
+                return None;
+            }
+
+            Some(RemotePort::Any(Some(start..=end)))
+        },
+    }
+}
+
+/// Parses a hex encoded SHA-256 fingerprint, optionally with its bytes separated by colons
+fn parse_fingerprint(name: &str, value: Option<String>) -> Result<[u8; FINGERPRINT_LENGTH], String> {
+    let value: String = parse_value(name, value)?;
+    let digits: Vec<char> = value.chars().filter(|x| *x != ':').collect();
+    let invalid = || format!("Invalid value '{}' for {}, expected a hex encoded SHA-256 fingerprint", value, name);
+    if digits.len() != FINGERPRINT_LENGTH * 2 {
+        return Err(invalid());
+    }
+
+    let mut fingerprint = [0; FINGERPRINT_LENGTH];
+    for (index, pair) in digits.chunks(2).enumerate() {
+        let pair: String = pair.iter().collect();
+        fingerprint[index] = u8::from_str_radix(&pair, 16).map_err(|_| invalid())?;
+    }
+
+    Ok(fingerprint)
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("No value provided for {}\n{}", name, USAGE))?;
+    value.parse().map_err(|_| format!("Invalid value '{}' for {}", value, name))
+}
@@ -1,46 +1,122 @@
-use tokio::net::{TcpStream};
-use tokio::prelude::*;
-use tokio::io::{BufReader};
+mod agent;
+mod config;
+mod sockets;
+mod tls;
+
 use std::io;
-use futures::io::ErrorKind;
+use std::process;
+use std::time::Duration;
+use dsrp_core::client_handler::{ClientHandler, ClientHandlerConfig};
+use dsrp_core::clock::SystemClock;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use webpki::DNSNameRef;
+use crate::agent::{Agent, Event, SessionOutcome};
+use crate::config::ClientConfig;
+
+/// Name sent to the DSRP server when its certificate is only checked against a pinned
+/// fingerprint, since the name isn't part of that verification
+const PINNED_SERVER_NAME: &str = "dsrp-server";
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait before the first attempt to reconnect to the DSRP server.  The delay doubles
+/// with each failed attempt, up to the maximum.
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let addr = "127.0.0.1:6142";
-    let server = connect_to_server(addr);
+    let config = match ClientConfig::from_args(std::env::args().skip(1)) {
+        Ok(x) => x,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    };
 
-    println!("DSRP server started running on {}", addr);
-    server.await
-}
+    // Channels that can't be restored yet are retried on the same schedule as reconnecting
+    let handler_config = ClientHandlerConfig {
+        auth_secret: config.auth_secret.clone(),
+        initial_restoration_retry_delay: INITIAL_RECONNECT_DELAY,
+        max_restoration_retry_delay: MAX_RECONNECT_DELAY,
+        ..ClientHandlerConfig::default()
+    };
 
-async fn connect_to_server(addr: &str) -> io::Result<()> {
-    let mut stream = TcpStream::connect(addr).await?;
-    println!("Connected to server!");
+    let (handler, mut handshake) = ClientHandler::with_config(handler_config, Box::new(SystemClock));
 
-    let (reader, mut writer) = stream.split();
-    let mut reader = BufReader::new(reader);
+    let tls = if config.uses_tls() {
+        let client_certificate = match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => Some((cert_path.as_path(), key_path.as_path())),
+            _ => None,
+        };
 
+        let connector = tls::build_connector(config.tls_ca_path.as_deref(), config.tls_fingerprint, client_certificate)?;
+        let server_name = config.tls_server_name.as_deref().unwrap_or(PINNED_SERVER_NAME);
+        let server_name = DNSNameRef::try_from_ascii_str(server_name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid tls server name '{}'", server_name)))?;
+
+        Some((connector, server_name))
+    } else {
+        None
+    };
+
+    let (events, mut event_receiver) = mpsc::unbounded_channel();
+    tokio::spawn(raise_ticks(events.clone()));
+    let mut agent = Agent::new(handler, config.tunnels, events);
+
+    // Keep reconnecting until the server turns the client away, backing off while it's unreachable
+    // or not ready to accept the client
+    let mut reconnect_delay = INITIAL_RECONNECT_DELAY;
     loop {
-        let mut line = String::new();
-        match reader.read_line(&mut line).await {
-            Ok(0) => {
-                println!("Connection disconnected!");
-                break;
-            }
-
-            Ok(_) => {
-                println!("Received: {}", line);
-                writer.write(line.as_bytes()).await?;
-            }
-
-            Err(e) if e.kind() == ErrorKind::WouldBlock => (),
-            Err(e) if e.kind() == ErrorKind::Interrupted => (),
-            Err(e) => {
-                println!("Error: {:?}", e);
-                break;
+        let outcome = match TcpStream::connect(config.server_address).await {
+            Err(error) => {
+                println!("Failed to connect to DSRP server at {}: {}", config.server_address, error);
+                SessionOutcome::Unavailable(handshake)
             },
-        }
+
+            Ok(stream) => {
+                println!("Connected to DSRP server at {}", config.server_address);
+                match &tls {
+                    None => agent.run_session(stream, handshake, &mut event_receiver).await,
+                    Some((connector, server_name)) => match connector.connect(*server_name, stream).await {
+                        Ok(stream) => {
+                            println!("Tls session established with the DSRP server");
+                            agent.run_session(stream, handshake, &mut event_receiver).await
+                        },
+
+                        Err(error) => {
+                            println!("Tls handshake with the DSRP server failed: {}", error);
+                            SessionOutcome::Unavailable(handshake)
+                        },
+                    },
+                }
+            },
+        };
+
+        handshake = match outcome {
+            SessionOutcome::Lost(x) => {
+                reconnect_delay = INITIAL_RECONNECT_DELAY;
+                x
+            },
+
+            SessionOutcome::Unavailable(x) => x,
+            SessionOutcome::Rejected => return Ok(()),
+        };
+
+        println!("Reconnecting to the DSRP server in {:?}", reconnect_delay);
+        tokio::time::delay_for(reconnect_delay).await;
+        reconnect_delay = std::cmp::min(reconnect_delay * 2, MAX_RECONNECT_DELAY);
     }
+}
 
-    Ok(())
-}
\ No newline at end of file
+/// Periodically gives the agent a chance to check that the DSRP server is still responsive
+async fn raise_ticks(events: mpsc::UnboundedSender<Event>) {
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if events.send(Event::Tick).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::net::SocketAddr;
+use dsrp_core::framing::{FrameDecoder, Frame, DEFAULT_MAX_FRAME_SIZE};
+use dsrp_core::messages::{ChannelId, ConnectionId, ConnectionFailureReason};
+use tokio::io;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::prelude::*;
+use tokio::sync::mpsc;
+use crate::agent::Event;
+
+const READ_BUFFER_SIZE: usize = 8192;
+const MAX_UDP_PACKET_SIZE: usize = 65536;
+
+/// Writes every chunk of bytes it's given to the socket, until all senders have been dropped
+/// and the write side can be shut down.  Returns the error if the socket could not be written to.
+pub async fn write_outbound<W>(mut writer: W, mut outbound: mpsc::UnboundedReceiver<Vec<u8>>) -> io::Result<()>
+    where W: AsyncWrite + Unpin {
+
+    while let Some(bytes) = outbound.recv().await {
+        if let Err(error) = writer.write_all(&bytes).await {
+            println!("Failed to write to socket: {}", error);
+            return Err(error);
+        }
+    }
+
+    let _ = writer.shutdown().await;
+    Ok(())
+}
+
+/// Reads the handshake response and message frames sent by the DSRP server and raises them as
+/// agent events
+pub async fn read_dsrp_server<R>(mut reader: R, events: mpsc::UnboundedSender<Event>)
+    where R: AsyncRead + Unpin {
+
+    let mut decoder = FrameDecoder::for_client(DEFAULT_MAX_FRAME_SIZE);
+    let mut buffer = [0; READ_BUFFER_SIZE];
+
+    'reading: loop {
+        let bytes_read = match reader.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(x) => x,
+            Err(error) => {
+                println!("Error reading from DSRP server: {}", error);
+                break;
+            },
+        };
+
+        decoder.push_bytes(&buffer[..bytes_read]);
+        loop {
+            let event = match decoder.next_frame() {
+                Ok(Some(Frame::HandshakeResponse(response))) => Event::HandshakeResponse {response},
+                Ok(Some(Frame::ServerMessage(message))) => Event::ServerMessage {message},
+                Ok(Some(frame)) => {
+                    println!("Unexpected frame received from DSRP server: {:?}", frame);
+                    break 'reading;
+                },
+
+                Ok(None) => break,
+                Err(error) => {
+                    println!("Invalid data received from DSRP server: {}", error);
+                    break 'reading;
+                },
+            };
+
+            if events.send(event).is_err() {
+                break 'reading;
+            }
+        }
+    }
+
+    let _ = events.send(Event::ServerDisconnected);
+}
+
+/// Opens a connection to the application server for a connection the DSRP server accepted, and
+/// raises an event once it's established.  Data queued for the connection is written after that.
+pub async fn run_local_tcp_connection(target: SocketAddr,
+                                      channel: ChannelId,
+                                      connection: ConnectionId,
+                                      outbound: mpsc::UnboundedReceiver<Vec<u8>>,
+                                      events: mpsc::UnboundedSender<Event>) {
+    let stream = match TcpStream::connect(target).await {
+        Ok(x) => x,
+        Err(error) => {
+            println!("Failed to connect to {}: {}", target, error);
+            let reason = match error.kind() {
+                io::ErrorKind::ConnectionRefused => ConnectionFailureReason::ConnectionRefused,
+                io::ErrorKind::TimedOut => ConnectionFailureReason::TimedOut,
+                _ => ConnectionFailureReason::Other,
+            };
+
+            let _ = events.send(Event::LocalTcpConnectionFailed {channel, connection, reason});
+            return;
+        }
+    };
+
+    if events.send(Event::LocalTcpConnectionEstablished {channel, connection}).is_err() {
+        return;
+    }
+
+    let (mut reader, writer) = stream.into_split();
+    tokio::spawn(write_local_tcp_connection(writer, outbound, channel, connection, events.clone()));
+
+    let mut buffer = [0; READ_BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buffer).await {
+            Ok(0) => {
+                // The application server may still be waiting on data even though it's done sending
+                let _ = events.send(Event::LocalTcpConnectionHalfClosed {channel, connection});
+                return;
+            },
+
+            Ok(x) => {
+                let data = buffer[..x].to_vec();
+                if events.send(Event::LocalTcpDataReceived {channel, connection, data}).is_err() {
+                    return;
+                }
+            },
+
+            Err(error) => {
+                println!("Error reading from {}: {}", target, error);
+                break;
+            },
+        }
+    }
+
+    let _ = events.send(Event::LocalTcpConnectionClosed {channel, connection});
+}
+
+/// Writes queued bytes to a connection with the application server.  Failing to write closes the
+/// connection, since the reader stops watching it once the application server half-closes, and
+/// a reset sent after that is only noticed here.
+async fn write_local_tcp_connection(writer: OwnedWriteHalf,
+                                    outbound: mpsc::UnboundedReceiver<Vec<u8>>,
+                                    channel: ChannelId,
+                                    connection: ConnectionId,
+                                    events: mpsc::UnboundedSender<Event>) {
+    if write_outbound(writer, outbound).await.is_err() {
+        let _ = events.send(Event::LocalTcpConnectionClosed {channel, connection});
+    }
+}
+
+/// Relays a remote peer's packets for a udp connection to the application server through a
+/// local socket, raising any replies as agent events
+pub async fn run_local_udp_socket(target: SocketAddr,
+                                  channel: ChannelId,
+                                  connection: ConnectionId,
+                                  mut outbound: mpsc::UnboundedReceiver<Vec<u8>>,
+                                  events: mpsc::UnboundedSender<Event>) {
+    let bind_address: SocketAddr = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+    let socket = match UdpSocket::bind(bind_address).await {
+        Ok(x) => x,
+        Err(error) => {
+            println!("Failed to bind local udp socket: {}", error);
+            let reason = ConnectionFailureReason::Other;
+            let _ = events.send(Event::LocalUdpConnectionFailed {channel, connection, reason});
+            return;
+        }
+    };
+
+    let (mut receiver, mut sender) = socket.split();
+    tokio::spawn(async move {
+        while let Some(data) = outbound.recv().await {
+            if let Err(error) = sender.send_to(&data, &target).await {
+                println!("Failed to send udp packet to {}: {}", target, error);
+            }
+        }
+    });
+
+    let mut buffer = vec![0; MAX_UDP_PACKET_SIZE];
+    loop {
+        match receiver.recv_from(&mut buffer).await {
+            Ok((size, peer)) if peer == target => {
+                let data = buffer[..size].to_vec();
+                if events.send(Event::LocalUdpDataReceived {channel, connection, data}).is_err() {
+                    break;
+                }
+            },
+
+            Ok(_) => (), // Only the application server should be sending to this socket
+            Err(error) => {
+                println!("Failed to receive udp packet from {}: {}", target, error);
+                break;
+            },
+        }
+    }
+
+    // Udp connections can only be closed from the server side, so the server is told the
+    // connection failed in order for it to stop relaying the peer's packets to this socket
+    let reason = ConnectionFailureReason::Other;
+    let _ = events.send(Event::LocalUdpConnectionFailed {channel, connection, reason});
+}
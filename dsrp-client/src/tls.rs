@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerCertVerified};
+use tokio_rustls::rustls::{ServerCertVerifier, TLSError, WebPKIVerifier};
+use tokio_rustls::rustls::internal::pemfile;
+use webpki::DNSNameRef;
+
+/// Length of a SHA-256 certificate fingerprint
+pub const FINGERPRINT_LENGTH: usize = 32;
+
+/// Builds the connector used to wrap the DSRP server connection in tls.  The server's
+/// certificate is verified against the CA bundle when one is given, and must match the pinned
+/// fingerprint when one is given.  The client certificate, given as a certificate chain and
+/// private key pair, is presented to servers that require clients to identify themselves.
+pub fn build_connector(ca_path: Option<&Path>,
+                       fingerprint: Option<[u8; FINGERPRINT_LENGTH]>,
+                       client_certificate: Option<(&Path, &Path)>) -> io::Result<TlsConnector> {
+
+    let mut config = ClientConfig::new();
+    if let Some(path) = ca_path {
+        let (valid_count, _) = config.root_store.add_pem_file(&mut open_pem_file(path)?)
+            .map_err(|_| invalid_ca_file(path))?;
+
+        if valid_count == 0 {
+            return Err(invalid_ca_file(path));
+        }
+    }
+
+    if let Some(fingerprint) = fingerprint {
+        let verifier = PinnedCertificateVerifier {
+            fingerprint,
+            verify_chain: ca_path.is_some(),
+        };
+
+        config.dangerous().set_certificate_verifier(Arc::new(verifier));
+    }
+
+    if let Some((cert_path, key_path)) = client_certificate {
+        let certs = pemfile::certs(&mut open_pem_file(cert_path)?)
+            .map_err(|_| invalid_pem_file(cert_path))?;
+
+        if certs.is_empty() {
+            return Err(invalid_pem_file(cert_path));
+        }
+
+        let key = load_private_key(key_path)?;
+        config.set_single_client_cert(certs, key)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    }
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Reads the first private key out of a PEM file, which can be either PKCS#8 or RSA encoded
+fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let pkcs8_keys = pemfile::pkcs8_private_keys(&mut open_pem_file(path)?)
+        .map_err(|_| invalid_pem_file(path))?;
+
+    if let Some(key) = pkcs8_keys.into_iter().next() {
+        return Ok(key);
+    }
+
+    let rsa_keys = pemfile::rsa_private_keys(&mut open_pem_file(path)?)
+        .map_err(|_| invalid_pem_file(path))?;
+
+    rsa_keys.into_iter().next().ok_or_else(|| invalid_pem_file(path))
+}
+
+/// Accepts the server's certificate only if its SHA-256 fingerprint matches the pinned one,
+/// optionally also requiring the certificate chain to be valid for the configured CAs
+struct PinnedCertificateVerifier {
+    fingerprint: [u8; FINGERPRINT_LENGTH],
+    verify_chain: bool,
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(&self,
+                          roots: &RootCertStore,
+                          presented_certs: &[Certificate],
+                          dns_name: DNSNameRef,
+                          ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
+
+        if self.verify_chain {
+            WebPKIVerifier::new().verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+        }
+
+        let certificate = presented_certs.first().ok_or(TLSError::NoCertificatesPresented)?;
+        if Sha256::digest(&certificate.0)[..] != self.fingerprint[..] {
+            let message = "Server certificate does not match the pinned fingerprint".to_owned();
+            return Err(TLSError::General(message));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn open_pem_file(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|error| io::Error::new(error.kind(), format!("Failed to open {}: {}", path.display(), error)))
+}
+
+fn invalid_ca_file(path: &Path) -> io::Error {
+    let message = format!("{} does not contain any valid PEM encoded certificates", path.display());
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn invalid_pem_file(path: &Path) -> io::Error {
+    let message = format!("{} does not contain a valid PEM encoded certificate or key", path.display());
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
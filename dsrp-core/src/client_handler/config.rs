@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+/// Settings that control how the client handler authenticates and manages its connections
+#[derive(Debug, Clone)]
+pub struct ClientHandlerConfig {
+    /// Shared secret used to answer the DSRP server's authentication challenge, if it sends one
+    pub auth_secret: Option<Vec<u8>>,
+
+    /// Most bytes of data held for a tcp connection while the client is still connecting to the
+    /// application server.  Connections that receive more than this before they're established
+    /// are aborted.
+    pub max_pending_connection_data: usize,
+
+    /// How often the DSRP server is sent a ping to make sure it's still responsive
+    pub heartbeat_interval: Duration,
+
+    /// How many pings in a row the DSRP server can leave unanswered before it's considered dead
+    pub max_missed_heartbeats: u32,
+
+    /// How long to wait before asking again for a lost channel the DSRP server couldn't restore
+    /// for now, such as while its port is still held by a session the server hasn't noticed is
+    /// dead.  The delay doubles with each failed attempt, up to the maximum.
+    pub initial_restoration_retry_delay: Duration,
+    pub max_restoration_retry_delay: Duration,
+}
+
+impl Default for ClientHandlerConfig {
+    fn default() -> Self {
+        ClientHandlerConfig {
+            auth_secret: None,
+            max_pending_connection_data: 64 * 1024,
+            heartbeat_interval: Duration::from_secs(30),
+            max_missed_heartbeats: 3,
+            initial_restoration_retry_delay: Duration::from_secs(1),
+            max_restoration_retry_delay: Duration::from_secs(60),
+        }
+    }
+}
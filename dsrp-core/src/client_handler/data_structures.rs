@@ -1,32 +1,176 @@
 use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+use handshake::HandshakeChallengeAnswer;
 use messages::{ClientMessage, ConnectionType, RequestId, ChannelId, ConnectionId};
-use messages::RegistrationFailureCause;
+use messages::{RegistrationFailureCause, ChannelRevocationReason};
+use stats::{ConnectionCounts, TrafficStats};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HandshakeState {
+    AwaitingResponse,
+    AwaitingChallengeVerdict,
+    Completed,
+}
+
+/// What the client should do after the DSRP server responded to its handshake
+#[derive(Debug, PartialEq)]
+pub enum HandshakeProgress {
+    /// The server accepted the client and messages can now be exchanged with it
+    Accepted,
+
+    /// The server refused the client and will close the connection
+    Rejected {
+        reason: String,
+    },
+
+    /// The server is shutting down and will close the connection, but it's worth reconnecting
+    /// later in case it's replaced
+    ServerShuttingDown,
+
+    /// The server requires the client to authenticate, and the answer needs to be sent to it
+    SendChallengeAnswer {
+        answer: HandshakeChallengeAnswer,
+    },
+}
 
 #[derive(Debug)]
 pub enum OutstandingRequest {
     Registration{
-        connection_type: ConnectionType,
-        port: u16,
+        registration: Registration,
+
+        /// The channel being re-registered, when the request was made to get back a channel
+        /// that was lost along with the connection to the DSRP server
+        restoring: Option<LostChannel>,
     }
 }
 
+/// What was asked for when registering a channel, so the same registration can be made again
+#[derive(Debug, Clone, PartialEq)]
+pub struct Registration {
+    pub connection_type: ConnectionType,
+
+    /// Port that was asked for, or `None` when the server was asked to choose one
+    pub port: Option<u16>,
+
+    /// Range the server was asked to choose a port from, if it was asked to choose one
+    pub port_range: Option<RangeInclusive<u16>>,
+
+    pub bind_address: Option<IpAddr>,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct ActiveChannel {
-    pub connection_type: ConnectionType,
+    pub registration: Registration,
+
+    /// Port the DSRP server bound for the channel
+    pub port: u16,
     pub connections: HashSet<ConnectionId>,
+    pub opened_at: Instant,
+    pub traffic: TrafficStats,
+    pub connection_counts: ConnectionCounts,
+}
+
+/// A channel that was active when the connection to the DSRP server was lost, and which needs
+/// to be registered again once the client reconnects
+#[derive(Debug, Clone)]
+pub struct LostChannel {
+    pub channel: ChannelId,
+    pub port: u16,
+    pub registration: Registration,
+
+    /// How long was waited before the latest attempt to restore the channel, if it has already
+    /// failed for a reason that's worth retrying
+    pub retry_delay: Option<Duration>,
+}
+
+/// A lost channel the DSRP server couldn't restore for now, which is asked for again once the
+/// retry time is reached
+#[derive(Debug)]
+pub struct DelayedRestoration {
+    pub retry_at: Instant,
+    pub lost_channel: LostChannel,
 }
 
 pub struct ActiveConnection {
     pub owner: ChannelId,
+    pub state: ConnectionState,
+
+    /// Data the DSRP server relayed before the connection to the application server was open
+    pub pending_data: Vec<Vec<u8>>,
+    pub pending_data_size: usize,
+
+    /// The remote peer has finished sending data over the connection
+    pub remote_write_closed: bool,
+
+    /// The application server has finished sending data over the connection
+    pub local_write_closed: bool,
+
+    pub opened_at: Instant,
+    pub traffic: TrafficStats,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    /// The client is still opening its own connection to the application server
+    Connecting,
+
+    /// Data can be relayed in both directions
+    Open,
+
+    /// The DSRP server closed the connection before the client finished connecting, and it's
+    /// only kept around until its pending data has been released
+    Closing,
 }
 
 #[derive(Debug)]
 pub enum ClientOperation {
     /// Notifies the client that a port registration request was successful and a channel
-    /// was assigned for communication over it.
+    /// was assigned for communication over it, along with the port the DSRP server bound.
     NotifyChannelOpened {
         registered_by_request: RequestId,
         opened_channel: ChannelId,
+        port: u16,
+    },
+
+    /// Notifies the client that a channel lost when the connection to the DSRP server dropped
+    /// was registered again after reconnecting.  The channel has a new id, and `same_port` says
+    /// whether the DSRP server managed to bind the port it had before.
+    NotifyChannelRestored {
+        previous_channel: ChannelId,
+        restored_channel: ChannelId,
+        port: u16,
+        same_port: bool,
+    },
+
+    /// Notifies the client that a channel lost when the connection to the DSRP server dropped
+    /// could not be registered again after reconnecting, and won't be asked for again.
+    NotifyChannelRestorationFailed {
+        previous_channel: ChannelId,
+        cause: RegistrationFailureCause,
+    },
+
+    /// Notifies the client that a channel lost when the connection to the DSRP server dropped
+    /// could not be registered again for now, such as when its port is still taken, and that
+    /// it will be asked for again at the retry time.
+    NotifyChannelRestorationDelayed {
+        previous_channel: ChannelId,
+        cause: RegistrationFailureCause,
+        retry_at: Instant,
+    },
+
+    /// Notifies the client that the DSRP server has closed a channel it asked to unregister.  Any
+    /// connections the channel still had are closed by separate operations.
+    NotifyChannelClosed {
+        channel: ChannelId,
+    },
+
+    /// Notifies the client that the DSRP server closed a channel without being asked to, and
+    /// why.  Any connections the channel still had are closed by separate operations.
+    NotifyChannelRevoked {
+        channel: ChannelId,
+        reason: ChannelRevocationReason,
     },
 
     /// The specified message should be sent to the DSRP server the client is connected to
@@ -43,6 +187,33 @@ pub enum ClientOperation {
         new_connection: ConnectionId,
     },
 
+    /// Notifies the client that the DSRP server has started receiving packets from a new remote
+    /// peer on a udp channel, and instructs the client to create a udp socket from the DSRP
+    /// client to the application server dedicated to that peer's traffic
+    CreateUdpConnectionForChannel {
+        channel: ChannelId,
+        new_connection: ConnectionId,
+        peer_address: SocketAddr,
+    },
+
+    /// Notifies the client that the DSRP server has expired an idle udp connection, and that the
+    /// client should close the udp socket it was using for that connection.
+    CloseUdpConnection {
+        channel: ChannelId,
+        connection: ConnectionId,
+    },
+
+    /// Notifies the client that the DSRP server has stopped answering pings, and that its
+    /// connection to the server should be treated as lost.
+    NotifyServerUnresponsive,
+
+    /// Notifies the client that the DSRP server is shutting down.  The server won't accept new
+    /// registrations or connections, and will close all channels at the deadline, so the client
+    /// should move its traffic to another server before then.
+    NotifyServerShuttingDown {
+        deadline: Instant,
+    },
+
     /// Notifies the client that the DSRP server has rejected a registration request, usually
     /// due to the port being requested still being in use.
     NotifyRegistrationFailed {
@@ -58,11 +229,21 @@ pub enum ClientOperation {
         connection: ConnectionId,
     },
 
+    /// Notifies the client that the remote peer has finished sending data over the TCP connection,
+    /// and that the client should shut down the write side of its connection to the application
+    /// server once any queued data has been written, while still relaying what it sends back.
+    ShutdownTcpConnectionWrite {
+        channel: ChannelId,
+        connection: ConnectionId,
+    },
+
     /// A data packet should be sent to the application server over the specified channel (or
-    /// connection for a tcp channel).
+    /// connection for a tcp channel).  Packets for udp channels specify the remote peer that
+    /// sent them, so replies can be sent back to that same peer.
     RelayRemotePacket {
         channel: ChannelId,
         connection: Option<ConnectionId>,
+        peer_address: Option<SocketAddr>,
         data: Vec<u8>,
     },
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 use std::fmt;
 use failure::Fail;
-use messages::RequestId;
+use messages::{RequestId, ChannelId, ConnectionId};
 
 #[derive(Debug)]
 pub struct ServerMessageHandlingError {
@@ -13,8 +13,59 @@ pub enum ServerMessageHandlingErrorKind {
     UnknownRequest(RequestId),
 }
 
+#[derive(Debug)]
+pub struct ClientRequestError {
+    pub kind: ClientRequestErrorKind,
+}
+
+#[derive(Debug, Fail)]
+pub enum ClientRequestErrorKind {
+    #[fail(display = "Channel {:?} is not an active channel", _0)]
+    UnknownChannel(ChannelId),
+
+    #[fail(display = "Connection {:?} is not an active connection on channel {:?}", connection, channel)]
+    UnknownConnection {
+        channel: ChannelId,
+        connection: ConnectionId,
+    },
+
+    #[fail(display = "Channel {:?} is not a TCP channel", _0)]
+    NotTcpChannel(ChannelId),
+
+    #[fail(display = "Connection {:?} on channel {:?} has already been established", connection, channel)]
+    ConnectionAlreadyEstablished {
+        channel: ChannelId,
+        connection: ConnectionId,
+    },
+}
+
+#[derive(Debug)]
+pub struct HandshakeResponseHandlingError {
+    pub kind: HandshakeResponseHandlingErrorKind,
+}
+
+#[derive(Debug, Fail)]
+pub enum HandshakeResponseHandlingErrorKind {
+    #[fail(display = "Server sent an authentication challenge but no auth secret was configured")]
+    NoAuthSecretConfigured,
+
+    #[fail(display = "Handshake response received when none was expected")]
+    UnexpectedHandshakeResponse,
+}
+
+impl fmt::Display for HandshakeResponseHandlingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.kind, f)
+    }
+}
+
 impl fmt::Display for ServerMessageHandlingError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(&self.kind, f)
     }
-}
\ No newline at end of file
+}
+impl fmt::Display for ClientRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.kind, f)
+    }
+}
@@ -1,63 +1,406 @@
+mod config;
 mod data_structures;
+#[allow(non_local_definitions)] // emitted by failure's derive macro
 mod errors;
+mod stats;
 
 pub use self::errors::{ServerMessageHandlingError, ServerMessageHandlingErrorKind};
+pub use self::errors::{ClientRequestError, ClientRequestErrorKind};
+pub use self::errors::{HandshakeResponseHandlingError, HandshakeResponseHandlingErrorKind};
+pub use self::data_structures::{ClientOperation, HandshakeProgress};
+pub use self::config::ClientHandlerConfig;
+pub use self::stats::{ClientStats, ChannelStats, ConnectionStats};
 
 use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::num::Wrapping;
-use handshake::HandshakeRequest;
-use messages::{ClientMessage, ServerMessage, ConnectionType};
-use messages::{RequestId, ChannelId, ConnectionId};
-use self::data_structures::{ClientOperation, OutstandingRequest, ActiveChannel, ActiveConnection};
+use std::ops::RangeInclusive;
+use std::cmp::min;
+use std::time::Instant;
+use ::clock::{Clock, SystemClock};
+use handshake::{HandshakeRequest, HandshakeResponse, HandshakeChallengeAnswer, SessionTicket, compute_challenge_answer};
+use messages::{ClientMessage, ServerMessage, ConnectionType, ConnectionFailureReason, ChannelRevocationReason};
+use messages::{RequestId, ChannelId, ConnectionId, RegistrationFailureCause};
+use ::stats::{ConnectionCounts, TrafficStats};
+use self::data_structures::{OutstandingRequest, ActiveChannel, ActiveConnection, HandshakeState};
+use self::data_structures::{ConnectionState, Registration, LostChannel, DelayedRestoration};
 
 pub struct ClientHandler {
+    config: ClientHandlerConfig,
+    handshake_state: HandshakeState,
     outstanding_requests: HashMap<RequestId, OutstandingRequest>,
     next_request_id: Wrapping<u32>,
     active_channels: HashMap<ChannelId, ActiveChannel>,
     active_connections: HashMap<ConnectionId, ActiveConnection>,
+    lost_channels: Vec<LostChannel>,
+    delayed_restorations: Vec<DelayedRestoration>,
+    session_ticket: Option<SessionTicket>,
+
+    /// Set when the DSRP server resumed the previous session, so lost channels are still
+    /// registered and don't need to be registered again
+    session_resumed: bool,
+    clock: Box<dyn Clock>,
+    last_ping_sent: Instant,
+    awaiting_pong: bool,
+    missed_pongs: u32,
+    traffic: TrafficStats,
+    connection_counts: ConnectionCounts,
 }
 
 impl ClientHandler {
     pub fn new() -> (Self, HandshakeRequest) {
+        ClientHandler::with_config(ClientHandlerConfig::default(), Box::new(SystemClock))
+    }
+
+    /// Creates a handler that can answer the DSRP server's authentication challenge using the
+    /// specified shared secret
+    pub fn with_auth_secret(secret: Vec<u8>) -> (Self, HandshakeRequest) {
+        let config = ClientHandlerConfig {
+            auth_secret: Some(secret),
+            ..ClientHandlerConfig::default()
+        };
+
+        ClientHandler::with_config(config, Box::new(SystemClock))
+    }
+
+    pub fn with_config(config: ClientHandlerConfig, clock: Box<dyn Clock>) -> (Self, HandshakeRequest) {
         let handshake = HandshakeRequest::new();
+        let now = clock.now();
 
         let client = ClientHandler {
+            config,
+            handshake_state: HandshakeState::AwaitingResponse,
             outstanding_requests: HashMap::new(),
             next_request_id: Wrapping(0),
             active_channels: HashMap::new(),
             active_connections: HashMap::new(),
+            lost_channels: Vec::new(),
+            delayed_restorations: Vec::new(),
+            session_ticket: None,
+            session_resumed: false,
+            clock,
+            last_ping_sent: now,
+            awaiting_pong: false,
+            missed_pongs: 0,
+            traffic: TrafficStats::default(),
+            connection_counts: ConnectionCounts::default(),
         };
 
         (client, handshake)
     }
 
-    pub fn request_registration(&mut self, connection_type: ConnectionType, port: u16) -> (RequestId, ClientMessage) {
-        let request_id;
-        loop {
-            self.next_request_id += Wrapping(1);
-            let next_request = RequestId(self.next_request_id.0);
-            if self.outstanding_requests.contains_key(&next_request) {
-                continue;
+    pub fn handle_handshake_response(&mut self, response: HandshakeResponse)
+        -> Result<HandshakeProgress, HandshakeResponseHandlingError> {
+
+        let progress = match (self.handshake_state, response) {
+            (HandshakeState::Completed, _) |
+            (HandshakeState::AwaitingChallengeVerdict, HandshakeResponse::Challenge {..}) => {
+                let kind = HandshakeResponseHandlingErrorKind::UnexpectedHandshakeResponse;
+                return Err(HandshakeResponseHandlingError {kind});
+            },
+
+            (_, HandshakeResponse::Success {session_ticket, resumed}) => {
+                self.session_ticket = Some(session_ticket);
+                self.session_resumed = resumed;
+                HandshakeProgress::Accepted
+            },
+
+            (_, HandshakeResponse::Failure {reason}) => HandshakeProgress::Rejected {reason},
+            (_, HandshakeResponse::ServerShuttingDown) => HandshakeProgress::ServerShuttingDown,
+            (HandshakeState::AwaitingResponse, HandshakeResponse::Challenge {nonce}) => {
+                let secret = match self.config.auth_secret {
+                    Some(ref x) => x,
+                    None => {
+                        let kind = HandshakeResponseHandlingErrorKind::NoAuthSecretConfigured;
+                        return Err(HandshakeResponseHandlingError {kind});
+                    },
+                };
+
+                let hmac = compute_challenge_answer(secret, &nonce);
+                HandshakeProgress::SendChallengeAnswer {answer: HandshakeChallengeAnswer {hmac}}
+            },
+        };
+
+        self.handshake_state = match progress {
+            HandshakeProgress::SendChallengeAnswer {..} => HandshakeState::AwaitingChallengeVerdict,
+            _ => HandshakeState::Completed,
+        };
+
+        if let HandshakeProgress::Accepted = progress {
+            self.last_ping_sent = self.clock.now();
+        }
+
+        Ok(progress)
+    }
+
+    /// Performs any time based work, such as pinging the DSRP server to detect when it has
+    /// stopped responding and asking again for lost channels whose restoration was delayed
+    pub fn tick(&mut self, now: Instant) -> Vec<ClientOperation> {
+        if self.handshake_state != HandshakeState::Completed {
+            return Vec::new();
+        }
+
+        let (due, delayed) = self.delayed_restorations.drain(..)
+            .partition::<Vec<_>, _>(|restoration| restoration.retry_at <= now);
+
+        self.delayed_restorations = delayed;
+        let mut operations = due.into_iter()
+            .map(|restoration| self.request_restoration(restoration.lost_channel))
+            .map(|message| ClientOperation::SendMessageToServer {message})
+            .collect::<Vec<_>>();
+
+        if now.duration_since(self.last_ping_sent) < self.config.heartbeat_interval {
+            return operations;
+        }
+
+        if self.awaiting_pong {
+            self.missed_pongs += 1;
+            if self.missed_pongs >= self.config.max_missed_heartbeats {
+                operations.push(ClientOperation::NotifyServerUnresponsive);
+                return operations;
             }
+        }
+
+        self.last_ping_sent = now;
+        self.awaiting_pong = true;
+        operations.push(ClientOperation::SendMessageToServer {message: ClientMessage::Ping});
+        operations
+    }
 
-            request_id = next_request;
-            break;
+    /// Resets the handler after its connection to the DSRP server was lost, returning the
+    /// operations that close every local connection along with the handshake to send when
+    /// reconnecting.  The handshake asks to resume the previous session if the server issued a
+    /// ticket for it.  The channels that were active are restored by `restore_registrations()`
+    /// once the new handshake has been accepted.
+    pub fn server_connection_lost(&mut self) -> (Vec<ClientOperation>, HandshakeRequest) {
+        let mut operations = Vec::new();
+        let channel_ids: Vec<_> = self.active_channels.keys().cloned().collect();
+        for channel_id in channel_ids {
+            let (channel, mut close_operations) = self.remove_channel(channel_id)
+                .expect("Channel was just listed as active");
+
+            operations.append(&mut close_operations);
+            self.lost_channels.push(LostChannel {
+                channel: channel_id,
+                port: channel.port,
+                registration: channel.registration,
+                retry_delay: None,
+            });
         }
 
-        let request = OutstandingRequest::Registration {connection_type: connection_type.clone(), port};
-        self.outstanding_requests.insert(request_id, request);
-        let message = ClientMessage::Register {
-            request: request_id,
+        self.handshake_state = HandshakeState::AwaitingResponse;
+        self.awaiting_pong = false;
+        self.missed_pongs = 0;
+
+        let handshake = match self.session_ticket {
+            Some(ticket) => HandshakeRequest::resuming(ticket),
+            None => HandshakeRequest::new(),
+        };
+
+        (operations, handshake)
+    }
+
+    /// Creates the operations that ask the DSRP server, after reconnecting, for every channel lost
+    /// with the previous connection, asking for the same port each channel had before.  When
+    /// the server resumed the previous session the channels are still registered, so they're
+    /// reported as restored right away instead.  Registration requests the previous session never
+    /// got an answer for are sent again as well under their original request ids, unless that
+    /// session was resumed and the server is still working on them.  Channels whose restoration
+    /// was delayed are asked for right away, since the server never registered them.
+    pub fn restore_registrations(&mut self) -> Vec<ClientOperation> {
+        let mut operations = Vec::new();
+        if !self.session_resumed {
+            operations.extend(self.outstanding_requests.iter()
+                .map(|(request_id, request)| match request {
+                    OutstandingRequest::Registration {registration, restoring: _} => registration_message(*request_id, registration),
+                })
+                .map(|message| ClientOperation::SendMessageToServer {message}));
+        } else {
+            for lost_channel in self.lost_channels.drain(..) {
+                let active_channel = ActiveChannel {
+                    registration: lost_channel.registration,
+                    port: lost_channel.port,
+                    connections: HashSet::new(),
+                    opened_at: self.clock.now(),
+                    traffic: TrafficStats::default(),
+                    connection_counts: ConnectionCounts::default(),
+                };
+
+                self.active_channels.insert(lost_channel.channel, active_channel);
+                operations.push(ClientOperation::NotifyChannelRestored {
+                    previous_channel: lost_channel.channel,
+                    restored_channel: lost_channel.channel,
+                    port: lost_channel.port,
+                    same_port: true,
+                });
+            }
+        }
+
+        let lost_channels = self.lost_channels.drain(..)
+            .chain(self.delayed_restorations.drain(..).map(|restoration| restoration.lost_channel))
+            .collect::<Vec<_>>();
+
+        for lost_channel in lost_channels {
+            let message = self.request_restoration(lost_channel);
+            operations.push(ClientOperation::SendMessageToServer {message});
+        }
+
+        operations
+    }
+
+    /// Requests the specified port be registered, optionally bound on one of the specific
+    /// addresses the DSRP server allows instead of its default address
+    pub fn request_registration(&mut self,
+                                connection_type: ConnectionType,
+                                port: u16,
+                                bind_address: Option<IpAddr>) -> (RequestId, ClientMessage) {
+        let registration = Registration {
             connection_type,
-            port,
+            port: Some(port),
+            port_range: None,
+            bind_address,
         };
 
-        (request_id, message)
+        self.request(registration, None)
+    }
+
+    /// Asks the DSRP server to choose a free port for the channel, optionally from within the
+    /// specified range.  The chosen port is reported when the channel is opened.
+    pub fn request_any_port_registration(&mut self,
+                                         connection_type: ConnectionType,
+                                         port_range: Option<RangeInclusive<u16>>,
+                                         bind_address: Option<IpAddr>) -> (RequestId, ClientMessage) {
+        let registration = Registration {
+            connection_type,
+            port: None,
+            port_range,
+            bind_address,
+        };
+
+        self.request(registration, None)
+    }
+
+    /// Asks the DSRP server to close one of the client's active channels.  The channel remains
+    /// active until the server acknowledges it has been closed.
+    pub fn request_unregistration(&mut self, channel: ChannelId) -> Result<ClientMessage, ClientRequestError> {
+        if !self.active_channels.contains_key(&channel) {
+            let kind = ClientRequestErrorKind::UnknownChannel(channel);
+            return Err(ClientRequestError {kind});
+        }
+
+        Ok(ClientMessage::Unregister {channel})
+    }
+
+    /// Creates the message to relay data the application server sent over one of the channel's
+    /// connections back through the DSRP server
+    pub fn send_data(&mut self, channel: ChannelId, connection: ConnectionId, data: Vec<u8>)
+        -> Result<ClientMessage, ClientRequestError> {
+        self.verify_connection(channel, connection)?;
+        self.record_traffic(channel, connection, |traffic| traffic.record_outbound(data.len()));
+
+        Ok(ClientMessage::DataBeingSent {
+            channel,
+            connection: Some(connection),
+            peer_address: None,
+            data,
+        })
+    }
+
+    /// Marks a tcp connection as open once the client has connected to the application server,
+    /// releasing any data the DSRP server relayed for it while it was still connecting
+    pub fn local_connection_established(&mut self, channel: ChannelId, connection: ConnectionId)
+        -> Result<Vec<ClientOperation>, ClientRequestError> {
+        self.verify_connection(channel, connection)?;
+        if self.active_channels[&channel].registration.connection_type != ConnectionType::Tcp {
+            let kind = ClientRequestErrorKind::NotTcpChannel(channel);
+            return Err(ClientRequestError {kind});
+        }
+
+        let active_connection = self.active_connections.get_mut(&connection)
+            .expect("Verified connections are always active");
+
+        let previous_state = active_connection.state;
+        if previous_state == ConnectionState::Open {
+            let kind = ClientRequestErrorKind::ConnectionAlreadyEstablished {channel, connection};
+            return Err(ClientRequestError {kind});
+        }
+
+        active_connection.state = ConnectionState::Open;
+        active_connection.pending_data_size = 0;
+        let mut operations: Vec<_> = active_connection.pending_data.drain(..)
+            .map(|data| ClientOperation::RelayRemotePacket {
+                channel,
+                connection: Some(connection),
+                peer_address: None,
+                data,
+            })
+            .collect();
+
+        if previous_state == ConnectionState::Closing {
+            self.remove_connection(channel, connection);
+            operations.push(ClientOperation::CloseTcpConnection {channel, connection});
+        } else if active_connection.remote_write_closed {
+            operations.push(ClientOperation::ShutdownTcpConnectionWrite {channel, connection});
+        }
+
+        Ok(operations)
+    }
+
+    /// Stops tracking a tcp connection that the application server closed, and creates the
+    /// message telling the DSRP server to close its side of the connection
+    pub fn local_connection_closed(&mut self, channel: ChannelId, connection: ConnectionId)
+        -> Result<ClientMessage, ClientRequestError> {
+        self.remove_local_tcp_connection(channel, connection)?;
+        Ok(ClientMessage::TcpConnectionDisconnected {channel, connection})
+    }
+
+    /// Records that the application server has finished sending data over a tcp connection, and
+    /// returns the operations to let the DSRP server know.  The connection is closed once both
+    /// sides have finished sending.
+    pub fn local_connection_half_closed(&mut self, channel: ChannelId, connection: ConnectionId)
+        -> Result<Vec<ClientOperation>, ClientRequestError> {
+        self.verify_connection(channel, connection)?;
+        if self.active_channels[&channel].registration.connection_type != ConnectionType::Tcp {
+            let kind = ClientRequestErrorKind::NotTcpChannel(channel);
+            return Err(ClientRequestError {kind});
+        }
+
+        let active_connection = self.active_connections.get_mut(&connection)
+            .expect("Verified connections are always active");
+
+        active_connection.local_write_closed = true;
+        let message = ClientMessage::TcpConnectionHalfClosed {channel, connection};
+        let mut operations = vec![ClientOperation::SendMessageToServer {message}];
+        if active_connection.remote_write_closed {
+            self.remove_connection(channel, connection);
+            operations.push(ClientOperation::CloseTcpConnection {channel, connection});
+        }
+
+        Ok(operations)
+    }
+
+    /// Stops tracking a connection that could not be opened to the application server, and
+    /// creates the message telling the DSRP server to abort its side of the connection
+    pub fn local_connection_failed(&mut self,
+                                   channel: ChannelId,
+                                   connection: ConnectionId,
+                                   reason: ConnectionFailureReason) -> Result<ClientMessage, ClientRequestError> {
+        self.verify_connection(channel, connection)?;
+        let connection_type = self.active_channels[&channel].registration.connection_type.clone();
+        self.remove_connection(channel, connection);
+        self.record_connections(channel, ConnectionCounts::record_failed);
+
+        let message = match connection_type {
+            ConnectionType::Tcp => ClientMessage::TcpConnectionFailed {channel, connection, reason},
+            ConnectionType::Udp => ClientMessage::UdpConnectionFailed {channel, connection, reason},
+        };
+
+        Ok(message)
     }
 
     pub fn handle_server_message(&mut self, message: ServerMessage) -> Result<Vec<ClientOperation>, ServerMessageHandlingError> {
         let operations = match message {
-            ServerMessage::RegistrationSuccessful {request: request_id, created_channel} => {
+            ServerMessage::RegistrationSuccessful {request: request_id, created_channel, port} => {
                 let request = match self.outstanding_requests.remove(&request_id) {
                     Some(x) => x,
                     None => {
@@ -67,10 +410,14 @@ impl ClientHandler {
                 };
 
                 match request {
-                    OutstandingRequest::Registration {connection_type, port: _} => {
+                    OutstandingRequest::Registration {registration, restoring: None} => {
                         let active_channel = ActiveChannel {
-                            connection_type,
+                            registration,
+                            port,
                             connections: HashSet::new(),
+                            opened_at: self.clock.now(),
+                            traffic: TrafficStats::default(),
+                            connection_counts: ConnectionCounts::default(),
                         };
 
                         self.active_channels.insert(created_channel, active_channel);
@@ -78,61 +425,120 @@ impl ClientHandler {
                         let notification = ClientOperation::NotifyChannelOpened {
                             opened_channel: created_channel,
                             registered_by_request: request_id,
+                            port,
                         };
 
                         vec![notification]
-                    }
+                    },
+
+                    OutstandingRequest::Registration {registration: _, restoring: Some(lost_channel)} => {
+                        let active_channel = ActiveChannel {
+                            registration: lost_channel.registration,
+                            port,
+                            connections: HashSet::new(),
+                            opened_at: self.clock.now(),
+                            traffic: TrafficStats::default(),
+                            connection_counts: ConnectionCounts::default(),
+                        };
+
+                        self.active_channels.insert(created_channel, active_channel);
+
+                        vec![ClientOperation::NotifyChannelRestored {
+                            previous_channel: lost_channel.channel,
+                            restored_channel: created_channel,
+                            port,
+                            same_port: port == lost_channel.port,
+                        }]
+                    },
                 }
             },
 
-            ServerMessage::DataReceived {channel: channel_id, connection: connection_id, data} => {
+            ServerMessage::DataReceived {channel: channel_id, connection: connection_id, peer_address, data} => {
                 let channel = match self.active_channels.get(&channel_id) {
                     Some(x) => x,
                     None => return Ok(Vec::new()),
                 };
 
-                match channel.connection_type {
+                match channel.registration.connection_type {
                     ConnectionType::Tcp => {
                         if connection_id.is_none() || !channel.connections.contains(&connection_id.unwrap()) {
                             return Ok(Vec::new()); // all tcp messages should be over a specific connection
                         }
+
+                        if peer_address.is_some() {
+                            return Ok(Vec::new()); // tcp connections are already tied to their remote peer
+                        }
                     },
 
                     ConnectionType::Udp => {
-                        if connection_id.is_some() {
-                            return Ok(Vec::new()); // A specific connection is not valid for udp channels
+                        if connection_id.is_none() || !channel.connections.contains(&connection_id.unwrap()) {
+                            return Ok(Vec::new()); // udp messages are tied to the remote peer's connection
+                        }
+
+                        if peer_address.is_none() {
+                            return Ok(Vec::new()); // udp packets need to say which remote peer sent them
                         }
                     },
                 }
 
+                let connection = self.active_connections.get_mut(&connection_id.unwrap())
+                    .expect("Channel connections are always active");
+
+                match connection.state {
+                    ConnectionState::Open => {
+                        self.record_traffic(channel_id, connection_id.unwrap(), |traffic| traffic.record_inbound(data.len()));
+                    },
+
+                    ConnectionState::Closing => return Ok(Vec::new()), // the server already closed it
+                    ConnectionState::Connecting => {
+                        // Hold on to the data until it can be written to the application server
+                        let length = data.len();
+                        if connection.pending_data_size + length <= self.config.max_pending_connection_data {
+                            connection.pending_data_size += length;
+                            connection.pending_data.push(data);
+                            self.record_traffic(channel_id, connection_id.unwrap(), |traffic| traffic.record_inbound(length));
+                            return Ok(Vec::new());
+                        }
+
+                        let connection_id = connection_id.unwrap();
+                        self.remove_connection(channel_id, connection_id);
+                        self.record_connections(channel_id, ConnectionCounts::record_failed);
+                        let message = ClientMessage::TcpConnectionFailed {
+                            channel: channel_id,
+                            connection: connection_id,
+                            reason: ConnectionFailureReason::Other,
+                        };
+
+                        return Ok(vec![
+                            ClientOperation::CloseTcpConnection {channel: channel_id, connection: connection_id},
+                            ClientOperation::SendMessageToServer {message},
+                        ]);
+                    },
+                }
+
                 vec![ClientOperation::RelayRemotePacket {
                     channel: channel_id,
                     connection: connection_id,
+                    peer_address,
                     data,
                 }]
             },
 
             ServerMessage::TcpConnectionClosed {channel: channel_id, connection: connection_id} => {
-                {
-                    // Validations
-                    let connection = match self.active_connections.get(&connection_id) {
-                        Some(x) => x,
-                        None => return Ok(Vec::new()),
-                    };
-
-                    if connection.owner != channel_id {
+                if let Some(connection) = self.active_connections.get_mut(&connection_id) {
+                    if connection.owner == channel_id &&
+                        connection.state == ConnectionState::Connecting &&
+                        !connection.pending_data.is_empty() {
+
+                        // Closing now would lose the data the remote peer sent before leaving
+                        connection.state = ConnectionState::Closing;
                         return Ok(Vec::new());
                     }
                 }
 
-                // Validations passed
-                let channel = match self.active_channels.get_mut(&channel_id) {
-                    Some(x) => x,
-                    None => return Ok(Vec::new()),
-                };
-
-                self.active_connections.remove(&connection_id);
-                channel.connections.remove(&connection_id);
+                if !self.remove_connection(channel_id, connection_id) {
+                    return Ok(Vec::new());
+                }
 
                 let operation = ClientOperation::CloseTcpConnection {
                     channel: channel_id,
@@ -143,19 +549,39 @@ impl ClientHandler {
 
             },
 
-            ServerMessage::NewIncomingTcpConnection {channel: channel_id, new_connection} => {
-                let channel = match self.active_channels.get_mut(&channel_id) {
-                    Some(x) => x,
-                    None => return Ok(Vec::new()),
+            ServerMessage::TcpConnectionHalfClosed {channel: channel_id, connection: connection_id} => {
+                let connection = match self.active_connections.get_mut(&connection_id) {
+                    Some(x) if x.owner == channel_id => x,
+                    _ => return Ok(Vec::new()),
                 };
 
-                if channel.connection_type != ConnectionType::Tcp {
+                connection.remote_write_closed = true;
+                if connection.local_write_closed {
+                    // Both sides have finished sending, so nothing else can happen over the connection
+                    self.remove_connection(channel_id, connection_id);
+                    vec![ClientOperation::CloseTcpConnection {channel: channel_id, connection: connection_id}]
+                } else if connection.state == ConnectionState::Connecting {
+                    Vec::new() // the write side is shut down once the connection is established
+                } else {
+                    vec![ClientOperation::ShutdownTcpConnectionWrite {channel: channel_id, connection: connection_id}]
+                }
+            },
+
+            ServerMessage::UdpConnectionClosed {channel: channel_id, connection: connection_id} => {
+                if !self.remove_connection(channel_id, connection_id) {
                     return Ok(Vec::new());
                 }
 
-                let active_connection = ActiveConnection {owner: channel_id};
-                channel.connections.insert(new_connection);
-                self.active_connections.insert(new_connection, active_connection);
+                vec![ClientOperation::CloseUdpConnection {
+                    channel: channel_id,
+                    connection: connection_id,
+                }]
+            },
+
+            ServerMessage::NewIncomingTcpConnection {channel: channel_id, new_connection} => {
+                if !self.add_connection(channel_id, ConnectionType::Tcp, new_connection) {
+                    return Ok(Vec::new());
+                }
 
                 let operation = ClientOperation::CreateTcpConnectionForChannel {
                     channel: channel_id,
@@ -165,24 +591,340 @@ impl ClientHandler {
                 vec![operation]
             },
 
+            ServerMessage::NewIncomingUdpConnection {channel: channel_id, new_connection, peer_address} => {
+                if !self.add_connection(channel_id, ConnectionType::Udp, new_connection) {
+                    return Ok(Vec::new());
+                }
+
+                vec![ClientOperation::CreateUdpConnectionForChannel {
+                    channel: channel_id,
+                    new_connection,
+                    peer_address,
+                }]
+            },
+
             ServerMessage::RegistrationFailed {request: request_id, cause} => {
-                match self.outstanding_requests.remove(&request_id) {
-                    Some(_) => (),
+                let request = match self.outstanding_requests.remove(&request_id) {
+                    Some(x) => x,
                     None => {
                         let kind = ServerMessageHandlingErrorKind::UnknownRequest(request_id);
                         return Err(ServerMessageHandlingError {kind});
                     },
+                };
+
+                match request {
+                    OutstandingRequest::Registration {registration: _, restoring: None} => {
+                        vec![ClientOperation::NotifyRegistrationFailed {
+                            request: request_id,
+                            cause,
+                        }]
+                    },
+
+                    OutstandingRequest::Registration {registration, restoring: Some(mut lost_channel)} => {
+                        // Channels the server originally chose the port for can live on any port,
+                        // so only give up on them once the server can't choose one again
+                        if registration.port.is_some() && lost_channel.registration.port.is_none() {
+                            let registration = lost_channel.registration.clone();
+                            let (_, message) = self.request(registration, Some(lost_channel));
+                            vec![ClientOperation::SendMessageToServer {message}]
+                        } else if is_temporary_failure(&cause) {
+                            let retry_delay = match lost_channel.retry_delay {
+                                Some(delay) => min(delay * 2, self.config.max_restoration_retry_delay),
+                                None => self.config.initial_restoration_retry_delay,
+                            };
+
+                            let previous_channel = lost_channel.channel;
+                            let retry_at = self.clock.now() + retry_delay;
+                            lost_channel.retry_delay = Some(retry_delay);
+                            self.delayed_restorations.push(DelayedRestoration {retry_at, lost_channel});
+                            vec![ClientOperation::NotifyChannelRestorationDelayed {previous_channel, cause, retry_at}]
+                        } else {
+                            vec![ClientOperation::NotifyChannelRestorationFailed {
+                                previous_channel: lost_channel.channel,
+                                cause,
+                            }]
+                        }
+                    },
                 }
+            },
 
-                vec![ClientOperation::NotifyRegistrationFailed {
-                    request: request_id,
-                    cause,
-                }]
-            }
+            ServerMessage::Ping => {
+                vec![ClientOperation::SendMessageToServer {message: ClientMessage::Pong}]
+            },
+
+            ServerMessage::Pong => {
+                self.awaiting_pong = false;
+                self.missed_pongs = 0;
+                Vec::new()
+            },
+
+            ServerMessage::ServerShuttingDown {deadline} => {
+                vec![ClientOperation::NotifyServerShuttingDown {deadline: self.clock.now() + deadline}]
+            },
+
+            ServerMessage::ChannelClosed {channel: channel_id} => {
+                let (_, mut operations) = match self.remove_channel(channel_id) {
+                    Some(x) => x,
+                    None => return Ok(Vec::new()),
+                };
+
+                operations.push(ClientOperation::NotifyChannelClosed {channel: channel_id});
+                operations
+            },
+
+            ServerMessage::ChannelRevoked {channel: channel_id, reason} => {
+                let (channel, mut operations) = match self.remove_channel(channel_id) {
+                    Some(x) => x,
+                    None => return Ok(Vec::new()),
+                };
+
+                // A server that's shutting down won't take the registration back, so the channel
+                // is restored along with the others once the client reconnects
+                if reason == ChannelRevocationReason::ServerShuttingDown {
+                    self.lost_channels.push(LostChannel {
+                        channel: channel_id,
+                        port: channel.port,
+                        registration: channel.registration,
+                        retry_delay: None,
+                    });
+                }
+
+                operations.push(ClientOperation::NotifyChannelRevoked {channel: channel_id, reason});
+                operations
+            },
         };
 
         Ok(operations)
     }
+
+    /// Takes a snapshot of the traffic relayed for each channel and connection that's currently
+    /// active, along with the totals relayed since the handler was created
+    pub fn stats(&self) -> ClientStats {
+        let channels = self.active_channels.iter()
+            .map(|(channel_id, channel)| ChannelStats {
+                id: *channel_id,
+                connection_type: channel.registration.connection_type.clone(),
+                port: channel.port,
+                opened_at: channel.opened_at,
+                traffic: channel.traffic,
+                connection_counts: channel.connection_counts,
+                connections: channel.connections.iter()
+                    .filter_map(|id| self.active_connections.get(id).map(|connection| ConnectionStats {
+                        id: *id,
+                        opened_at: connection.opened_at,
+                        traffic: connection.traffic,
+                    }))
+                    .collect(),
+            })
+            .collect();
+
+        ClientStats {
+            traffic: self.traffic,
+            connection_counts: self.connection_counts,
+            open_connections: self.active_connections.len(),
+            channels,
+        }
+    }
+
+    /// Creates the message asking the DSRP server for a lost channel again, starting with the
+    /// port the channel had before
+    fn request_restoration(&mut self, lost_channel: LostChannel) -> ClientMessage {
+        let registration = Registration {
+            port: Some(lost_channel.port),
+            port_range: None,
+            ..lost_channel.registration.clone()
+        };
+
+        let (_, message) = self.request(registration, Some(lost_channel));
+        message
+    }
+
+    /// Tracks a registration request and creates the message asking the DSRP server for it
+    fn request(&mut self, registration: Registration, restoring: Option<LostChannel>) -> (RequestId, ClientMessage) {
+        let request_id = self.next_unused_request_id();
+        let message = registration_message(request_id, &registration);
+        self.outstanding_requests.insert(request_id, OutstandingRequest::Registration {registration, restoring});
+
+        (request_id, message)
+    }
+
+    fn next_unused_request_id(&mut self) -> RequestId {
+        loop {
+            self.next_request_id += Wrapping(1);
+            let request_id = RequestId(self.next_request_id.0);
+            if !self.outstanding_requests.contains_key(&request_id) {
+                return request_id;
+            }
+        }
+    }
+
+    /// Makes sure the connection is active and belongs to the specified channel
+    fn verify_connection(&self, channel_id: ChannelId, connection_id: ConnectionId) -> Result<(), ClientRequestError> {
+        if !self.active_channels.contains_key(&channel_id) {
+            let kind = ClientRequestErrorKind::UnknownChannel(channel_id);
+            return Err(ClientRequestError {kind});
+        }
+
+        match self.active_connections.get(&connection_id) {
+            Some(connection) if connection.owner == channel_id => Ok(()),
+            _ => {
+                let kind = ClientRequestErrorKind::UnknownConnection {
+                    channel: channel_id,
+                    connection: connection_id,
+                };
+
+                Err(ClientRequestError {kind})
+            },
+        }
+    }
+
+    fn remove_local_tcp_connection(&mut self, channel_id: ChannelId, connection_id: ConnectionId)
+        -> Result<(), ClientRequestError> {
+        self.verify_connection(channel_id, connection_id)?;
+        if self.active_channels[&channel_id].registration.connection_type != ConnectionType::Tcp {
+            let kind = ClientRequestErrorKind::NotTcpChannel(channel_id);
+            return Err(ClientRequestError {kind});
+        }
+
+        self.remove_connection(channel_id, connection_id);
+        Ok(())
+    }
+
+    /// Starts tracking a connection the server announced, returning false if the channel is
+    /// unknown or does not carry the specified type of traffic
+    fn add_connection(&mut self, channel_id: ChannelId, connection_type: ConnectionType, connection_id: ConnectionId)
+        -> bool {
+        let channel = match self.active_channels.get_mut(&channel_id) {
+            Some(x) => x,
+            None => return false,
+        };
+
+        if channel.registration.connection_type != connection_type {
+            return false;
+        }
+
+        // Udp sockets can be written to right away, while tcp connections need to connect first
+        let state = match connection_type {
+            ConnectionType::Tcp => ConnectionState::Connecting,
+            ConnectionType::Udp => ConnectionState::Open,
+        };
+
+        let active_connection = ActiveConnection {
+            owner: channel_id,
+            state,
+            pending_data: Vec::new(),
+            pending_data_size: 0,
+            remote_write_closed: false,
+            local_write_closed: false,
+            opened_at: self.clock.now(),
+            traffic: TrafficStats::default(),
+        };
+
+        channel.connections.insert(connection_id);
+        self.active_connections.insert(connection_id, active_connection);
+        self.record_connections(channel_id, ConnectionCounts::record_opened);
+        true
+    }
+
+    /// Counts traffic relayed over a connection towards the connection, its channel and the
+    /// handler's totals
+    fn record_traffic<F>(&mut self, channel_id: ChannelId, connection_id: ConnectionId, record: F)
+        where F: Fn(&mut TrafficStats) {
+        record(&mut self.traffic);
+        if let Some(channel) = self.active_channels.get_mut(&channel_id) {
+            record(&mut channel.traffic);
+        }
+
+        if let Some(connection) = self.active_connections.get_mut(&connection_id) {
+            record(&mut connection.traffic);
+        }
+    }
+
+    /// Counts a connection event on a channel towards the channel and the handler's totals
+    fn record_connections<F>(&mut self, channel_id: ChannelId, record: F) where F: Fn(&mut ConnectionCounts) {
+        record(&mut self.connection_counts);
+        if let Some(channel) = self.active_channels.get_mut(&channel_id) {
+            record(&mut channel.connection_counts);
+        }
+    }
+
+    /// Stops tracking a connection, returning false if it's not a known connection on the
+    /// specified channel
+    fn remove_connection(&mut self, channel_id: ChannelId, connection_id: ConnectionId) -> bool {
+        match self.active_connections.get(&connection_id) {
+            Some(connection) if connection.owner == channel_id => (),
+            _ => return false,
+        }
+
+        let channel = match self.active_channels.get_mut(&channel_id) {
+            Some(x) => x,
+            None => return false,
+        };
+
+        self.active_connections.remove(&connection_id);
+        channel.connections.remove(&connection_id);
+        true
+    }
+
+    /// Stops tracking a channel and all of its connections, returning the channel along with the
+    /// operations needed to close those connections, or `None` if the channel is not active
+    fn remove_channel(&mut self, channel_id: ChannelId) -> Option<(ActiveChannel, Vec<ClientOperation>)> {
+        let mut channel = self.active_channels.remove(&channel_id)?;
+        let mut operations = Vec::new();
+        for connection_id in channel.connections.drain() {
+            self.active_connections.remove(&connection_id);
+            let operation = match channel.registration.connection_type {
+                ConnectionType::Tcp => ClientOperation::CloseTcpConnection {
+                    channel: channel_id,
+                    connection: connection_id,
+                },
+
+                ConnectionType::Udp => ClientOperation::CloseUdpConnection {
+                    channel: channel_id,
+                    connection: connection_id,
+                },
+            };
+
+            operations.push(operation);
+        }
+
+        Some((channel, operations))
+    }
+}
+
+fn registration_message(request_id: RequestId, registration: &Registration) -> ClientMessage {
+    match registration.port {
+        Some(port) => ClientMessage::Register {
+            request: request_id,
+            connection_type: registration.connection_type.clone(),
+            port,
+            bind_address: registration.bind_address,
+        },
+
+        None => ClientMessage::RegisterAnyPort {
+            request: request_id,
+            connection_type: registration.connection_type.clone(),
+            port_range: registration.port_range.clone(),
+            bind_address: registration.bind_address,
+        },
+    }
+}
+
+/// Whether a registration failed for a reason that may clear up by itself, such as a port still
+/// being held by a session the server hasn't noticed is dead, rather than because of the
+/// server's policy
+fn is_temporary_failure(cause: &RegistrationFailureCause) -> bool {
+    match *cause {
+        RegistrationFailureCause::PortAlreadyRegistered |
+        RegistrationFailureCause::SocketBindingFailed |
+        RegistrationFailureCause::NoPortAvailable |
+        RegistrationFailureCause::ServerShuttingDown => true,
+
+        RegistrationFailureCause::NotAuthorized |
+        RegistrationFailureCause::PortNotAllowed |
+        RegistrationFailureCause::ChannelLimitReached |
+        RegistrationFailureCause::BindAddressNotAllowed => false,
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,32 @@
+use std::time::Instant;
+use messages::{ChannelId, ConnectionId, ConnectionType};
+use stats::{ConnectionCounts, TrafficStats};
+
+/// Snapshot of everything the client handler has relayed.  The totals include channels and
+/// connections that have since been closed, including ones lost along with a connection to the
+/// DSRP server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientStats {
+    pub traffic: TrafficStats,
+    pub connection_counts: ConnectionCounts,
+    pub open_connections: usize,
+    pub channels: Vec<ChannelStats>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelStats {
+    pub id: ChannelId,
+    pub connection_type: ConnectionType,
+    pub port: u16,
+    pub opened_at: Instant,
+    pub traffic: TrafficStats,
+    pub connection_counts: ConnectionCounts,
+    pub connections: Vec<ConnectionStats>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionStats {
+    pub id: ConnectionId,
+    pub opened_at: Instant,
+    pub traffic: TrafficStats,
+}
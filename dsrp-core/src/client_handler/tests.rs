@@ -1,7 +1,12 @@
 use super::*;
-use handshake::CURRENT_VERSION;
-use messages::{ChannelId, ConnectionId, RegistrationFailureCause};
+use handshake::{CURRENT_VERSION, CHALLENGE_NONCE_LENGTH, SESSION_TICKET_LENGTH};
+use messages::{ChannelId, ConnectionId, RegistrationFailureCause, ChannelRevocationReason};
+use messages::ConnectionFailureReason;
 use rand;
+use std::time::Duration;
+use ::stats::{ConnectionCounts, TrafficCounters};
+use ::test_utils::test_clock::TestClock;
+use std::net::SocketAddr;
 
 #[test]
 fn new_handler_creates_handshake_request_with_current_protocol_version() {
@@ -9,12 +14,87 @@ fn new_handler_creates_handshake_request_with_current_protocol_version() {
     assert_eq!(request.client_protocol_version, CURRENT_VERSION, "Unexpected protocol version");
 }
 
+#[test]
+fn success_handshake_response_accepts_client() {
+    let (mut client, _) = ClientHandler::new();
+    let progress = client.handle_handshake_response(success_response()).unwrap();
+
+    assert_eq!(progress, HandshakeProgress::Accepted, "Unexpected handshake progress");
+}
+
+#[test]
+fn failure_handshake_response_rejects_client() {
+    let (mut client, _) = ClientHandler::new();
+    let response = HandshakeResponse::Failure {reason: "test".to_owned()};
+    let progress = client.handle_handshake_response(response).unwrap();
+
+    assert_eq!(progress, HandshakeProgress::Rejected {reason: "test".to_owned()}, "Unexpected handshake progress");
+}
+
+#[test]
+fn server_shutting_down_response_reported_separately_from_rejection() {
+    let (mut client, _) = ClientHandler::new();
+    let progress = client.handle_handshake_response(HandshakeResponse::ServerShuttingDown).unwrap();
+
+    assert_eq!(progress, HandshakeProgress::ServerShuttingDown, "Unexpected handshake progress");
+}
+
+#[test]
+fn challenge_answered_with_hmac_of_nonce_keyed_by_secret() {
+    let (mut client, _) = ClientHandler::with_auth_secret(b"secret".to_vec());
+    let nonce = [3; CHALLENGE_NONCE_LENGTH];
+    let progress = client.handle_handshake_response(HandshakeResponse::Challenge {nonce}).unwrap();
+
+    let expected_answer = HandshakeChallengeAnswer {hmac: compute_challenge_answer(b"secret", &nonce)};
+    assert_eq!(progress, HandshakeProgress::SendChallengeAnswer {answer: expected_answer}, "Unexpected handshake progress");
+
+    let progress = client.handle_handshake_response(success_response()).unwrap();
+    assert_eq!(progress, HandshakeProgress::Accepted, "Unexpected handshake progress after answering");
+}
+
+#[test]
+fn error_returned_when_challenged_without_auth_secret() {
+    let (mut client, _) = ClientHandler::new();
+    let nonce = [3; CHALLENGE_NONCE_LENGTH];
+    let error = client.handle_handshake_response(HandshakeResponse::Challenge {nonce}).unwrap_err();
+
+    match error.kind {
+        HandshakeResponseHandlingErrorKind::NoAuthSecretConfigured => (),
+        x => panic!("Expected NoAuthSecretConfigured error, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn error_returned_when_challenged_a_second_time() {
+    let (mut client, _) = ClientHandler::with_auth_secret(b"secret".to_vec());
+    let nonce = [3; CHALLENGE_NONCE_LENGTH];
+    let _ = client.handle_handshake_response(HandshakeResponse::Challenge {nonce}).unwrap();
+    let error = client.handle_handshake_response(HandshakeResponse::Challenge {nonce}).unwrap_err();
+
+    match error.kind {
+        HandshakeResponseHandlingErrorKind::UnexpectedHandshakeResponse => (),
+        x => panic!("Expected UnexpectedHandshakeResponse error, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn error_returned_when_handshake_response_received_after_handshake_completed() {
+    let (mut client, _) = ClientHandler::new();
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    let error = client.handle_handshake_response(success_response()).unwrap_err();
+
+    match error.kind {
+        HandshakeResponseHandlingErrorKind::UnexpectedHandshakeResponse => (),
+        x => panic!("Expected UnexpectedHandshakeResponse error, instead got {:?}", x),
+    }
+}
+
 #[test]
 fn client_can_generate_tcp_port_registration_message() {
     let (mut client, _) = ClientHandler::new();
-    let (request_id, message) = client.request_registration(ConnectionType::Tcp, 23);
+    let (request_id, message) = client.request_registration(ConnectionType::Tcp, 23, None);
     match message {
-        ClientMessage::Register {request, connection_type, port} => {
+        ClientMessage::Register {request, connection_type, port, bind_address: _} => {
             assert_eq!(request, request_id, "Unexpected request ID in message");
             assert_eq!(connection_type, ConnectionType::Tcp, "Unexpected connection type in message");
             assert_eq!(port, 23, "Unexpected port in message");
@@ -27,9 +107,9 @@ fn client_can_generate_tcp_port_registration_message() {
 #[test]
 fn client_can_generate_udp_port_registration_message() {
     let (mut client, _) = ClientHandler::new();
-    let (request_id, message) = client.request_registration(ConnectionType::Udp, 23);
+    let (request_id, message) = client.request_registration(ConnectionType::Udp, 23, None);
     match message {
-        ClientMessage::Register {request, connection_type, port} => {
+        ClientMessage::Register {request, connection_type, port, bind_address: _} => {
             assert_eq!(request, request_id, "Unexpected request ID in message");
             assert_eq!(connection_type, ConnectionType::Udp, "Unexpected connection type in message");
             assert_eq!(port, 23, "Unexpected port in message");
@@ -39,24 +119,62 @@ fn client_can_generate_udp_port_registration_message() {
     }
 }
 
+#[test]
+fn client_can_generate_any_port_registration_message() {
+    let (mut client, _) = ClientHandler::new();
+    let (request_id, message) = client.request_any_port_registration(ConnectionType::Tcp, Some(9000..=9100), None);
+    match message {
+        ClientMessage::RegisterAnyPort {request, connection_type, port_range, bind_address: _} => {
+            assert_eq!(request, request_id, "Unexpected request ID in message");
+            assert_eq!(connection_type, ConnectionType::Tcp, "Unexpected connection type in message");
+            assert_eq!(port_range, Some(9000..=9100), "Unexpected port range in message");
+        },
+
+        x => panic!("Expected RegisterAnyPort message, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn channel_opened_notification_reports_port_chosen_by_server() {
+    let (mut client, _) = ClientHandler::new();
+    let (request_id, _) = client.request_any_port_registration(ConnectionType::Tcp, None, None);
+
+    let channel = ChannelId(5);
+    let response = ServerMessage::RegistrationSuccessful {
+        request: request_id,
+        created_channel: channel,
+        port: 50123,
+    };
+
+    let results = client.handle_server_message(response).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelOpened {registered_by_request, opened_channel, port}
+    => {
+        assert_eq!(*registered_by_request, request_id, "Unexpected request id returned");
+        assert_eq!(*opened_channel, channel, "Unexpected channel id");
+        assert_eq!(*port, 50123, "Unexpected port");
+    });
+}
+
 #[test]
 fn can_process_valid_tcp_registration_success_result() {
     let port = 23;
     let (mut client, _) = ClientHandler::new();
-    let (request_id, _) = client.request_registration(ConnectionType::Tcp, port);
+    let (request_id, _) = client.request_registration(ConnectionType::Tcp, port, None);
 
     let channel = ChannelId(5);
     let response = ServerMessage::RegistrationSuccessful {
         request: request_id,
         created_channel: channel,
+        port,
     };
 
     let results = client.handle_server_message(response).unwrap();
 
-    assert_vec_contains!(results, ClientOperation::NotifyChannelOpened {registered_by_request, opened_channel}
+    assert_vec_contains!(results, ClientOperation::NotifyChannelOpened {registered_by_request, opened_channel, port: opened_port}
     => {
         assert_eq!(*registered_by_request, request_id, "Unexpected request id returned");
         assert_eq!(*opened_channel, channel, "Unexpected channel id");
+        assert_eq!(*opened_port, port, "Unexpected port");
     });
 }
 
@@ -64,20 +182,22 @@ fn can_process_valid_tcp_registration_success_result() {
 fn can_process_valid_udp_registration_success_result() {
     let port = 23;
     let (mut client, _) = ClientHandler::new();
-    let (request_id, _) = client.request_registration(ConnectionType::Udp, port);
+    let (request_id, _) = client.request_registration(ConnectionType::Udp, port, None);
 
     let channel = ChannelId(5);
     let response = ServerMessage::RegistrationSuccessful {
         request: request_id,
         created_channel: channel,
+        port,
     };
 
     let results = client.handle_server_message(response).unwrap();
 
-    assert_vec_contains!(results, ClientOperation::NotifyChannelOpened {registered_by_request, opened_channel}
+    assert_vec_contains!(results, ClientOperation::NotifyChannelOpened {registered_by_request, opened_channel, port: opened_port}
         => {
             assert_eq!(*registered_by_request, request_id, "Unexpected request id returned");
             assert_eq!(*opened_channel, channel, "Unexpected channel id");
+            assert_eq!(*opened_port, port, "Unexpected port");
         });
 }
 
@@ -85,12 +205,13 @@ fn can_process_valid_udp_registration_success_result() {
 fn error_if_response_does_not_match_outstanding_request_id() {
     let port = 23;
     let (mut client, _) = ClientHandler::new();
-    let (request_id, _) = client.request_registration(ConnectionType::Udp, port);
+    let (request_id, _) = client.request_registration(ConnectionType::Udp, port, None);
 
     let bad_request = RequestId(request_id.0 + 1);
     let response = ServerMessage::RegistrationSuccessful {
         request: bad_request,
         created_channel: ChannelId(22),
+        port,
     };
 
     let error = client.handle_server_message(response).unwrap_err();
@@ -104,7 +225,7 @@ fn error_if_response_does_not_match_outstanding_request_id() {
 #[test]
 fn registration_failed_notification_raised_when_server_rejects_registration() {
     let (mut client, _) = ClientHandler::new();
-    let (request_id, _) = client.request_registration(ConnectionType::Tcp, 23);
+    let (request_id, _) = client.request_registration(ConnectionType::Tcp, 23, None);
 
     let response = ServerMessage::RegistrationFailed {
         request: request_id,
@@ -123,7 +244,7 @@ fn registration_failed_notification_raised_when_server_rejects_registration() {
 #[test]
 fn error_returned_when_registration_failure_message_for_untracked_registration() {
     let (mut client, _) = ClientHandler::new();
-    let (request_id, _) = client.request_registration(ConnectionType::Tcp, 23);
+    let (request_id, _) = client.request_registration(ConnectionType::Tcp, 23, None);
 
     let bad_request = RequestId(request_id.0 + 1);
     let response = ServerMessage::RegistrationFailed {
@@ -263,14 +384,16 @@ fn packet_relay_operation_returned_when_dsrp_reports_incoming_data_over_tcp_conn
     let message = ServerMessage::DataReceived {
         channel: channel1,
         connection: Some(connection1),
+        peer_address: None,
         data: expected_data.clone(),
     };
 
     let results = client.handle_server_message(message).unwrap();
-    assert_vec_contains!(results, ClientOperation::RelayRemotePacket {channel, connection, data}
+    assert_vec_contains!(results, ClientOperation::RelayRemotePacket {channel, connection, peer_address, data}
     => {
         assert_eq!(*channel, channel1, "Unexpected channel identifier");
         assert_eq!(*connection, Some(connection1), "Unexpected connection identifier");
+        assert_eq!(*peer_address, None, "Unexpected peer address");
         assert_eq!(&data[..], &expected_data[..], "Unexpected data in packet");
     });
 }
@@ -279,19 +402,22 @@ fn packet_relay_operation_returned_when_dsrp_reports_incoming_data_over_tcp_conn
 fn packet_relay_operation_returned_when_dsrp_reports_incoming_data_over_udp_channel() {
     let (mut client, _) = ClientHandler::new();
     let channel1 = open_channel(&mut client, ConnectionType::Udp, 23);
+    let connection1 = create_udp_connection(&mut client, channel1);
 
     let expected_data = vec![1,2,3,4];
     let message = ServerMessage::DataReceived {
         channel: channel1,
-        connection: None,
+        connection: Some(connection1),
+        peer_address: Some(peer_address()),
         data: expected_data.clone(),
     };
 
     let results = client.handle_server_message(message).unwrap();
-    assert_vec_contains!(results, ClientOperation::RelayRemotePacket {channel, connection, data}
+    assert_vec_contains!(results, ClientOperation::RelayRemotePacket {channel, connection, peer_address: address, data}
     => {
         assert_eq!(*channel, channel1, "Unexpected channel identifier");
-        assert_eq!(*connection, None, "Unexpected connection identifier");
+        assert_eq!(*connection, Some(connection1), "Unexpected connection identifier");
+        assert_eq!(*address, Some(peer_address()), "Unexpected peer address");
         assert_eq!(&data[..], &expected_data[..], "Unexpected data in packet");
     });
 }
@@ -305,6 +431,7 @@ fn no_operation_when_data_received_message_for_unknown_channel() {
     let message = ServerMessage::DataReceived {
         channel: ChannelId(channel1.0 + 1),
         connection: None,
+        peer_address: Some(peer_address()),
         data: expected_data.clone(),
     };
 
@@ -322,6 +449,7 @@ fn no_operation_when_data_received_message_for_unknown_connection() {
     let message = ServerMessage::DataReceived {
         channel: channel1,
         connection: Some(ConnectionId(connection1.0 + 1)),
+        peer_address: None,
         data: expected_data.clone(),
     };
 
@@ -340,6 +468,7 @@ fn no_operation_when_data_received_message_for_connection_not_owned_by_channel()
     let message = ServerMessage::DataReceived {
         channel: channel2,
         connection: Some(connection1),
+        peer_address: None,
         data: expected_data.clone(),
     };
 
@@ -357,6 +486,7 @@ fn no_operation_when_data_received_message_for_tcp_channel_without_connectionl()
     let message = ServerMessage::DataReceived {
         channel: channel1,
         connection: None,
+        peer_address: Some(peer_address()),
         data: expected_data.clone(),
     };
 
@@ -364,37 +494,1227 @@ fn no_operation_when_data_received_message_for_tcp_channel_without_connectionl()
     assert_eq!(results.len(), 0, "Unexpected number of operations returned");
 }
 
-fn open_channel(client: &mut ClientHandler, connection_type: ConnectionType, port: u16) -> ChannelId {
-    let (request_id, _) = client.request_registration(connection_type, port);
-    let channel = ChannelId(rand::random());
-    let response = ServerMessage::RegistrationSuccessful {
-        request: request_id,
-        created_channel: channel,
+#[test]
+fn no_operation_when_udp_data_received_message_has_no_peer_address() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Udp, 23);
+    let connection1 = create_udp_connection(&mut client, channel1);
+
+    let message = ServerMessage::DataReceived {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1,2,3,4],
     };
 
-    let results = client.handle_server_message(response).unwrap();
-    assert_vec_contains!(results, ClientOperation::NotifyChannelOpened {registered_by_request, opened_channel}
-    => {
-        assert_eq!(*registered_by_request, request_id, "Unexpected request id returned");
-        assert_eq!(*opened_channel, channel, "Unexpected channel id");
-    });
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 0, "Unexpected number of operations returned");
+}
 
-    channel
+#[test]
+fn no_operation_when_udp_data_received_message_has_no_connection() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Udp, 23);
+    let _ = create_udp_connection(&mut client, channel1);
+
+    let message = ServerMessage::DataReceived {
+        channel: channel1,
+        connection: None,
+        peer_address: Some(peer_address()),
+        data: vec![1,2,3,4],
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 0, "Unexpected number of operations returned");
 }
 
-fn create_connection (client: &mut ClientHandler, channel: ChannelId) -> ConnectionId {
-    let connection1 = ConnectionId(rand::random());
-    let message = ServerMessage::NewIncomingTcpConnection {
-        channel,
+#[test]
+fn notification_raised_when_dsrp_server_reports_new_incoming_udp_connection() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Udp, 23);
+
+    let connection1 = ConnectionId(55);
+    let message = ServerMessage::NewIncomingUdpConnection {
+        channel: channel1,
         new_connection: connection1,
+        peer_address: peer_address(),
     };
 
     let results = client.handle_server_message(message).unwrap();
-    assert_vec_contains!(results, ClientOperation::CreateTcpConnectionForChannel {channel: notification_channel, new_connection}
+    assert_vec_contains!(results, ClientOperation::CreateUdpConnectionForChannel {channel, new_connection, peer_address: address}
     => {
-        assert_eq!(*notification_channel, channel, "Unexpected channel identifier");
+        assert_eq!(*channel, channel1, "Unexpected channel identifier");
         assert_eq!(*new_connection, connection1, "Unexpected new connection identifier");
+        assert_eq!(*address, peer_address(), "Unexpected peer address");
     });
+}
 
-    connection1
-}
\ No newline at end of file
+#[test]
+fn no_operation_when_dsrp_server_reports_udp_connection_over_tcp_channel() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+
+    let message = ServerMessage::NewIncomingUdpConnection {
+        channel: channel1,
+        new_connection: ConnectionId(55),
+        peer_address: peer_address(),
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 0, "Unexpected number of operations returned");
+}
+
+#[test]
+fn close_udp_connection_operation_returned_when_dsrp_server_reports_closed_udp_connection() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Udp, 23);
+    let connection1 = create_udp_connection(&mut client, channel1);
+
+    let message = ServerMessage::UdpConnectionClosed {
+        channel: channel1,
+        connection: connection1,
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::CloseUdpConnection {channel, connection}
+    => {
+        assert_eq!(*channel, channel1, "Unexpected channel identifier");
+        assert_eq!(*connection, connection1, "Unexpected connection identifier");
+    });
+
+    let message = ServerMessage::DataReceived {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: Some(peer_address()),
+        data: vec![1,2,3,4],
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 0, "Expected no operations for data over a closed connection");
+}
+
+#[test]
+fn client_can_generate_unregister_message_for_active_channel() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+
+    let message = client.request_unregistration(channel1).unwrap();
+    assert_eq!(message, ClientMessage::Unregister {channel: channel1}, "Unexpected message");
+}
+
+#[test]
+fn error_when_requesting_unregistration_of_unknown_channel() {
+    let (mut client, _) = ClientHandler::new();
+
+    let error = client.request_unregistration(ChannelId(22)).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::UnknownChannel(channel) => {
+            assert_eq!(channel, ChannelId(22), "Unexpected channel in error");
+        },
+
+        x => panic!("Unexpected error: {}", x),
+    }
+}
+
+#[test]
+fn channel_closed_notification_raised_when_dsrp_server_acknowledges_unregistration() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let _ = client.request_unregistration(channel1).unwrap();
+
+    let results = client.handle_server_message(ServerMessage::ChannelClosed {channel: channel1}).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelClosed {channel} => {
+        assert_eq!(*channel, channel1, "Unexpected channel closed");
+    });
+
+    let error = client.request_unregistration(channel1).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::UnknownChannel(_) => (),
+        x => panic!("Unexpected error: {}", x),
+    }
+}
+
+#[test]
+fn closed_channel_closes_its_tcp_connections() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connection(&mut client, channel1);
+
+    let results = client.handle_server_message(ServerMessage::ChannelClosed {channel: channel1}).unwrap();
+    assert_vec_contains!(results, ClientOperation::CloseTcpConnection {channel, connection} => {
+        assert_eq!(*channel, channel1, "Unexpected channel identifier");
+        assert_eq!(*connection, connection1, "Unexpected connection identifier");
+    });
+
+    let message = ServerMessage::DataReceived {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1,2,3,4],
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 0, "Expected no operations for data over a closed channel");
+}
+
+#[test]
+fn closed_channel_closes_its_udp_connections() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Udp, 23);
+    let connection1 = create_udp_connection(&mut client, channel1);
+
+    let results = client.handle_server_message(ServerMessage::ChannelClosed {channel: channel1}).unwrap();
+    assert_vec_contains!(results, ClientOperation::CloseUdpConnection {channel, connection} => {
+        assert_eq!(*channel, channel1, "Unexpected channel identifier");
+        assert_eq!(*connection, connection1, "Unexpected connection identifier");
+    });
+}
+
+#[test]
+fn no_operation_when_dsrp_server_reports_unknown_channel_closed() {
+    let (mut client, _) = ClientHandler::new();
+
+    let results = client.handle_server_message(ServerMessage::ChannelClosed {channel: ChannelId(22)}).unwrap();
+    assert_eq!(results.len(), 0, "Unexpected number of operations returned");
+}
+
+#[test]
+fn channel_revoked_notification_raised_when_dsrp_server_revokes_channel() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connection(&mut client, channel1);
+
+    let message = ServerMessage::ChannelRevoked {
+        channel: channel1,
+        reason: ChannelRevocationReason::Administrative,
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelRevoked {channel, reason} => {
+        assert_eq!(*channel, channel1, "Unexpected channel revoked");
+        assert_eq!(*reason, ChannelRevocationReason::Administrative, "Unexpected reason");
+    });
+
+    assert_vec_contains!(results, ClientOperation::CloseTcpConnection {channel, connection} => {
+        assert_eq!(*channel, channel1, "Unexpected channel identifier");
+        assert_eq!(*connection, connection1, "Unexpected connection identifier");
+    });
+
+    let error = client.request_unregistration(channel1).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::UnknownChannel(_) => (),
+        x => panic!("Unexpected error: {}", x),
+    }
+}
+
+#[test]
+fn channel_revoked_for_shutdown_registered_again_after_reconnecting() {
+    let (mut client, _) = ClientHandler::new();
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+
+    let message = ServerMessage::ChannelRevoked {
+        channel: channel1,
+        reason: ChannelRevocationReason::ServerShuttingDown,
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelRevoked {channel, reason: _} => {
+        assert_eq!(*channel, channel1, "Unexpected channel revoked");
+    });
+
+    let _ = client.server_connection_lost();
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    let results = client.restore_registrations();
+    assert_eq!(results.len(), 1, "Unexpected number of operations returned");
+    assert_vec_contains!(results, ClientOperation::SendMessageToServer {
+        message: ClientMessage::Register {request: _, connection_type: ConnectionType::Tcp, port, bind_address: _}
+    } => {
+        assert_eq!(*port, 23, "Unexpected port requested");
+    });
+}
+
+#[test]
+fn no_operation_when_dsrp_server_revokes_unknown_channel() {
+    let (mut client, _) = ClientHandler::new();
+
+    let message = ServerMessage::ChannelRevoked {
+        channel: ChannelId(22),
+        reason: ChannelRevocationReason::Administrative,
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 0, "Unexpected number of operations returned");
+}
+
+#[test]
+fn client_can_generate_data_message_for_tcp_connection() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connection(&mut client, channel1);
+
+    let message = client.send_data(channel1, connection1, vec![1,2,3]).unwrap();
+    let expected = ClientMessage::DataBeingSent {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1,2,3],
+    };
+
+    assert_eq!(message, expected, "Unexpected message");
+}
+
+#[test]
+fn client_can_generate_data_message_for_udp_connection() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Udp, 23);
+    let connection1 = create_udp_connection(&mut client, channel1);
+
+    let message = client.send_data(channel1, connection1, vec![1,2,3]).unwrap();
+    let expected = ClientMessage::DataBeingSent {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1,2,3],
+    };
+
+    assert_eq!(message, expected, "Unexpected message");
+}
+
+#[test]
+fn error_when_sending_data_over_unknown_channel() {
+    let (mut client, _) = ClientHandler::new();
+
+    let error = client.send_data(ChannelId(22), ConnectionId(23), vec![1,2,3]).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::UnknownChannel(channel) => {
+            assert_eq!(channel, ChannelId(22), "Unexpected channel in error");
+        },
+
+        x => panic!("Unexpected error: {}", x),
+    }
+}
+
+#[test]
+fn error_when_sending_data_over_unknown_connection() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+
+    let error = client.send_data(channel1, ConnectionId(23), vec![1,2,3]).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::UnknownConnection {channel, connection} => {
+            assert_eq!(channel, channel1, "Unexpected channel in error");
+            assert_eq!(connection, ConnectionId(23), "Unexpected connection in error");
+        },
+
+        x => panic!("Unexpected error: {}", x),
+    }
+}
+
+#[test]
+fn error_when_sending_data_over_connection_not_owned_by_channel() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let channel2 = open_channel(&mut client, ConnectionType::Tcp, 24);
+    let connection1 = create_connection(&mut client, channel1);
+
+    let error = client.send_data(channel2, connection1, vec![1,2,3]).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::UnknownConnection {..} => (),
+        x => panic!("Unexpected error: {}", x),
+    }
+}
+
+#[test]
+fn client_can_generate_disconnection_message_for_closed_local_connection() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connection(&mut client, channel1);
+
+    let message = client.local_connection_closed(channel1, connection1).unwrap();
+    let expected = ClientMessage::TcpConnectionDisconnected {
+        channel: channel1,
+        connection: connection1,
+    };
+
+    assert_eq!(message, expected, "Unexpected message");
+}
+
+#[test]
+fn locally_closed_connection_is_no_longer_tracked() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connection(&mut client, channel1);
+    let _ = client.local_connection_closed(channel1, connection1).unwrap();
+
+    let error = client.send_data(channel1, connection1, vec![1,2,3]).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::UnknownConnection {..} => (),
+        x => panic!("Unexpected error: {}", x),
+    }
+
+    let message = ServerMessage::TcpConnectionClosed {channel: channel1, connection: connection1};
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 0, "Unexpected number of operations returned");
+}
+
+#[test]
+fn error_when_local_connection_closed_for_connection_server_already_closed() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connection(&mut client, channel1);
+
+    let message = ServerMessage::TcpConnectionClosed {channel: channel1, connection: connection1};
+    let _ = client.handle_server_message(message).unwrap();
+
+    let error = client.local_connection_closed(channel1, connection1).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::UnknownConnection {..} => (),
+        x => panic!("Unexpected error: {}", x),
+    }
+}
+
+#[test]
+fn error_when_local_connection_closed_for_udp_channel() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Udp, 23);
+    let connection1 = create_udp_connection(&mut client, channel1);
+
+    let error = client.local_connection_closed(channel1, connection1).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::NotTcpChannel(channel) => {
+            assert_eq!(channel, channel1, "Unexpected channel in error");
+        },
+
+        x => panic!("Unexpected error: {}", x),
+    }
+}
+
+#[test]
+fn client_can_generate_failure_message_for_local_connection_that_could_not_be_opened() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connection(&mut client, channel1);
+
+    let message = client.local_connection_failed(channel1, connection1, ConnectionFailureReason::ConnectionRefused).unwrap();
+    let expected = ClientMessage::TcpConnectionFailed {
+        channel: channel1,
+        connection: connection1,
+        reason: ConnectionFailureReason::ConnectionRefused,
+    };
+
+    assert_eq!(message, expected, "Unexpected message");
+
+    let error = client.send_data(channel1, connection1, vec![1,2,3]).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::UnknownConnection {..} => (),
+        x => panic!("Unexpected error: {}", x),
+    }
+}
+
+#[test]
+fn client_can_generate_failure_message_for_local_udp_socket_that_could_not_be_opened() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Udp, 23);
+    let connection1 = create_udp_connection(&mut client, channel1);
+
+    let message = client.local_connection_failed(channel1, connection1, ConnectionFailureReason::Other).unwrap();
+    let expected = ClientMessage::UdpConnectionFailed {
+        channel: channel1,
+        connection: connection1,
+        reason: ConnectionFailureReason::Other,
+    };
+
+    assert_eq!(message, expected, "Unexpected message");
+
+    let error = client.send_data(channel1, connection1, vec![1,2,3]).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::UnknownConnection {..} => (),
+        x => panic!("Unexpected error: {}", x),
+    }
+}
+
+#[test]
+fn data_received_before_local_connection_established_is_held() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connecting_connection(&mut client, channel1);
+
+    let message = ServerMessage::DataReceived {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1,2,3,4],
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 0, "Unexpected number of operations returned");
+}
+
+#[test]
+fn held_data_released_in_order_once_local_connection_established() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connecting_connection(&mut client, channel1);
+
+    for data in [vec![1,2], vec![3,4]] {
+        let message = ServerMessage::DataReceived {
+            channel: channel1,
+            connection: Some(connection1),
+            peer_address: None,
+            data,
+        };
+
+        let _ = client.handle_server_message(message).unwrap();
+    }
+
+    let results = client.local_connection_established(channel1, connection1).unwrap();
+    assert_eq!(results.len(), 2, "Unexpected number of operations returned");
+    match (&results[0], &results[1]) {
+        (ClientOperation::RelayRemotePacket {channel: channel_a, connection: connection_a, peer_address: None, data: data_a},
+         ClientOperation::RelayRemotePacket {channel: channel_b, connection: connection_b, peer_address: None, data: data_b}) => {
+            assert_eq!(*channel_a, channel1, "Unexpected channel identifier");
+            assert_eq!(*channel_b, channel1, "Unexpected channel identifier");
+            assert_eq!(*connection_a, Some(connection1), "Unexpected connection identifier");
+            assert_eq!(*connection_b, Some(connection1), "Unexpected connection identifier");
+            assert_eq!(data_a, &vec![1,2], "Unexpected first packet");
+            assert_eq!(data_b, &vec![3,4], "Unexpected second packet");
+        },
+
+        x => panic!("Expected two relay operations, instead got {:?}", x),
+    }
+
+    let message = ServerMessage::DataReceived {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![5,6],
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::RelayRemotePacket {channel: _, connection: _, peer_address: _, data} => {
+        assert_eq!(data, &vec![5,6], "Unexpected data relayed");
+    });
+}
+
+#[test]
+fn connection_aborted_when_held_data_exceeds_limit() {
+    let config = ClientHandlerConfig {
+        max_pending_connection_data: 4,
+        ..ClientHandlerConfig::default()
+    };
+
+    let (mut client, _) = ClientHandler::with_config(config, Box::new(TestClock::new()));
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connecting_connection(&mut client, channel1);
+
+    let message = ServerMessage::DataReceived {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1,2,3,4,5],
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::CloseTcpConnection {channel, connection} => {
+        assert_eq!(*channel, channel1, "Unexpected channel identifier");
+        assert_eq!(*connection, connection1, "Unexpected connection identifier");
+    });
+
+    assert_vec_contains!(results, ClientOperation::SendMessageToServer {
+        message: ClientMessage::TcpConnectionFailed {channel, connection, reason: _}
+    } => {
+        assert_eq!(*channel, channel1, "Unexpected channel identifier");
+        assert_eq!(*connection, connection1, "Unexpected connection identifier");
+    });
+
+    let error = client.local_connection_established(channel1, connection1).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::UnknownConnection {..} => (),
+        x => panic!("Unexpected error: {}", x),
+    }
+}
+
+#[test]
+fn connection_closed_by_server_while_connecting_is_closed_after_held_data_released() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connecting_connection(&mut client, channel1);
+
+    let message = ServerMessage::DataReceived {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1,2,3,4],
+    };
+
+    let _ = client.handle_server_message(message).unwrap();
+    let message = ServerMessage::TcpConnectionClosed {channel: channel1, connection: connection1};
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 0, "Expected close to wait until held data is released");
+
+    let results = client.local_connection_established(channel1, connection1).unwrap();
+    assert_eq!(results.len(), 2, "Unexpected number of operations returned");
+    match (&results[0], &results[1]) {
+        (ClientOperation::RelayRemotePacket {data, ..}, ClientOperation::CloseTcpConnection {channel, connection}) => {
+            assert_eq!(data, &vec![1,2,3,4], "Unexpected data relayed");
+            assert_eq!(*channel, channel1, "Unexpected channel identifier");
+            assert_eq!(*connection, connection1, "Unexpected connection identifier");
+        },
+
+        x => panic!("Expected relay followed by close operations, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn connection_closed_by_server_while_connecting_without_held_data_is_closed_immediately() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connecting_connection(&mut client, channel1);
+
+    let message = ServerMessage::TcpConnectionClosed {channel: channel1, connection: connection1};
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::CloseTcpConnection {channel, connection} => {
+        assert_eq!(*channel, channel1, "Unexpected channel identifier");
+        assert_eq!(*connection, connection1, "Unexpected connection identifier");
+    });
+}
+
+#[test]
+fn error_when_establishing_connection_twice() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connection(&mut client, channel1);
+
+    let error = client.local_connection_established(channel1, connection1).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::ConnectionAlreadyEstablished {channel, connection} => {
+            assert_eq!(channel, channel1, "Unexpected channel in error");
+            assert_eq!(connection, connection1, "Unexpected connection in error");
+        },
+
+        x => panic!("Unexpected error: {}", x),
+    }
+}
+
+#[test]
+fn shutdown_write_operation_returned_when_dsrp_server_reports_half_closed_connection() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connection(&mut client, channel1);
+
+    let message = ServerMessage::TcpConnectionHalfClosed {channel: channel1, connection: connection1};
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 1, "Unexpected number of operations returned");
+    assert_vec_contains!(results, ClientOperation::ShutdownTcpConnectionWrite {channel, connection} => {
+        assert_eq!(*channel, channel1, "Unexpected channel identifier");
+        assert_eq!(*connection, connection1, "Unexpected connection identifier");
+    });
+
+    // The application server can still reply
+    let _ = client.send_data(channel1, connection1, vec![1,2,3]).unwrap();
+}
+
+#[test]
+fn write_shutdown_waits_for_local_connection_to_be_established() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connecting_connection(&mut client, channel1);
+
+    let message = ServerMessage::DataReceived {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1,2,3,4],
+    };
+
+    let _ = client.handle_server_message(message).unwrap();
+    let message = ServerMessage::TcpConnectionHalfClosed {channel: channel1, connection: connection1};
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 0, "Expected shutdown to wait until connection is established");
+
+    let results = client.local_connection_established(channel1, connection1).unwrap();
+    assert_eq!(results.len(), 2, "Unexpected number of operations returned");
+    match (&results[0], &results[1]) {
+        (ClientOperation::RelayRemotePacket {data, ..}, ClientOperation::ShutdownTcpConnectionWrite {channel, connection}) => {
+            assert_eq!(data, &vec![1,2,3,4], "Unexpected data relayed");
+            assert_eq!(*channel, channel1, "Unexpected channel identifier");
+            assert_eq!(*connection, connection1, "Unexpected connection identifier");
+        },
+
+        x => panic!("Expected relay followed by shutdown operations, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn client_can_generate_half_close_message_for_local_connection() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connection(&mut client, channel1);
+
+    let results = client.local_connection_half_closed(channel1, connection1).unwrap();
+    assert_eq!(results.len(), 1, "Unexpected number of operations returned");
+    assert_vec_contains!(results, ClientOperation::SendMessageToServer {
+        message: ClientMessage::TcpConnectionHalfClosed {channel, connection}
+    } => {
+        assert_eq!(*channel, channel1, "Unexpected channel identifier");
+        assert_eq!(*connection, connection1, "Unexpected connection identifier");
+    });
+
+    // The remote peer can still send data
+    let message = ServerMessage::DataReceived {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1,2,3,4],
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::RelayRemotePacket {channel: _, connection: _, peer_address: _, data: _});
+}
+
+#[test]
+fn connection_closed_once_both_sides_half_close() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connection(&mut client, channel1);
+    let connection2 = create_connection(&mut client, channel1);
+
+    // Remote peer finishes first
+    let message = ServerMessage::TcpConnectionHalfClosed {channel: channel1, connection: connection1};
+    let _ = client.handle_server_message(message).unwrap();
+    let results = client.local_connection_half_closed(channel1, connection1).unwrap();
+    assert_vec_contains!(results, ClientOperation::SendMessageToServer {message: ClientMessage::TcpConnectionHalfClosed {..}});
+    assert_vec_contains!(results, ClientOperation::CloseTcpConnection {channel: _, connection} if *connection == connection1);
+
+    // Application server finishes first
+    let _ = client.local_connection_half_closed(channel1, connection2).unwrap();
+    let message = ServerMessage::TcpConnectionHalfClosed {channel: channel1, connection: connection2};
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::CloseTcpConnection {channel: _, connection} if *connection == connection2);
+
+    assert!(client.send_data(channel1, connection1, vec![1]).is_err(), "Expected connection1 to be closed");
+    assert!(client.send_data(channel1, connection2, vec![1]).is_err(), "Expected connection2 to be closed");
+}
+
+#[test]
+fn no_operation_when_dsrp_server_reports_half_close_for_non_owning_channel() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let channel2 = open_channel(&mut client, ConnectionType::Tcp, 24);
+    let connection1 = create_connection(&mut client, channel1);
+
+    let message = ServerMessage::TcpConnectionHalfClosed {channel: channel2, connection: connection1};
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 0, "Unexpected number of operations returned");
+}
+
+#[test]
+fn ping_sent_to_server_on_tick_after_heartbeat_interval() {
+    let clock = TestClock::new();
+    let (mut client, _) = create_heartbeat_client(&clock);
+
+    clock.advance(Duration::from_secs(9));
+    let results = client.tick(clock.now());
+    assert_eq!(results.len(), 0, "Expected no operations before the heartbeat interval");
+
+    clock.advance(Duration::from_secs(1));
+    let results = client.tick(clock.now());
+    assert_vec_contains!(results, ClientOperation::SendMessageToServer {message: ClientMessage::Ping});
+}
+
+#[test]
+fn no_ping_sent_before_handshake_completes() {
+    let clock = TestClock::new();
+    let config = ClientHandlerConfig {heartbeat_interval: Duration::from_secs(10), ..ClientHandlerConfig::default()};
+    let (mut client, _) = ClientHandler::with_config(config, Box::new(clock.clone()));
+
+    clock.advance(Duration::from_secs(60));
+    let results = client.tick(clock.now());
+    assert_eq!(results.len(), 0, "Expected no operations before the handshake completed");
+}
+
+#[test]
+fn pong_sent_when_server_pings() {
+    let (mut client, _) = ClientHandler::new();
+    let results = client.handle_server_message(ServerMessage::Ping).unwrap();
+    assert_vec_contains!(results, ClientOperation::SendMessageToServer {message: ClientMessage::Pong});
+}
+
+#[test]
+fn server_reported_unresponsive_after_missing_too_many_pongs() {
+    let clock = TestClock::new();
+    let (mut client, _) = create_heartbeat_client(&clock);
+
+    clock.advance(Duration::from_secs(10));
+    let _ = client.tick(clock.now());
+    clock.advance(Duration::from_secs(10));
+    let results = client.tick(clock.now());
+    assert_vec_contains!(results, ClientOperation::SendMessageToServer {message: ClientMessage::Ping});
+
+    clock.advance(Duration::from_secs(10));
+    let results = client.tick(clock.now());
+    assert_vec_contains!(results, ClientOperation::NotifyServerUnresponsive);
+}
+
+#[test]
+fn server_pong_resets_missed_heartbeats() {
+    let clock = TestClock::new();
+    let (mut client, _) = create_heartbeat_client(&clock);
+
+    for _ in 0..5 {
+        clock.advance(Duration::from_secs(10));
+        let results = client.tick(clock.now());
+        assert_vec_contains!(results, ClientOperation::SendMessageToServer {message: ClientMessage::Ping});
+
+        let results = client.handle_server_message(ServerMessage::Pong).unwrap();
+        assert_eq!(results.len(), 0, "Unexpected operations for pong");
+    }
+}
+
+#[test]
+fn server_shutdown_notice_reported_with_deadline() {
+    let clock = TestClock::new();
+    let (mut client, _) = create_heartbeat_client(&clock);
+
+    let message = ServerMessage::ServerShuttingDown {deadline: Duration::from_secs(30)};
+    let results = client.handle_server_message(message).unwrap();
+    assert_eq!(results.len(), 1, "Expected a single operation but got {:?}", results);
+    assert_vec_contains!(results, ClientOperation::NotifyServerShuttingDown {deadline} => {
+        assert_eq!(*deadline, clock.now() + Duration::from_secs(30), "Unexpected deadline");
+    });
+}
+
+#[test]
+fn local_connections_closed_when_server_connection_lost() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let channel2 = open_channel(&mut client, ConnectionType::Udp, 24);
+    let connection1 = create_connection(&mut client, channel1);
+    let connection2 = create_udp_connection(&mut client, channel2);
+
+    let (results, _) = client.server_connection_lost();
+    assert_vec_contains!(results, ClientOperation::CloseTcpConnection {channel, connection} => {
+        assert_eq!(*channel, channel1, "Unexpected channel identifier");
+        assert_eq!(*connection, connection1, "Unexpected connection identifier");
+    });
+
+    assert_vec_contains!(results, ClientOperation::CloseUdpConnection {channel, connection} => {
+        assert_eq!(*channel, channel2, "Unexpected channel identifier");
+        assert_eq!(*connection, connection2, "Unexpected connection identifier");
+    });
+
+    let error = client.send_data(channel1, connection1, vec![1,2,3]).unwrap_err();
+    match error.kind {
+        ClientRequestErrorKind::UnknownChannel(channel) => assert_eq!(channel, channel1, "Unexpected channel in error"),
+        x => panic!("Expected unknown channel error, instead received {:?}", x),
+    }
+}
+
+#[test]
+fn lost_channel_registered_again_on_same_port_after_reconnecting() {
+    let (mut client, _) = ClientHandler::new();
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+
+    let (_, handshake) = client.server_connection_lost();
+    assert_eq!(handshake.client_protocol_version, CURRENT_VERSION, "Unexpected protocol version");
+
+    let progress = client.handle_handshake_response(success_response()).unwrap();
+    assert_eq!(progress, HandshakeProgress::Accepted, "Expected reconnection to be accepted");
+
+    let results = client.restore_registrations();
+    assert_eq!(results.len(), 1, "Expected a single registration request");
+    let request_id = match results[0] {
+        ClientOperation::SendMessageToServer {message: ClientMessage::Register {request, ref connection_type, port, bind_address}} => {
+            assert_eq!(*connection_type, ConnectionType::Tcp, "Unexpected connection type");
+            assert_eq!(port, 23, "Unexpected port requested");
+            assert_eq!(bind_address, None, "Unexpected bind address");
+            request
+        },
+
+        ref x => panic!("Expected registration message, instead received {:?}", x),
+    };
+
+    let channel2 = ChannelId(rand::random());
+    let message = ServerMessage::RegistrationSuccessful {request: request_id, created_channel: channel2, port: 23};
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelRestored {previous_channel, restored_channel, port, same_port} => {
+        assert_eq!(*previous_channel, channel1, "Unexpected previous channel");
+        assert_eq!(*restored_channel, channel2, "Unexpected restored channel");
+        assert_eq!(*port, 23, "Unexpected port");
+        assert!(*same_port, "Expected the same port to be reported");
+    });
+
+    let connection1 = create_connection(&mut client, channel2);
+    let _ = client.send_data(channel2, connection1, vec![1,2,3]).unwrap();
+}
+
+#[test]
+fn lost_any_port_channel_asks_for_any_port_when_previous_port_is_taken() {
+    let (mut client, _) = ClientHandler::new();
+    let (request_id, _) = client.request_any_port_registration(ConnectionType::Udp, Some(50000..=50010), None);
+    let channel1 = ChannelId(rand::random());
+    let message = ServerMessage::RegistrationSuccessful {request: request_id, created_channel: channel1, port: 50005};
+    let _ = client.handle_server_message(message).unwrap();
+
+    let _ = client.server_connection_lost();
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    let results = client.restore_registrations();
+    let request_id = match results[0] {
+        ClientOperation::SendMessageToServer {message: ClientMessage::Register {request, connection_type: _, port, bind_address: _}} => {
+            assert_eq!(port, 50005, "Expected the previous port to be requested first");
+            request
+        },
+
+        ref x => panic!("Expected registration message, instead received {:?}", x),
+    };
+
+    let cause = RegistrationFailureCause::PortAlreadyRegistered;
+    let results = client.handle_server_message(ServerMessage::RegistrationFailed {request: request_id, cause}).unwrap();
+    assert_eq!(results.len(), 1, "Expected a single operation");
+    let request_id = match results[0] {
+        ClientOperation::SendMessageToServer {message: ClientMessage::RegisterAnyPort {request, ref connection_type, ref port_range, bind_address: _}} => {
+            assert_eq!(*connection_type, ConnectionType::Udp, "Unexpected connection type");
+            assert_eq!(*port_range, Some(50000..=50010), "Unexpected port range");
+            request
+        },
+
+        ref x => panic!("Expected any port registration message, instead received {:?}", x),
+    };
+
+    let channel2 = ChannelId(rand::random());
+    let message = ServerMessage::RegistrationSuccessful {request: request_id, created_channel: channel2, port: 50007};
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelRestored {previous_channel, restored_channel, port, same_port} => {
+        assert_eq!(*previous_channel, channel1, "Unexpected previous channel");
+        assert_eq!(*restored_channel, channel2, "Unexpected restored channel");
+        assert_eq!(*port, 50007, "Unexpected port");
+        assert!(!*same_port, "Expected a different port to be reported");
+    });
+}
+
+#[test]
+fn restoration_failure_reported_when_lost_channel_port_is_not_allowed() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+
+    let _ = client.server_connection_lost();
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    let results = client.restore_registrations();
+    let request_id = match results[0] {
+        ClientOperation::SendMessageToServer {message: ClientMessage::Register {request, ..}} => request,
+        ref x => panic!("Expected registration message, instead received {:?}", x),
+    };
+
+    let cause = RegistrationFailureCause::PortNotAllowed;
+    let results = client.handle_server_message(ServerMessage::RegistrationFailed {request: request_id, cause}).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelRestorationFailed {previous_channel, cause} => {
+        assert_eq!(*previous_channel, channel1, "Unexpected previous channel");
+        assert_eq!(*cause, RegistrationFailureCause::PortNotAllowed, "Unexpected cause");
+    });
+}
+
+#[test]
+fn restoration_retried_with_backoff_while_lost_channel_port_is_taken() {
+    let clock = TestClock::new();
+    let (mut client, _) = create_restoration_retry_client(&clock);
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+
+    let _ = client.server_connection_lost();
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    let results = client.restore_registrations();
+    let request_id = registration_request_for_port(&results, 23);
+
+    let cause = RegistrationFailureCause::PortAlreadyRegistered;
+    let results = client.handle_server_message(ServerMessage::RegistrationFailed {request: request_id, cause}).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelRestorationDelayed {previous_channel, cause, retry_at} => {
+        assert_eq!(*previous_channel, channel1, "Unexpected previous channel");
+        assert_eq!(*cause, RegistrationFailureCause::PortAlreadyRegistered, "Unexpected cause");
+        assert_eq!(*retry_at, clock.now() + Duration::from_secs(5), "Unexpected retry time");
+    });
+
+    clock.advance(Duration::from_secs(4));
+    let results = client.tick(clock.now());
+    assert_eq!(results.len(), 0, "Expected no operations before the retry time but got {:?}", results);
+
+    clock.advance(Duration::from_secs(1));
+    let results = client.tick(clock.now());
+    let request_id = registration_request_for_port(&results, 23);
+
+    let cause = RegistrationFailureCause::ServerShuttingDown;
+    let results = client.handle_server_message(ServerMessage::RegistrationFailed {request: request_id, cause}).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelRestorationDelayed {previous_channel: _, cause: _, retry_at} => {
+        assert_eq!(*retry_at, clock.now() + Duration::from_secs(10), "Expected the retry delay to double");
+    });
+
+    clock.advance(Duration::from_secs(10));
+    let results = client.tick(clock.now());
+    let request_id = registration_request_for_port(&results, 23);
+
+    let channel2 = ChannelId(rand::random());
+    let message = ServerMessage::RegistrationSuccessful {request: request_id, created_channel: channel2, port: 23};
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelRestored {previous_channel, restored_channel, port: _, same_port: _} => {
+        assert_eq!(*previous_channel, channel1, "Unexpected previous channel");
+        assert_eq!(*restored_channel, channel2, "Unexpected restored channel");
+    });
+}
+
+#[test]
+fn delayed_restoration_requested_right_away_after_reconnecting() {
+    let clock = TestClock::new();
+    let (mut client, _) = create_restoration_retry_client(&clock);
+    let _ = open_channel(&mut client, ConnectionType::Tcp, 23);
+
+    let _ = client.server_connection_lost();
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    let results = client.restore_registrations();
+    let request_id = registration_request_for_port(&results, 23);
+
+    let cause = RegistrationFailureCause::PortAlreadyRegistered;
+    let _ = client.handle_server_message(ServerMessage::RegistrationFailed {request: request_id, cause}).unwrap();
+
+    let response = HandshakeResponse::Success {session_ticket: [1; SESSION_TICKET_LENGTH], resumed: true};
+    let _ = client.server_connection_lost();
+    let _ = client.handle_handshake_response(response).unwrap();
+    let results = client.restore_registrations();
+    let _ = registration_request_for_port(&results, 23);
+}
+
+#[test]
+fn unanswered_registration_requested_again_after_reconnecting() {
+    let (mut client, _) = ClientHandler::new();
+    let (request_id, _) = client.request_registration(ConnectionType::Tcp, 23, None);
+
+    let _ = client.server_connection_lost();
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    let results = client.restore_registrations();
+    assert_vec_contains!(results, ClientOperation::SendMessageToServer {message: ClientMessage::Register {request, connection_type: _, port, bind_address: _}} => {
+        assert_eq!(*request, request_id, "Expected the original request id");
+        assert_eq!(*port, 23, "Unexpected port requested");
+    });
+
+    let channel1 = ChannelId(rand::random());
+    let message = ServerMessage::RegistrationSuccessful {request: request_id, created_channel: channel1, port: 23};
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelOpened {registered_by_request, opened_channel: _, port: _} => {
+        assert_eq!(*registered_by_request, request_id, "Unexpected request id");
+    });
+}
+
+#[test]
+fn session_ticket_presented_when_reconnecting() {
+    let (mut client, _) = ClientHandler::new();
+    let response = HandshakeResponse::Success {session_ticket: [5; SESSION_TICKET_LENGTH], resumed: false};
+    let _ = client.handle_handshake_response(response).unwrap();
+
+    let (_, handshake) = client.server_connection_lost();
+    assert_eq!(handshake.session_ticket, Some([5; SESSION_TICKET_LENGTH]), "Unexpected session ticket");
+}
+
+#[test]
+fn channels_restored_without_registering_again_when_session_resumed() {
+    let (mut client, _) = ClientHandler::new();
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let _ = client.server_connection_lost();
+
+    let response = HandshakeResponse::Success {session_ticket: [6; SESSION_TICKET_LENGTH], resumed: true};
+    let _ = client.handle_handshake_response(response).unwrap();
+    let results = client.restore_registrations();
+    assert_eq!(results.len(), 1, "Expected a single operation but got {:?}", results);
+    assert_vec_contains!(results, ClientOperation::NotifyChannelRestored {previous_channel, restored_channel, port, same_port} => {
+        assert_eq!(*previous_channel, channel1, "Unexpected previous channel");
+        assert_eq!(*restored_channel, channel1, "Expected the channel id to be kept");
+        assert_eq!(*port, 23, "Unexpected port");
+        assert!(*same_port, "Expected the same port to be reported");
+    });
+
+    let _ = create_connection(&mut client, channel1);
+}
+
+#[test]
+fn unanswered_registration_not_requested_again_when_session_resumed() {
+    let (mut client, _) = ClientHandler::new();
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    let (request_id, _) = client.request_registration(ConnectionType::Tcp, 23, None);
+    let _ = client.server_connection_lost();
+
+    let response = HandshakeResponse::Success {session_ticket: [6; SESSION_TICKET_LENGTH], resumed: true};
+    let _ = client.handle_handshake_response(response).unwrap();
+    let results = client.restore_registrations();
+    assert_eq!(results.len(), 0, "Expected no operations but got {:?}", results);
+
+    let channel1 = ChannelId(rand::random());
+    let message = ServerMessage::RegistrationSuccessful {request: request_id, created_channel: channel1, port: 23};
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelOpened {registered_by_request, opened_channel, port: _} => {
+        assert_eq!(*registered_by_request, request_id, "Unexpected request id");
+        assert_eq!(*opened_channel, channel1, "Unexpected channel");
+    });
+}
+
+#[test]
+fn traffic_counted_in_both_directions() {
+    let clock = TestClock::new();
+    let (mut client, _) = ClientHandler::with_config(ClientHandlerConfig::default(), Box::new(clock.clone()));
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connection(&mut client, channel1);
+
+    let message = ServerMessage::DataReceived {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1, 2, 3],
+    };
+
+    let _ = client.handle_server_message(message).unwrap();
+    let _ = client.send_data(channel1, connection1, vec![4, 5]).unwrap();
+    let _ = client.send_data(channel1, connection1, vec![6]).unwrap();
+
+    let stats = client.stats();
+    assert_eq!(stats.traffic.inbound, TrafficCounters {bytes: 3, packets: 1}, "Unexpected inbound traffic");
+    assert_eq!(stats.traffic.outbound, TrafficCounters {bytes: 3, packets: 2}, "Unexpected outbound traffic");
+    assert_eq!(stats.open_connections, 1, "Unexpected open connection count");
+
+    let channel = &stats.channels[0];
+    assert_eq!(channel.id, channel1, "Unexpected channel");
+    assert_eq!(channel.opened_at, clock.now(), "Unexpected channel open time");
+    assert_eq!(channel.traffic, stats.traffic, "Expected the channel to have all the traffic");
+    assert_eq!(channel.connections[0].id, connection1, "Unexpected connection");
+    assert_eq!(channel.connections[0].traffic, stats.traffic, "Expected the connection to have all the traffic");
+}
+
+#[test]
+fn failed_connections_counted() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Tcp, 23);
+    let connection1 = create_connecting_connection(&mut client, channel1);
+    let _ = create_connection(&mut client, channel1);
+    let _ = client.local_connection_failed(channel1, connection1, ConnectionFailureReason::ConnectionRefused).unwrap();
+
+    let stats = client.stats();
+    assert_eq!(stats.connection_counts, ConnectionCounts {opened: 2, failed: 1}, "Unexpected connection counts");
+    assert_eq!(stats.channels[0].connection_counts, stats.connection_counts, "Unexpected channel connection counts");
+    assert_eq!(stats.open_connections, 1, "Unexpected open connection count");
+}
+
+#[test]
+fn traffic_totals_kept_when_server_connection_lost() {
+    let (mut client, _) = ClientHandler::new();
+    let channel1 = open_channel(&mut client, ConnectionType::Udp, 23);
+    let connection1 = create_udp_connection(&mut client, channel1);
+    let _ = client.send_data(channel1, connection1, vec![1, 2, 3]).unwrap();
+    let _ = client.server_connection_lost();
+
+    let stats = client.stats();
+    assert_eq!(stats.channels.len(), 0, "Expected no active channels");
+    assert_eq!(stats.traffic.outbound, TrafficCounters {bytes: 3, packets: 1}, "Unexpected outbound traffic");
+    assert_eq!(stats.connection_counts.opened, 1, "Unexpected opened connection count");
+}
+
+fn success_response() -> HandshakeResponse {
+    HandshakeResponse::Success {session_ticket: [1; SESSION_TICKET_LENGTH], resumed: false}
+}
+
+fn create_heartbeat_client(clock: &TestClock) -> (ClientHandler, HandshakeRequest) {
+    let config = ClientHandlerConfig {
+        heartbeat_interval: Duration::from_secs(10),
+        max_missed_heartbeats: 2,
+        ..ClientHandlerConfig::default()
+    };
+
+    let (mut client, request) = ClientHandler::with_config(config, Box::new(clock.clone()));
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    (client, request)
+}
+
+fn create_restoration_retry_client(clock: &TestClock) -> (ClientHandler, HandshakeRequest) {
+    let config = ClientHandlerConfig {
+        initial_restoration_retry_delay: Duration::from_secs(5),
+        max_restoration_retry_delay: Duration::from_secs(20),
+        ..ClientHandlerConfig::default()
+    };
+
+    let (mut client, request) = ClientHandler::with_config(config, Box::new(clock.clone()));
+    let _ = client.handle_handshake_response(success_response()).unwrap();
+    (client, request)
+}
+
+fn registration_request_for_port(operations: &[ClientOperation], expected_port: u16) -> RequestId {
+    let mut request_id = RequestId(u32::MAX);
+    assert_vec_contains!(operations, ClientOperation::SendMessageToServer {message: ClientMessage::Register {request, connection_type: _, port, bind_address: _}} => {
+        assert_eq!(*port, expected_port, "Unexpected port requested");
+        request_id = *request;
+    });
+
+    request_id
+}
+
+fn peer_address() -> SocketAddr {
+    "192.168.1.10:5000".parse().unwrap()
+}
+
+fn open_channel(client: &mut ClientHandler, connection_type: ConnectionType, port: u16) -> ChannelId {
+    let (request_id, _) = client.request_registration(connection_type, port, None);
+    let channel = ChannelId(rand::random());
+    let response = ServerMessage::RegistrationSuccessful {
+        request: request_id,
+        created_channel: channel,
+        port,
+    };
+
+    let results = client.handle_server_message(response).unwrap();
+    assert_vec_contains!(results, ClientOperation::NotifyChannelOpened {registered_by_request, opened_channel, port: opened_port}
+    => {
+        assert_eq!(*registered_by_request, request_id, "Unexpected request id returned");
+        assert_eq!(*opened_channel, channel, "Unexpected channel id");
+        assert_eq!(*opened_port, port, "Unexpected port");
+    });
+
+    channel
+}
+
+fn create_connection(client: &mut ClientHandler, channel: ChannelId) -> ConnectionId {
+    let connection1 = create_connecting_connection(client, channel);
+    let results = client.local_connection_established(channel, connection1).unwrap();
+    assert_eq!(results.len(), 0, "Unexpected operations when establishing connection");
+
+    connection1
+}
+
+fn create_connecting_connection(client: &mut ClientHandler, channel: ChannelId) -> ConnectionId {
+    let connection1 = ConnectionId(rand::random());
+    let message = ServerMessage::NewIncomingTcpConnection {
+        channel,
+        new_connection: connection1,
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::CreateTcpConnectionForChannel {channel: notification_channel, new_connection}
+    => {
+        assert_eq!(*notification_channel, channel, "Unexpected channel identifier");
+        assert_eq!(*new_connection, connection1, "Unexpected new connection identifier");
+    });
+
+    connection1
+}
+
+fn create_udp_connection(client: &mut ClientHandler, channel: ChannelId) -> ConnectionId {
+    let connection1 = ConnectionId(rand::random());
+    let message = ServerMessage::NewIncomingUdpConnection {
+        channel,
+        new_connection: connection1,
+        peer_address: peer_address(),
+    };
+
+    let results = client.handle_server_message(message).unwrap();
+    assert_vec_contains!(results, ClientOperation::CreateUdpConnectionForChannel {channel: notification_channel, new_connection, peer_address: _}
+    => {
+        assert_eq!(*notification_channel, channel, "Unexpected channel identifier");
+        assert_eq!(*new_connection, connection1, "Unexpected new connection identifier");
+    });
+
+    connection1
+}
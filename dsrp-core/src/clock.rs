@@ -0,0 +1,15 @@
+use std::time::Instant;
+
+/// Source of the current time for the handlers, allowing time to be controlled in tests
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+/// Clock that reports the actual system time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
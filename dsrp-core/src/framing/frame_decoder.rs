@@ -0,0 +1,442 @@
+use std::fmt;
+use failure::Fail;
+use handshake::{HandshakeRequest, HandshakeResponse, HandshakeChallengeAnswer, CHALLENGE_NONCE_LENGTH};
+use handshake::SESSION_TICKET_LENGTH;
+use handshake::{HandshakeRequestParseErrorsKind, HandshakeResponseParseErrorKind};
+use handshake::{HANDSHAKE_REQUEST_PREFIX, HANDSHAKE_RESPONSE_PREFIX, HANDSHAKE_CHALLENGE_ANSWER_PREFIX};
+use messages::{ClientMessage, ServerMessage, MessageParseErrorKind, encoded_message_length};
+
+/// Largest frame accepted by default, which leaves plenty of room for relayed data packets
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// A complete unit of data received over a DSRP control connection
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    HandshakeRequest(HandshakeRequest),
+    HandshakeResponse(HandshakeResponse),
+    HandshakeChallengeAnswer(HandshakeChallengeAnswer),
+    ClientMessage(ClientMessage),
+    ServerMessage(ServerMessage),
+}
+
+#[derive(Debug)]
+pub struct FrameDecodeError {
+    pub kind: FrameDecodeErrorKind,
+}
+
+#[derive(Debug, Fail)]
+pub enum FrameDecodeErrorKind {
+    #[fail(display = "Frame of {} bytes exceeds the maximum frame size of {} bytes", size, max_size)]
+    FrameTooLarge {
+        size: usize,
+        max_size: usize,
+    },
+
+    #[fail(display = "Invalid handshake request: {}", _0)]
+    InvalidHandshakeRequest(#[cause] HandshakeRequestParseErrorsKind),
+
+    #[fail(display = "Invalid handshake response: {}", _0)]
+    InvalidHandshakeResponse(#[cause] HandshakeResponseParseErrorKind),
+
+    #[fail(display = "Invalid message: {}", _0)]
+    InvalidMessage(#[cause] MessageParseErrorKind),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DecoderStage {
+    AwaitingHandshakeRequest,
+    AwaitingHandshakeResponse,
+    AwaitingChallengeAnswer,
+    ReadingClientMessages,
+    ReadingServerMessages,
+}
+
+/// Accumulates bytes read from a DSRP control connection and splits them into complete frames,
+/// regardless of how the reads cut up or coalesce them.  The first frame is always expected to
+/// be the handshake, with every frame after that being a message.
+///
+/// Once an error has been returned the contents of the stream can no longer be trusted, and
+/// the connection should be closed.
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    stage: DecoderStage,
+    max_frame_size: usize,
+}
+
+impl FrameDecoder {
+    /// Creates a decoder for the bytes a DSRP server receives from a DSRP client, meaning a
+    /// handshake request followed by client messages.
+    pub fn for_server(max_frame_size: usize) -> Self {
+        FrameDecoder {
+            buffer: Vec::new(),
+            stage: DecoderStage::AwaitingHandshakeRequest,
+            max_frame_size,
+        }
+    }
+
+    /// Creates a decoder for the bytes a DSRP client receives from a DSRP server, meaning a
+    /// handshake response followed by server messages.
+    pub fn for_client(max_frame_size: usize) -> Self {
+        FrameDecoder {
+            buffer: Vec::new(),
+            stage: DecoderStage::AwaitingHandshakeResponse,
+            max_frame_size,
+        }
+    }
+
+    /// Tells a server side decoder that the client has been challenged, so the next frame it
+    /// sends will be the answer to that challenge instead of a message
+    pub fn expect_challenge_answer(&mut self) {
+        self.stage = DecoderStage::AwaitingChallengeAnswer;
+    }
+
+    /// Adds bytes read off the connection to the end of the decoder's buffer
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Number of buffered bytes that have not been returned as part of a frame yet
+    pub fn buffered_byte_count(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Attempts to decode the next frame out of the buffered bytes.  `Ok(None)` is returned
+    /// when the buffer does not yet contain a complete frame and more bytes need to be pushed.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, FrameDecodeError> {
+        let frame_length = match self.next_frame_length() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        if frame_length > self.max_frame_size {
+            let kind = FrameDecodeErrorKind::FrameTooLarge {
+                size: frame_length,
+                max_size: self.max_frame_size,
+            };
+
+            return Err(FrameDecodeError {kind});
+        }
+
+        if self.buffer.len() < frame_length {
+            return Ok(None);
+        }
+
+        let frame = {
+            let bytes = &self.buffer[..frame_length];
+            match self.stage {
+                DecoderStage::AwaitingHandshakeRequest => {
+                    let request = HandshakeRequest::from_bytes(bytes)
+                        .map_err(|e| FrameDecodeErrorKind::InvalidHandshakeRequest(e.kind))?;
+
+                    Frame::HandshakeRequest(request)
+                },
+
+                DecoderStage::AwaitingHandshakeResponse => {
+                    let (response, _) = HandshakeResponse::from_bytes(bytes)
+                        .map_err(|e| FrameDecodeErrorKind::InvalidHandshakeResponse(e.kind))?;
+
+                    Frame::HandshakeResponse(response)
+                },
+
+                DecoderStage::AwaitingChallengeAnswer => {
+                    let answer = HandshakeChallengeAnswer::from_bytes(bytes)
+                        .map_err(|e| FrameDecodeErrorKind::InvalidHandshakeRequest(e.kind))?;
+
+                    Frame::HandshakeChallengeAnswer(answer)
+                },
+
+                DecoderStage::ReadingClientMessages => {
+                    let (message, _) = ClientMessage::from_bytes(bytes)
+                        .map_err(|e| FrameDecodeErrorKind::InvalidMessage(e.kind))?;
+
+                    Frame::ClientMessage(message)
+                },
+
+                DecoderStage::ReadingServerMessages => {
+                    let (message, _) = ServerMessage::from_bytes(bytes)
+                        .map_err(|e| FrameDecodeErrorKind::InvalidMessage(e.kind))?;
+
+                    Frame::ServerMessage(message)
+                },
+            }
+        };
+
+        self.buffer.drain(..frame_length);
+        self.stage = match (self.stage, &frame) {
+            // A challenge is followed by another handshake response with the final verdict
+            (_, Frame::HandshakeResponse(HandshakeResponse::Challenge {..})) => DecoderStage::AwaitingHandshakeResponse,
+            (DecoderStage::AwaitingHandshakeRequest, _) => DecoderStage::ReadingClientMessages,
+            (DecoderStage::AwaitingChallengeAnswer, _) => DecoderStage::ReadingClientMessages,
+            (DecoderStage::AwaitingHandshakeResponse, _) => DecoderStage::ReadingServerMessages,
+            (x, _) => x,
+        };
+
+        Ok(Some(frame))
+    }
+
+    /// Determines how many bytes the next frame takes up based on its header, or `None` if not
+    /// enough of the header has been buffered yet.
+    fn next_frame_length(&self) -> Option<usize> {
+        match self.stage {
+            DecoderStage::AwaitingHandshakeRequest => {
+                let prefix_length = HANDSHAKE_REQUEST_PREFIX.len();
+                let version_length = *self.buffer.get(prefix_length)? as usize;
+                let ticket_length_index = prefix_length + 1 + version_length;
+                let ticket_length = *self.buffer.get(ticket_length_index)? as usize;
+                Some(ticket_length_index + 1 + ticket_length)
+            },
+
+            DecoderStage::AwaitingHandshakeResponse => {
+                let prefix_length = HANDSHAKE_RESPONSE_PREFIX.len();
+                match *self.buffer.get(prefix_length)? {
+                    // Values below 128 are the length of the failure reason, 128 is success
+                    // followed by the session ticket and resumed flag, 129 is a challenge
+                    // followed by its nonce, and anything else is either the server shutting
+                    // down or invalid, neither of which has trailing bytes
+                    x if x < 128 => Some(prefix_length + 1 + x as usize),
+                    128 => Some(prefix_length + 1 + SESSION_TICKET_LENGTH + 1),
+                    129 => Some(prefix_length + 1 + CHALLENGE_NONCE_LENGTH),
+                    _ => Some(prefix_length + 1),
+                }
+            },
+
+            DecoderStage::AwaitingChallengeAnswer => {
+                let prefix_length = HANDSHAKE_CHALLENGE_ANSWER_PREFIX.len();
+                let hmac_length = *self.buffer.get(prefix_length)? as usize;
+                Some(prefix_length + 1 + hmac_length)
+            },
+
+            DecoderStage::ReadingClientMessages | DecoderStage::ReadingServerMessages => {
+                encoded_message_length(&self.buffer)
+            },
+        }
+    }
+}
+
+impl fmt::Display for FrameDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.kind, f)
+    }
+}
+
+impl From<FrameDecodeErrorKind> for FrameDecodeError {
+    fn from(kind: FrameDecodeErrorKind) -> Self {
+        FrameDecodeError { kind }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use messages::{ChannelId, ConnectionId, MESSAGE_FORMAT_VERSION, MESSAGE_HEADER_LENGTH};
+
+    fn success() -> HandshakeResponse {
+        HandshakeResponse::Success {session_ticket: [3; SESSION_TICKET_LENGTH], resumed: false}
+    }
+
+    fn data_message(data: Vec<u8>) -> ClientMessage {
+        ClientMessage::DataBeingSent {
+            channel: ChannelId(1),
+            connection: Some(ConnectionId(2)),
+            peer_address: None,
+            data,
+        }
+    }
+
+    #[test]
+    fn need_more_bytes_returned_when_buffer_is_empty() {
+        let mut decoder = FrameDecoder::for_server(DEFAULT_MAX_FRAME_SIZE);
+
+        assert_eq!(decoder.next_frame().unwrap(), None, "Expected no frame");
+    }
+
+    #[test]
+    fn server_decoder_returns_handshake_request_then_client_messages() {
+        let mut bytes = HandshakeRequest::new().into_bytes();
+        bytes.extend(data_message(vec![1, 2, 3]).into_bytes());
+
+        let mut decoder = FrameDecoder::for_server(DEFAULT_MAX_FRAME_SIZE);
+        decoder.push_bytes(&bytes);
+
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::HandshakeRequest(HandshakeRequest::new())));
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::ClientMessage(data_message(vec![1, 2, 3]))));
+        assert_eq!(decoder.next_frame().unwrap(), None, "Expected no more frames");
+        assert_eq!(decoder.buffered_byte_count(), 0, "Expected all bytes to be consumed");
+    }
+
+    #[test]
+    fn server_decoder_returns_handshake_request_with_session_ticket() {
+        let request = || HandshakeRequest::resuming([9; SESSION_TICKET_LENGTH]);
+        let mut bytes = request().into_bytes();
+        bytes.extend(data_message(vec![1, 2, 3]).into_bytes());
+
+        let mut decoder = FrameDecoder::for_server(DEFAULT_MAX_FRAME_SIZE);
+        decoder.push_bytes(&bytes);
+
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::HandshakeRequest(request())));
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::ClientMessage(data_message(vec![1, 2, 3]))));
+        assert_eq!(decoder.buffered_byte_count(), 0, "Expected all bytes to be consumed");
+    }
+
+    #[test]
+    fn client_decoder_returns_handshake_response_then_server_messages() {
+        let message = || ServerMessage::TcpConnectionClosed {
+            channel: ChannelId(1),
+            connection: ConnectionId(2),
+        };
+
+        let mut bytes = success().into_bytes().unwrap();
+        bytes.extend(message().into_bytes());
+
+        let mut decoder = FrameDecoder::for_client(DEFAULT_MAX_FRAME_SIZE);
+        decoder.push_bytes(&bytes);
+
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::HandshakeResponse(success())));
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::ServerMessage(message())));
+        assert_eq!(decoder.next_frame().unwrap(), None, "Expected no more frames");
+    }
+
+    #[test]
+    fn client_decoder_handles_failure_handshake_response() {
+        let response = || HandshakeResponse::Failure {reason: "bad version".to_owned()};
+        let bytes = response().into_bytes().unwrap();
+
+        let mut decoder = FrameDecoder::for_client(DEFAULT_MAX_FRAME_SIZE);
+        decoder.push_bytes(&bytes);
+
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::HandshakeResponse(response())));
+    }
+
+    #[test]
+    fn client_decoder_handles_server_shutting_down_handshake_response() {
+        let bytes = HandshakeResponse::ServerShuttingDown.into_bytes().unwrap();
+
+        let mut decoder = FrameDecoder::for_client(DEFAULT_MAX_FRAME_SIZE);
+        decoder.push_bytes(&bytes);
+
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::HandshakeResponse(HandshakeResponse::ServerShuttingDown)));
+        assert_eq!(decoder.buffered_byte_count(), 0, "Expected the whole response to be consumed");
+    }
+
+    #[test]
+    fn client_decoder_returns_challenge_then_final_handshake_response_then_server_messages() {
+        let message = || ServerMessage::TcpConnectionClosed {
+            channel: ChannelId(1),
+            connection: ConnectionId(2),
+        };
+
+        let challenge = HandshakeResponse::Challenge {nonce: [7; CHALLENGE_NONCE_LENGTH]};
+        let mut bytes = challenge.into_bytes().unwrap();
+        bytes.extend(success().into_bytes().unwrap());
+        bytes.extend(message().into_bytes());
+
+        let mut decoder = FrameDecoder::for_client(DEFAULT_MAX_FRAME_SIZE);
+        decoder.push_bytes(&bytes);
+
+        let challenge = HandshakeResponse::Challenge {nonce: [7; CHALLENGE_NONCE_LENGTH]};
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::HandshakeResponse(challenge)));
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::HandshakeResponse(success())));
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::ServerMessage(message())));
+        assert_eq!(decoder.next_frame().unwrap(), None, "Expected no more frames");
+    }
+
+    #[test]
+    fn server_decoder_returns_challenge_answer_when_expected() {
+        let answer = || HandshakeChallengeAnswer {hmac: vec![1, 2, 3]};
+        let mut decoder = FrameDecoder::for_server(DEFAULT_MAX_FRAME_SIZE);
+        decoder.push_bytes(&HandshakeRequest::new().into_bytes());
+
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::HandshakeRequest(HandshakeRequest::new())));
+
+        decoder.expect_challenge_answer();
+        let mut bytes = answer().into_bytes();
+        bytes.extend(data_message(vec![1, 2, 3]).into_bytes());
+        decoder.push_bytes(&bytes);
+
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::HandshakeChallengeAnswer(answer())));
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::ClientMessage(data_message(vec![1, 2, 3]))));
+        assert_eq!(decoder.next_frame().unwrap(), None, "Expected no more frames");
+    }
+
+    #[test]
+    fn frames_split_across_single_byte_reads_are_reassembled() {
+        let mut bytes = HandshakeRequest::new().into_bytes();
+        bytes.extend(data_message(vec![5; 30]).into_bytes());
+
+        let mut decoder = FrameDecoder::for_server(DEFAULT_MAX_FRAME_SIZE);
+        let mut frames = Vec::new();
+        for byte in bytes {
+            decoder.push_bytes(&[byte]);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(frame);
+            }
+        }
+
+        assert_eq!(frames.len(), 2, "Unexpected number of frames");
+        assert_eq!(frames[0], Frame::HandshakeRequest(HandshakeRequest::new()));
+        assert_eq!(frames[1], Frame::ClientMessage(data_message(vec![5; 30])));
+    }
+
+    #[test]
+    fn partial_frame_is_kept_until_remaining_bytes_arrive() {
+        let mut bytes = HandshakeRequest::new().into_bytes();
+        let message_bytes = data_message(vec![1, 2, 3]).into_bytes();
+        bytes.extend_from_slice(&message_bytes[..4]);
+
+        let mut decoder = FrameDecoder::for_server(DEFAULT_MAX_FRAME_SIZE);
+        decoder.push_bytes(&bytes);
+
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::HandshakeRequest(HandshakeRequest::new())));
+        assert_eq!(decoder.next_frame().unwrap(), None, "Expected partial message to not be returned");
+        assert_eq!(decoder.buffered_byte_count(), 4, "Unexpected number of buffered bytes");
+
+        decoder.push_bytes(&message_bytes[4..]);
+        assert_eq!(decoder.next_frame().unwrap(), Some(Frame::ClientMessage(data_message(vec![1, 2, 3]))));
+    }
+
+    #[test]
+    fn error_returned_when_frame_exceeds_max_frame_size() {
+        let mut decoder = FrameDecoder::for_server(50);
+        decoder.push_bytes(&HandshakeRequest::new().into_bytes());
+        decoder.next_frame().unwrap();
+
+        let message_bytes = data_message(vec![0; 100]).into_bytes();
+        decoder.push_bytes(&message_bytes[..MESSAGE_HEADER_LENGTH]);
+
+        let error = decoder.next_frame().unwrap_err();
+        match error.kind {
+            FrameDecodeErrorKind::FrameTooLarge {size, max_size} => {
+                assert_eq!(size, message_bytes.len(), "Unexpected frame size in error");
+                assert_eq!(max_size, 50, "Unexpected max size in error");
+            },
+
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn error_returned_for_invalid_handshake_prefix() {
+        let mut decoder = FrameDecoder::for_server(DEFAULT_MAX_FRAME_SIZE);
+        decoder.push_bytes(b"abcde\x01z\x00");
+
+        let error = decoder.next_frame().unwrap_err();
+        match error.kind {
+            FrameDecodeErrorKind::InvalidHandshakeRequest(HandshakeRequestParseErrorsKind::InvalidPrefix) => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn error_returned_for_unsupported_message_version() {
+        let mut decoder = FrameDecoder::for_client(DEFAULT_MAX_FRAME_SIZE);
+        decoder.push_bytes(&success().into_bytes().unwrap());
+        decoder.next_frame().unwrap();
+
+        decoder.push_bytes(&[MESSAGE_FORMAT_VERSION + 1, 1, 255, 255, 255, 255]);
+        let error = decoder.next_frame().unwrap_err();
+        match error.kind {
+            FrameDecodeErrorKind::InvalidMessage(MessageParseErrorKind::UnsupportedVersion(_)) => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+}
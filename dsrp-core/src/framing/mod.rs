@@ -0,0 +1,5 @@
+#[allow(non_local_definitions)] // emitted by failure's derive macro
+mod frame_decoder;
+
+pub use self::frame_decoder::{FrameDecoder, Frame, FrameDecodeError, FrameDecodeErrorKind};
+pub use self::frame_decoder::DEFAULT_MAX_FRAME_SIZE;
@@ -0,0 +1,86 @@
+use hmac::{Hmac, Mac};
+use rand::{Rng, thread_rng};
+use sha2::Sha256;
+
+/// Number of random bytes the server sends a client to prove it knows a shared secret
+pub const CHALLENGE_NONCE_LENGTH: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub(crate) fn generate_nonce() -> [u8; CHALLENGE_NONCE_LENGTH] {
+    let mut nonce = [0; CHALLENGE_NONCE_LENGTH];
+    thread_rng().fill(&mut nonce);
+    nonce
+}
+
+/// Computes the answer to a server's authentication challenge, which is the HMAC-SHA256 of the
+/// nonce keyed by the shared secret.  This lets the client prove it knows the secret without
+/// the secret itself ever being sent over the connection.
+pub fn compute_challenge_answer(secret: &[u8], nonce: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(nonce);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Checks an answer against the expected HMAC in constant time
+pub(crate) fn is_valid_challenge_answer(secret: &[u8], nonce: &[u8], answer: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(nonce);
+    mac.verify_slice(answer).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_nonces_are_not_repeated() {
+        let nonce1 = generate_nonce();
+        let nonce2 = generate_nonce();
+
+        assert_ne!(nonce1, nonce2, "Expected each nonce to be different");
+    }
+
+    #[test]
+    fn answer_computed_with_same_secret_is_valid() {
+        let nonce = generate_nonce();
+        let answer = compute_challenge_answer(b"secret", &nonce);
+
+        assert!(is_valid_challenge_answer(b"secret", &nonce, &answer), "Expected answer to be valid");
+    }
+
+    #[test]
+    fn answer_computed_with_different_secret_is_invalid() {
+        let nonce = generate_nonce();
+        let answer = compute_challenge_answer(b"wrong", &nonce);
+
+        assert!(!is_valid_challenge_answer(b"secret", &nonce, &answer), "Expected answer to be invalid");
+    }
+
+    #[test]
+    fn answer_computed_for_different_nonce_is_invalid() {
+        let answer = compute_challenge_answer(b"secret", &generate_nonce());
+
+        assert!(!is_valid_challenge_answer(b"secret", &generate_nonce(), &answer), "Expected answer to be invalid");
+    }
+
+    #[test]
+    fn truncated_answer_is_invalid() {
+        let nonce = generate_nonce();
+        let answer = compute_challenge_answer(b"secret", &nonce);
+
+        assert!(!is_valid_challenge_answer(b"secret", &nonce, &answer[..16]), "Expected answer to be invalid");
+    }
+
+    #[test]
+    fn answer_matches_known_hmac_sha256_value() {
+        // RFC 4231 test case 2
+        let answer = compute_challenge_answer(b"Jefe", b"what do ya want for nothing?");
+        let expected = [
+            0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75, 0xc7,
+            0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec, 0x38, 0x43,
+        ];
+
+        assert_eq!(&answer[..], &expected[..], "Unexpected HMAC value");
+    }
+}
@@ -0,0 +1,91 @@
+use super::HANDSHAKE_CHALLENGE_ANSWER_PREFIX;
+use super::{HandshakeRequestParseError, HandshakeRequestParseErrorsKind};
+
+/// Sent by a client in reply to a `HandshakeResponse::Challenge` to prove that it knows one of
+/// the server's shared secrets
+#[derive(Debug, PartialEq)]
+pub struct HandshakeChallengeAnswer {
+    pub hmac: Vec<u8>,
+}
+
+impl HandshakeChallengeAnswer {
+    pub fn into_bytes(self) -> Vec<u8> {
+        if self.hmac.len() > 255 {
+            panic!("Challenge answer is {} bytes, but it can't be more than 255", self.hmac.len());
+        }
+
+        let mut bytes = Vec::with_capacity(HANDSHAKE_CHALLENGE_ANSWER_PREFIX.len() + 1 + self.hmac.len());
+        bytes.extend_from_slice(HANDSHAKE_CHALLENGE_ANSWER_PREFIX);
+        bytes.push(self.hmac.len() as u8);
+        bytes.extend_from_slice(&self.hmac);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, HandshakeRequestParseError> {
+        let prefix_length = HANDSHAKE_CHALLENGE_ANSWER_PREFIX.len();
+        if bytes.len() < prefix_length + 1 {
+            let kind = HandshakeRequestParseErrorsKind::InvalidNumberOfBytes;
+            return Err(HandshakeRequestParseError {kind});
+        }
+
+        if &bytes[..prefix_length] != HANDSHAKE_CHALLENGE_ANSWER_PREFIX {
+            let kind = HandshakeRequestParseErrorsKind::InvalidPrefix;
+            return Err(HandshakeRequestParseError {kind});
+        }
+
+        let hmac_length = bytes[prefix_length] as usize;
+        if bytes.len() != prefix_length + 1 + hmac_length {
+            let kind = HandshakeRequestParseErrorsKind::InvalidNumberOfBytes;
+            return Err(HandshakeRequestParseError {kind});
+        }
+
+        Ok(HandshakeChallengeAnswer {hmac: bytes[prefix_length + 1..].to_vec()})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_convert_answer_into_bytes() {
+        let answer = HandshakeChallengeAnswer {hmac: vec![1, 2, 3]};
+        let bytes = answer.into_bytes();
+
+        let prefix_length = HANDSHAKE_CHALLENGE_ANSWER_PREFIX.len();
+        assert_eq!(bytes.len(), prefix_length + 1 + 3, "Unexpected byte length");
+        assert_eq!(&bytes[..prefix_length], HANDSHAKE_CHALLENGE_ANSWER_PREFIX, "Unexpected prefix");
+        assert_eq!(bytes[prefix_length], 3, "Unexpected hmac length");
+        assert_eq!(&bytes[prefix_length + 1..], &[1, 2, 3], "Unexpected hmac bytes");
+    }
+
+    #[test]
+    fn can_read_serialized_answer() {
+        let answer = HandshakeChallengeAnswer {hmac: vec![5, 6, 7, 8]};
+        let bytes = answer.into_bytes();
+        let answer = HandshakeChallengeAnswer::from_bytes(&bytes).unwrap();
+
+        assert_eq!(answer, HandshakeChallengeAnswer {hmac: vec![5, 6, 7, 8]}, "Unexpected answer");
+    }
+
+    #[test]
+    fn invalid_prefix_returns_error() {
+        let error = HandshakeChallengeAnswer::from_bytes(b"abcde\x01z").unwrap_err();
+        match error.kind {
+            HandshakeRequestParseErrorsKind::InvalidPrefix => (),
+            x => panic!("Expected invalid prefix error, received {}", x),
+        }
+    }
+
+    #[test]
+    fn truncated_hmac_returns_error() {
+        let mut bytes = HandshakeChallengeAnswer {hmac: vec![5, 6, 7, 8]}.into_bytes();
+        bytes.pop();
+
+        let error = HandshakeChallengeAnswer::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            HandshakeRequestParseErrorsKind::InvalidNumberOfBytes => (),
+            x => panic!("Expected invalid number of bytes error, received {}", x),
+        }
+    }
+}
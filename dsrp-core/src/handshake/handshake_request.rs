@@ -4,10 +4,14 @@ use std::fmt;
 use std::string::FromUtf8Error;
 use byteorder::{ WriteBytesExt};
 use failure::Fail;
-use super::{CURRENT_VERSION, HANDSHAKE_REQUEST_PREFIX};
+use super::{CURRENT_VERSION, HANDSHAKE_REQUEST_PREFIX, SessionTicket, SESSION_TICKET_LENGTH};
 
+#[derive(Debug, PartialEq)]
 pub struct HandshakeRequest {
     pub client_protocol_version: String,
+
+    /// Ticket from a previous session the client wants to resume, if it's reconnecting
+    pub session_ticket: Option<SessionTicket>,
 }
 
 #[derive(Debug)]
@@ -23,6 +27,9 @@ pub enum HandshakeRequestParseErrorsKind {
     #[fail(display = "Invalid prefix")]
     InvalidPrefix,
 
+    #[fail(display = "Invalid session ticket length: {}", _0)]
+    InvalidSessionTicketLength(u8),
+
     #[fail(display = "{}", _0)]
     Io(#[cause] io::Error),
 
@@ -30,10 +37,25 @@ pub enum HandshakeRequestParseErrorsKind {
     FromUtf8Error(#[cause] FromUtf8Error),
 }
 
+impl Default for HandshakeRequest {
+    fn default() -> Self {
+        HandshakeRequest::new()
+    }
+}
+
 impl HandshakeRequest {
     pub fn new() -> Self {
         HandshakeRequest {
             client_protocol_version: CURRENT_VERSION.to_owned(),
+            session_ticket: None,
+        }
+    }
+
+    /// Creates a request asking the server to resume the session the ticket was issued for
+    pub fn resuming(session_ticket: SessionTicket) -> Self {
+        HandshakeRequest {
+            session_ticket: Some(session_ticket),
+            ..HandshakeRequest::new()
         }
     }
 
@@ -49,6 +71,15 @@ impl HandshakeRequest {
 
         bytes.write_u8(self.client_protocol_version.len() as u8).unwrap();
         bytes.extend_from_slice(self.client_protocol_version.as_bytes());
+        match self.session_ticket {
+            Some(ticket) => {
+                bytes.write_u8(SESSION_TICKET_LENGTH as u8).unwrap();
+                bytes.extend_from_slice(&ticket);
+            },
+
+            None => bytes.write_u8(0).unwrap(),
+        }
+
         bytes
     }
 
@@ -62,24 +93,46 @@ impl HandshakeRequest {
         let prefix = &bytes[..prefix_length];
         let version_length = bytes[prefix_length];
 
-        if &prefix[..] != HANDSHAKE_REQUEST_PREFIX {
+        if prefix != HANDSHAKE_REQUEST_PREFIX {
             let kind = HandshakeRequestParseErrorsKind::InvalidPrefix;
             return Err(HandshakeRequestParseError {kind});
         }
 
-        let expected_length = prefix_length + 1 + (version_length as usize);
+        let ticket_length_index = prefix_length + 1 + (version_length as usize);
+        let ticket_length = match bytes.get(ticket_length_index) {
+            Some(x) => *x,
+            None => {
+                let kind = HandshakeRequestParseErrorsKind::InvalidNumberOfBytes;
+                return Err(HandshakeRequestParseError {kind});
+            },
+        };
+
+        if ticket_length != 0 && ticket_length as usize != SESSION_TICKET_LENGTH {
+            let kind = HandshakeRequestParseErrorsKind::InvalidSessionTicketLength(ticket_length);
+            return Err(HandshakeRequestParseError {kind});
+        }
+
+        let expected_length = ticket_length_index + 1 + (ticket_length as usize);
         if bytes.len() != expected_length {
             let kind = HandshakeRequestParseErrorsKind::InvalidNumberOfBytes;
             return Err(HandshakeRequestParseError {kind});
         }
 
         let start_index = prefix_length + 1;
-        let mut buffer = Vec::with_capacity(version_length as usize);
-        buffer.resize(version_length as usize, 0);
-        (&bytes[start_index..]).read(&mut buffer)?;
+        let mut buffer = vec![0; version_length as usize];
+        (&bytes[start_index..]).read_exact(&mut buffer)?;
 
         let value = String::from_utf8(buffer)?;
-        Ok(HandshakeRequest{client_protocol_version: value})
+        let session_ticket = match ticket_length {
+            0 => None,
+            _ => {
+                let mut ticket = [0; SESSION_TICKET_LENGTH];
+                ticket.copy_from_slice(&bytes[ticket_length_index + 1..]);
+                Some(ticket)
+            },
+        };
+
+        Ok(HandshakeRequest{client_protocol_version: value, session_ticket})
     }
 }
 
@@ -116,22 +169,22 @@ mod tests {
     #[test]
     fn can_convert_request_into_bytes() {
         const VERSION: &str = "12345";
-        let request = HandshakeRequest { client_protocol_version: VERSION.to_owned() };
+        let request = HandshakeRequest { client_protocol_version: VERSION.to_owned(), session_ticket: None };
         let bytes = request.into_bytes();
 
         let prefix_length = HANDSHAKE_REQUEST_PREFIX.len();
 
-        assert_eq!(bytes.len(), prefix_length + 1 + VERSION.len(), "Unexpected byte length");
+        assert_eq!(bytes.len(), prefix_length + 1 + VERSION.len() + 1, "Unexpected byte length");
         assert_eq!(&bytes[..prefix_length], HANDSHAKE_REQUEST_PREFIX, "Unexpected handshake prefix");
 
         let mut cursor = Cursor::new(&bytes[prefix_length..]);
         let version_length = cursor.read_u8().unwrap() as usize;
         assert_eq!(version_length, VERSION.len(), "Unexpected version string length");
 
-        let mut buffer = Vec::new();
-        buffer.resize(version_length as usize, 0);
+        let mut buffer = vec![0; version_length];
         cursor.read_exact(&mut buffer[..]).unwrap();
         assert_eq!(&buffer[..], VERSION.as_bytes(), "Unexpected protocol version");
+        assert_eq!(cursor.read_u8().unwrap(), 0, "Expected no session ticket");
     }
 
     #[test]
@@ -143,12 +196,42 @@ mod tests {
 
     #[test]
     fn can_read_deserialized_request() {
-        const VERSION: &'static str = "abcdefg";
-        let request = HandshakeRequest { client_protocol_version: VERSION.to_owned() };
+        const VERSION: &str = "abcdefg";
+        let request = HandshakeRequest { client_protocol_version: VERSION.to_owned(), session_ticket: None };
         let bytes = request.into_bytes();
         let request = HandshakeRequest::from_bytes(&bytes).unwrap();
 
         assert_eq!(request.client_protocol_version, VERSION, "Unexpected client version");
+        assert_eq!(request.session_ticket, None, "Unexpected session ticket");
+    }
+
+    #[test]
+    fn can_read_deserialized_request_with_session_ticket() {
+        let mut ticket = [0; SESSION_TICKET_LENGTH];
+        ticket[0] = 3;
+        ticket[SESSION_TICKET_LENGTH - 1] = 8;
+
+        let bytes = HandshakeRequest::resuming(ticket).into_bytes();
+        let request = HandshakeRequest::from_bytes(&bytes).unwrap();
+
+        assert_eq!(request.client_protocol_version, CURRENT_VERSION, "Unexpected client version");
+        assert_eq!(request.session_ticket, Some(ticket), "Unexpected session ticket");
+    }
+
+    #[test]
+    fn invalid_session_ticket_length_returns_error() {
+        let mut bytes = HandshakeRequest::new().into_bytes();
+        bytes.pop();
+        bytes.push(5);
+        bytes.extend_from_slice(&[1, 2, 3, 4, 5]);
+
+        match HandshakeRequest::from_bytes(&bytes) {
+            Err(HandshakeRequestParseError{kind: HandshakeRequestParseErrorsKind::InvalidSessionTicketLength(5)})
+                => (), // success
+
+            Ok(_) => panic!("Expected error, received OK()"),
+            Err(x) => panic!("Expected invalid session ticket length error, received {}", x),
+        }
     }
 
     #[test]
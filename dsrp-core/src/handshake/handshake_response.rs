@@ -2,12 +2,27 @@ use std::io;
 use std::fmt;
 use std::string::FromUtf8Error;
 use failure::Fail;
-use super::{HANDSHAKE_RESPONSE_PREFIX};
+use super::{HANDSHAKE_RESPONSE_PREFIX, CHALLENGE_NONCE_LENGTH, SessionTicket, SESSION_TICKET_LENGTH};
+
+const SUCCESS_MARKER: u8 = 0b10000000;
+const CHALLENGE_MARKER: u8 = 0b10000001;
+const SERVER_SHUTTING_DOWN_MARKER: u8 = 0b10000010;
 
 #[derive(PartialEq, Debug)]
 pub enum HandshakeResponse {
-    Success,
+    /// The client was accepted, and given a ticket it can use to resume this session if it gets
+    /// disconnected.  `resumed` is set when the client's previous session was resumed, meaning
+    /// its channels are still registered.
+    Success{session_ticket: SessionTicket, resumed: bool},
+
     Failure{reason: String},
+
+    /// The server requires the client to prove it knows a shared secret before it's accepted
+    Challenge{nonce: [u8; CHALLENGE_NONCE_LENGTH]},
+
+    /// The server is shutting down and can't accept the client, but another server may take
+    /// its place so it's worth trying again later
+    ServerShuttingDown,
 }
 
 #[derive(Debug)]
@@ -50,8 +65,19 @@ impl HandshakeResponse {
         bytes.extend_from_slice(HANDSHAKE_RESPONSE_PREFIX);
 
         match self {
-            HandshakeResponse::Success => {
-                bytes.push(0b10000000);
+            HandshakeResponse::Success {session_ticket, resumed} => {
+                bytes.push(SUCCESS_MARKER);
+                bytes.extend_from_slice(&session_ticket);
+                bytes.push(resumed as u8);
+            },
+
+            HandshakeResponse::Challenge {nonce} => {
+                bytes.push(CHALLENGE_MARKER);
+                bytes.extend_from_slice(&nonce);
+            },
+
+            HandshakeResponse::ServerShuttingDown => {
+                bytes.push(SERVER_SHUTTING_DOWN_MARKER);
             },
 
             HandshakeResponse::Failure {reason} => {
@@ -76,21 +102,50 @@ impl HandshakeResponse {
             return Err(HandshakeResponseParseError{kind});
         }
 
-        if &bytes[..handshake_length] != &HANDSHAKE_RESPONSE_PREFIX[..] {
+        if bytes[..handshake_length] != HANDSHAKE_RESPONSE_PREFIX[..] {
             let kind = HandshakeResponseParseErrorKind::InvalidPrefix;
             return Err(HandshakeResponseParseError{kind});
         }
 
         let response = match bytes[handshake_length] {
-            // values above 128 are reserved
-            x if x > 128 => {
+            // values above 130 are reserved
+            x if x > SERVER_SHUTTING_DOWN_MARKER => {
                 let kind = HandshakeResponseParseErrorKind::InvalidMarkerByte(x);
                 return Err(HandshakeResponseParseError{kind});
             },
 
-            // 128 signifies success
-            128 => {
-                (HandshakeResponse::Success, &bytes[handshake_length + 1..])
+            // 128 signifies success, followed by the session ticket and whether it was resumed
+            SUCCESS_MARKER => {
+                let start_index = handshake_length + 1;
+                let end_index = start_index + SESSION_TICKET_LENGTH;
+                if bytes.len() < end_index + 1 {
+                    let kind = HandshakeResponseParseErrorKind::NotEnoughBytes;
+                    return Err(HandshakeResponseParseError{kind});
+                }
+
+                let mut session_ticket = [0; SESSION_TICKET_LENGTH];
+                session_ticket.copy_from_slice(&bytes[start_index..end_index]);
+                let resumed = bytes[end_index] != 0;
+                (HandshakeResponse::Success {session_ticket, resumed}, &bytes[end_index + 1..])
+            },
+
+            // 129 signifies a challenge, followed by the nonce the client has to answer with
+            CHALLENGE_MARKER => {
+                let start_index = handshake_length + 1;
+                let end_index = start_index + CHALLENGE_NONCE_LENGTH;
+                if bytes.len() < end_index {
+                    let kind = HandshakeResponseParseErrorKind::NotEnoughBytes;
+                    return Err(HandshakeResponseParseError{kind});
+                }
+
+                let mut nonce = [0; CHALLENGE_NONCE_LENGTH];
+                nonce.copy_from_slice(&bytes[start_index..end_index]);
+                (HandshakeResponse::Challenge {nonce}, &bytes[end_index..])
+            },
+
+            // 130 signifies the server is shutting down, with nothing following it
+            SERVER_SHUTTING_DOWN_MARKER => {
+                (HandshakeResponse::ServerShuttingDown, &bytes[handshake_length + 1..])
             },
 
             // values below 128 are considered failures, with the actual number
@@ -160,13 +215,15 @@ mod tests {
 
     #[test]
     fn can_convert_success_response_into_bytes() {
-        let response = HandshakeResponse::Success;
+        let response = HandshakeResponse::Success {session_ticket: [4; SESSION_TICKET_LENGTH], resumed: true};
         let bytes = response.into_bytes().unwrap();
 
         let prefix_length = HANDSHAKE_RESPONSE_PREFIX.len();
-        assert_eq!(bytes.len(), prefix_length + 1, "Unexpected number of bytes");
+        assert_eq!(bytes.len(), prefix_length + 1 + SESSION_TICKET_LENGTH + 1, "Unexpected number of bytes");
         assert_eq!(&bytes[..prefix_length], HANDSHAKE_RESPONSE_PREFIX, "Unexpected prefix");
         assert_eq!(bytes[prefix_length], 0b10000000, "Unexpected response value");
+        assert_eq!(&bytes[prefix_length + 1..prefix_length + 1 + SESSION_TICKET_LENGTH], &[4; SESSION_TICKET_LENGTH], "Unexpected session ticket");
+        assert_eq!(bytes[prefix_length + 1 + SESSION_TICKET_LENGTH], 1, "Unexpected resumed flag");
     }
 
     #[test]
@@ -184,11 +241,27 @@ mod tests {
 
     #[test]
     fn can_read_success_bytes() {
-        let response = HandshakeResponse::Success;
-        let bytes = response.into_bytes().unwrap();
-        let (response, _) = HandshakeResponse::from_bytes(&bytes).unwrap();
+        let response = || HandshakeResponse::Success {session_ticket: [6; SESSION_TICKET_LENGTH], resumed: false};
+        let bytes = response().into_bytes().unwrap();
+        let (parsed, extra_bytes) = HandshakeResponse::from_bytes(&bytes).unwrap();
 
-        assert_eq!(response, HandshakeResponse::Success, "Unexpected response parsed");
+        assert_eq!(parsed, response(), "Unexpected response parsed");
+        assert_eq!(extra_bytes.len(), 0, "Unexpected extra bytes");
+    }
+
+    #[test]
+    fn truncated_success_returns_error() {
+        let mut bytes = HandshakeResponse::Success {session_ticket: [6; SESSION_TICKET_LENGTH], resumed: false}
+            .into_bytes()
+            .unwrap();
+
+        bytes.pop();
+
+        let error = HandshakeResponse::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            HandshakeResponseParseErrorKind::NotEnoughBytes => (),
+            x => panic!("Unexpected error: {}", x),
+        }
     }
 
     #[test]
@@ -201,6 +274,57 @@ mod tests {
         assert_eq!(response, HandshakeResponse::Failure {reason: message.clone()}, "Unexpected response");
     }
 
+    #[test]
+    fn can_read_challenge_bytes() {
+        let mut nonce = [0; CHALLENGE_NONCE_LENGTH];
+        nonce[0] = 5;
+        nonce[CHALLENGE_NONCE_LENGTH - 1] = 9;
+
+        let bytes = HandshakeResponse::Challenge {nonce}.into_bytes().unwrap();
+        let prefix_length = HANDSHAKE_RESPONSE_PREFIX.len();
+        assert_eq!(bytes.len(), prefix_length + 1 + CHALLENGE_NONCE_LENGTH, "Unexpected number of bytes");
+
+        let (response, extra_bytes) = HandshakeResponse::from_bytes(&bytes).unwrap();
+        assert_eq!(response, HandshakeResponse::Challenge {nonce}, "Unexpected response");
+        assert_eq!(extra_bytes.len(), 0, "Unexpected extra bytes");
+    }
+
+    #[test]
+    fn truncated_challenge_returns_error() {
+        let mut bytes = HandshakeResponse::Challenge {nonce: [1; CHALLENGE_NONCE_LENGTH]}.into_bytes().unwrap();
+        bytes.pop();
+
+        let error = HandshakeResponse::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            HandshakeResponseParseErrorKind::NotEnoughBytes => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn can_round_trip_server_shutting_down_response() {
+        let bytes = HandshakeResponse::ServerShuttingDown.into_bytes().unwrap();
+        let prefix_length = HANDSHAKE_RESPONSE_PREFIX.len();
+        assert_eq!(bytes.len(), prefix_length + 1, "Unexpected number of bytes");
+
+        let (response, extra_bytes) = HandshakeResponse::from_bytes(&bytes).unwrap();
+        assert_eq!(response, HandshakeResponse::ServerShuttingDown, "Unexpected response");
+        assert_eq!(extra_bytes.len(), 0, "Unexpected extra bytes");
+    }
+
+    #[test]
+    fn reserved_marker_byte_returns_error() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(HANDSHAKE_RESPONSE_PREFIX);
+        bytes.push(131);
+
+        let error = HandshakeResponse::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            HandshakeResponseParseErrorKind::InvalidMarkerByte(131) => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
     #[test]
     fn parse_process_returns_extra_bytes() {
         let message = "test fail".to_owned();
@@ -211,7 +335,7 @@ mod tests {
         let (response, extra_bytes) = HandshakeResponse::from_bytes(&bytes).unwrap();
 
         assert_eq!(response, HandshakeResponse::Failure {reason: message.clone()}, "Unexpected response");
-        assert_eq!(&extra_bytes[..], &[1, 2, 3], "Unexpected extra bytes");
+        assert_eq!(extra_bytes, &[1, 2, 3], "Unexpected extra bytes");
     }
 
     #[test]
@@ -1,9 +1,21 @@
+mod challenge;
+mod handshake_challenge_answer;
+#[allow(non_local_definitions)] // emitted by failure's derive macro
 mod handshake_request;
+#[allow(non_local_definitions)] // emitted by failure's derive macro
 mod handshake_response;
+mod session_ticket;
 
+pub use self::challenge::{compute_challenge_answer, CHALLENGE_NONCE_LENGTH};
+pub use self::handshake_challenge_answer::HandshakeChallengeAnswer;
 pub use self::handshake_request::{HandshakeRequest, HandshakeRequestParseError, HandshakeRequestParseErrorsKind};
-pub use self::handshake_response::{HandshakeResponse};
+pub use self::handshake_response::{HandshakeResponse, HandshakeResponseParseError, HandshakeResponseParseErrorKind};
+pub use self::session_ticket::{SessionTicket, SESSION_TICKET_LENGTH};
+
+pub(crate) use self::challenge::{generate_nonce, is_valid_challenge_answer};
+pub(crate) use self::session_ticket::{generate_session_ticket, is_same_session_ticket};
 
 pub(crate) static CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
-const HANDSHAKE_REQUEST_PREFIX: &[u8; 5] = b"DSRPA";
-const HANDSHAKE_RESPONSE_PREFIX: &[u8; 5] = b"DSRPB";
+pub(crate) const HANDSHAKE_REQUEST_PREFIX: &[u8; 5] = b"DSRPA";
+pub(crate) const HANDSHAKE_RESPONSE_PREFIX: &[u8; 5] = b"DSRPB";
+pub(crate) const HANDSHAKE_CHALLENGE_ANSWER_PREFIX: &[u8; 5] = b"DSRPC";
@@ -0,0 +1,51 @@
+use rand::{Rng, thread_rng};
+
+/// Number of random bytes in the ticket a client presents to resume its previous session
+pub const SESSION_TICKET_LENGTH: usize = 32;
+
+/// Issued by the server when a client is accepted, letting the client reclaim its client id and
+/// channels if it reconnects before the server's grace period for the session runs out
+pub type SessionTicket = [u8; SESSION_TICKET_LENGTH];
+
+pub(crate) fn generate_session_ticket() -> SessionTicket {
+    let mut ticket = [0; SESSION_TICKET_LENGTH];
+    thread_rng().fill(&mut ticket);
+    ticket
+}
+
+/// Compares two tickets in constant time, so how long the comparison takes doesn't reveal how
+/// much of a guessed ticket was correct
+pub(crate) fn is_same_session_ticket(ticket: &SessionTicket, other: &SessionTicket) -> bool {
+    ticket.iter()
+        .zip(other.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_tickets_are_not_repeated() {
+        let ticket1 = generate_session_ticket();
+        let ticket2 = generate_session_ticket();
+
+        assert_ne!(ticket1, ticket2, "Expected different tickets");
+    }
+
+    #[test]
+    fn identical_tickets_are_the_same() {
+        let ticket = generate_session_ticket();
+
+        assert!(is_same_session_ticket(&ticket, &ticket), "Expected tickets to be the same");
+    }
+
+    #[test]
+    fn tickets_differing_in_last_byte_are_not_the_same() {
+        let ticket = generate_session_ticket();
+        let mut other = ticket;
+        other[SESSION_TICKET_LENGTH - 1] ^= 1;
+
+        assert!(!is_same_session_ticket(&ticket, &other), "Expected tickets to be different");
+    }
+}
@@ -1,14 +1,20 @@
-#[macro_use] extern crate failure;
+extern crate failure;
 extern crate byteorder;
 extern crate rand;
+extern crate hmac;
+extern crate sha2;
 
 #[cfg(test)]
 #[macro_use]
 mod test_utils {
     #[macro_use] pub mod assert_vec_contains_macro;
+    pub mod test_clock;
 }
 
+pub mod clock;
 pub mod handshake;
 pub mod messages;
+pub mod framing;
 pub mod server_handler;
 pub mod client_handler;
+pub mod stats;
@@ -1,13 +1,53 @@
+use std::io::Cursor;
+use byteorder::ReadBytesExt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
 use super::{ConnectionType, RequestId, ChannelId, ConnectionId};
+use super::encoding::{MessageParseError, MessageParseErrorKind, encode_frame, decode_frame};
+use super::encoding::{verify_payload_consumed, write_request_id, read_request_id};
+use super::encoding::{write_channel_id, read_channel_id, write_connection_id, read_connection_id};
+use super::encoding::{write_optional_connection_id, read_optional_connection_id};
+use super::encoding::{write_connection_type, read_connection_type, write_port, read_port};
+use super::encoding::{write_optional_socket_address, read_optional_socket_address};
+use super::encoding::{write_optional_port_range, read_optional_port_range};
+use super::encoding::{write_optional_ip_address, read_optional_ip_address};
+use super::encoding::{write_data, read_data};
 
-#[derive(Debug)]
+const REGISTER_MARKER: u8 = 1;
+const UNREGISTER_MARKER: u8 = 2;
+const TCP_CONNECTION_DISCONNECTED_MARKER: u8 = 3;
+const DATA_BEING_SENT_MARKER: u8 = 4;
+const REGISTER_ANY_PORT_MARKER: u8 = 5;
+const TCP_CONNECTION_FAILED_MARKER: u8 = 6;
+const TCP_CONNECTION_HALF_CLOSED_MARKER: u8 = 7;
+const PING_MARKER: u8 = 8;
+const PONG_MARKER: u8 = 9;
+const UDP_CONNECTION_FAILED_MARKER: u8 = 10;
+
+const CONNECTION_REFUSED_MARKER: u8 = 1;
+const CONNECTION_TIMED_OUT_MARKER: u8 = 2;
+const OTHER_CONNECTION_FAILURE_MARKER: u8 = 3;
+
+#[derive(Debug, PartialEq)]
 pub enum ClientMessage {
     /// A Request to have the DSRP server relay all tcp or udp traffic from the specified port
-    /// to the client sending the request.
+    /// to the client sending the request.  The port is bound on the server's default address
+    /// unless one of the server's other bind addresses is specified.
     Register {
         request: RequestId,
         connection_type: ConnectionType,
         port: u16,
+        bind_address: Option<IpAddr>,
+    },
+
+    /// A request for the DSRP server to pick a free port for the client, optionally limited to
+    /// the specified range, and relay all tcp or udp traffic from it to the client.  The chosen
+    /// port is reported back in the registration's response.
+    RegisterAnyPort {
+        request: RequestId,
+        connection_type: ConnectionType,
+        port_range: Option<RangeInclusive<u16>>,
+        bind_address: Option<IpAddr>,
     },
 
     /// Tells the DSRP server that the channel should be closed, meaning the server should not
@@ -22,11 +62,504 @@ pub enum ClientMessage {
         connection: ConnectionId,
     },
 
+    /// Tells the DSRP server that the application server has finished sending data over the TCP
+    /// connection, but may still be waiting to receive data over it
+    TcpConnectionHalfClosed {
+        channel: ChannelId,
+        connection: ConnectionId,
+    },
+
     /// Relays an outbound packet, so the DSRP server can relay it to the originator of the
-    /// the connection.
+    /// the connection.  Packets for udp channels specify the address of the remote peer they
+    /// should be sent to.
     DataBeingSent {
         channel: ChannelId,
         connection: Option<ConnectionId>,
+        peer_address: Option<SocketAddr>,
         data: Vec<u8>,
     },
-}
\ No newline at end of file
+
+    /// Tells the DSRP server that the client could not open its own connection to the
+    /// application server for a TCP connection the server accepted, so the remote peer's
+    /// connection should be aborted.
+    TcpConnectionFailed {
+        channel: ChannelId,
+        connection: ConnectionId,
+        reason: ConnectionFailureReason,
+    },
+
+    /// Tells the DSRP server that the client could not open a udp socket to the application
+    /// server for a remote peer the server announced, so the server should stop tracking it
+    UdpConnectionFailed {
+        channel: ChannelId,
+        connection: ConnectionId,
+        reason: ConnectionFailureReason,
+    },
+
+    /// Checks that the DSRP server is still responsive, which it should answer with a `Pong`
+    Ping,
+
+    /// Answers a `Ping` sent by the DSRP server
+    Pong,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionFailureReason {
+    /// The application server actively refused the connection, usually because it isn't running
+    ConnectionRefused,
+
+    /// The application server did not respond in time
+    TimedOut,
+
+    /// The connection failed for any other reason
+    Other,
+}
+
+impl ClientMessage {
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let marker = match self {
+            ClientMessage::Register {request, connection_type, port, bind_address} => {
+                write_request_id(&mut payload, request);
+                write_connection_type(&mut payload, &connection_type);
+                write_port(&mut payload, port);
+                write_optional_ip_address(&mut payload, bind_address);
+                REGISTER_MARKER
+            },
+
+            ClientMessage::RegisterAnyPort {request, connection_type, port_range, bind_address} => {
+                write_request_id(&mut payload, request);
+                write_connection_type(&mut payload, &connection_type);
+                write_optional_port_range(&mut payload, port_range.as_ref());
+                write_optional_ip_address(&mut payload, bind_address);
+                REGISTER_ANY_PORT_MARKER
+            },
+
+            ClientMessage::Unregister {channel} => {
+                write_channel_id(&mut payload, channel);
+                UNREGISTER_MARKER
+            },
+
+            ClientMessage::TcpConnectionDisconnected {channel, connection} => {
+                write_channel_id(&mut payload, channel);
+                write_connection_id(&mut payload, connection);
+                TCP_CONNECTION_DISCONNECTED_MARKER
+            },
+
+            ClientMessage::TcpConnectionHalfClosed {channel, connection} => {
+                write_channel_id(&mut payload, channel);
+                write_connection_id(&mut payload, connection);
+                TCP_CONNECTION_HALF_CLOSED_MARKER
+            },
+
+            ClientMessage::DataBeingSent {channel, connection, peer_address, data} => {
+                write_channel_id(&mut payload, channel);
+                write_optional_connection_id(&mut payload, connection);
+                write_optional_socket_address(&mut payload, peer_address);
+                write_data(&mut payload, &data[..]);
+                DATA_BEING_SENT_MARKER
+            },
+
+            ClientMessage::TcpConnectionFailed {channel, connection, reason} => {
+                write_channel_id(&mut payload, channel);
+                write_connection_id(&mut payload, connection);
+                payload.push(reason.into_marker());
+                TCP_CONNECTION_FAILED_MARKER
+            },
+
+            ClientMessage::UdpConnectionFailed {channel, connection, reason} => {
+                write_channel_id(&mut payload, channel);
+                write_connection_id(&mut payload, connection);
+                payload.push(reason.into_marker());
+                UDP_CONNECTION_FAILED_MARKER
+            },
+
+            ClientMessage::Ping => PING_MARKER,
+            ClientMessage::Pong => PONG_MARKER,
+        };
+
+        encode_frame(marker, payload)
+    }
+
+    /// Parses the first message contained in the bytes, returning it along with any bytes
+    /// that came after it.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), MessageParseError> {
+        let (marker, payload, remaining_bytes) = decode_frame(bytes)?;
+        let mut cursor = Cursor::new(payload);
+
+        let message = match marker {
+            REGISTER_MARKER => {
+                let request = read_request_id(&mut cursor)?;
+                let connection_type = read_connection_type(&mut cursor)?;
+                let port = read_port(&mut cursor)?;
+                let bind_address = read_optional_ip_address(&mut cursor)?;
+                ClientMessage::Register {request, connection_type, port, bind_address}
+            },
+
+            REGISTER_ANY_PORT_MARKER => {
+                let request = read_request_id(&mut cursor)?;
+                let connection_type = read_connection_type(&mut cursor)?;
+                let port_range = read_optional_port_range(&mut cursor)?;
+                let bind_address = read_optional_ip_address(&mut cursor)?;
+                ClientMessage::RegisterAnyPort {request, connection_type, port_range, bind_address}
+            },
+
+            UNREGISTER_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                ClientMessage::Unregister {channel}
+            },
+
+            TCP_CONNECTION_DISCONNECTED_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                let connection = read_connection_id(&mut cursor)?;
+                ClientMessage::TcpConnectionDisconnected {channel, connection}
+            },
+
+            TCP_CONNECTION_HALF_CLOSED_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                let connection = read_connection_id(&mut cursor)?;
+                ClientMessage::TcpConnectionHalfClosed {channel, connection}
+            },
+
+            DATA_BEING_SENT_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                let connection = read_optional_connection_id(&mut cursor)?;
+                let peer_address = read_optional_socket_address(&mut cursor)?;
+                let data = read_data(&mut cursor)?;
+                ClientMessage::DataBeingSent {channel, connection, peer_address, data}
+            },
+
+            TCP_CONNECTION_FAILED_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                let connection = read_connection_id(&mut cursor)?;
+                let reason = ConnectionFailureReason::from_marker(cursor.read_u8()?)?;
+                ClientMessage::TcpConnectionFailed {channel, connection, reason}
+            },
+
+            UDP_CONNECTION_FAILED_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                let connection = read_connection_id(&mut cursor)?;
+                let reason = ConnectionFailureReason::from_marker(cursor.read_u8()?)?;
+                ClientMessage::UdpConnectionFailed {channel, connection, reason}
+            },
+
+            PING_MARKER => ClientMessage::Ping,
+            PONG_MARKER => ClientMessage::Pong,
+
+            x => {
+                let kind = MessageParseErrorKind::InvalidMessageType(x);
+                return Err(MessageParseError {kind});
+            },
+        };
+
+        verify_payload_consumed(&cursor)?;
+        Ok((message, remaining_bytes))
+    }
+}
+
+impl ConnectionFailureReason {
+    fn into_marker(self) -> u8 {
+        match self {
+            ConnectionFailureReason::ConnectionRefused => CONNECTION_REFUSED_MARKER,
+            ConnectionFailureReason::TimedOut => CONNECTION_TIMED_OUT_MARKER,
+            ConnectionFailureReason::Other => OTHER_CONNECTION_FAILURE_MARKER,
+        }
+    }
+
+    fn from_marker(marker: u8) -> Result<Self, MessageParseError> {
+        match marker {
+            CONNECTION_REFUSED_MARKER => Ok(ConnectionFailureReason::ConnectionRefused),
+            CONNECTION_TIMED_OUT_MARKER => Ok(ConnectionFailureReason::TimedOut),
+            OTHER_CONNECTION_FAILURE_MARKER => Ok(ConnectionFailureReason::Other),
+            x => {
+                let kind = MessageParseErrorKind::InvalidConnectionFailureReason(x);
+                Err(MessageParseError {kind})
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{MESSAGE_FORMAT_VERSION, MESSAGE_HEADER_LENGTH};
+
+    fn assert_round_trip(message: ClientMessage, expected: ClientMessage) {
+        let bytes = message.into_bytes();
+        let (parsed, remaining_bytes) = ClientMessage::from_bytes(&bytes).unwrap();
+
+        assert_eq!(parsed, expected, "Unexpected message parsed");
+        assert_eq!(remaining_bytes.len(), 0, "Unexpected remaining bytes");
+    }
+
+    #[test]
+    fn can_round_trip_tcp_register_message() {
+        let message = || ClientMessage::Register {
+            request: RequestId(23),
+            connection_type: ConnectionType::Tcp,
+            port: 8080,
+            bind_address: None,
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_udp_register_message() {
+        let message = || ClientMessage::Register {
+            request: RequestId(23),
+            connection_type: ConnectionType::Udp,
+            port: 53,
+            bind_address: Some("10.8.0.1".parse().unwrap()),
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_register_any_port_message_without_range() {
+        let message = || ClientMessage::RegisterAnyPort {
+            request: RequestId(23),
+            connection_type: ConnectionType::Tcp,
+            port_range: None,
+            bind_address: None,
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_register_any_port_message_with_range() {
+        let message = || ClientMessage::RegisterAnyPort {
+            request: RequestId(23),
+            connection_type: ConnectionType::Udp,
+            port_range: Some(9000..=9100),
+            bind_address: Some("fd00::1".parse().unwrap()),
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_unregister_message() {
+        let message = || ClientMessage::Unregister {channel: ChannelId(99)};
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_tcp_connection_disconnected_message() {
+        let message = || ClientMessage::TcpConnectionDisconnected {
+            channel: ChannelId(5),
+            connection: ConnectionId(6),
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_ping_message() {
+        assert_round_trip(ClientMessage::Ping, ClientMessage::Ping);
+    }
+
+    #[test]
+    fn can_round_trip_pong_message() {
+        assert_round_trip(ClientMessage::Pong, ClientMessage::Pong);
+    }
+
+    #[test]
+    fn can_round_trip_tcp_connection_half_closed_message() {
+        let message = || ClientMessage::TcpConnectionHalfClosed {
+            channel: ChannelId(5),
+            connection: ConnectionId(6),
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_tcp_connection_failed_message() {
+        let message = || ClientMessage::TcpConnectionFailed {
+            channel: ChannelId(5),
+            connection: ConnectionId(6),
+            reason: ConnectionFailureReason::ConnectionRefused,
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_udp_connection_failed_message() {
+        let message = || ClientMessage::UdpConnectionFailed {
+            channel: ChannelId(5),
+            connection: ConnectionId(6),
+            reason: ConnectionFailureReason::Other,
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn error_returned_for_invalid_connection_failure_reason() {
+        let message = ClientMessage::TcpConnectionFailed {
+            channel: ChannelId(5),
+            connection: ConnectionId(6),
+            reason: ConnectionFailureReason::TimedOut,
+        };
+
+        let mut bytes = message.into_bytes();
+        bytes[MESSAGE_HEADER_LENGTH + 8] = 99;
+
+        let error = ClientMessage::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            MessageParseErrorKind::InvalidConnectionFailureReason(99) => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn can_round_trip_data_being_sent_message_with_connection() {
+        let message = || ClientMessage::DataBeingSent {
+            channel: ChannelId(5),
+            connection: Some(ConnectionId(6)),
+            peer_address: None,
+            data: vec![1, 2, 3, 4],
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_data_being_sent_message_without_connection() {
+        let message = || ClientMessage::DataBeingSent {
+            channel: ChannelId(5),
+            connection: None,
+            peer_address: Some("10.1.2.3:5353".parse().unwrap()),
+            data: vec![1, 2, 3, 4],
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_data_being_sent_message_with_ipv6_peer_address() {
+        let message = || ClientMessage::DataBeingSent {
+            channel: ChannelId(5),
+            connection: None,
+            peer_address: Some("[2001:db8::1]:5353".parse().unwrap()),
+            data: vec![1, 2, 3, 4],
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn encoded_message_starts_with_version_and_payload_length() {
+        let bytes = ClientMessage::Unregister {channel: ChannelId(99)}.into_bytes();
+
+        assert_eq!(bytes[0], MESSAGE_FORMAT_VERSION, "Unexpected format version");
+        assert_eq!(bytes[1], UNREGISTER_MARKER, "Unexpected message type marker");
+        assert_eq!(&bytes[2..MESSAGE_HEADER_LENGTH], &[0, 0, 0, 4], "Unexpected payload length");
+        assert_eq!(bytes.len(), MESSAGE_HEADER_LENGTH + 4, "Unexpected number of bytes");
+    }
+
+    #[test]
+    fn parse_process_returns_extra_bytes() {
+        let mut bytes = ClientMessage::Unregister {channel: ChannelId(99)}.into_bytes();
+        bytes.extend_from_slice(&[1, 2, 3]);
+
+        let (message, extra_bytes) = ClientMessage::from_bytes(&bytes).unwrap();
+
+        assert_eq!(message, ClientMessage::Unregister {channel: ChannelId(99)}, "Unexpected message");
+        assert_eq!(extra_bytes, &[1, 2, 3], "Unexpected extra bytes");
+    }
+
+    #[test]
+    fn error_returned_when_not_enough_bytes_passed_in() {
+        let bytes = ClientMessage::Unregister {channel: ChannelId(99)}.into_bytes();
+        let error = ClientMessage::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err();
+
+        match error.kind {
+            MessageParseErrorKind::NotEnoughBytes => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn error_returned_for_unsupported_version() {
+        let mut bytes = ClientMessage::Unregister {channel: ChannelId(99)}.into_bytes();
+        bytes[0] = MESSAGE_FORMAT_VERSION + 1;
+
+        let error = ClientMessage::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            MessageParseErrorKind::UnsupportedVersion(version) => {
+                assert_eq!(version, MESSAGE_FORMAT_VERSION + 1, "Unexpected version in error");
+            },
+
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn error_returned_for_unknown_message_type() {
+        let mut bytes = ClientMessage::Unregister {channel: ChannelId(99)}.into_bytes();
+        bytes[1] = 200;
+
+        let error = ClientMessage::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            MessageParseErrorKind::InvalidMessageType(200) => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn error_returned_for_invalid_connection_type() {
+        let message = ClientMessage::Register {
+            request: RequestId(23),
+            connection_type: ConnectionType::Tcp,
+            port: 8080,
+            bind_address: None,
+        };
+
+        let mut bytes = message.into_bytes();
+        bytes[MESSAGE_HEADER_LENGTH + 4] = 7;
+
+        let error = ClientMessage::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            MessageParseErrorKind::InvalidConnectionType(7) => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn error_returned_for_port_range_that_ends_before_it_starts() {
+        let message = ClientMessage::RegisterAnyPort {
+            request: RequestId(23),
+            connection_type: ConnectionType::Tcp,
+            port_range: Some(9000..=9100),
+            bind_address: None,
+        };
+
+        let mut bytes = message.into_bytes();
+        let range_start = MESSAGE_HEADER_LENGTH + 6;
+        bytes[range_start..range_start + 2].copy_from_slice(&[0x23, 0x8D]); // 9101
+
+        let error = ClientMessage::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            MessageParseErrorKind::InvalidPortRange(9101, 9100) => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn error_returned_when_payload_has_unused_bytes() {
+        let mut bytes = ClientMessage::Unregister {channel: ChannelId(99)}.into_bytes();
+        bytes[5] = 5;
+        bytes.push(0);
+
+        let error = ClientMessage::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            MessageParseErrorKind::PayloadLengthMismatch {declared: 5, consumed: 4} => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use std::io;
+use std::io::{Cursor, Read};
+use std::cmp::min;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use failure::Fail;
+use super::{ChannelId, ConnectionId, ConnectionType, RequestId};
+
+/// Version of the binary message format.  Every encoded message starts with this byte so
+/// the format can evolve without older peers misinterpreting newer frames.  Version 2 added
+/// the udp peer, half-close, revocation, heartbeat and shutdown messages.
+pub const MESSAGE_FORMAT_VERSION: u8 = 2;
+
+/// Number of bytes that precede every message payload: the format version, the message
+/// type marker and the length of the payload.
+pub const MESSAGE_HEADER_LENGTH: usize = 6;
+
+#[derive(Debug)]
+pub struct MessageParseError {
+    pub kind: MessageParseErrorKind,
+}
+
+#[derive(Debug, Fail)]
+pub enum MessageParseErrorKind {
+    #[fail(display = "Not enough bytes for a complete message")]
+    NotEnoughBytes,
+
+    #[fail(display = "Unsupported message format version: {}", _0)]
+    UnsupportedVersion(u8),
+
+    #[fail(display = "Invalid message type marker: {}", _0)]
+    InvalidMessageType(u8),
+
+    #[fail(display = "Invalid connection type marker: {}", _0)]
+    InvalidConnectionType(u8),
+
+    #[fail(display = "Invalid registration failure cause marker: {}", _0)]
+    InvalidRegistrationFailureCause(u8),
+
+    #[fail(display = "Invalid channel revocation reason marker: {}", _0)]
+    InvalidChannelRevocationReason(u8),
+
+    #[fail(display = "Invalid connection failure reason marker: {}", _0)]
+    InvalidConnectionFailureReason(u8),
+
+    #[fail(display = "Invalid optional value marker: {}", _0)]
+    InvalidOptionalMarker(u8),
+
+    #[fail(display = "Invalid address family marker: {}", _0)]
+    InvalidAddressFamily(u8),
+
+    #[fail(display = "Invalid port range: {} to {}", _0, _1)]
+    InvalidPortRange(u16, u16),
+
+    #[fail(display = "Payload declared {} bytes but its fields used {}", declared, consumed)]
+    PayloadLengthMismatch {
+        declared: usize,
+        consumed: usize,
+    },
+
+    #[fail(display = "{}", _0)]
+    Io(#[cause] io::Error),
+}
+
+/// Determines the total number of bytes taken up by the message starting at the beginning of
+/// the bytes, or `None` if the header is not complete yet.  A header with an unsupported version
+/// is reported as having no payload, so parsing it reports the version instead of waiting for a
+/// length that can't be trusted.
+pub(crate) fn encoded_message_length(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < MESSAGE_HEADER_LENGTH {
+        return None;
+    }
+
+    if bytes[0] != MESSAGE_FORMAT_VERSION {
+        return Some(MESSAGE_HEADER_LENGTH);
+    }
+
+    let payload_length = BigEndian::read_u32(&bytes[2..MESSAGE_HEADER_LENGTH]) as usize;
+    Some(MESSAGE_HEADER_LENGTH + payload_length)
+}
+
+/// Wraps a message payload with the format version, message type and payload length
+pub(super) fn encode_frame(message_type: u8, payload: Vec<u8>) -> Vec<u8> {
+    if payload.len() > u32::MAX as usize {
+        panic!("Message payload is {} bytes, but it can't be more than {}", payload.len(), u32::MAX);
+    }
+
+    let mut bytes = Vec::with_capacity(MESSAGE_HEADER_LENGTH + payload.len());
+    bytes.push(MESSAGE_FORMAT_VERSION);
+    bytes.push(message_type);
+    bytes.write_u32::<BigEndian>(payload.len() as u32).unwrap();
+    bytes.extend_from_slice(&payload[..]);
+    bytes
+}
+
+/// Splits the first complete message out of the bytes, returning its type marker, its payload
+/// and any bytes that come after it.
+pub(super) fn decode_frame(bytes: &[u8]) -> Result<(u8, &[u8], &[u8]), MessageParseError> {
+    if bytes.len() < MESSAGE_HEADER_LENGTH {
+        let kind = MessageParseErrorKind::NotEnoughBytes;
+        return Err(MessageParseError {kind});
+    }
+
+    if bytes[0] != MESSAGE_FORMAT_VERSION {
+        let kind = MessageParseErrorKind::UnsupportedVersion(bytes[0]);
+        return Err(MessageParseError {kind});
+    }
+
+    let message_type = bytes[1];
+    let payload_length = (&bytes[2..MESSAGE_HEADER_LENGTH]).read_u32::<BigEndian>()? as usize;
+    let end_index = MESSAGE_HEADER_LENGTH + payload_length;
+    if bytes.len() < end_index {
+        let kind = MessageParseErrorKind::NotEnoughBytes;
+        return Err(MessageParseError {kind});
+    }
+
+    Ok((message_type, &bytes[MESSAGE_HEADER_LENGTH..end_index], &bytes[end_index..]))
+}
+
+/// Verifies that parsing a payload used every byte it declared
+pub(super) fn verify_payload_consumed(cursor: &Cursor<&[u8]>) -> Result<(), MessageParseError> {
+    let declared = cursor.get_ref().len();
+    let consumed = cursor.position() as usize;
+    if declared != consumed {
+        let kind = MessageParseErrorKind::PayloadLengthMismatch {declared, consumed};
+        return Err(MessageParseError {kind});
+    }
+
+    Ok(())
+}
+
+pub(super) fn write_request_id(bytes: &mut Vec<u8>, request: RequestId) {
+    bytes.write_u32::<BigEndian>(request.0).unwrap();
+}
+
+pub(super) fn read_request_id(cursor: &mut Cursor<&[u8]>) -> Result<RequestId, MessageParseError> {
+    Ok(RequestId(cursor.read_u32::<BigEndian>()?))
+}
+
+pub(super) fn write_channel_id(bytes: &mut Vec<u8>, channel: ChannelId) {
+    bytes.write_u32::<BigEndian>(channel.0).unwrap();
+}
+
+pub(super) fn read_channel_id(cursor: &mut Cursor<&[u8]>) -> Result<ChannelId, MessageParseError> {
+    Ok(ChannelId(cursor.read_u32::<BigEndian>()?))
+}
+
+pub(super) fn write_connection_id(bytes: &mut Vec<u8>, connection: ConnectionId) {
+    bytes.write_u32::<BigEndian>(connection.0).unwrap();
+}
+
+pub(super) fn read_connection_id(cursor: &mut Cursor<&[u8]>) -> Result<ConnectionId, MessageParseError> {
+    Ok(ConnectionId(cursor.read_u32::<BigEndian>()?))
+}
+
+pub(super) fn write_optional_connection_id(bytes: &mut Vec<u8>, connection: Option<ConnectionId>) {
+    match connection {
+        None => bytes.push(0),
+        Some(id) => {
+            bytes.push(1);
+            write_connection_id(bytes, id);
+        },
+    }
+}
+
+pub(super) fn read_optional_connection_id(cursor: &mut Cursor<&[u8]>)
+    -> Result<Option<ConnectionId>, MessageParseError> {
+    match cursor.read_u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(read_connection_id(cursor)?)),
+        x => {
+            let kind = MessageParseErrorKind::InvalidOptionalMarker(x);
+            Err(MessageParseError {kind})
+        },
+    }
+}
+
+pub(super) fn write_connection_type(bytes: &mut Vec<u8>, connection_type: &ConnectionType) {
+    match *connection_type {
+        ConnectionType::Tcp => bytes.push(0),
+        ConnectionType::Udp => bytes.push(1),
+    }
+}
+
+pub(super) fn read_connection_type(cursor: &mut Cursor<&[u8]>) -> Result<ConnectionType, MessageParseError> {
+    match cursor.read_u8()? {
+        0 => Ok(ConnectionType::Tcp),
+        1 => Ok(ConnectionType::Udp),
+        x => {
+            let kind = MessageParseErrorKind::InvalidConnectionType(x);
+            Err(MessageParseError {kind})
+        },
+    }
+}
+
+pub(super) fn write_port(bytes: &mut Vec<u8>, port: u16) {
+    bytes.write_u16::<BigEndian>(port).unwrap();
+}
+
+pub(super) fn read_port(cursor: &mut Cursor<&[u8]>) -> Result<u16, MessageParseError> {
+    Ok(cursor.read_u16::<BigEndian>()?)
+}
+
+pub(super) fn write_optional_port_range(bytes: &mut Vec<u8>, range: Option<&RangeInclusive<u16>>) {
+    match range {
+        None => bytes.push(0),
+        Some(range) => {
+            bytes.push(1);
+            write_port(bytes, *range.start());
+            write_port(bytes, *range.end());
+        },
+    }
+}
+
+pub(super) fn read_optional_port_range(cursor: &mut Cursor<&[u8]>)
+    -> Result<Option<RangeInclusive<u16>>, MessageParseError> {
+    match cursor.read_u8()? {
+        0 => Ok(None),
+        1 => {
+            let start = read_port(cursor)?;
+            let end = read_port(cursor)?;
+            if start > end {
+                let kind = MessageParseErrorKind::InvalidPortRange(start, end);
+                return Err(MessageParseError {kind});
+            }
+
+            Ok(Some(start..=end))
+        },
+
+        x => {
+            let kind = MessageParseErrorKind::InvalidOptionalMarker(x);
+            Err(MessageParseError {kind})
+        },
+    }
+}
+
+pub(super) fn write_socket_address(bytes: &mut Vec<u8>, address: SocketAddr) {
+    write_optional_socket_address(bytes, Some(address));
+}
+
+pub(super) fn read_socket_address(cursor: &mut Cursor<&[u8]>) -> Result<SocketAddr, MessageParseError> {
+    match read_optional_socket_address(cursor)? {
+        Some(address) => Ok(address),
+        None => {
+            let kind = MessageParseErrorKind::InvalidAddressFamily(0);
+            Err(MessageParseError {kind})
+        },
+    }
+}
+
+pub(super) fn write_optional_socket_address(bytes: &mut Vec<u8>, address: Option<SocketAddr>) {
+    write_optional_ip_address(bytes, address.map(|x| x.ip()));
+    if let Some(address) = address {
+        write_port(bytes, address.port());
+    }
+}
+
+pub(super) fn read_optional_socket_address(cursor: &mut Cursor<&[u8]>)
+    -> Result<Option<SocketAddr>, MessageParseError> {
+    let ip = match read_optional_ip_address(cursor)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let port = read_port(cursor)?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+pub(super) fn write_optional_ip_address(bytes: &mut Vec<u8>, address: Option<IpAddr>) {
+    match address {
+        None => bytes.push(0),
+        Some(IpAddr::V4(address)) => {
+            bytes.push(4);
+            bytes.extend_from_slice(&address.octets());
+        },
+
+        Some(IpAddr::V6(address)) => {
+            bytes.push(6);
+            bytes.extend_from_slice(&address.octets());
+        },
+    }
+}
+
+pub(super) fn read_optional_ip_address(cursor: &mut Cursor<&[u8]>)
+    -> Result<Option<IpAddr>, MessageParseError> {
+    match cursor.read_u8()? {
+        0 => Ok(None),
+        4 => {
+            let mut octets = [0; 4];
+            cursor.read_exact(&mut octets)?;
+            Ok(Some(IpAddr::V4(Ipv4Addr::from(octets))))
+        },
+
+        6 => {
+            let mut octets = [0; 16];
+            cursor.read_exact(&mut octets)?;
+            Ok(Some(IpAddr::V6(Ipv6Addr::from(octets))))
+        },
+
+        x => {
+            let kind = MessageParseErrorKind::InvalidAddressFamily(x);
+            Err(MessageParseError {kind})
+        },
+    }
+}
+
+/// Durations are sent as whole milliseconds, with anything too long for the wire capped at the
+/// longest duration that fits
+pub(super) fn write_duration(bytes: &mut Vec<u8>, duration: Duration) {
+    let milliseconds = min(duration.as_millis(), u128::from(u32::MAX)) as u32;
+    bytes.write_u32::<BigEndian>(milliseconds).unwrap();
+}
+
+pub(super) fn read_duration(cursor: &mut Cursor<&[u8]>) -> Result<Duration, MessageParseError> {
+    Ok(Duration::from_millis(u64::from(cursor.read_u32::<BigEndian>()?)))
+}
+
+pub(super) fn write_data(bytes: &mut Vec<u8>, data: &[u8]) {
+    if data.len() > u32::MAX as usize {
+        panic!("Data is {} bytes, but it can't be more than {}", data.len(), u32::MAX);
+    }
+
+    bytes.write_u32::<BigEndian>(data.len() as u32).unwrap();
+    bytes.extend_from_slice(data);
+}
+
+pub(super) fn read_data(cursor: &mut Cursor<&[u8]>) -> Result<Vec<u8>, MessageParseError> {
+    let length = cursor.read_u32::<BigEndian>()? as usize;
+    let remaining = cursor.get_ref().len() - cursor.position() as usize;
+    if length > remaining {
+        let kind = MessageParseErrorKind::PayloadLengthMismatch {
+            declared: cursor.get_ref().len(),
+            consumed: cursor.position() as usize + length,
+        };
+
+        return Err(MessageParseError {kind});
+    }
+
+    let mut data = vec![0; length];
+    cursor.read_exact(&mut data)?;
+    Ok(data)
+}
+
+impl fmt::Display for MessageParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.kind, f)
+    }
+}
+
+impl From<MessageParseErrorKind> for MessageParseError {
+    fn from(kind: MessageParseErrorKind) -> Self {
+        MessageParseError { kind }
+    }
+}
+
+impl From<io::Error> for MessageParseError {
+    fn from(error: io::Error) -> Self {
+        MessageParseError { kind: MessageParseErrorKind::Io(error) }
+    }
+}
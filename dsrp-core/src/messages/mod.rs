@@ -1,8 +1,13 @@
 mod client_message;
 mod server_message;
+#[allow(non_local_definitions)] // emitted by failure's derive macro
+mod encoding;
 
-pub use self::server_message::{ServerMessage, RegistrationFailureCause};
-pub use self::client_message::{ClientMessage};
+pub use self::server_message::{ServerMessage, RegistrationFailureCause, ChannelRevocationReason};
+pub use self::client_message::{ClientMessage, ConnectionFailureReason};
+pub use self::encoding::{MessageParseError, MessageParseErrorKind};
+pub use self::encoding::{MESSAGE_FORMAT_VERSION, MESSAGE_HEADER_LENGTH};
+pub(crate) use self::encoding::encoded_message_length;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
 pub struct RequestId(pub(crate) u32);
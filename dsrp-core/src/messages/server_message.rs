@@ -1,13 +1,54 @@
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::time::Duration;
+use byteorder::ReadBytesExt;
 use super::{RequestId, ChannelId, ConnectionId};
+use super::encoding::{MessageParseError, MessageParseErrorKind, encode_frame, decode_frame};
+use super::encoding::{verify_payload_consumed, write_request_id, read_request_id};
+use super::encoding::{write_channel_id, read_channel_id, write_connection_id, read_connection_id};
+use super::encoding::{write_optional_connection_id, read_optional_connection_id};
+use super::encoding::{write_optional_socket_address, read_optional_socket_address};
+use super::encoding::{write_socket_address, read_socket_address};
+use super::encoding::{write_port, read_port};
+use super::encoding::{write_data, read_data};
+use super::encoding::{write_duration, read_duration};
 
-#[derive(Debug)]
+const REGISTRATION_SUCCESSFUL_MARKER: u8 = 1;
+const REGISTRATION_FAILED_MARKER: u8 = 2;
+const NEW_INCOMING_TCP_CONNECTION_MARKER: u8 = 3;
+const TCP_CONNECTION_CLOSED_MARKER: u8 = 4;
+const DATA_RECEIVED_MARKER: u8 = 5;
+const NEW_INCOMING_UDP_CONNECTION_MARKER: u8 = 6;
+const UDP_CONNECTION_CLOSED_MARKER: u8 = 7;
+const CHANNEL_CLOSED_MARKER: u8 = 8;
+const CHANNEL_REVOKED_MARKER: u8 = 9;
+const TCP_CONNECTION_HALF_CLOSED_MARKER: u8 = 10;
+const PING_MARKER: u8 = 11;
+const PONG_MARKER: u8 = 12;
+const SERVER_SHUTTING_DOWN_MARKER: u8 = 13;
+
+const PORT_ALREADY_REGISTERED_MARKER: u8 = 1;
+const SOCKET_BINDING_FAILED_MARKER: u8 = 2;
+const NOT_AUTHORIZED_MARKER: u8 = 3;
+const PORT_NOT_ALLOWED_MARKER: u8 = 4;
+const CHANNEL_LIMIT_REACHED_MARKER: u8 = 5;
+const NO_PORT_AVAILABLE_MARKER: u8 = 6;
+const BIND_ADDRESS_NOT_ALLOWED_MARKER: u8 = 7;
+const SERVER_SHUTTING_DOWN_CAUSE_MARKER: u8 = 8;
+
+const ADMINISTRATIVE_REVOCATION_MARKER: u8 = 1;
+const SERVER_SHUTTING_DOWN_REVOCATION_MARKER: u8 = 2;
+const SOCKET_FAILED_REVOCATION_MARKER: u8 = 3;
+
+#[derive(Debug, PartialEq)]
 pub enum ServerMessage {
     /// Tells the client that their registration request was successful, and defines a
     /// channel id that will be used for communicating traffic information for the registered
-    /// tcp or udp port.
+    /// tcp or udp port.  The port is included since the server may have been asked to choose it.
     RegistrationSuccessful {
         request: RequestId,
         created_channel: ChannelId,
+        port: u16,
     },
 
     /// Informs the client that their registration request was not successful, and the
@@ -32,17 +73,632 @@ pub enum ServerMessage {
         connection: ConnectionId,
     },
 
-    /// Data was received by the DSRP server.  If the data came over a TCP connection we provide
-    /// the identifier for the connection id it was received on.
+    /// Informs the client that the originator of a TCP connection has finished sending data,
+    /// but may still be waiting to receive data over it
+    TcpConnectionHalfClosed {
+        channel: ChannelId,
+        connection: ConnectionId,
+    },
+
+    /// Informs the client that a remote peer the DSRP server has not seen recently sent a packet
+    /// to a udp channel.  It establishes a connection id that will be used to communicate
+    /// traffic for just this peer.
+    NewIncomingUdpConnection {
+        channel: ChannelId,
+        new_connection: ConnectionId,
+        peer_address: SocketAddr,
+    },
+
+    /// Informs the client that a udp connection was closed by the DSRP server after it went
+    /// idle for too long.
+    UdpConnectionClosed {
+        channel: ChannelId,
+        connection: ConnectionId,
+    },
+
+    /// Data was received by the DSRP server, along with the identifier of the connection it was
+    /// received on.  Data received over UDP also provides the address of the remote peer that
+    /// sent it.
     DataReceived {
         channel: ChannelId,
         connection: Option<ConnectionId>,
+        peer_address: Option<SocketAddr>,
         data: Vec<u8>,
     },
+
+    /// Acknowledges that a channel the client asked to unregister has been closed, along with
+    /// all of its connections.  The port is free to be registered again once this is received.
+    ChannelClosed {
+        channel: ChannelId,
+    },
+
+    /// Informs the client that the DSRP server closed one of its channels without being asked
+    /// to, along with all of its connections.  The client may try to register the port again.
+    ChannelRevoked {
+        channel: ChannelId,
+        reason: ChannelRevocationReason,
+    },
+
+    /// Checks that the DSRP client is still responsive, which it should answer with a `Pong`
+    Ping,
+
+    /// Answers a `Ping` sent by the DSRP client
+    Pong,
+
+    /// Warns the client that the DSRP server is shutting down.  No new registrations or
+    /// connections will be accepted, and once the deadline (measured from when this message was
+    /// sent) passes all of the client's channels are closed and it is disconnected.
+    ServerShuttingDown {
+        deadline: Duration,
+    },
 }
 
 #[derive(Debug, PartialEq)]
 pub enum RegistrationFailureCause {
     PortAlreadyRegistered,
     SocketBindingFailed,
-}
\ No newline at end of file
+
+    /// The client's identity does not permit it to register the requested port
+    NotAuthorized,
+
+    /// The server's port policy does not allow the requested port to be registered by anyone
+    PortNotAllowed,
+
+    /// The client already has as many channels open as the server allows a single client
+    ChannelLimitReached,
+
+    /// The server was asked to choose a port but none in the requested range are free
+    NoPortAvailable,
+
+    /// The requested bind address is not one the server allows channels to be bound on
+    BindAddressNotAllowed,
+
+    /// The server is shutting down and no longer accepts registrations
+    ServerShuttingDown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChannelRevocationReason {
+    /// The channel was closed by whoever is operating the DSRP server
+    Administrative,
+
+    /// The DSRP server's shutdown deadline passed.  The channel can be registered again once the
+    /// client reconnects to a running server.
+    ServerShuttingDown,
+
+    /// The socket bound for the channel stopped working and had to be closed
+    SocketFailed,
+}
+
+impl ServerMessage {
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let marker = match self {
+            ServerMessage::RegistrationSuccessful {request, created_channel, port} => {
+                write_request_id(&mut payload, request);
+                write_channel_id(&mut payload, created_channel);
+                write_port(&mut payload, port);
+                REGISTRATION_SUCCESSFUL_MARKER
+            },
+
+            ServerMessage::RegistrationFailed {request, cause} => {
+                write_request_id(&mut payload, request);
+                payload.push(cause.into_marker());
+                REGISTRATION_FAILED_MARKER
+            },
+
+            ServerMessage::NewIncomingTcpConnection {channel, new_connection} => {
+                write_channel_id(&mut payload, channel);
+                write_connection_id(&mut payload, new_connection);
+                NEW_INCOMING_TCP_CONNECTION_MARKER
+            },
+
+            ServerMessage::TcpConnectionClosed {channel, connection} => {
+                write_channel_id(&mut payload, channel);
+                write_connection_id(&mut payload, connection);
+                TCP_CONNECTION_CLOSED_MARKER
+            },
+
+            ServerMessage::TcpConnectionHalfClosed {channel, connection} => {
+                write_channel_id(&mut payload, channel);
+                write_connection_id(&mut payload, connection);
+                TCP_CONNECTION_HALF_CLOSED_MARKER
+            },
+
+            ServerMessage::NewIncomingUdpConnection {channel, new_connection, peer_address} => {
+                write_channel_id(&mut payload, channel);
+                write_connection_id(&mut payload, new_connection);
+                write_socket_address(&mut payload, peer_address);
+                NEW_INCOMING_UDP_CONNECTION_MARKER
+            },
+
+            ServerMessage::UdpConnectionClosed {channel, connection} => {
+                write_channel_id(&mut payload, channel);
+                write_connection_id(&mut payload, connection);
+                UDP_CONNECTION_CLOSED_MARKER
+            },
+
+            ServerMessage::DataReceived {channel, connection, peer_address, data} => {
+                write_channel_id(&mut payload, channel);
+                write_optional_connection_id(&mut payload, connection);
+                write_optional_socket_address(&mut payload, peer_address);
+                write_data(&mut payload, &data[..]);
+                DATA_RECEIVED_MARKER
+            },
+
+            ServerMessage::ChannelClosed {channel} => {
+                write_channel_id(&mut payload, channel);
+                CHANNEL_CLOSED_MARKER
+            },
+
+            ServerMessage::ChannelRevoked {channel, reason} => {
+                write_channel_id(&mut payload, channel);
+                payload.push(reason.into_marker());
+                CHANNEL_REVOKED_MARKER
+            },
+
+            ServerMessage::Ping => PING_MARKER,
+            ServerMessage::Pong => PONG_MARKER,
+
+            ServerMessage::ServerShuttingDown {deadline} => {
+                write_duration(&mut payload, deadline);
+                SERVER_SHUTTING_DOWN_MARKER
+            },
+        };
+
+        encode_frame(marker, payload)
+    }
+
+    /// Parses the first message contained in the bytes, returning it along with any bytes
+    /// that came after it.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), MessageParseError> {
+        let (marker, payload, remaining_bytes) = decode_frame(bytes)?;
+        let mut cursor = Cursor::new(payload);
+
+        let message = match marker {
+            REGISTRATION_SUCCESSFUL_MARKER => {
+                let request = read_request_id(&mut cursor)?;
+                let created_channel = read_channel_id(&mut cursor)?;
+                let port = read_port(&mut cursor)?;
+                ServerMessage::RegistrationSuccessful {request, created_channel, port}
+            },
+
+            REGISTRATION_FAILED_MARKER => {
+                let request = read_request_id(&mut cursor)?;
+                let cause = RegistrationFailureCause::from_marker(cursor.read_u8()?)?;
+                ServerMessage::RegistrationFailed {request, cause}
+            },
+
+            NEW_INCOMING_TCP_CONNECTION_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                let new_connection = read_connection_id(&mut cursor)?;
+                ServerMessage::NewIncomingTcpConnection {channel, new_connection}
+            },
+
+            TCP_CONNECTION_CLOSED_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                let connection = read_connection_id(&mut cursor)?;
+                ServerMessage::TcpConnectionClosed {channel, connection}
+            },
+
+            TCP_CONNECTION_HALF_CLOSED_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                let connection = read_connection_id(&mut cursor)?;
+                ServerMessage::TcpConnectionHalfClosed {channel, connection}
+            },
+
+            NEW_INCOMING_UDP_CONNECTION_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                let new_connection = read_connection_id(&mut cursor)?;
+                let peer_address = read_socket_address(&mut cursor)?;
+                ServerMessage::NewIncomingUdpConnection {channel, new_connection, peer_address}
+            },
+
+            UDP_CONNECTION_CLOSED_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                let connection = read_connection_id(&mut cursor)?;
+                ServerMessage::UdpConnectionClosed {channel, connection}
+            },
+
+            DATA_RECEIVED_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                let connection = read_optional_connection_id(&mut cursor)?;
+                let peer_address = read_optional_socket_address(&mut cursor)?;
+                let data = read_data(&mut cursor)?;
+                ServerMessage::DataReceived {channel, connection, peer_address, data}
+            },
+
+            CHANNEL_CLOSED_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                ServerMessage::ChannelClosed {channel}
+            },
+
+            CHANNEL_REVOKED_MARKER => {
+                let channel = read_channel_id(&mut cursor)?;
+                let reason = ChannelRevocationReason::from_marker(cursor.read_u8()?)?;
+                ServerMessage::ChannelRevoked {channel, reason}
+            },
+
+            PING_MARKER => ServerMessage::Ping,
+            PONG_MARKER => ServerMessage::Pong,
+
+            SERVER_SHUTTING_DOWN_MARKER => {
+                let deadline = read_duration(&mut cursor)?;
+                ServerMessage::ServerShuttingDown {deadline}
+            },
+
+            x => {
+                let kind = MessageParseErrorKind::InvalidMessageType(x);
+                return Err(MessageParseError {kind});
+            },
+        };
+
+        verify_payload_consumed(&cursor)?;
+        Ok((message, remaining_bytes))
+    }
+}
+
+impl RegistrationFailureCause {
+    fn into_marker(self) -> u8 {
+        match self {
+            RegistrationFailureCause::PortAlreadyRegistered => PORT_ALREADY_REGISTERED_MARKER,
+            RegistrationFailureCause::SocketBindingFailed => SOCKET_BINDING_FAILED_MARKER,
+            RegistrationFailureCause::NotAuthorized => NOT_AUTHORIZED_MARKER,
+            RegistrationFailureCause::PortNotAllowed => PORT_NOT_ALLOWED_MARKER,
+            RegistrationFailureCause::ChannelLimitReached => CHANNEL_LIMIT_REACHED_MARKER,
+            RegistrationFailureCause::NoPortAvailable => NO_PORT_AVAILABLE_MARKER,
+            RegistrationFailureCause::BindAddressNotAllowed => BIND_ADDRESS_NOT_ALLOWED_MARKER,
+            RegistrationFailureCause::ServerShuttingDown => SERVER_SHUTTING_DOWN_CAUSE_MARKER,
+        }
+    }
+
+    fn from_marker(marker: u8) -> Result<Self, MessageParseError> {
+        match marker {
+            PORT_ALREADY_REGISTERED_MARKER => Ok(RegistrationFailureCause::PortAlreadyRegistered),
+            SOCKET_BINDING_FAILED_MARKER => Ok(RegistrationFailureCause::SocketBindingFailed),
+            NOT_AUTHORIZED_MARKER => Ok(RegistrationFailureCause::NotAuthorized),
+            PORT_NOT_ALLOWED_MARKER => Ok(RegistrationFailureCause::PortNotAllowed),
+            CHANNEL_LIMIT_REACHED_MARKER => Ok(RegistrationFailureCause::ChannelLimitReached),
+            NO_PORT_AVAILABLE_MARKER => Ok(RegistrationFailureCause::NoPortAvailable),
+            BIND_ADDRESS_NOT_ALLOWED_MARKER => Ok(RegistrationFailureCause::BindAddressNotAllowed),
+            SERVER_SHUTTING_DOWN_CAUSE_MARKER => Ok(RegistrationFailureCause::ServerShuttingDown),
+            x => {
+                let kind = MessageParseErrorKind::InvalidRegistrationFailureCause(x);
+                Err(MessageParseError {kind})
+            },
+        }
+    }
+}
+
+impl ChannelRevocationReason {
+    fn into_marker(self) -> u8 {
+        match self {
+            ChannelRevocationReason::Administrative => ADMINISTRATIVE_REVOCATION_MARKER,
+            ChannelRevocationReason::ServerShuttingDown => SERVER_SHUTTING_DOWN_REVOCATION_MARKER,
+            ChannelRevocationReason::SocketFailed => SOCKET_FAILED_REVOCATION_MARKER,
+        }
+    }
+
+    fn from_marker(marker: u8) -> Result<Self, MessageParseError> {
+        match marker {
+            ADMINISTRATIVE_REVOCATION_MARKER => Ok(ChannelRevocationReason::Administrative),
+            SERVER_SHUTTING_DOWN_REVOCATION_MARKER => Ok(ChannelRevocationReason::ServerShuttingDown),
+            SOCKET_FAILED_REVOCATION_MARKER => Ok(ChannelRevocationReason::SocketFailed),
+            x => {
+                let kind = MessageParseErrorKind::InvalidChannelRevocationReason(x);
+                Err(MessageParseError {kind})
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::MESSAGE_HEADER_LENGTH;
+
+    fn assert_round_trip(message: ServerMessage, expected: ServerMessage) {
+        let bytes = message.into_bytes();
+        let (parsed, remaining_bytes) = ServerMessage::from_bytes(&bytes).unwrap();
+
+        assert_eq!(parsed, expected, "Unexpected message parsed");
+        assert_eq!(remaining_bytes.len(), 0, "Unexpected remaining bytes");
+    }
+
+    #[test]
+    fn can_round_trip_registration_successful_message() {
+        let message = || ServerMessage::RegistrationSuccessful {
+            request: RequestId(23),
+            created_channel: ChannelId(24),
+            port: 8080,
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_registration_failed_message_for_each_cause() {
+        let message = || ServerMessage::RegistrationFailed {
+            request: RequestId(23),
+            cause: RegistrationFailureCause::PortAlreadyRegistered,
+        };
+
+        assert_round_trip(message(), message());
+
+        let message = || ServerMessage::RegistrationFailed {
+            request: RequestId(23),
+            cause: RegistrationFailureCause::SocketBindingFailed,
+        };
+
+        assert_round_trip(message(), message());
+
+        let message = || ServerMessage::RegistrationFailed {
+            request: RequestId(23),
+            cause: RegistrationFailureCause::NotAuthorized,
+        };
+
+        assert_round_trip(message(), message());
+
+        let message = || ServerMessage::RegistrationFailed {
+            request: RequestId(23),
+            cause: RegistrationFailureCause::PortNotAllowed,
+        };
+
+        assert_round_trip(message(), message());
+
+        let message = || ServerMessage::RegistrationFailed {
+            request: RequestId(23),
+            cause: RegistrationFailureCause::ChannelLimitReached,
+        };
+
+        assert_round_trip(message(), message());
+
+        let message = || ServerMessage::RegistrationFailed {
+            request: RequestId(23),
+            cause: RegistrationFailureCause::NoPortAvailable,
+        };
+
+        assert_round_trip(message(), message());
+
+        let message = || ServerMessage::RegistrationFailed {
+            request: RequestId(23),
+            cause: RegistrationFailureCause::BindAddressNotAllowed,
+        };
+
+        assert_round_trip(message(), message());
+
+        let message = || ServerMessage::RegistrationFailed {
+            request: RequestId(23),
+            cause: RegistrationFailureCause::ServerShuttingDown,
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_new_incoming_tcp_connection_message() {
+        let message = || ServerMessage::NewIncomingTcpConnection {
+            channel: ChannelId(5),
+            new_connection: ConnectionId(6),
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_tcp_connection_closed_message() {
+        let message = || ServerMessage::TcpConnectionClosed {
+            channel: ChannelId(5),
+            connection: ConnectionId(6),
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_new_incoming_udp_connection_message() {
+        let message = || ServerMessage::NewIncomingUdpConnection {
+            channel: ChannelId(5),
+            new_connection: ConnectionId(6),
+            peer_address: "[2001:db8::1]:5353".parse().unwrap(),
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_udp_connection_closed_message() {
+        let message = || ServerMessage::UdpConnectionClosed {
+            channel: ChannelId(5),
+            connection: ConnectionId(6),
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_ping_message() {
+        assert_round_trip(ServerMessage::Ping, ServerMessage::Ping);
+    }
+
+    #[test]
+    fn can_round_trip_pong_message() {
+        assert_round_trip(ServerMessage::Pong, ServerMessage::Pong);
+    }
+
+    #[test]
+    fn can_round_trip_server_shutting_down_message() {
+        let message = || ServerMessage::ServerShuttingDown {deadline: Duration::from_millis(30_500)};
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_tcp_connection_half_closed_message() {
+        let message = || ServerMessage::TcpConnectionHalfClosed {
+            channel: ChannelId(5),
+            connection: ConnectionId(6),
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_channel_closed_message() {
+        let message = || ServerMessage::ChannelClosed {
+            channel: ChannelId(5),
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_channel_revoked_message() {
+        let message = || ServerMessage::ChannelRevoked {
+            channel: ChannelId(5),
+            reason: ChannelRevocationReason::Administrative,
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_channel_revoked_message_for_each_reason() {
+        let reasons = vec![ChannelRevocationReason::ServerShuttingDown, ChannelRevocationReason::SocketFailed];
+        for reason in reasons {
+            let message = || ServerMessage::ChannelRevoked {channel: ChannelId(5), reason: reason.clone()};
+            assert_round_trip(message(), message());
+        }
+    }
+
+    #[test]
+    fn can_round_trip_data_received_message_with_connection() {
+        let message = || ServerMessage::DataReceived {
+            channel: ChannelId(5),
+            connection: Some(ConnectionId(6)),
+            peer_address: None,
+            data: vec![1, 2, 3, 4],
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn can_round_trip_data_received_message_without_connection() {
+        let message = || ServerMessage::DataReceived {
+            channel: ChannelId(5),
+            connection: None,
+            peer_address: Some("192.168.0.1:27015".parse().unwrap()),
+            data: Vec::new(),
+        };
+
+        assert_round_trip(message(), message());
+    }
+
+    #[test]
+    fn error_returned_for_invalid_peer_address_family() {
+        let message = ServerMessage::DataReceived {
+            channel: ChannelId(5),
+            connection: None,
+            peer_address: Some("192.168.0.1:27015".parse().unwrap()),
+            data: vec![1],
+        };
+
+        let mut bytes = message.into_bytes();
+        bytes[MESSAGE_HEADER_LENGTH + 5] = 5;
+
+        let error = ServerMessage::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            MessageParseErrorKind::InvalidAddressFamily(5) => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn parse_process_returns_extra_bytes() {
+        let message = || ServerMessage::TcpConnectionClosed {
+            channel: ChannelId(5),
+            connection: ConnectionId(6),
+        };
+
+        let mut bytes = message().into_bytes();
+        bytes.extend_from_slice(&[1, 2, 3]);
+
+        let (parsed, extra_bytes) = ServerMessage::from_bytes(&bytes).unwrap();
+
+        assert_eq!(parsed, message(), "Unexpected message");
+        assert_eq!(extra_bytes, &[1, 2, 3], "Unexpected extra bytes");
+    }
+
+    #[test]
+    fn error_returned_for_invalid_registration_failure_cause() {
+        let message = ServerMessage::RegistrationFailed {
+            request: RequestId(23),
+            cause: RegistrationFailureCause::PortAlreadyRegistered,
+        };
+
+        let mut bytes = message.into_bytes();
+        bytes[MESSAGE_HEADER_LENGTH + 4] = 99;
+
+        let error = ServerMessage::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            MessageParseErrorKind::InvalidRegistrationFailureCause(99) => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn error_returned_for_invalid_channel_revocation_reason() {
+        let message = ServerMessage::ChannelRevoked {
+            channel: ChannelId(5),
+            reason: ChannelRevocationReason::Administrative,
+        };
+
+        let mut bytes = message.into_bytes();
+        bytes[MESSAGE_HEADER_LENGTH + 4] = 99;
+
+        let error = ServerMessage::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            MessageParseErrorKind::InvalidChannelRevocationReason(99) => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn error_returned_for_invalid_optional_connection_marker() {
+        let message = ServerMessage::DataReceived {
+            channel: ChannelId(5),
+            connection: None,
+            peer_address: None,
+            data: vec![1],
+        };
+
+        let mut bytes = message.into_bytes();
+        bytes[MESSAGE_HEADER_LENGTH + 4] = 2;
+
+        let error = ServerMessage::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            MessageParseErrorKind::InvalidOptionalMarker(2) => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+
+    #[test]
+    fn error_returned_when_data_length_exceeds_payload() {
+        let message = ServerMessage::DataReceived {
+            channel: ChannelId(5),
+            connection: None,
+            peer_address: None,
+            data: vec![1, 2],
+        };
+
+        let mut bytes = message.into_bytes();
+        let data_length_index = MESSAGE_HEADER_LENGTH + 6;
+        bytes[data_length_index + 3] = 10;
+
+        let error = ServerMessage::from_bytes(&bytes).unwrap_err();
+        match error.kind {
+            MessageParseErrorKind::PayloadLengthMismatch {..} => (),
+            x => panic!("Unexpected error: {}", x),
+        }
+    }
+}
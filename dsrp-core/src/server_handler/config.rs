@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+/// Settings that control how the server handler manages its clients, channels and connections
+#[derive(Debug, Clone)]
+pub struct ServerHandlerConfig {
+    /// How long a udp pseudo-connection can go without any traffic in either direction before
+    /// it's expired
+    pub udp_connection_idle_timeout: Duration,
+
+    /// Shared secrets that clients must prove they know one of during the handshake.  When
+    /// empty, clients are not required to authenticate at all.
+    pub auth_secrets: Vec<Vec<u8>>,
+
+    /// Ports that clients with each identity are allowed to register.  Once any identities
+    /// are listed, clients whose identity isn't listed (including clients without an identity)
+    /// can't register any ports.
+    pub identity_port_permissions: HashMap<String, Vec<RangeInclusive<u16>>>,
+
+    /// Which ports clients may register tcp channels on
+    pub tcp_port_policy: PortPolicy,
+
+    /// Which ports clients may register udp channels on
+    pub udp_port_policy: PortPolicy,
+
+    /// Address channels are bound on when a client doesn't ask for a specific one
+    pub default_bind_address: IpAddr,
+
+    /// Other addresses clients may ask for their channels to be bound on
+    pub bind_addresses: Vec<IpAddr>,
+
+    /// Pool of ports the server picks from when a client asks it to choose a port
+    pub ephemeral_port_range: RangeInclusive<u16>,
+
+    /// Most channels a single client can have registered at once, or unlimited when `None`
+    pub max_channels_per_client: Option<usize>,
+
+    /// Most connections a single channel can have open at once, or unlimited when `None`.  Each
+    /// remote peer sending to a udp channel counts as one of its connections.
+    pub max_connections_per_channel: Option<usize>,
+
+    /// Most connections a single client can have open across all of its channels, counting tcp
+    /// connections and udp peers alike, or unlimited when `None`
+    pub max_connections_per_client: Option<usize>,
+
+    /// How often each client is sent a ping to make sure it's still responsive
+    pub heartbeat_interval: Duration,
+
+    /// How many pings in a row a client can leave unanswered before it's considered dead and
+    /// removed, along with all of its channels
+    pub max_missed_heartbeats: u32,
+
+    /// How long a disconnected client's channels stay bound, waiting for it to reconnect and
+    /// resume its session.  Clients are removed as soon as they disconnect when this is zero.
+    /// A client can also take over a session that still looks connected, but only when it
+    /// answered the challenge or has a certificate identity, so a ticket alone isn't enough.
+    pub session_grace_period: Duration,
+}
+
+/// Server wide rules for which ports can be registered, regardless of which client asks
+#[derive(Debug, Clone, Default)]
+pub struct PortPolicy {
+    /// Ranges of ports that may be registered.  When empty, every port not explicitly denied
+    /// may be registered.
+    pub allowed_ranges: Vec<RangeInclusive<u16>>,
+
+    /// Ranges of ports that may never be registered, even when they fall inside an allowed range
+    pub denied_ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl PortPolicy {
+    pub fn is_allowed(&self, port: u16) -> bool {
+        if self.denied_ranges.iter().any(|range| range.contains(&port)) {
+            return false;
+        }
+
+        self.allowed_ranges.is_empty() || self.allowed_ranges.iter().any(|range| range.contains(&port))
+    }
+}
+
+impl Default for ServerHandlerConfig {
+    fn default() -> Self {
+        ServerHandlerConfig {
+            udp_connection_idle_timeout: Duration::from_secs(60),
+            auth_secrets: Vec::new(),
+            identity_port_permissions: HashMap::new(),
+            tcp_port_policy: PortPolicy::default(),
+            udp_port_policy: PortPolicy::default(),
+            default_bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            bind_addresses: Vec::new(),
+            ephemeral_port_range: 49152..=65535,
+            max_channels_per_client: None,
+            max_connections_per_channel: None,
+            max_connections_per_client: None,
+            heartbeat_interval: Duration::from_secs(30),
+            max_missed_heartbeats: 3,
+            session_grace_period: Duration::from_secs(15),
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use std::fmt;
-use std::collections::HashSet;
-use handshake::HandshakeResponse;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use std::collections::{HashMap, HashSet};
+use handshake::{HandshakeResponse, SessionTicket, CHALLENGE_NONCE_LENGTH};
 use messages::{ChannelId, ConnectionId, ServerMessage, ConnectionType, RequestId};
+use stats::{ConnectionCounts, TrafficStats};
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Hash)]
 pub struct ClientId(pub(crate) u32);
@@ -10,52 +13,112 @@ pub struct ClientId(pub(crate) u32);
 pub struct NewClient {
     pub id: ClientId,
     pub response: HandshakeResponse,
+
+    /// Operations needed to set the client up, such as resetting the connections of a session
+    /// it took over from a stale control connection
+    pub operations: Vec<ServerOperation>,
 }
 
 pub struct ActiveClient {
     pub channels: HashSet<ChannelId>,
+
+    /// Nonce the client still has to answer before it's allowed to send messages
+    pub pending_challenge: Option<[u8; CHALLENGE_NONCE_LENGTH]>,
+
+    /// Ticket for the session the client asked to resume, which it's only attached to once it
+    /// answers its challenge
+    pub resuming: Option<SessionTicket>,
+
+    /// Who the client was verified to be by the transport, such as through a tls client
+    /// certificate
+    pub identity: Option<String>,
+
+    pub last_ping_sent: Instant,
+    pub awaiting_pong: bool,
+    pub missed_pongs: u32,
+
+    /// Ticket the client can present to resume its session after being disconnected
+    pub session_ticket: SessionTicket,
+
+    /// When the client's connection was lost, if its session is waiting to be resumed
+    pub disconnected_at: Option<Instant>,
+
+    /// Messages raised while the client was disconnected, which are sent once it resumes its
+    /// session
+    pub queued_messages: Vec<ServerMessage>,
+
+    pub connected_at: Instant,
+    pub traffic: TrafficStats,
+    pub connection_counts: ConnectionCounts,
 }
 
 pub struct ActiveChannel {
+    pub bind_address: IpAddr,
     pub port: u16,
     pub connection_type: ConnectionType,
     pub owner: ClientId,
     pub tcp_connections: HashSet<ConnectionId>,
+    pub udp_connections: HashMap<SocketAddr, ConnectionId>,
     pub socket_has_been_bound: bool,
     pub registration_request: RequestId,
+    pub opened_at: Instant,
+    pub traffic: TrafficStats,
+    pub connection_counts: ConnectionCounts,
 }
 
 pub struct ActiveTcpConnection {
     pub owning_channel: ChannelId,
     pub owning_client: ClientId,
+
+    /// The remote peer has finished sending data over the connection
+    pub peer_write_closed: bool,
+
+    /// The application server behind the client has finished sending data over the connection
+    pub client_write_closed: bool,
+
+    pub opened_at: Instant,
+    pub traffic: TrafficStats,
+}
+
+pub struct ActiveUdpConnection {
+    pub owning_channel: ChannelId,
+    pub owning_client: ClientId,
+    pub peer_address: SocketAddr,
+    pub last_activity: Instant,
+    pub opened_at: Instant,
+    pub traffic: TrafficStats,
 }
 
 /// Represents the different type of operations that the server handler instructs the
 /// server to perform
 #[derive(Debug)]
 pub enum ServerOperation {
-    /// Instructs the server to listen for TCP connections on the specified port, and what
-    /// channel the connections should have events raised on
+    /// Instructs the server to listen for TCP connections on the specified address and port, and
+    /// what channel the connections should have events raised on
     StartTcpOperations {
+        address: IpAddr,
         port: u16,
         channel: ChannelId,
     },
 
-    /// Instructs the server to disconnect all tcp connections on the specified port and remove
-    /// any associated tcp listeners
+    /// Instructs the server to disconnect all tcp connections on the specified address and port
+    /// and remove any associated tcp listeners
     StopTcpOperations {
+        address: IpAddr,
         port: u16,
     },
 
-    /// Instructs the server to listen for UDP packets on the specified port, and what channel
-    /// it should relay them to the server handler under
+    /// Instructs the server to listen for UDP packets on the specified address and port, and what
+    /// channel it should relay them to the server handler under
     StartUdpOperations {
+        address: IpAddr,
         port: u16,
         channel: ChannelId,
     },
 
-    /// Instructs the server to stop listening for UDP packets on the specified port
+    /// Instructs the server to stop listening for UDP packets on the specified address and port
     StopUdpOperations {
+        address: IpAddr,
         port: u16,
     },
 
@@ -65,6 +128,26 @@ pub enum ServerOperation {
         connection: ConnectionId,
     },
 
+    /// Instructs the server to finish sending data over the specified TCP connection, while
+    /// still reading anything the remote peer sends over it
+    ShutdownConnectionWrite {
+        connection: ConnectionId,
+    },
+
+    /// Instructs the server to abort the specified TCP connection, so the remote peer sees the
+    /// connection reset rather than closed normally.  This is caused by the dsrp client failing
+    /// to connect to the application server on its side.
+    ResetConnection {
+        connection: ConnectionId,
+    },
+
+    /// Instructs the server to close its connection to the specified client, which was found to
+    /// be unresponsive or is being disconnected as part of a shutdown.  The client's channels
+    /// have already been cleaned up.
+    DisconnectDsrpClient {
+        client: ClientId,
+    },
+
     /// Instructs a server to send a DSRP server message to the specified client
     SendMessageToDsrpClient {
         client: ClientId,
@@ -72,10 +155,11 @@ pub enum ServerOperation {
     },
 
     /// Instructs the server to send a specific payload across a channel (and connection if
-    /// the channel is for a TCP channel).
+    /// the channel is for a TCP channel).  UDP payloads specify the remote peer to send to.
     SendByteData {
         channel: ChannelId,
         connection: Option<ConnectionId>,
+        peer_address: Option<SocketAddr>,
         data: Vec<u8>,
     },
 }
@@ -13,6 +13,9 @@ pub enum ClientMessageHandlingErrorKind {
     #[fail(display = "Unknown client id: {:?}", _0)]
     UnknownClientId(ClientId),
 
+    #[fail(display = "Client {} has not answered its authentication challenge", _0)]
+    ClientNotAuthenticated(ClientId),
+
     #[fail(display = "{:?} does not exist", _0)]
     ChannelNotFound(ChannelId),
 
@@ -39,6 +42,18 @@ pub enum NewConnectionErrorKind {
 
     #[fail(display = "Connection created for channel {:?} that's marked as unbound", _0)]
     ConnectionAddedToUnboundChannel(ChannelId),
+
+    #[fail(display = "Channel {:?} already has the maximum number of connections", _0)]
+    ChannelConnectionLimitReached(ChannelId),
+
+    #[fail(display = "Client {} already has the maximum number of connections", _0)]
+    ClientConnectionLimitReached(ClientId),
+
+    #[fail(display = "Client {} is disconnected and its session is waiting to be resumed", _0)]
+    ClientDisconnected(ClientId),
+
+    #[fail(display = "The server is shutting down")]
+    ServerShuttingDown,
 }
 
 impl fmt::Display for ClientMessageHandlingError {
@@ -1,42 +1,93 @@
+#[allow(non_local_definitions)] // emitted by failure's derive macro
 mod errors;
 mod data_structures;
+mod config;
+mod stats;
 
+use std::cmp::{max, min};
 use std::collections::{HashSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::mem;
 use std::num::Wrapping;
-use ::handshake::{HandshakeRequest, HandshakeResponse, CURRENT_VERSION};
-use ::messages::{ClientMessage, ServerMessage, ChannelId, RegistrationFailureCause};
-use ::messages::{ConnectionType, ConnectionId};
-use self::data_structures::{ActiveChannel, ActiveClient};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+use rand::{Rng, thread_rng};
+use ::clock::{Clock, SystemClock};
+use ::handshake::{HandshakeRequest, HandshakeResponse, HandshakeChallengeAnswer, CURRENT_VERSION};
+use ::handshake::{generate_nonce, generate_session_ticket, is_same_session_ticket, is_valid_challenge_answer};
+use ::handshake::SessionTicket;
+use ::messages::{ClientMessage, ServerMessage, ChannelId, RegistrationFailureCause, ChannelRevocationReason};
+use ::messages::{ConnectionType, ConnectionId, RequestId};
+use ::stats::{ConnectionCounts, TrafficStats};
+use self::data_structures::{ActiveChannel, ActiveClient, ActiveUdpConnection};
 
 pub use self::errors::{ClientMessageHandlingError, ClientMessageHandlingErrorKind};
 pub use self::errors::{NewConnectionError, NewConnectionErrorKind};
 pub use self::data_structures::{NewClient, ClientId, ServerOperation, ActiveTcpConnection};
+pub use self::config::{ServerHandlerConfig, PortPolicy};
+pub use self::stats::{ServerStats, DsrpClientStats, ChannelStats, ConnectionStats};
 
 /// Contains the logic for handling the logic of a DSRP server
 pub struct ServerHandler {
+    config: ServerHandlerConfig,
+    clock: Box<dyn Clock>,
     active_clients: HashMap<ClientId, ActiveClient>,
-    active_ports: HashMap<u16, ChannelId>,
+    active_ports: HashMap<u16, HashSet<IpAddr>>,
     active_channels: HashMap<ChannelId, ActiveChannel>,
     active_tcp_connections: HashMap<ConnectionId, ActiveTcpConnection>,
+    active_udp_connections: HashMap<ConnectionId, ActiveUdpConnection>,
     next_client_id: Wrapping<u32>,
     next_channel_id: Wrapping<u32>,
     next_connection_id: Wrapping<u32>,
+    shutdown_deadline: Option<Instant>,
+    traffic: TrafficStats,
+    connection_counts: ConnectionCounts,
+}
+
+impl Default for ServerHandler {
+    fn default() -> Self {
+        ServerHandler::new()
+    }
 }
 
 impl ServerHandler {
     pub fn new() -> Self {
+        ServerHandler::with_config(ServerHandlerConfig::default(), Box::new(SystemClock))
+    }
+
+    pub fn with_config(config: ServerHandlerConfig, clock: Box<dyn Clock>) -> Self {
         ServerHandler {
+            config,
+            clock,
             active_clients: HashMap::new(),
             active_ports: HashMap::new(),
             active_channels: HashMap::new(),
             active_tcp_connections: HashMap::new(),
+            active_udp_connections: HashMap::new(),
             next_client_id: Wrapping(0),
             next_channel_id: Wrapping(0),
             next_connection_id: Wrapping(0),
+            shutdown_deadline: None,
+            traffic: TrafficStats::default(),
+            connection_counts: ConnectionCounts::default(),
         }
     }
 
     pub fn add_dsrp_client(&mut self, request: HandshakeRequest) -> Result<NewClient, HandshakeResponse> {
+        self.add_client(request, None)
+    }
+
+    /// Adds a client whose identity has already been verified outside of DSRP, such as by a tls
+    /// client certificate.  The identity determines which ports the client may register.
+    pub fn add_dsrp_client_with_identity(&mut self, request: HandshakeRequest, identity: String)
+        -> Result<NewClient, HandshakeResponse> {
+
+        self.add_client(request, Some(identity))
+    }
+
+    fn add_client(&mut self, request: HandshakeRequest, identity: Option<String>)
+        -> Result<NewClient, HandshakeResponse> {
+
         // For now only accept clients running the same version as the server
         if request.client_protocol_version != CURRENT_VERSION {
             let message = format!("Protocol version {} requested but only protocol version {} is supported",
@@ -45,27 +96,171 @@ impl ServerHandler {
             return Err(HandshakeResponse::Failure {reason: message});
         }
 
+        if self.shutdown_deadline.is_some() {
+            return Err(HandshakeResponse::ServerShuttingDown);
+        }
+
+        // Tickets are sent in the clear unless tls is used, so when secrets are configured the
+        // client still has to answer the challenge before it's attached to the session
+        let mut resuming = None;
+        if let Some(ticket) = request.session_ticket {
+            if self.config.auth_secrets.is_empty() {
+                if let Some(new_client) = self.resume_session(&ticket, &identity) {
+                    return Ok(new_client);
+                }
+            } else if self.find_resumable_session(&ticket, &identity).is_some() {
+                resuming = Some(ticket);
+            }
+        }
+
+        // When secrets are configured the client isn't allowed to do anything until it answers
+        // the challenge, so it can prove it knows a secret without sending it over the wire
+        let pending_challenge = if self.config.auth_secrets.is_empty() {
+            None
+        } else {
+            Some(generate_nonce())
+        };
+
+        let session_ticket = generate_session_ticket();
         let mut client_id;
         loop {
-            self.next_client_id = self.next_client_id + Wrapping(1);
+            self.next_client_id += Wrapping(1);
             client_id = ClientId(self.next_client_id.0);
             if self.active_clients.contains_key(&client_id) {
                 continue;
             }
 
-            let client = ActiveClient { channels: HashSet::new() };
+            let client = ActiveClient {
+                channels: HashSet::new(),
+                pending_challenge,
+                resuming,
+                identity: identity.clone(),
+                last_ping_sent: self.clock.now(),
+                awaiting_pong: false,
+                missed_pongs: 0,
+                session_ticket,
+                disconnected_at: None,
+                queued_messages: Vec::new(),
+                connected_at: self.clock.now(),
+                traffic: TrafficStats::default(),
+                connection_counts: ConnectionCounts::default(),
+            };
+
             self.active_clients.insert(client_id, client);
             break;
         }
 
+        let response = match pending_challenge {
+            Some(nonce) => HandshakeResponse::Challenge {nonce},
+            None => HandshakeResponse::Success {session_ticket, resumed: false},
+        };
+
         let new_client = NewClient {
             id: client_id,
-            response: HandshakeResponse::Success,
+            response,
+            operations: Vec::new(),
         };
 
         Ok(new_client)
     }
 
+    /// Verifies a client's answer to the challenge it was sent during its handshake.  If the
+    /// answer is wrong the client is removed, and the returned failure should be sent to it
+    /// before disconnecting.  A client that asked to resume a session is attached to it once
+    /// the answer checks out, in which case the accepted client has the session's id instead.
+    pub fn handle_challenge_answer(&mut self, client_id: ClientId, answer: HandshakeChallengeAnswer)
+        -> Result<NewClient, HandshakeResponse> {
+
+        let nonce = match self.active_clients.get(&client_id).and_then(|x| x.pending_challenge) {
+            Some(x) => x,
+            None => {
+                let reason = "No authentication challenge is outstanding".to_owned();
+                return Err(HandshakeResponse::Failure {reason});
+            },
+        };
+
+        let is_valid = self.config.auth_secrets.iter()
+            .any(|secret| is_valid_challenge_answer(secret, &nonce, &answer.hmac));
+
+        if !is_valid {
+            self.active_clients.remove(&client_id);
+            let reason = "Invalid authentication challenge answer".to_owned();
+            return Err(HandshakeResponse::Failure {reason});
+        }
+
+        if self.shutdown_deadline.is_some() {
+            self.active_clients.remove(&client_id);
+            return Err(HandshakeResponse::ServerShuttingDown);
+        }
+
+        let client = self.active_clients.get_mut(&client_id)
+            .expect("Client with a pending challenge is active");
+
+        client.pending_challenge = None;
+        if let Some(ticket) = client.resuming.take() {
+            // The session may have expired while the client was answering, in which case it
+            // just carries on with a new one
+            let identity = client.identity.clone();
+            if let Some(resumed_client) = self.resume_session(&ticket, &identity) {
+                self.active_clients.remove(&client_id);
+                return Ok(resumed_client);
+            }
+        }
+
+        let client = &self.active_clients[&client_id];
+        Ok(NewClient {
+            id: client_id,
+            response: HandshakeResponse::Success {session_ticket: client.session_ticket, resumed: false},
+            operations: Vec::new(),
+        })
+    }
+
+    /// Handles the connection to a client being lost.  Clients that were accepted keep their
+    /// channels bound for the configured grace period so they can resume their session, but
+    /// their connections are reset since nothing can be relayed for them in the meantime.
+    pub fn dsrp_client_disconnected(&mut self, client_id: ClientId) -> Vec<ServerOperation> {
+        let now = self.clock.now();
+        let keep_session = self.config.session_grace_period > Duration::from_secs(0)
+            && self.shutdown_deadline.is_none();
+        let channels = match self.active_clients.get_mut(&client_id) {
+            // Already handled, such as when the client stopped answering heartbeats
+            Some(client) if client.disconnected_at.is_some() => return Vec::new(),
+
+            Some(client) if keep_session && client.pending_challenge.is_none() => {
+                client.disconnected_at = Some(now);
+                client.channels.clone()
+            },
+
+            Some(_) => return self.remove_dsrp_client(client_id),
+            None => return Vec::new(),
+        };
+
+        self.reset_client_connections(channels)
+    }
+
+    /// Drops every connection on the given channels, since the client they belong to can no
+    /// longer relay data for them
+    fn reset_client_connections(&mut self, channels: HashSet<ChannelId>) -> Vec<ServerOperation> {
+        let mut operations = Vec::new();
+        for channel_id in channels {
+            let channel = match self.active_channels.get_mut(&channel_id) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            for connection_id in channel.tcp_connections.drain() {
+                self.active_tcp_connections.remove(&connection_id);
+                operations.push(ServerOperation::ResetConnection {connection: connection_id});
+            }
+
+            for (_, connection_id) in channel.udp_connections.drain() {
+                self.active_udp_connections.remove(&connection_id);
+            }
+        }
+
+        operations
+    }
+
     pub fn remove_dsrp_client(&mut self, client_id: ClientId) -> Vec<ServerOperation> {
         let mut results = Vec::new();
         let client = match self.active_clients.remove(&client_id) {
@@ -83,111 +278,176 @@ impl ServerHandler {
         results
     }
 
+    /// Starts shutting the server down by warning every client of the deadline and refusing any
+    /// new clients, registrations and connections.  Existing connections can keep relaying data
+    /// until the deadline passes or they have all closed, at which point the next tick closes
+    /// everything.
+    pub fn begin_shutdown(&mut self, deadline: Instant) -> Vec<ServerOperation> {
+        let now = self.clock.now();
+        self.shutdown_deadline = Some(deadline);
+
+        // Disconnected clients can no longer resume their session, so there's no reason to keep
+        // their channels around
+        let disconnected_clients = self.active_clients.iter()
+            .filter(|(_, client)| client.disconnected_at.is_some())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        let mut operations = Vec::new();
+        for client_id in disconnected_clients {
+            operations.append(&mut self.remove_dsrp_client(client_id));
+        }
+
+        let remaining = if deadline > now { deadline.duration_since(now) } else { Duration::from_secs(0) };
+        for (client_id, client) in &self.active_clients {
+            if client.pending_challenge.is_none() {
+                operations.push(ServerOperation::SendMessageToDsrpClient {
+                    client: *client_id,
+                    message: ServerMessage::ServerShuttingDown {deadline: remaining},
+                });
+            }
+        }
+
+        operations
+    }
+
+    /// Whether a shutdown was started and everything has since been closed
+    pub fn has_shut_down(&self) -> bool {
+        self.shutdown_deadline.is_some() && self.active_clients.is_empty()
+    }
+
+    /// Takes a snapshot of the traffic relayed for each client, channel and connection that's
+    /// currently active, along with the totals relayed since the handler was created
+    pub fn stats(&self) -> ServerStats {
+        let clients = self.active_clients.iter()
+            .map(|(client_id, client)| {
+                let channels = client.channels.iter()
+                    .filter_map(|channel_id| self.channel_stats(*channel_id))
+                    .collect::<Vec<_>>();
+
+                DsrpClientStats {
+                    id: *client_id,
+                    identity: client.identity.clone(),
+                    connected_at: client.connected_at,
+                    traffic: client.traffic,
+                    connection_counts: client.connection_counts,
+                    open_connections: channels.iter().map(|channel| channel.connections.len()).sum(),
+                    channels,
+                }
+            })
+            .collect();
+
+        ServerStats {
+            traffic: self.traffic,
+            connection_counts: self.connection_counts,
+            open_connections: self.active_tcp_connections.len() + self.active_udp_connections.len(),
+            clients,
+        }
+    }
+
+    /// Closes a channel the owning client did not ask to close, along with all of its
+    /// connections, and lets the client know why it was closed
+    pub fn revoke_channel(&mut self, channel_id: ChannelId, reason: ChannelRevocationReason) -> Vec<ServerOperation> {
+        let (channel, mut operations) = match self.remove_channel(channel_id) {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+
+        let message = ServerMessage::ChannelRevoked {channel: channel_id, reason};
+        operations.extend(self.send_to_client(channel.owner, message));
+        operations
+    }
+
     pub fn handle_client_message(&mut self, client_id: ClientId, message: ClientMessage)
         -> Result<Vec<ServerOperation>, ClientMessageHandlingError> {
 
-        if !self.active_clients.contains_key(&client_id) {
-            let kind = ClientMessageHandlingErrorKind::UnknownClientId(client_id);
-            return Err(ClientMessageHandlingError {kind});
+        match self.active_clients.get(&client_id) {
+            None => {
+                let kind = ClientMessageHandlingErrorKind::UnknownClientId(client_id);
+                return Err(ClientMessageHandlingError {kind});
+            },
+
+            Some(client) if client.pending_challenge.is_some() => {
+                let kind = ClientMessageHandlingErrorKind::ClientNotAuthenticated(client_id);
+                return Err(ClientMessageHandlingError {kind});
+            },
+
+            Some(_) => (),
         }
 
         let response = match message {
-            ClientMessage::Register {request, connection_type, port} => {
-                if self.active_ports.contains_key(&port) {
-                    vec![ServerOperation::SendMessageToDsrpClient {
+            ClientMessage::Register {request, connection_type, port, bind_address} => {
+                match self.resolve_bind_address(bind_address) {
+                    Some(_) if self.shutdown_deadline.is_some() => vec![ServerOperation::SendMessageToDsrpClient {
                         client: client_id,
                         message: ServerMessage::RegistrationFailed {
                             request,
-                            cause: RegistrationFailureCause::PortAlreadyRegistered,
+                            cause: RegistrationFailureCause::ServerShuttingDown,
                         }
-                    }]
-                }
-                else {
-                    let mut channel_id;
-                    loop {
-                        self.next_channel_id = self.next_channel_id + Wrapping(1);
-                        channel_id = ChannelId(self.next_channel_id.0);
-                        if self.active_channels.contains_key(&channel_id) {
-                            continue;
-                        }
-
-                        break;
-                    }
+                    }],
 
-                    let channel = ActiveChannel {
-                        port,
-                        connection_type: connection_type.clone(),
-                        owner: client_id,
-                        tcp_connections: HashSet::new(),
-                        socket_has_been_bound: false,
-                        registration_request: request,
-                    };
-
-                    self.active_ports.insert(port, channel_id);
-                    self.active_channels.insert(channel_id, channel);
-
-                    // Unwrap should be safe here due to if statement above verifying the client exists
-                    let client = self.active_clients.get_mut(&client_id).unwrap();
-                    client.channels.insert(channel_id);
-
-                    let start_operation = match connection_type {
-                        ConnectionType::Tcp => ServerOperation::StartTcpOperations {
-                            port,
-                            channel: channel_id,
-                        },
+                    Some(address) => self.register_port(client_id, request, connection_type, address, port),
+                    None => vec![ServerOperation::SendMessageToDsrpClient {
+                        client: client_id,
+                        message: ServerMessage::RegistrationFailed {
+                            request,
+                            cause: RegistrationFailureCause::BindAddressNotAllowed,
+                        }
+                    }],
+                }
+            },
 
-                        ConnectionType::Udp => ServerOperation::StartUdpOperations {
-                            port,
-                            channel: channel_id,
-                        },
-                    };
+            ClientMessage::RegisterAnyPort {request, connection_type, port_range, bind_address} => {
+                let chosen_port = match self.resolve_bind_address(bind_address) {
+                    None => Err(RegistrationFailureCause::BindAddressNotAllowed),
+                    Some(_) if self.shutdown_deadline.is_some() => Err(RegistrationFailureCause::ServerShuttingDown),
+                    Some(_) if self.has_reached_channel_limit(client_id) => {
+                        Err(RegistrationFailureCause::ChannelLimitReached)
+                    },
+
+                    Some(address) => self.find_available_port(client_id, &connection_type, address, port_range)
+                        .map(|port| (address, port))
+                        .ok_or(RegistrationFailureCause::NoPortAvailable),
+                };
 
-                    vec![start_operation]
+                match chosen_port {
+                    Ok((address, port)) => self.register_port(client_id, request, connection_type, address, port),
+                    Err(cause) => vec![ServerOperation::SendMessageToDsrpClient {
+                        client: client_id,
+                        message: ServerMessage::RegistrationFailed {request, cause},
+                    }],
                 }
             },
 
             ClientMessage::Unregister {channel} => {
-                let port;
-                let connection_type;
-                let connection_ids;
-                {
-                    let channel_details = self.active_channels.get(&channel);
-                    if let None = channel_details {
+                let owner = match self.active_channels.get(&channel) {
+                    Some(x) => x.owner,
+                    None => {
                         let kind = ClientMessageHandlingErrorKind::ChannelNotFound(channel);
                         return Err(ClientMessageHandlingError { kind });
-                    }
-
-                    let channel_details = channel_details.unwrap();
-                    if channel_details.owner != client_id {
-                        let kind = ClientMessageHandlingErrorKind::ChannelNotOwnedByRequester {
-                            channel,
-                            requesting_client: client_id,
-                            owning_client: channel_details.owner,
-                        };
-
-                        return Err(ClientMessageHandlingError { kind });
-                    }
+                    },
+                };
 
-                    port = channel_details.port;
-                    connection_type = channel_details.connection_type.clone();
-                    connection_ids = channel_details.tcp_connections.clone();
-                }
+                if owner != client_id {
+                    let kind = ClientMessageHandlingErrorKind::ChannelNotOwnedByRequester {
+                        channel,
+                        requesting_client: client_id,
+                        owning_client: owner,
+                    };
 
-                let mut operations = Vec::new();
-                for connection in connection_ids {
-                    self.active_tcp_connections.remove(&connection);
-                    operations.push(ServerOperation::DisconnectConnection {connection });
+                    return Err(ClientMessageHandlingError { kind });
                 }
 
-                let operation = match connection_type {
-                    ConnectionType::Tcp => ServerOperation::StopTcpOperations {port},
-                    ConnectionType::Udp => ServerOperation::StopUdpOperations {port},
+                let mut operations = match self.remove_channel(channel) {
+                    Some((_, operations)) => operations,
+                    None => Vec::new(),
                 };
 
-                self.active_ports.remove(&port);
-                self.active_channels.remove(&channel);
+                operations.push(ServerOperation::SendMessageToDsrpClient {
+                    client: client_id,
+                    message: ServerMessage::ChannelClosed {channel},
+                });
 
-                operations.push(operation);
                 operations
             },
 
@@ -195,8 +455,55 @@ impl ServerHandler {
                 self.handle_dsrp_client_disconnection_notification(client_id, channel_id, connection_id)
             }
 
-            ClientMessage::DataBeingSent {channel: channel_id, connection: connection_id, data} => {
-                self.handle_dsrp_client_data_sent_message(client_id, channel_id, connection_id, data)
+            ClientMessage::TcpConnectionHalfClosed {channel: channel_id, connection: connection_id} => {
+                self.handle_dsrp_client_half_close_notification(client_id, channel_id, connection_id)
+            }
+
+            ClientMessage::Ping => {
+                vec![ServerOperation::SendMessageToDsrpClient {
+                    client: client_id,
+                    message: ServerMessage::Pong,
+                }]
+            },
+
+            ClientMessage::Pong => {
+                if let Some(client) = self.active_clients.get_mut(&client_id) {
+                    client.awaiting_pong = false;
+                    client.missed_pongs = 0;
+                }
+
+                Vec::new()
+            },
+
+            ClientMessage::TcpConnectionFailed {channel: channel_id, connection: connection_id, reason: _} => {
+                if !self.remove_client_tcp_connection(client_id, channel_id, connection_id) {
+                    return Ok(Vec::new());
+                }
+
+                self.record_connections(client_id, channel_id, ConnectionCounts::record_failed);
+                vec![ServerOperation::ResetConnection {connection: connection_id}]
+            }
+
+            ClientMessage::UdpConnectionFailed {channel: channel_id, connection: connection_id, reason: _} => {
+                let is_owned = self.active_udp_connections.get(&connection_id)
+                    .is_some_and(|connection| connection.owning_channel == channel_id && connection.owning_client == client_id);
+
+                if !is_owned {
+                    return Ok(Vec::new());
+                }
+
+                // The peer is announced as a new connection again if it keeps sending packets
+                let connection = self.active_udp_connections.remove(&connection_id).unwrap();
+                if let Some(channel) = self.active_channels.get_mut(&channel_id) {
+                    channel.udp_connections.remove(&connection.peer_address);
+                }
+
+                self.record_connections(client_id, channel_id, ConnectionCounts::record_failed);
+                Vec::new()
+            },
+
+            ClientMessage::DataBeingSent {channel: channel_id, connection: connection_id, peer_address, data} => {
+                self.handle_dsrp_client_data_sent_message(client_id, channel_id, connection_id, peer_address, data)
             },
         };
 
@@ -205,7 +512,17 @@ impl ServerHandler {
 
     pub fn new_channel_tcp_connection(&mut self, channel_id: ChannelId)
         -> Result<(ConnectionId, ServerOperation), NewConnectionError> {
-        let channel = match self.active_channels.get_mut(&channel_id) {
+        if self.shutdown_deadline.is_some() {
+            let kind = NewConnectionErrorKind::ServerShuttingDown;
+            return Err(NewConnectionError {kind});
+        }
+
+        let client_connection_count = match self.active_channels.get(&channel_id) {
+            Some(channel) => self.client_connection_count(channel.owner),
+            None => 0,
+        };
+
+        let channel = match self.active_channels.get(&channel_id) {
             Some(x) => x,
             None => {
                 let kind = NewConnectionErrorKind::UnknownChannelId(channel_id);
@@ -213,6 +530,11 @@ impl ServerHandler {
             },
         };
 
+        if is_disconnected(&self.active_clients, channel.owner) {
+            let kind = NewConnectionErrorKind::ClientDisconnected(channel.owner);
+            return Err(NewConnectionError {kind});
+        }
+
         if !channel.socket_has_been_bound {
             let kind = NewConnectionErrorKind::ConnectionAddedToUnboundChannel(channel_id);
             return Err(NewConnectionError {kind});
@@ -226,26 +548,40 @@ impl ServerHandler {
             }
         }
 
-        let mut new_connection_id;
-        loop {
-            self.next_connection_id = self.next_connection_id + Wrapping(1);
-            new_connection_id = ConnectionId(self.next_connection_id.0);
-            if self.active_tcp_connections.contains_key(&new_connection_id) {
-                continue;
+        if let Some(limit) = self.config.max_connections_per_channel {
+            if channel.tcp_connections.len() >= limit {
+                let kind = NewConnectionErrorKind::ChannelConnectionLimitReached(channel_id);
+                return Err(NewConnectionError {kind});
+            }
+        }
+
+        if let Some(limit) = self.config.max_connections_per_client {
+            if client_connection_count >= limit {
+                let kind = NewConnectionErrorKind::ClientConnectionLimitReached(channel.owner);
+                return Err(NewConnectionError {kind});
             }
+        }
 
-            let connection = ActiveTcpConnection {
-                owning_channel: channel_id,
-                owning_client: channel.owner,
-            };
+        let owner = channel.owner;
+        let new_connection_id = self.next_unused_connection_id();
+        let connection = ActiveTcpConnection {
+            owning_channel: channel_id,
+            owning_client: owner,
+            peer_write_closed: false,
+            client_write_closed: false,
+            opened_at: self.clock.now(),
+            traffic: TrafficStats::default(),
+        };
 
-            self.active_tcp_connections.insert(new_connection_id, connection);
+        self.active_tcp_connections.insert(new_connection_id, connection);
+        if let Some(channel) = self.active_channels.get_mut(&channel_id) {
             channel.tcp_connections.insert(new_connection_id);
-            break;
         }
 
+        self.record_connections(owner, channel_id, ConnectionCounts::record_opened);
+
         let operation = ServerOperation::SendMessageToDsrpClient {
-            client: channel.owner,
+            client: owner,
             message: ServerMessage::NewIncomingTcpConnection {
                 new_connection: new_connection_id,
                 channel: channel_id
@@ -256,10 +592,7 @@ impl ServerHandler {
     }
 
     pub fn tcp_connection_disconnected(&mut self, connection_id: ConnectionId) -> Option<ServerOperation> {
-        let connection = match self.active_tcp_connections.remove(&connection_id) {
-            Some(x) => x,
-            None => return None,
-        };
+        let connection = self.active_tcp_connections.remove(&connection_id)?;
 
         let channel = self.active_channels.get_mut(&connection.owning_channel);
 
@@ -277,60 +610,243 @@ impl ServerHandler {
         }
     }
 
-    pub fn tcp_data_received(&self, connection_id: ConnectionId, data: &[u8]) -> Option<ServerOperation> {
-        let connection = match self.active_tcp_connections.get(&connection_id) {
+    /// Lets the client know the remote peer has finished sending data over a tcp connection.  The
+    /// connection is closed once both sides have finished sending.
+    pub fn tcp_connection_half_closed(&mut self, connection_id: ConnectionId) -> Vec<ServerOperation> {
+        let connection = match self.active_tcp_connections.get_mut(&connection_id) {
             Some(x) => x,
+            None => return Vec::new(),
+        };
+
+        connection.peer_write_closed = true;
+        let mut operations = vec![ServerOperation::SendMessageToDsrpClient {
+            client: connection.owning_client,
+            message: ServerMessage::TcpConnectionHalfClosed {
+                channel: connection.owning_channel,
+                connection: connection_id,
+            },
+        }];
+
+        if connection.client_write_closed {
+            let channel_id = connection.owning_channel;
+            self.active_tcp_connections.remove(&connection_id);
+            if let Some(channel) = self.active_channels.get_mut(&channel_id) {
+                channel.tcp_connections.remove(&connection_id);
+            }
+
+            operations.push(ServerOperation::DisconnectConnection {connection: connection_id});
+        }
+
+        operations
+    }
+
+    pub fn tcp_data_received(&mut self, connection_id: ConnectionId, data: &[u8]) -> Option<ServerOperation> {
+        let (client_id, channel_id) = match self.active_tcp_connections.get(&connection_id) {
+            Some(connection) => (connection.owning_client, connection.owning_channel),
             None => return None,
         };
 
+        self.record_traffic(client_id, channel_id, connection_id, |traffic| traffic.record_inbound(data.len()));
+
         let mut data_copy = Vec::new();
         data_copy.extend_from_slice(data);
 
         let message = ServerMessage::DataReceived {
-            channel: connection.owning_channel,
+            channel: channel_id,
             connection: Some(connection_id),
+            peer_address: None,
             data: data_copy,
         };
 
         let operation = ServerOperation::SendMessageToDsrpClient {
-            client: connection.owning_client,
+            client: client_id,
             message
         };
         Some(operation)
     }
 
-    pub fn udp_data_received(&self, channel_id: ChannelId, data: &[u8]) -> Option<ServerOperation> {
+    /// Relays a udp packet to the client that owns the channel.  Each remote peer is tracked as
+    /// its own pseudo-connection, so the first packet from a peer that has not been seen
+    /// recently announces a new connection before the data is relayed.  Packets from new peers
+    /// are dropped while the channel or its client is at its connection limit.
+    pub fn udp_data_received(&mut self, channel_id: ChannelId, peer_address: SocketAddr, data: &[u8])
+        -> Vec<ServerOperation> {
+        let mut operations = Vec::new();
+        let now = self.clock.now();
+        let client_connection_count = match self.active_channels.get(&channel_id) {
+            Some(channel) => self.client_connection_count(channel.owner),
+            None => 0,
+        };
+
+        let channel_limit = self.config.max_connections_per_channel;
+        let client_limit = self.config.max_connections_per_client;
         let channel = match self.active_channels.get(&channel_id) {
             Some(x) => x,
-            None => return None,
+            None => return operations,
         };
 
-        if !channel.socket_has_been_bound {
-            return None;
+        if !channel.socket_has_been_bound || channel.connection_type != ConnectionType::Udp {
+            return operations;
+        }
+
+        let owner = channel.owner;
+        if is_disconnected(&self.active_clients, owner) {
+            return operations; // nobody to relay the packet to until the client resumes its session
         }
 
+        let connection_id = match channel.udp_connections.get(&peer_address) {
+            Some(x) => *x,
+            None if self.shutdown_deadline.is_some() => return operations,
+            None if channel_limit.is_some_and(|limit| channel.udp_connections.len() >= limit) => return operations,
+            None if client_limit.is_some_and(|limit| client_connection_count >= limit) => return operations,
+            None => {
+                let new_connection_id = self.next_unused_connection_id();
+                let connection = ActiveUdpConnection {
+                    owning_channel: channel_id,
+                    owning_client: owner,
+                    peer_address,
+                    last_activity: now,
+                    opened_at: now,
+                    traffic: TrafficStats::default(),
+                };
+
+                self.active_udp_connections.insert(new_connection_id, connection);
+                if let Some(channel) = self.active_channels.get_mut(&channel_id) {
+                    channel.udp_connections.insert(peer_address, new_connection_id);
+                }
+
+                self.record_connections(owner, channel_id, ConnectionCounts::record_opened);
+                operations.push(ServerOperation::SendMessageToDsrpClient {
+                    client: owner,
+                    message: ServerMessage::NewIncomingUdpConnection {
+                        channel: channel_id,
+                        new_connection: new_connection_id,
+                        peer_address,
+                    },
+                });
+
+                new_connection_id
+            },
+        };
+
+        if let Some(connection) = self.active_udp_connections.get_mut(&connection_id) {
+            connection.last_activity = now;
+        }
+
+        self.record_traffic(owner, channel_id, connection_id, |traffic| traffic.record_inbound(data.len()));
+
         let mut data_copy = Vec::new();
         data_copy.extend_from_slice(data);
 
         let message = ServerMessage::DataReceived {
             channel: channel_id,
-            connection: None,
+            connection: Some(connection_id),
+            peer_address: Some(peer_address),
             data: data_copy,
         };
 
-        let operation = ServerOperation::SendMessageToDsrpClient {
-            client: channel.owner,
+        operations.push(ServerOperation::SendMessageToDsrpClient {
+            client: owner,
             message
-        };
+        });
 
-        Some(operation)
+        operations
+    }
+
+    /// Performs any time based work, such as closing udp connections that have gone idle,
+    /// pinging clients to find ones that have stopped responding, and removing disconnected
+    /// clients that didn't resume their session in time.  Once a shutdown's deadline has passed
+    /// every client is disconnected instead.
+    pub fn tick(&mut self, now: Instant) -> Vec<ServerOperation> {
+        let drained = self.active_tcp_connections.is_empty() && self.active_udp_connections.is_empty();
+        if self.shutdown_deadline.is_some_and(|deadline| now >= deadline || drained) {
+            let client_ids = self.active_clients.keys().cloned().collect::<Vec<_>>();
+            let mut operations = Vec::new();
+            for client_id in client_ids {
+                // Revoking each channel first tells the client why it's losing them
+                let channels = self.active_clients[&client_id].channels.iter().cloned().collect::<Vec<_>>();
+                for channel_id in channels {
+                    operations.append(&mut self.revoke_channel(channel_id, ChannelRevocationReason::ServerShuttingDown));
+                }
+
+                operations.append(&mut self.remove_dsrp_client(client_id));
+                operations.push(ServerOperation::DisconnectDsrpClient {client: client_id});
+            }
+
+            return operations;
+        }
+
+        let idle_timeout = self.config.udp_connection_idle_timeout;
+        let expired_connections = self.active_udp_connections.iter()
+            .filter(|(_, connection)| now.duration_since(connection.last_activity) >= idle_timeout)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        let mut operations = Vec::new();
+        for connection_id in expired_connections {
+            let connection = self.active_udp_connections.remove(&connection_id).unwrap();
+            if let Some(channel) = self.active_channels.get_mut(&connection.owning_channel) {
+                channel.udp_connections.remove(&connection.peer_address);
+            }
+
+            operations.push(ServerOperation::SendMessageToDsrpClient {
+                client: connection.owning_client,
+                message: ServerMessage::UdpConnectionClosed {
+                    channel: connection.owning_channel,
+                    connection: connection_id,
+                },
+            });
+        }
+
+        let heartbeat_interval = self.config.heartbeat_interval;
+        let grace_period = self.config.session_grace_period;
+        let mut unresponsive_clients = Vec::new();
+        let mut expired_sessions = Vec::new();
+        for (client_id, client) in self.active_clients.iter_mut() {
+            if let Some(disconnected_at) = client.disconnected_at {
+                if now.duration_since(disconnected_at) >= grace_period {
+                    expired_sessions.push(*client_id);
+                }
+
+                continue;
+            }
+
+            if client.pending_challenge.is_some() || now.duration_since(client.last_ping_sent) < heartbeat_interval {
+                continue;
+            }
+
+            if client.awaiting_pong {
+                client.missed_pongs += 1;
+                if client.missed_pongs >= self.config.max_missed_heartbeats {
+                    unresponsive_clients.push(*client_id);
+                    continue;
+                }
+            }
+
+            client.last_ping_sent = now;
+            client.awaiting_pong = true;
+            operations.push(ServerOperation::SendMessageToDsrpClient {
+                client: *client_id,
+                message: ServerMessage::Ping,
+            });
+        }
+
+        // Unresponsive clients are treated like any other lost connection, so they can still
+        // resume their session if they reconnect in time
+        for client_id in unresponsive_clients {
+            operations.append(&mut self.dsrp_client_disconnected(client_id));
+            operations.push(ServerOperation::DisconnectDsrpClient {client: client_id});
+        }
+
+        for client_id in expired_sessions {
+            operations.append(&mut self.remove_dsrp_client(client_id));
+        }
+
+        operations
     }
 
     pub fn socket_binding_successful(&mut self, channel_id: ChannelId) -> Option<ServerOperation> {
-        let channel = match self.active_channels.get_mut(&channel_id) {
-            Some(x) => x,
-            None => return None,
-        };
+        let channel = self.active_channels.get_mut(&channel_id)?;
 
         if channel.socket_has_been_bound {
             return None; // Since the socket has already been bound this call is meaningless
@@ -342,23 +858,17 @@ impl ServerHandler {
         let message = ServerMessage::RegistrationSuccessful {
             request: channel.registration_request,
             created_channel: channel_id,
+            port: channel.port,
         };
 
-        let operation = ServerOperation::SendMessageToDsrpClient {
-            client: channel.owner,
-            message,
-        };
-
-        Some(operation)
+        let owner = channel.owner;
+        self.send_to_client(owner, message)
     }
 
     pub fn socket_binding_failed(&mut self, channel_id: ChannelId) -> Option<ServerOperation> {
         {
             // validations
-            let channel = match self.active_channels.get_mut(&channel_id) {
-                Some(x) => x,
-                None => return None,
-            };
+            let channel = self.active_channels.get_mut(&channel_id)?;
 
             if channel.socket_has_been_bound {
                 return None; // Since the socket has already been bound this call is meaningless
@@ -373,12 +883,18 @@ impl ServerHandler {
             cause: RegistrationFailureCause::SocketBindingFailed,
         };
 
-        let operation = ServerOperation::SendMessageToDsrpClient {
-            client: channel.owner,
-            message,
-        };
+        self.send_to_client(channel.owner, message)
+    }
 
-        Some(operation)
+    /// Handles the socket bound for a channel failing after it was bound, which closes the
+    /// channel and lets its client know it was lost
+    pub fn socket_failed(&mut self, channel_id: ChannelId) -> Vec<ServerOperation> {
+        match self.active_channels.get(&channel_id) {
+            Some(channel) if channel.socket_has_been_bound => (),
+            _ => return Vec::new(), // binding failures are reported by `socket_binding_failed()`
+        }
+
+        self.revoke_channel(channel_id, ChannelRevocationReason::SocketFailed)
     }
 
     fn handle_dsrp_client_disconnection_notification(&mut self,
@@ -387,35 +903,72 @@ impl ServerHandler {
                                                      connection_id: ConnectionId)
         -> Vec<ServerOperation> {
 
+        if !self.remove_client_tcp_connection(client_id, channel_id, connection_id) {
+            return Vec::new();
+        }
+
+        vec![ServerOperation::DisconnectConnection {connection: connection_id}]
+    }
+
+    fn handle_dsrp_client_half_close_notification(&mut self,
+                                                  client_id: ClientId,
+                                                  channel_id: ChannelId,
+                                                  connection_id: ConnectionId)
+        -> Vec<ServerOperation> {
+
+        let peer_write_closed = match self.active_tcp_connections.get_mut(&connection_id) {
+            Some(connection) if connection.owning_channel == channel_id && connection.owning_client == client_id => {
+                connection.client_write_closed = true;
+                connection.peer_write_closed
+            },
+
+            _ => return Vec::new(),
+        };
+
+        if !peer_write_closed {
+            return vec![ServerOperation::ShutdownConnectionWrite {connection: connection_id}];
+        }
+
+        // Both sides have finished sending, so nothing else can happen over the connection
+        self.remove_client_tcp_connection(client_id, channel_id, connection_id);
+        vec![ServerOperation::DisconnectConnection {connection: connection_id}]
+    }
+
+    /// Stops tracking a tcp connection the client reported on, returning false if it's not a
+    /// connection on the specified channel or the channel isn't owned by the client
+    fn remove_client_tcp_connection(&mut self,
+                                    client_id: ClientId,
+                                    channel_id: ChannelId,
+                                    connection_id: ConnectionId) -> bool {
         // Validations
         let channel;
         {
             let connection = match self.active_tcp_connections.get(&connection_id) {
                 Some(x) => x,
-                None => return Vec::new(),
+                None => return false,
             };
 
             channel = match self.active_channels.get_mut(&channel_id) {
                 Some(x) => x,
-                None => return Vec::new(),
+                None => return false,
             };
 
             if connection.owning_channel != channel_id || channel.owner != client_id {
-                return Vec::new()
+                return false;
             }
         }
 
         // If we got here without an early return then all validations check out
         self.active_tcp_connections.remove(&connection_id);
         channel.tcp_connections.remove(&connection_id);
-
-        vec![ServerOperation::DisconnectConnection {connection: connection_id}]
+        true
     }
 
-    fn handle_dsrp_client_data_sent_message(&self,
+    fn handle_dsrp_client_data_sent_message(&mut self,
                                             client_id: ClientId,
                                             channel_id: ChannelId,
                                             connection_id: Option<ConnectionId>,
+                                            peer_address: Option<SocketAddr>,
                                             data: Vec<u8>) -> Vec<ServerOperation> {
         let channel = match self.active_channels.get(&channel_id) {
             Some(x) => x,
@@ -426,7 +979,7 @@ impl ServerHandler {
             return Vec::new(); // channel is not owned by this client
         }
 
-        if channel.connection_type == ConnectionType::Tcp {
+        let destination_address = if channel.connection_type == ConnectionType::Tcp {
             if let Some(id) = connection_id {
                 if !channel.tcp_connections.contains(&id) {
                     return Vec::new(); // Not an active connection for this channel
@@ -434,31 +987,131 @@ impl ServerHandler {
             } else {
                 return Vec::new(); // tcp channels must have a connection
             }
+
+            if peer_address.is_some() {
+                return Vec::new(); // tcp connections are already tied to their remote peer
+            }
+
+            None
         } else {
-            if connection_id.is_some() {
-                return Vec::new(); // UDP channels do not use connections
+            let now = self.clock.now();
+            let connection = match connection_id {
+                Some(id) => match self.active_udp_connections.get_mut(&id) {
+                    Some(x) => x,
+                    None => return Vec::new(), // Not an active connection
+                },
+
+                None => return Vec::new(), // udp data must be sent over a known connection
+            };
+
+            if connection.owning_channel != channel_id {
+                return Vec::new(); // Not an active connection for this channel
             }
-        }
+
+            if peer_address.is_some() && peer_address != Some(connection.peer_address) {
+                return Vec::new(); // udp connections are tied to a single remote peer
+            }
+
+            connection.last_activity = now;
+            Some(connection.peer_address)
+        };
 
         // If we got here that means this is a valid request to relay
+        if let Some(id) = connection_id {
+            self.record_traffic(client_id, channel_id, id, |traffic| traffic.record_outbound(data.len()));
+        }
+
         vec![ServerOperation::SendByteData {
             channel: channel_id,
             connection: connection_id,
+            peer_address: destination_address,
             data,
         }]
     }
 
+    fn channel_stats(&self, channel_id: ChannelId) -> Option<ChannelStats> {
+        let channel = self.active_channels.get(&channel_id)?;
+        let tcp_connections = channel.tcp_connections.iter()
+            .filter_map(|id| self.active_tcp_connections.get(id).map(|connection| ConnectionStats {
+                id: *id,
+                peer_address: None,
+                opened_at: connection.opened_at,
+                traffic: connection.traffic,
+            }));
+
+        let udp_connections = channel.udp_connections.values()
+            .filter_map(|id| self.active_udp_connections.get(id).map(|connection| ConnectionStats {
+                id: *id,
+                peer_address: Some(connection.peer_address),
+                opened_at: connection.opened_at,
+                traffic: connection.traffic,
+            }));
+
+        Some(ChannelStats {
+            id: channel_id,
+            connection_type: channel.connection_type.clone(),
+            bind_address: channel.bind_address,
+            port: channel.port,
+            opened_at: channel.opened_at,
+            traffic: channel.traffic,
+            connection_counts: channel.connection_counts,
+            connections: tcp_connections.chain(udp_connections).collect(),
+        })
+    }
+
+    /// Counts traffic relayed over a connection towards the connection, its channel, its client
+    /// and the server's totals
+    fn record_traffic<F>(&mut self, client_id: ClientId, channel_id: ChannelId, connection_id: ConnectionId, record: F)
+        where F: Fn(&mut TrafficStats) {
+        record(&mut self.traffic);
+        if let Some(client) = self.active_clients.get_mut(&client_id) {
+            record(&mut client.traffic);
+        }
+
+        if let Some(channel) = self.active_channels.get_mut(&channel_id) {
+            record(&mut channel.traffic);
+        }
+
+        if let Some(connection) = self.active_tcp_connections.get_mut(&connection_id) {
+            record(&mut connection.traffic);
+        } else if let Some(connection) = self.active_udp_connections.get_mut(&connection_id) {
+            record(&mut connection.traffic);
+        }
+    }
+
+    /// Counts a connection event on a channel towards the channel, its client and the server's
+    /// totals
+    fn record_connections<F>(&mut self, client_id: ClientId, channel_id: ChannelId, record: F)
+        where F: Fn(&mut ConnectionCounts) {
+        record(&mut self.connection_counts);
+        if let Some(client) = self.active_clients.get_mut(&client_id) {
+            record(&mut client.connection_counts);
+        }
+
+        if let Some(channel) = self.active_channels.get_mut(&channel_id) {
+            record(&mut channel.connection_counts);
+        }
+    }
+
     fn remove_channel(&mut self, channel_id: ChannelId) -> Option<(ActiveChannel, Vec<ServerOperation>)> {
         let mut operations = Vec::new();
-        let active_channel = match self.active_channels.remove(&channel_id) {
-            Some(x) => x,
-            None => return None,
-        };
+        let active_channel = self.active_channels.remove(&channel_id)?;
+        if let Some(client) = self.active_clients.get_mut(&active_channel.owner) {
+            client.channels.remove(&channel_id);
+        }
+
+        let address = active_channel.bind_address;
+        let port = active_channel.port;
+        if let Some(addresses) = self.active_ports.get_mut(&port) {
+            addresses.remove(&address);
+            if addresses.is_empty() {
+                self.active_ports.remove(&port);
+            }
+        }
 
-        self.active_ports.remove(&active_channel.port);
         let operation = match active_channel.connection_type {
-            ConnectionType::Tcp => ServerOperation::StopTcpOperations {port: active_channel.port},
-            ConnectionType::Udp => ServerOperation::StopUdpOperations {port: active_channel.port},
+            ConnectionType::Tcp => ServerOperation::StopTcpOperations {address, port},
+            ConnectionType::Udp => ServerOperation::StopUdpOperations {address, port},
         };
 
         operations.push(operation);
@@ -468,8 +1121,282 @@ impl ServerHandler {
             operations.push(ServerOperation::DisconnectConnection {connection: *connection});
         }
 
+        for connection in active_channel.udp_connections.values() {
+            self.active_udp_connections.remove(connection);
+        }
+
         Some((active_channel, operations))
     }
+
+    /// Reattaches a reconnecting client to the disconnected session its ticket was issued for,
+    /// as long as the session's grace period hasn't run out and the client has the same identity.
+    /// The client is issued a new ticket, so each ticket can only be used once.  Any messages
+    /// raised for the session while the client was disconnected are sent once it's resumed.
+    fn resume_session(&mut self, ticket: &SessionTicket, identity: &Option<String>) -> Option<NewClient> {
+        let now = self.clock.now();
+        let client_id = self.find_resumable_session(ticket, identity)?;
+        let client = self.active_clients.get_mut(&client_id).expect("Resumable session is active");
+
+        // A session that still looks connected is taken over, since the client wouldn't be
+        // reconnecting unless it had lost the old control connection
+        let taken_over = client.disconnected_at.is_none();
+        let channels = client.channels.clone();
+
+        client.disconnected_at = None;
+        client.session_ticket = generate_session_ticket();
+        client.last_ping_sent = now;
+        client.awaiting_pong = false;
+        client.missed_pongs = 0;
+
+        let response = HandshakeResponse::Success {session_ticket: client.session_ticket, resumed: true};
+        let queued_messages = mem::take(&mut client.queued_messages);
+        let mut operations = if taken_over {
+            self.reset_client_connections(channels)
+        } else {
+            Vec::new()
+        };
+
+        operations.extend(queued_messages.into_iter()
+            .map(|message| ServerOperation::SendMessageToDsrpClient {client: client_id, message}));
+
+        Some(NewClient {id: client_id, response, operations})
+    }
+
+    /// Finds the session a ticket was issued for, as long as its grace period hasn't run out
+    /// and the client presenting it has the same identity.  A session that still looks connected
+    /// can only be taken over when the client also proves who it is, either by answering the
+    /// challenge or with its certificate identity, since otherwise a leaked ticket would be
+    /// enough to hijack a live session.
+    fn find_resumable_session(&self, ticket: &SessionTicket, identity: &Option<String>) -> Option<ClientId> {
+        let now = self.clock.now();
+        let grace_period = self.config.session_grace_period;
+        let can_take_over = grace_period > Duration::from_secs(0)
+            && (!self.config.auth_secrets.is_empty() || identity.is_some());
+
+        self.active_clients.iter()
+            .filter(|(_, client)| is_same_session_ticket(&client.session_ticket, ticket) && client.identity == *identity)
+            .find(|(_, client)| match client.disconnected_at {
+                Some(disconnected_at) => now.duration_since(disconnected_at) < grace_period,
+                None => can_take_over && client.pending_challenge.is_none(),
+            })
+            .map(|(id, _)| *id)
+    }
+
+    /// Creates the operation to send a message to a client, unless the client is disconnected and
+    /// waiting to resume its session, in which case the message is held until it does
+    fn send_to_client(&mut self, client_id: ClientId, message: ServerMessage) -> Option<ServerOperation> {
+        match self.active_clients.get_mut(&client_id) {
+            Some(client) if client.disconnected_at.is_some() => {
+                client.queued_messages.push(message);
+                None
+            },
+
+            _ => Some(ServerOperation::SendMessageToDsrpClient {client: client_id, message}),
+        }
+    }
+
+    fn is_client_permitted_port(&self, client_id: ClientId, port: u16) -> bool {
+        if self.config.identity_port_permissions.is_empty() {
+            return true;
+        }
+
+        self.active_clients.get(&client_id)
+            .and_then(|client| client.identity.as_ref())
+            .and_then(|identity| self.config.identity_port_permissions.get(identity))
+            .map(|ranges| ranges.iter().any(|range| range.contains(&port)))
+            .unwrap_or(false)
+    }
+
+    fn register_port(&mut self,
+                     client_id: ClientId,
+                     request: RequestId,
+                     connection_type: ConnectionType,
+                     address: IpAddr,
+                     port: u16) -> Vec<ServerOperation> {
+        let port_policy = match connection_type {
+            ConnectionType::Tcp => &self.config.tcp_port_policy,
+            ConnectionType::Udp => &self.config.udp_port_policy,
+        };
+
+        if !port_policy.is_allowed(port) {
+            vec![ServerOperation::SendMessageToDsrpClient {
+                client: client_id,
+                message: ServerMessage::RegistrationFailed {
+                    request,
+                    cause: RegistrationFailureCause::PortNotAllowed,
+                }
+            }]
+        }
+        else if !self.is_client_permitted_port(client_id, port) {
+            vec![ServerOperation::SendMessageToDsrpClient {
+                client: client_id,
+                message: ServerMessage::RegistrationFailed {
+                    request,
+                    cause: RegistrationFailureCause::NotAuthorized,
+                }
+            }]
+        }
+        else if self.has_reached_channel_limit(client_id) {
+            vec![ServerOperation::SendMessageToDsrpClient {
+                client: client_id,
+                message: ServerMessage::RegistrationFailed {
+                    request,
+                    cause: RegistrationFailureCause::ChannelLimitReached,
+                }
+            }]
+        }
+        else if self.is_port_in_use(address, port) {
+            vec![ServerOperation::SendMessageToDsrpClient {
+                client: client_id,
+                message: ServerMessage::RegistrationFailed {
+                    request,
+                    cause: RegistrationFailureCause::PortAlreadyRegistered,
+                }
+            }]
+        }
+        else {
+            let mut channel_id;
+            loop {
+                self.next_channel_id += Wrapping(1);
+                channel_id = ChannelId(self.next_channel_id.0);
+                if self.active_channels.contains_key(&channel_id) {
+                    continue;
+                }
+
+                break;
+            }
+
+            let channel = ActiveChannel {
+                bind_address: address,
+                port,
+                connection_type: connection_type.clone(),
+                owner: client_id,
+                tcp_connections: HashSet::new(),
+                udp_connections: HashMap::new(),
+                socket_has_been_bound: false,
+                registration_request: request,
+                opened_at: self.clock.now(),
+                traffic: TrafficStats::default(),
+                connection_counts: ConnectionCounts::default(),
+            };
+
+            self.active_ports.entry(port).or_default().insert(address);
+            self.active_channels.insert(channel_id, channel);
+
+            // Unwrap should be safe here due to if statement above verifying the client exists
+            let client = self.active_clients.get_mut(&client_id).unwrap();
+            client.channels.insert(channel_id);
+
+            let start_operation = match connection_type {
+                ConnectionType::Tcp => ServerOperation::StartTcpOperations {
+                    address,
+                    port,
+                    channel: channel_id,
+                },
+
+                ConnectionType::Udp => ServerOperation::StartUdpOperations {
+                    address,
+                    port,
+                    channel: channel_id,
+                },
+            };
+
+            vec![start_operation]
+        }
+    }
+
+    /// Picks a random free port from the ephemeral pool (narrowed to the requested range if one
+    /// was given) that the client is allowed to register
+    fn find_available_port(&self,
+                           client_id: ClientId,
+                           connection_type: &ConnectionType,
+                           address: IpAddr,
+                           requested_range: Option<RangeInclusive<u16>>) -> Option<u16> {
+        let pool = &self.config.ephemeral_port_range;
+        let (mut start, mut end) = (*pool.start(), *pool.end());
+        if let Some(range) = requested_range {
+            start = max(start, *range.start());
+            end = min(end, *range.end());
+        }
+
+        if start > end {
+            return None;
+        }
+
+        let port_policy = match *connection_type {
+            ConnectionType::Tcp => &self.config.tcp_port_policy,
+            ConnectionType::Udp => &self.config.udp_port_policy,
+        };
+
+        // Start at a random offset so a port that fails to bind isn't handed out every time
+        let port_count = u32::from(end - start) + 1;
+        let offset = thread_rng().gen_range(0, port_count);
+        (0..port_count)
+            .map(|index| start + ((offset + index) % port_count) as u16)
+            .find(|port| !self.is_port_in_use(address, *port)
+                && port_policy.is_allowed(*port)
+                && self.is_client_permitted_port(client_id, *port))
+    }
+
+    /// Determines which address a channel should be bound on, or `None` if the client asked for
+    /// an address the server doesn't allow
+    fn resolve_bind_address(&self, requested: Option<IpAddr>) -> Option<IpAddr> {
+        match requested {
+            None => Some(self.config.default_bind_address),
+            Some(address) if address == self.config.default_bind_address => Some(address),
+            Some(address) if self.config.bind_addresses.contains(&address) => Some(address),
+            Some(_) => None,
+        }
+    }
+
+    /// A port is in use on an address if a channel has it bound on the same address, or either
+    /// address is unspecified, since binding on an unspecified address covers every interface
+    fn is_port_in_use(&self, address: IpAddr, port: u16) -> bool {
+        match self.active_ports.get(&port) {
+            Some(addresses) => address.is_unspecified()
+                || addresses.contains(&address)
+                || addresses.iter().any(|active_address| active_address.is_unspecified()),
+
+            None => false,
+        }
+    }
+
+    fn has_reached_channel_limit(&self, client_id: ClientId) -> bool {
+        let limit = match self.config.max_channels_per_client {
+            Some(x) => x,
+            None => return false,
+        };
+
+        self.active_clients.get(&client_id)
+            .map(|client| client.channels.len() >= limit)
+            .unwrap_or(false)
+    }
+
+    /// Counts the tcp connections and udp peers open across all of the client's channels
+    fn client_connection_count(&self, client_id: ClientId) -> usize {
+        self.active_clients.get(&client_id)
+            .map(|client| client.channels.iter()
+                .filter_map(|channel_id| self.active_channels.get(channel_id))
+                .map(|channel| channel.tcp_connections.len() + channel.udp_connections.len())
+                .sum())
+            .unwrap_or(0)
+    }
+
+    fn next_unused_connection_id(&mut self) -> ConnectionId {
+        loop {
+            self.next_connection_id += Wrapping(1);
+            let connection_id = ConnectionId(self.next_connection_id.0);
+            if !self.active_tcp_connections.contains_key(&connection_id)
+                && !self.active_udp_connections.contains_key(&connection_id) {
+                return connection_id;
+            }
+        }
+    }
+}
+
+/// Whether the client's connection was lost and its session is waiting to be resumed
+fn is_disconnected(active_clients: &HashMap<ClientId, ActiveClient>, client_id: ClientId) -> bool {
+    active_clients.get(&client_id).is_some_and(|client| client.disconnected_at.is_some())
 }
 
 #[cfg(test)]
@@ -0,0 +1,50 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use messages::{ChannelId, ConnectionId, ConnectionType};
+use stats::{ConnectionCounts, TrafficStats};
+use super::ClientId;
+
+/// Snapshot of everything the server handler has relayed.  The totals include clients,
+/// channels and connections that have since been closed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerStats {
+    pub traffic: TrafficStats,
+    pub connection_counts: ConnectionCounts,
+    pub open_connections: usize,
+    pub clients: Vec<DsrpClientStats>,
+}
+
+/// Traffic relayed for a single DSRP client over its whole session, including channels that
+/// have since been closed
+#[derive(Debug, Clone, PartialEq)]
+pub struct DsrpClientStats {
+    pub id: ClientId,
+    pub identity: Option<String>,
+    pub connected_at: Instant,
+    pub traffic: TrafficStats,
+    pub connection_counts: ConnectionCounts,
+    pub open_connections: usize,
+    pub channels: Vec<ChannelStats>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelStats {
+    pub id: ChannelId,
+    pub connection_type: ConnectionType,
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub opened_at: Instant,
+    pub traffic: TrafficStats,
+    pub connection_counts: ConnectionCounts,
+    pub connections: Vec<ConnectionStats>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionStats {
+    pub id: ConnectionId,
+
+    /// Remote peer of a udp connection.  Tcp connections are only known by their id.
+    pub peer_address: Option<SocketAddr>,
+    pub opened_at: Instant,
+    pub traffic: TrafficStats,
+}
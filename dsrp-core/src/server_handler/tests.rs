@@ -1,6 +1,11 @@
 use super::*;
-use std::u32;
-use ::messages::{ConnectionType, RequestId};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use ::clock::Clock;
+use ::handshake::{compute_challenge_answer, CHALLENGE_NONCE_LENGTH};
+use ::messages::{ConnectionType, RequestId, ConnectionFailureReason};
+use ::stats::TrafficCounters;
+use ::test_utils::test_clock::TestClock;
 
 #[test]
 fn can_create_client_with_current_handshake_protocol_version() {
@@ -8,13 +13,16 @@ fn can_create_client_with_current_handshake_protocol_version() {
     let mut handler = ServerHandler::new();
     let new_client = handler.add_dsrp_client(handshake).unwrap();
 
-    assert_eq!(new_client.response, HandshakeResponse::Success, "Unexpected handshake response");
+    match new_client.response {
+        HandshakeResponse::Success {session_ticket: _, resumed: false} => (),
+        x => panic!("Expected success, instead got {:?}", x),
+    }
 }
 
 #[test]
 fn cannot_create_client_with_incorrect_handshake_protocol_version() {
     let test_version = CURRENT_VERSION.to_owned() + "a";
-    let handshake = HandshakeRequest {client_protocol_version: test_version};
+    let handshake = HandshakeRequest {client_protocol_version: test_version, session_ticket: None};
     let mut handler = ServerHandler::new();
     let error = handler.add_dsrp_client(handshake).unwrap_err();
 
@@ -24,6 +32,113 @@ fn cannot_create_client_with_incorrect_handshake_protocol_version() {
     }
 }
 
+#[test]
+fn client_challenged_when_auth_secrets_are_configured() {
+    let mut handler = handler_requiring_auth_secret(b"secret");
+    let new_client = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    match new_client.response {
+        HandshakeResponse::Challenge {nonce: _} => (),
+        x => panic!("Expected challenge, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn challenged_clients_are_given_different_nonces() {
+    let mut handler = handler_requiring_auth_secret(b"secret");
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let client2 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    assert_ne!(challenge_nonce(&client1), challenge_nonce(&client2), "Expected different nonces");
+}
+
+#[test]
+fn client_accepted_when_challenge_answered_with_configured_secret() {
+    let mut config = ServerHandlerConfig::default();
+    config.auth_secrets.push(b"other".to_vec());
+    config.auth_secrets.push(b"secret".to_vec());
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let answer = HandshakeChallengeAnswer {
+        hmac: compute_challenge_answer(b"secret", &challenge_nonce(&client1)),
+    };
+
+    let accepted_client = handler.handle_challenge_answer(client1.id, answer).unwrap();
+    assert_eq!(accepted_client.id, client1.id, "Unexpected client id");
+    match accepted_client.response {
+        HandshakeResponse::Success {session_ticket: _, resumed: false} => (),
+        x => panic!("Expected success, instead got {:?}", x),
+    }
+
+    let (_, message) = open_channel_request(ConnectionType::Tcp, 23);
+    let result = handler.handle_client_message(client1.id, message);
+    assert!(result.is_ok(), "Expected authenticated client's message to be handled");
+}
+
+#[test]
+fn client_rejected_and_removed_when_challenge_answered_with_unknown_secret() {
+    let mut handler = handler_requiring_auth_secret(b"secret");
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let answer = HandshakeChallengeAnswer {
+        hmac: compute_challenge_answer(b"wrong", &challenge_nonce(&client1)),
+    };
+
+    match handler.handle_challenge_answer(client1.id, answer).unwrap_err() {
+        HandshakeResponse::Failure {reason: _} => (),
+        x => panic!("Expected failure, instead got {:?}", x),
+    }
+
+    let (_, message) = open_channel_request(ConnectionType::Tcp, 23);
+    let error = handler.handle_client_message(client1.id, message).unwrap_err();
+    match error.kind {
+        ClientMessageHandlingErrorKind::UnknownClientId(id) => assert_eq!(id, client1.id, "Unexpected client id"),
+        x => panic!("Expected UnknownClientId error, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn client_rejected_when_answering_challenge_twice() {
+    let mut handler = handler_requiring_auth_secret(b"secret");
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let hmac = compute_challenge_answer(b"secret", &challenge_nonce(&client1));
+
+    let accepted_client = handler.handle_challenge_answer(client1.id, HandshakeChallengeAnswer {hmac: hmac.clone()}).unwrap();
+    match accepted_client.response {
+        HandshakeResponse::Success {session_ticket: _, resumed: false} => (),
+        x => panic!("Expected success, instead got {:?}", x),
+    }
+
+    match handler.handle_challenge_answer(client1.id, HandshakeChallengeAnswer {hmac}).unwrap_err() {
+        HandshakeResponse::Failure {reason: _} => (),
+        x => panic!("Expected failure, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn challenge_answer_from_unknown_client_is_rejected() {
+    let mut handler = handler_requiring_auth_secret(b"secret");
+    let answer = HandshakeChallengeAnswer {hmac: vec![1, 2, 3]};
+
+    match handler.handle_challenge_answer(ClientId(55), answer).unwrap_err() {
+        HandshakeResponse::Failure {reason: _} => (),
+        x => panic!("Expected failure, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn error_returned_when_unauthenticated_client_sends_message() {
+    let mut handler = handler_requiring_auth_secret(b"secret");
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    let (_, message) = open_channel_request(ConnectionType::Tcp, 23);
+    let error = handler.handle_client_message(client1.id, message).unwrap_err();
+    match error.kind {
+        ClientMessageHandlingErrorKind::ClientNotAuthenticated(id) => assert_eq!(id, client1.id, "Unexpected client id"),
+        x => panic!("Expected ClientNotAuthenticated error, instead got {:?}", x),
+    }
+}
+
 #[test]
 fn multiple_clients_have_different_ids()
 {
@@ -46,6 +161,7 @@ fn error_when_handling_message_from_unknown_client_id() {
         connection_type: ConnectionType::Tcp,
         port: 23,
         request: RequestId(25),
+        bind_address: None,
     };
 
     let error = handler.handle_client_message(client_id, message).unwrap_err();
@@ -70,6 +186,7 @@ fn client_cannot_register_single_port_twice_for_same_protocol()
         connection_type: ConnectionType::Tcp,
         port: 23,
         request: request_id,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client1.id, message).unwrap();
@@ -95,6 +212,7 @@ fn client_cannot_register_single_port_twice_for_different_protocols()
         connection_type: ConnectionType::Udp,
         port: 23,
         request: request_id,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client1.id, message).unwrap();
@@ -120,6 +238,7 @@ fn different_clients_cannot_request_same_port() {
         connection_type: ConnectionType::Udp,
         port: 23,
         request: request_id,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client2.id, message).unwrap();
@@ -133,6 +252,311 @@ fn different_clients_cannot_request_same_port() {
     });
 }
 
+#[test]
+fn client_can_register_port_permitted_for_its_identity() {
+    let mut handler = handler_with_identity_permissions("client-a", 8000..=8100);
+    let client1 = handler.add_dsrp_client_with_identity(HandshakeRequest::new(), "client-a".to_owned()).unwrap();
+
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 8050);
+}
+
+#[test]
+fn client_cannot_register_port_outside_of_its_identity_permissions() {
+    let mut handler = handler_with_identity_permissions("client-a", 8000..=8100);
+    let client1 = handler.add_dsrp_client_with_identity(HandshakeRequest::new(), "client-a".to_owned()).unwrap();
+
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Tcp, 8101, RegistrationFailureCause::NotAuthorized);
+}
+
+#[test]
+fn client_with_unlisted_identity_cannot_register_ports_when_permissions_configured() {
+    let mut handler = handler_with_identity_permissions("client-a", 8000..=8100);
+    let client1 = handler.add_dsrp_client_with_identity(HandshakeRequest::new(), "client-b".to_owned()).unwrap();
+
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Tcp, 8050, RegistrationFailureCause::NotAuthorized);
+}
+
+#[test]
+fn client_without_identity_cannot_register_ports_when_permissions_configured() {
+    let mut handler = handler_with_identity_permissions("client-a", 8000..=8100);
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Tcp, 8050, RegistrationFailureCause::NotAuthorized);
+}
+
+#[test]
+fn client_with_identity_can_register_any_port_when_no_permissions_configured() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client_with_identity(HandshakeRequest::new(), "client-a".to_owned()).unwrap();
+
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+}
+
+#[test]
+fn client_cannot_register_port_outside_of_allowed_ranges() {
+    let mut config = ServerHandlerConfig::default();
+    config.tcp_port_policy.allowed_ranges.push(8000..=8100);
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Tcp, 22, RegistrationFailureCause::PortNotAllowed);
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 8000);
+}
+
+#[test]
+fn client_cannot_register_denied_port_inside_allowed_range() {
+    let mut config = ServerHandlerConfig::default();
+    config.tcp_port_policy.allowed_ranges.push(8000..=8100);
+    config.tcp_port_policy.denied_ranges.push(8080..=8080);
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Tcp, 8080, RegistrationFailureCause::PortNotAllowed);
+}
+
+#[test]
+fn client_cannot_register_denied_port_when_no_ranges_configured() {
+    let mut config = ServerHandlerConfig::default();
+    config.udp_port_policy.denied_ranges.push(53..=53);
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Udp, 53, RegistrationFailureCause::PortNotAllowed);
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Udp, 54);
+}
+
+#[test]
+fn client_cannot_register_port_inside_denied_range() {
+    let mut config = ServerHandlerConfig::default();
+    config.tcp_port_policy.denied_ranges.push(1..=1023);
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Tcp, 1, RegistrationFailureCause::PortNotAllowed);
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Tcp, 443, RegistrationFailureCause::PortNotAllowed);
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Tcp, 1023, RegistrationFailureCause::PortNotAllowed);
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 1024);
+}
+
+#[test]
+fn tcp_and_udp_port_policies_are_applied_separately() {
+    let mut config = ServerHandlerConfig::default();
+    config.tcp_port_policy.denied_ranges.push(5000..=5000);
+    config.udp_port_policy.allowed_ranges.push(6000..=6010);
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Tcp, 5000, RegistrationFailureCause::PortNotAllowed);
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Udp, 7000, RegistrationFailureCause::PortNotAllowed);
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Udp, 6005);
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 7000);
+}
+
+#[test]
+fn client_cannot_register_more_channels_than_limit() {
+    let config = ServerHandlerConfig {
+        max_channels_per_client: Some(2),
+        ..ServerHandlerConfig::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let client2 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Udp, 24);
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Tcp, 25, RegistrationFailureCause::ChannelLimitReached);
+    let _ = open_channel(&mut handler, client2.id, ConnectionType::Tcp, 25);
+}
+
+#[test]
+fn client_can_register_channel_again_after_unregistering_at_limit() {
+    let config = ServerHandlerConfig {
+        max_channels_per_client: Some(1),
+        ..ServerHandlerConfig::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+
+    let _ = handler.handle_client_message(client1.id, ClientMessage::Unregister {channel: channel1}).unwrap();
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 24);
+}
+
+#[test]
+fn server_picks_port_from_ephemeral_range_when_any_port_requested() {
+    let config = ServerHandlerConfig {
+        ephemeral_port_range: 9000..=9100,
+        ..ServerHandlerConfig::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    let port = register_any_port(&mut handler, client1.id, None);
+    assert!((9000..=9100).contains(&port), "Port {} outside of ephemeral range", port);
+}
+
+#[test]
+fn server_picks_port_within_requested_range() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    let port = register_any_port(&mut handler, client1.id, Some(50000..=50002));
+    assert!((50000..=50002).contains(&port), "Port {} outside of requested range", port);
+}
+
+#[test]
+fn server_does_not_pick_registered_or_disallowed_ports() {
+    let mut config = ServerHandlerConfig {
+        ephemeral_port_range: 9000..=9002,
+        ..ServerHandlerConfig::default()
+    };
+
+    config.tcp_port_policy.denied_ranges.push(9001..=9001);
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 9000);
+
+    let port = register_any_port(&mut handler, client1.id, None);
+    assert_eq!(port, 9002, "Unexpected port chosen");
+}
+
+#[test]
+fn no_port_available_when_every_port_in_range_is_registered() {
+    let config = ServerHandlerConfig {
+        ephemeral_port_range: 9000..=9001,
+        ..ServerHandlerConfig::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let _ = register_any_port(&mut handler, client1.id, None);
+    let _ = register_any_port(&mut handler, client1.id, None);
+
+    let message = ClientMessage::RegisterAnyPort {
+        request: RequestId(26),
+        connection_type: ConnectionType::Tcp,
+        port_range: None,
+        bind_address: None,
+    };
+
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client: _,
+        message: ServerMessage::RegistrationFailed {request: _, cause: RegistrationFailureCause::NoPortAvailable}
+    });
+}
+
+#[test]
+fn no_port_available_when_requested_range_outside_of_ephemeral_range() {
+    let config = ServerHandlerConfig {
+        ephemeral_port_range: 9000..=9100,
+        ..ServerHandlerConfig::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let message = ClientMessage::RegisterAnyPort {
+        request: RequestId(26),
+        connection_type: ConnectionType::Udp,
+        port_range: Some(8000..=8100),
+        bind_address: None,
+    };
+
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client: _,
+        message: ServerMessage::RegistrationFailed {request: _, cause: RegistrationFailureCause::NoPortAvailable}
+    });
+}
+
+#[test]
+fn channel_bound_on_default_address_when_none_requested() {
+    let config = ServerHandlerConfig {
+        default_bind_address: "10.0.0.1".parse().unwrap(),
+        ..ServerHandlerConfig::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    let response = register_on_address(&mut handler, client1.id, None, 23);
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address, port: 23, channel: _} => {
+        assert_eq!(*address, "10.0.0.1".parse::<IpAddr>().unwrap(), "Unexpected bind address");
+    });
+}
+
+#[test]
+fn channel_bound_on_requested_address_when_allowed() {
+    let mut handler = handler_with_bind_addresses();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    let response = register_on_address(&mut handler, client1.id, Some(vpn_address()), 23);
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address, port: 23, channel: _} => {
+        assert_eq!(*address, vpn_address(), "Unexpected bind address");
+    });
+}
+
+#[test]
+fn registration_fails_when_requested_bind_address_not_allowed() {
+    let mut handler = handler_with_bind_addresses();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    let response = register_on_address(&mut handler, client1.id, Some("192.168.5.5".parse().unwrap()), 23);
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client: _,
+        message: ServerMessage::RegistrationFailed {request: _, cause: RegistrationFailureCause::BindAddressNotAllowed}
+    });
+}
+
+#[test]
+fn same_port_can_be_registered_on_different_specific_addresses() {
+    let mut handler = handler_with_bind_addresses();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    let response = register_on_address(&mut handler, client1.id, Some(vpn_address()), 23);
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port: 23, channel: _});
+
+    let response = register_on_address(&mut handler, client1.id, Some(public_address()), 23);
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port: 23, channel: _});
+}
+
+#[test]
+fn port_on_unspecified_address_conflicts_with_same_port_on_specific_address() {
+    let mut config = ServerHandlerConfig::default();
+    config.bind_addresses.push(vpn_address());
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    let response = register_on_address(&mut handler, client1.id, Some(vpn_address()), 23);
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port: 23, channel: _});
+
+    let response = register_on_address(&mut handler, client1.id, None, 23);
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client: _,
+        message: ServerMessage::RegistrationFailed {request: _, cause: RegistrationFailureCause::PortAlreadyRegistered}
+    });
+}
+
+#[test]
+fn unregistering_stops_operations_on_bound_address() {
+    let mut handler = handler_with_bind_addresses();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    let response = register_on_address(&mut handler, client1.id, Some(vpn_address()), 23);
+    let mut channel1 = ChannelId(u32::MAX);
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port: _, channel} => {
+        channel1 = *channel;
+    });
+
+    let response = handler.handle_client_message(client1.id, ClientMessage::Unregister {channel: channel1}).unwrap();
+    assert_vec_contains!(response, ServerOperation::StopTcpOperations {address, port: 23} => {
+        assert_eq!(*address, vpn_address(), "Unexpected bind address");
+    });
+}
+
 #[test]
 fn multiple_registrations_return_different_channel_ids() {
     let mut handler = ServerHandler::new();
@@ -154,12 +578,13 @@ fn valid_tcp_registration_instructs_server_to_start_tcp_operations_with_same_cha
         connection_type: ConnectionType::Tcp,
         port: requested_port,
         request: request_id,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client1.id, message).unwrap();
 
     let mut operation_channel = ChannelId(u32::MAX);
-    assert_vec_contains!(response, ServerOperation::StartTcpOperations {port, channel}
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port, channel}
     => {
         assert_eq!(*port, requested_port, "Incorrect port in operation");
         operation_channel = *channel
@@ -179,12 +604,13 @@ fn valid_udp_registration_instructs_server_to_start_udp_operations_with_same_cha
         connection_type: ConnectionType::Udp,
         port: requested_port,
         request: request_id,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client1.id, message).unwrap();
 
     let mut operation_channel = ChannelId(u32::MAX);
-    assert_vec_contains!(response, ServerOperation::StartUdpOperations {port, channel}
+    assert_vec_contains!(response, ServerOperation::StartUdpOperations {address: _, port, channel}
     => {
         assert_eq!(*port, requested_port, "Incorrect port in operation");
         operation_channel = *channel
@@ -205,12 +631,13 @@ fn registration_success_message_sent_to_dsrp_client_after_server_reports_binding
         connection_type: ConnectionType::Tcp,
         port: requested_port,
         request: request_id,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client_id, message).unwrap();
     let mut operation_channel = ChannelId(u32::MAX);
 
-    assert_vec_contains!(response, ServerOperation::StartTcpOperations {port, channel}
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port, channel}
     => {
         assert_eq!(*port, requested_port, "Incorrect port in operation");
         operation_channel = *channel
@@ -220,9 +647,10 @@ fn registration_success_message_sent_to_dsrp_client_after_server_reports_binding
     match operation {
         ServerOperation::SendMessageToDsrpClient {
             client: intended_client_id,
-            message: ServerMessage::RegistrationSuccessful {request: response_request_id, created_channel}
+            message: ServerMessage::RegistrationSuccessful {request: response_request_id, created_channel, port}
         } => {
             assert_eq!(response_request_id, request_id, "Unexpected request id in response");
+            assert_eq!(port, requested_port, "Unexpected port in response");
             assert_eq!(intended_client_id, client_id, "Unexpected client id");
             assert_eq!(created_channel, operation_channel, "Unexpected channel id");
         },
@@ -243,12 +671,13 @@ fn registration_failure_message_sent_to_dsrp_client_after_server_reports_binding
         connection_type: ConnectionType::Tcp,
         port: requested_port,
         request: request_id,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client_id, message).unwrap();
     let mut operation_channel = ChannelId(u32::MAX);
 
-    assert_vec_contains!(response, ServerOperation::StartTcpOperations {port, channel}
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port, channel}
     => {
         assert_eq!(*port, requested_port, "Incorrect port in operation");
         operation_channel = *channel
@@ -284,12 +713,13 @@ fn binding_failure_allows_re_registration_of_port() {
         connection_type: ConnectionType::Tcp,
         port: requested_port,
         request: request_id,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client_id, message).unwrap();
     let mut operation_channel = ChannelId(u32::MAX);
 
-    assert_vec_contains!(response, ServerOperation::StartTcpOperations {port, channel}
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port, channel}
     => {
         assert_eq!(*port, requested_port, "Incorrect port in operation");
         operation_channel = *channel
@@ -303,10 +733,11 @@ fn binding_failure_allows_re_registration_of_port() {
         connection_type: ConnectionType::Tcp,
         port: requested_port,
         request: request_id,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client_id, message).unwrap();
-    assert_vec_contains!(response, ServerOperation::StartTcpOperations {port, channel: _}
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port, channel: _}
     => {
         assert_eq!(*port, requested_port, "Incorrect port in operation");
     });
@@ -321,12 +752,12 @@ fn removing_client_returns_stop_operations_on_all_registered_channels() {
 
     let operations = handler.remove_dsrp_client(client1.id);
 
-    assert_vec_contains!(operations, ServerOperation::StopTcpOperations {port}
+    assert_vec_contains!(operations, ServerOperation::StopTcpOperations {address: _, port}
         => {
             assert_eq!(*port, 23, "Unexpected port for stop tcp operation");
         });
 
-    assert_vec_contains!(operations, ServerOperation::StopUdpOperations {port}
+    assert_vec_contains!(operations, ServerOperation::StopUdpOperations {address: _, port}
         => {
             assert_eq!(*port, 25, "Unexpected port for stop udp operation");
         });
@@ -347,11 +778,12 @@ fn removing_client_reopens_port() {
         connection_type: ConnectionType::Tcp,
         port: PORT,
         request: request2,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client2.id, message1).unwrap();
 
-    assert_vec_contains!(response, ServerOperation::StartTcpOperations {port, channel: _}
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port, channel: _}
     => {
         assert_eq!(*port, PORT, "Unexpected port");
     });
@@ -366,7 +798,7 @@ fn unregister_request_returns_stop_operation_on_tcp_channel() {
     let unregister_message = ClientMessage::Unregister {channel: channel1};
     let unregister_response = handler.handle_client_message(client1.id, unregister_message).unwrap();
 
-    assert_vec_contains!(unregister_response, ServerOperation::StopTcpOperations {port} => {
+    assert_vec_contains!(unregister_response, ServerOperation::StopTcpOperations {address: _, port} => {
         assert_eq!(*port, 23, "Unexpected port stopped");
     });
 }
@@ -380,11 +812,29 @@ fn unregister_request_returns_stop_operation_on_udp_channel() {
     let unregister_message = ClientMessage::Unregister {channel: channel1};
     let unregister_response = handler.handle_client_message(client1.id, unregister_message).unwrap();
 
-    assert_vec_contains!(unregister_response, ServerOperation::StopUdpOperations {port} => {
+    assert_vec_contains!(unregister_response, ServerOperation::StopUdpOperations {address: _, port} => {
         assert_eq!(*port, 23, "Unexpected port stopped");
     });
 }
 
+#[test]
+fn unregister_request_acknowledges_closed_channel_to_client() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+
+    let unregister_message = ClientMessage::Unregister {channel: channel1};
+    let unregister_response = handler.handle_client_message(client1.id, unregister_message).unwrap();
+
+    assert_vec_contains!(unregister_response, ServerOperation::SendMessageToDsrpClient {
+        client,
+        message: ServerMessage::ChannelClosed {channel}
+    } => {
+        assert_eq!(*client, client1.id, "Unexpected client id");
+        assert_eq!(*channel, channel1, "Unexpected channel closed");
+    });
+}
+
 #[test]
 fn error_when_attempting_to_unregister_nonexistent_channel() {
     let mut handler = ServerHandler::new();
@@ -435,10 +885,11 @@ fn unregistering_allows_port_to_be_reused() {
         connection_type: ConnectionType::Tcp,
         port: 23,
         request: request2,
+        bind_address: None,
     };
 
     let register2_response = handler.handle_client_message(client1.id, register_message).unwrap();
-    assert_vec_contains!(register2_response, ServerOperation::StartTcpOperations {port, channel: _}
+    assert_vec_contains!(register2_response, ServerOperation::StartTcpOperations {address: _, port, channel: _}
     => {
         assert_eq!(*port, 23, "Unexpected port");
     });
@@ -461,48 +912,194 @@ fn error_when_adding_new_tcp_connection_to_non_existent_channel() {
 }
 
 #[test]
-fn error_when_adding_connection_to_udp_channel() {
-    let mut handler = ServerHandler::new();
+fn error_when_adding_more_tcp_connections_to_channel_than_limit() {
+    let config = ServerHandlerConfig {
+        max_connections_per_channel: Some(2),
+        ..ServerHandlerConfig::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
     let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
-    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let channel2 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 24);
 
+    let _ = handler.new_channel_tcp_connection(channel1).unwrap();
+    let _ = handler.new_channel_tcp_connection(channel1).unwrap();
     let error = handler.new_channel_tcp_connection(channel1).unwrap_err();
     match error.kind {
-        NewConnectionErrorKind::ConnectionAddedToNonTcpChannel(channel) => {
+        NewConnectionErrorKind::ChannelConnectionLimitReached(channel) => {
             assert_eq!(channel, channel1, "Unexpected channel in error message")
         },
 
-        x => panic!("Expected ConnectionAddedToNonTcpChannel error, instead got {:?}", x),
+        x => panic!("Expected ChannelConnectionLimitReached error, instead got {:?}", x),
     }
+
+    let _ = handler.new_channel_tcp_connection(channel2).unwrap();
 }
 
 #[test]
-fn can_add_tcp_connection_to_valid_channel() {
-    let mut handler = ServerHandler::new();
+fn rejected_tcp_connections_do_not_use_up_connection_ids() {
+    let config = ServerHandlerConfig {
+        max_connections_per_channel: Some(1),
+        ..ServerHandlerConfig::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
     let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
     let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let channel2 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 24);
 
-    let (connection, operation) = handler.new_channel_tcp_connection(channel1).unwrap();
-    match operation {
-        ServerOperation::SendMessageToDsrpClient {client, message} => {
-            match message {
-                ServerMessage::NewIncomingTcpConnection {channel, new_connection} => {
-                    assert_eq!(channel, channel1, "Unexpected channel in server message");
-                    assert_eq!(new_connection, connection, "Connection identifiers do not match");
-                    assert_eq!(client, client1.id, "Unexpected client id")
-                },
-
-                x => panic!("Expected new incoming tcp connection message, instead got {:?}", x),
-            }
-        },
-
-        x => panic!("Expected send message to client operation, instead got {:?}", x),
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+    for _ in 0..5 {
+        let _ = handler.new_channel_tcp_connection(channel1).unwrap_err();
     }
+
+    let (connection2, _) = handler.new_channel_tcp_connection(channel2).unwrap();
+    assert_eq!(connection2, ConnectionId(connection1.0 + 1), "Unexpected connection id for second connection");
 }
 
 #[test]
-fn removing_client_returns_operations_to_disconnect_connections() {
-    let mut handler = ServerHandler::new();
+fn error_when_adding_more_tcp_connections_to_client_than_limit() {
+    let config = ServerHandlerConfig {
+        max_connections_per_client: Some(2),
+        ..ServerHandlerConfig::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let client2 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let channel2 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 24);
+    let channel3 = open_channel(&mut handler, client2.id, ConnectionType::Tcp, 25);
+
+    let _ = handler.new_channel_tcp_connection(channel1).unwrap();
+    let _ = handler.new_channel_tcp_connection(channel2).unwrap();
+    let error = handler.new_channel_tcp_connection(channel2).unwrap_err();
+    match error.kind {
+        NewConnectionErrorKind::ClientConnectionLimitReached(client) => {
+            assert_eq!(client, client1.id, "Unexpected client in error message")
+        },
+
+        x => panic!("Expected ClientConnectionLimitReached error, instead got {:?}", x),
+    }
+
+    let _ = handler.new_channel_tcp_connection(channel3).unwrap();
+}
+
+#[test]
+fn tcp_connection_can_be_added_after_another_disconnects_at_limit() {
+    let config = ServerHandlerConfig {
+        max_connections_per_channel: Some(1),
+        ..ServerHandlerConfig::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+    let _ = handler.tcp_connection_disconnected(connection1);
+    let _ = handler.new_channel_tcp_connection(channel1).unwrap();
+}
+
+#[test]
+fn packets_from_new_udp_peers_dropped_once_channel_reaches_limit() {
+    let config = ServerHandlerConfig {
+        max_connections_per_channel: Some(1),
+        ..ServerHandlerConfig::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let channel2 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 24);
+    let connection1 = open_udp_connection(&mut handler, channel1, peer_address());
+
+    let response = handler.udp_data_received(channel1, second_peer_address(), &[1, 2, 3]);
+    assert_eq!(response.len(), 0, "Expected no operations but got {:?}", response);
+
+    let response = handler.udp_data_received(channel1, peer_address(), &[1, 2, 3]);
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client: _,
+        message: ServerMessage::DataReceived {channel: _, connection, peer_address: _, data: _}
+    } => {
+        assert_eq!(*connection, Some(connection1), "Unexpected connection in message");
+    });
+
+    let _ = open_udp_connection(&mut handler, channel2, second_peer_address());
+}
+
+#[test]
+fn udp_peers_count_towards_client_connection_limit() {
+    let config = ServerHandlerConfig {
+        max_connections_per_client: Some(2),
+        ..ServerHandlerConfig::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let tcp_channel = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let udp_channel = open_channel(&mut handler, client1.id, ConnectionType::Udp, 24);
+
+    let _ = open_udp_connection(&mut handler, udp_channel, peer_address());
+    let _ = handler.new_channel_tcp_connection(tcp_channel).unwrap();
+
+    let response = handler.udp_data_received(udp_channel, second_peer_address(), &[1, 2, 3]);
+    assert_eq!(response.len(), 0, "Expected no operations but got {:?}", response);
+
+    let error = handler.new_channel_tcp_connection(tcp_channel).unwrap_err();
+    match error.kind {
+        NewConnectionErrorKind::ClientConnectionLimitReached(client) => {
+            assert_eq!(client, client1.id, "Unexpected client in error message")
+        },
+
+        x => panic!("Expected ClientConnectionLimitReached error, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn error_when_adding_connection_to_udp_channel() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+
+    let error = handler.new_channel_tcp_connection(channel1).unwrap_err();
+    match error.kind {
+        NewConnectionErrorKind::ConnectionAddedToNonTcpChannel(channel) => {
+            assert_eq!(channel, channel1, "Unexpected channel in error message")
+        },
+
+        x => panic!("Expected ConnectionAddedToNonTcpChannel error, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn can_add_tcp_connection_to_valid_channel() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+
+    let (connection, operation) = handler.new_channel_tcp_connection(channel1).unwrap();
+    match operation {
+        ServerOperation::SendMessageToDsrpClient {client, message} => {
+            match message {
+                ServerMessage::NewIncomingTcpConnection {channel, new_connection} => {
+                    assert_eq!(channel, channel1, "Unexpected channel in server message");
+                    assert_eq!(new_connection, connection, "Connection identifiers do not match");
+                    assert_eq!(client, client1.id, "Unexpected client id")
+                },
+
+                x => panic!("Expected new incoming tcp connection message, instead got {:?}", x),
+            }
+        },
+
+        x => panic!("Expected send message to client operation, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn removing_client_returns_operations_to_disconnect_connections() {
+    let mut handler = ServerHandler::new();
     let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
     let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
 
@@ -530,6 +1127,86 @@ fn unregister_call_returns_server_operations_to_disconnect_connections() {
     assert_vec_contains!(results, ServerOperation::DisconnectConnection {connection} if *connection == connection2);
 }
 
+#[test]
+fn revoking_channel_notifies_owning_client() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+
+    let results = handler.revoke_channel(channel1, ChannelRevocationReason::Administrative);
+    assert_vec_contains!(results, ServerOperation::SendMessageToDsrpClient {
+        client,
+        message: ServerMessage::ChannelRevoked {channel, reason}
+    } => {
+        assert_eq!(*client, client1.id, "Unexpected client id");
+        assert_eq!(*channel, channel1, "Unexpected channel revoked");
+        assert_eq!(*reason, ChannelRevocationReason::Administrative, "Unexpected reason");
+    });
+}
+
+#[test]
+fn revoking_channel_stops_operations_and_disconnects_connections() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    let results = handler.revoke_channel(channel1, ChannelRevocationReason::Administrative);
+    assert_vec_contains!(results, ServerOperation::StopTcpOperations {address: _, port} => {
+        assert_eq!(*port, 23, "Unexpected port stopped");
+    });
+
+    assert_vec_contains!(results, ServerOperation::DisconnectConnection {connection} if *connection == connection1);
+
+    // The port is free for anyone to register again
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+}
+
+#[test]
+fn socket_failure_revokes_bound_channel() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+
+    let results = handler.socket_failed(channel1);
+    assert_vec_contains!(results, ServerOperation::StopUdpOperations {address: _, port} => {
+        assert_eq!(*port, 23, "Unexpected port stopped");
+    });
+
+    assert_vec_contains!(results, ServerOperation::SendMessageToDsrpClient {
+        client,
+        message: ServerMessage::ChannelRevoked {channel, reason}
+    } => {
+        assert_eq!(*client, client1.id, "Unexpected client id");
+        assert_eq!(*channel, channel1, "Unexpected channel revoked");
+        assert_eq!(*reason, ChannelRevocationReason::SocketFailed, "Unexpected reason");
+    });
+}
+
+#[test]
+fn socket_failure_ignored_for_channel_not_yet_bound() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let (_, message) = open_channel_request(ConnectionType::Tcp, 23);
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+
+    let mut channel1 = ChannelId(u32::MAX);
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port: _, channel} => {
+        channel1 = *channel;
+    });
+
+    let results = handler.socket_failed(channel1);
+    assert_eq!(results.len(), 0, "Unexpected operations returned: {:?}", results);
+}
+
+#[test]
+fn revoking_unknown_channel_returns_no_operations() {
+    let mut handler = ServerHandler::new();
+
+    let results = handler.revoke_channel(ChannelId(22), ChannelRevocationReason::Administrative);
+    assert_eq!(results.len(), 0, "Unexpected number of operations returned");
+}
+
 #[test]
 fn server_side_tcp_disconnection_sends_dsrp_client_notification() {
     let mut handler = ServerHandler::new();
@@ -593,9 +1270,10 @@ fn data_relayed_to_client_when_tcp_data_is_received() {
             assert_eq!(client, client1.id, "Unexpected dsrp client for message");
 
             match message {
-                ServerMessage::DataReceived {channel, connection, data} => {
+                ServerMessage::DataReceived {channel, connection, peer_address: address, data} => {
                     assert_eq!(channel, channel1, "Unexpected channel in message");
                     assert_eq!(connection, Some(connection1), "Unexpected connection in message");
+                    assert_eq!(address, None, "Unexpected peer address in message");
                     assert_eq!(&data[..], &received_data[..], "Unexpected data in message");
                 },
 
@@ -614,101 +1292,1049 @@ fn data_relayed_to_client_when_udp_data_is_received() {
     let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
 
     let received_data = [1, 2, 3, 4, 5, 6];
-    match handler.udp_data_received(channel1, &received_data).unwrap() {
-        ServerOperation::SendMessageToDsrpClient {client, message} => {
-            assert_eq!(client, client1.id, "Unexpected dsrp client for message");
+    let response = handler.udp_data_received(channel1, peer_address(), &received_data);
 
-            match message {
-                ServerMessage::DataReceived {channel, connection, data} => {
-                    assert_eq!(channel, channel1, "Unexpected channel in message");
-                    assert_eq!(connection, None, "Unexpected connection in message");
-                    assert_eq!(&data[..], &received_data[..], "Unexpected data in message");
-                },
+    let mut new_connection_id = ConnectionId(u32::MAX);
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client,
+        message: ServerMessage::NewIncomingUdpConnection {channel, new_connection, peer_address: address}
+    } => {
+        assert_eq!(*client, client1.id, "Unexpected dsrp client for message");
+        assert_eq!(*channel, channel1, "Unexpected channel in message");
+        assert_eq!(*address, peer_address(), "Unexpected peer address in message");
+        new_connection_id = *new_connection;
+    });
 
-                x => panic!("Expected DataReceived message, instead received {:?}", x),
-            }
-        },
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client,
+        message: ServerMessage::DataReceived {channel, connection, peer_address: address, data}
+    } => {
+        assert_eq!(*client, client1.id, "Unexpected dsrp client for message");
+        assert_eq!(*channel, channel1, "Unexpected channel in message");
+        assert_eq!(*connection, Some(new_connection_id), "Unexpected connection in message");
+        assert_eq!(*address, Some(peer_address()), "Unexpected peer address in message");
+        assert_eq!(&data[..], &received_data[..], "Unexpected data in message");
+    });
+}
 
-        x => panic!("Expected SendMessageToDsrpClient operation, received {:?}", x),
+#[test]
+fn udp_connection_not_announced_again_when_same_peer_sends_more_data() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let connection1 = open_udp_connection(&mut handler, channel1, peer_address());
+
+    let response = handler.udp_data_received(channel1, peer_address(), &[1, 2, 3]);
+    assert_eq!(response.len(), 1, "Unexpected number of operations returned");
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client: _,
+        message: ServerMessage::DataReceived {channel: _, connection, peer_address: _, data: _}
+    } => {
+        assert_eq!(*connection, Some(connection1), "Unexpected connection in message");
+    });
+}
+
+#[test]
+fn different_udp_peers_are_given_different_connection_ids() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+
+    let connection1 = open_udp_connection(&mut handler, channel1, peer_address());
+    let connection2 = open_udp_connection(&mut handler, channel1, second_peer_address());
+    assert_ne!(connection1, connection2, "Expected different connection ids for each peer");
+}
+
+#[test]
+fn udp_data_from_known_peer_does_not_use_up_connection_ids() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let connection1 = open_udp_connection(&mut handler, channel1, peer_address());
+
+    for _ in 0..5 {
+        handler.udp_data_received(channel1, peer_address(), &[1, 2, 3]);
+    }
+
+    let connection2 = open_udp_connection(&mut handler, channel1, second_peer_address());
+    assert_eq!(connection2, ConnectionId(connection1.0 + 1), "Unexpected connection id for second peer");
+}
+
+#[test]
+fn idle_udp_connection_closed_on_tick_after_timeout() {
+    let clock = TestClock::new();
+    let config = ServerHandlerConfig {udp_connection_idle_timeout: Duration::from_secs(30), ..Default::default()};
+    let mut handler = ServerHandler::with_config(config, Box::new(clock.clone()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let connection1 = open_udp_connection(&mut handler, channel1, peer_address());
+
+    clock.advance(Duration::from_secs(29));
+    let response = handler.tick(clock.now());
+    assert_eq!(response.len(), 0, "Expected no operations before the idle timeout");
+
+    clock.advance(Duration::from_secs(2));
+    let response = handler.tick(clock.now());
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client,
+        message: ServerMessage::UdpConnectionClosed {channel, connection}
+    } => {
+        assert_eq!(*client, client1.id, "Unexpected dsrp client for message");
+        assert_eq!(*channel, channel1, "Unexpected channel in message");
+        assert_eq!(*connection, connection1, "Unexpected connection in message");
+    });
+
+    let message = ClientMessage::DataBeingSent {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1,2,3,4,5],
+    };
+
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+    assert_eq!(response.len(), 0, "Expected no operations for data sent over expired connection");
+}
+
+#[test]
+fn udp_activity_keeps_connection_open_on_tick() {
+    let clock = TestClock::new();
+    let config = ServerHandlerConfig {
+        udp_connection_idle_timeout: Duration::from_secs(30),
+        heartbeat_interval: Duration::from_secs(120),
+        ..Default::default()
+    };
+    let mut handler = ServerHandler::with_config(config, Box::new(clock.clone()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let connection1 = open_udp_connection(&mut handler, channel1, peer_address());
+
+    clock.advance(Duration::from_secs(20));
+    let message = ClientMessage::DataBeingSent {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1,2,3,4,5],
+    };
+
+    let _ = handler.handle_client_message(client1.id, message).unwrap();
+
+    clock.advance(Duration::from_secs(20));
+    let response = handler.tick(clock.now());
+    assert_eq!(response.len(), 0, "Expected no operations for recently active connection");
+}
+
+#[test]
+fn ping_sent_to_client_on_tick_after_heartbeat_interval() {
+    let clock = TestClock::new();
+    let config = ServerHandlerConfig {heartbeat_interval: Duration::from_secs(10), ..Default::default()};
+    let mut handler = ServerHandler::with_config(config, Box::new(clock.clone()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    clock.advance(Duration::from_secs(9));
+    let response = handler.tick(clock.now());
+    assert_eq!(response.len(), 0, "Expected no operations before the heartbeat interval");
+
+    clock.advance(Duration::from_secs(1));
+    let response = handler.tick(clock.now());
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {client, message: ServerMessage::Ping} => {
+        assert_eq!(*client, client1.id, "Unexpected dsrp client for message");
+    });
+}
+
+#[test]
+fn pong_sent_when_client_pings() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    let response = handler.handle_client_message(client1.id, ClientMessage::Ping).unwrap();
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {client, message: ServerMessage::Pong} => {
+        assert_eq!(*client, client1.id, "Unexpected dsrp client for message");
+    });
+}
+
+#[test]
+fn client_removed_after_missing_too_many_pongs_when_grace_period_is_zero() {
+    let clock = TestClock::new();
+    let config = ServerHandlerConfig {
+        heartbeat_interval: Duration::from_secs(10),
+        max_missed_heartbeats: 2,
+        session_grace_period: Duration::from_secs(0),
+        ..Default::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(clock.clone()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    clock.advance(Duration::from_secs(10));
+    let _ = handler.tick(clock.now());
+    clock.advance(Duration::from_secs(10));
+    let response = handler.tick(clock.now());
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {client: _, message: ServerMessage::Ping});
+
+    clock.advance(Duration::from_secs(10));
+    let response = handler.tick(clock.now());
+    assert_vec_contains!(response, ServerOperation::DisconnectDsrpClient {client} => {
+        assert_eq!(*client, client1.id, "Unexpected dsrp client disconnected");
+    });
+
+    assert_vec_contains!(response, ServerOperation::StopTcpOperations {address: _, port} => {
+        assert_eq!(*port, 23, "Unexpected port stopped");
+    });
+
+    assert_vec_contains!(response, ServerOperation::DisconnectConnection {connection} => {
+        assert_eq!(*connection, connection1, "Unexpected connection disconnected");
+    });
+}
+
+
+#[test]
+fn unresponsive_client_keeps_session_during_grace_period() {
+    let clock = TestClock::new();
+    let config = ServerHandlerConfig {
+        heartbeat_interval: Duration::from_secs(10),
+        max_missed_heartbeats: 2,
+        session_grace_period: Duration::from_secs(60),
+        ..Default::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(clock.clone()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    for _ in 0..2 {
+        clock.advance(Duration::from_secs(10));
+        let _ = handler.tick(clock.now());
     }
+
+    clock.advance(Duration::from_secs(10));
+    let response = handler.tick(clock.now());
+    assert_vec_contains!(response, ServerOperation::DisconnectDsrpClient {client} => {
+        assert_eq!(*client, client1.id, "Unexpected dsrp client disconnected");
+    });
+
+    assert_vec_contains!(response, ServerOperation::ResetConnection {connection} => {
+        assert_eq!(*connection, connection1, "Unexpected connection reset");
+    });
+
+    assert!(!response.iter().any(|x| matches!(x, ServerOperation::StopTcpOperations {..})),
+            "Expected the channel to stay bound, but got {:?}", response);
+
+    let response = handler.dsrp_client_disconnected(client1.id);
+    assert_eq!(response.len(), 0, "Expected no operations once already disconnected, got {:?}", response);
+
+    let resumed_client = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    assert_eq!(resumed_client.id, client1.id, "Expected the same client id");
+    assert!(resumed(&resumed_client), "Expected the session to be resumed");
+}
+
+#[test]
+fn pong_resets_missed_heartbeats() {
+    let clock = TestClock::new();
+    let config = ServerHandlerConfig {
+        heartbeat_interval: Duration::from_secs(10),
+        max_missed_heartbeats: 2,
+        ..Default::default()
+    };
+
+    let mut handler = ServerHandler::with_config(config, Box::new(clock.clone()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    for _ in 0..5 {
+        clock.advance(Duration::from_secs(10));
+        let response = handler.tick(clock.now());
+        assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {client: _, message: ServerMessage::Ping});
+
+        let response = handler.handle_client_message(client1.id, ClientMessage::Pong).unwrap();
+        assert_eq!(response.len(), 0, "Unexpected operations for pong");
+    }
+}
+
+#[test]
+fn accepted_clients_are_given_different_session_tickets() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let client2 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    assert_ne!(session_ticket(&client1), session_ticket(&client2), "Expected different session tickets");
+}
+
+#[test]
+fn disconnected_client_keeps_channels_but_loses_connections() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    let response = handler.dsrp_client_disconnected(client1.id);
+    assert_eq!(response.len(), 1, "Expected a single operation but got {:?}", response);
+    assert_vec_contains!(response, ServerOperation::ResetConnection {connection} => {
+        assert_eq!(*connection, connection1, "Unexpected connection reset");
+    });
+
+    let response = handler.tcp_data_received(connection1, &[1, 2, 3]);
+    assert!(response.is_none(), "Expected no operation for data over a reset connection");
+}
+
+#[test]
+fn client_removed_on_disconnect_when_grace_period_is_zero() {
+    let config = ServerHandlerConfig {session_grace_period: Duration::from_secs(0), ..Default::default()};
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+
+    let response = handler.dsrp_client_disconnected(client1.id);
+    assert_vec_contains!(response, ServerOperation::StopTcpOperations {address: _, port} => {
+        assert_eq!(*port, 23, "Unexpected port stopped");
+    });
+}
+
+#[test]
+fn inbound_connections_refused_while_client_is_disconnected() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let tcp_channel = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let udp_channel = open_channel(&mut handler, client1.id, ConnectionType::Udp, 24);
+    let _ = handler.dsrp_client_disconnected(client1.id);
+
+    let error = handler.new_channel_tcp_connection(tcp_channel).unwrap_err();
+    match error.kind {
+        NewConnectionErrorKind::ClientDisconnected(id) => assert_eq!(id, client1.id, "Unexpected client id"),
+        x => panic!("Expected ClientDisconnected error, instead got {:?}", x),
+    }
+
+    let response = handler.udp_data_received(udp_channel, peer_address(), &[1, 2, 3]);
+    assert_eq!(response.len(), 0, "Expected no operations but got {:?}", response);
+}
+
+#[test]
+fn client_resumes_session_with_same_id_and_channels_when_presenting_ticket() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let _ = handler.dsrp_client_disconnected(client1.id);
+
+    let resumed_client = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    assert_eq!(resumed_client.id, client1.id, "Expected the same client id");
+    assert!(resumed(&resumed_client), "Expected the session to be resumed");
+    assert_ne!(session_ticket(&resumed_client), session_ticket(&client1), "Expected a new session ticket");
+
+    let (_, operation) = handler.new_channel_tcp_connection(channel1).unwrap();
+    match operation {
+        ServerOperation::SendMessageToDsrpClient {client, message: ServerMessage::NewIncomingTcpConnection {..}} => {
+            assert_eq!(client, client1.id, "Unexpected client notified");
+        },
+
+        x => panic!("Expected new connection message, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn resuming_client_still_challenged_when_secrets_are_configured() {
+    let mut handler = handler_requiring_auth_secret(b"secret");
+    let client1 = authenticated_client(&mut handler, b"secret");
+    let _ = handler.dsrp_client_disconnected(client1.id);
+
+    let client2 = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    match client2.response {
+        HandshakeResponse::Challenge {..} => (),
+        x => panic!("Expected challenge, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn resume_with_valid_ticket_refused_until_challenge_is_answered() {
+    let mut handler = handler_requiring_auth_secret(b"secret");
+    let client1 = authenticated_client(&mut handler, b"secret");
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let _ = handler.dsrp_client_disconnected(client1.id);
+
+    let client2 = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    let (_, message) = open_channel_request(ConnectionType::Tcp, 24);
+    let error = handler.handle_client_message(client2.id, message).unwrap_err();
+    match error.kind {
+        ClientMessageHandlingErrorKind::ClientNotAuthenticated(id) => assert_eq!(id, client2.id, "Unexpected client id"),
+        x => panic!("Expected ClientNotAuthenticated error, instead got {:?}", x),
+    }
+
+    let error = handler.new_channel_tcp_connection(channel1).unwrap_err();
+    match error.kind {
+        NewConnectionErrorKind::ClientDisconnected(id) => assert_eq!(id, client1.id, "Unexpected client id"),
+        x => panic!("Expected ClientDisconnected error, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn resume_with_valid_ticket_refused_when_challenge_answer_is_wrong() {
+    let mut handler = handler_requiring_auth_secret(b"secret");
+    let client1 = authenticated_client(&mut handler, b"secret");
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let _ = handler.dsrp_client_disconnected(client1.id);
+
+    let client2 = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    let answer = HandshakeChallengeAnswer {
+        hmac: compute_challenge_answer(b"wrong", &challenge_nonce(&client2)),
+    };
+
+    match handler.handle_challenge_answer(client2.id, answer).unwrap_err() {
+        HandshakeResponse::Failure {reason: _} => (),
+        x => panic!("Expected failure, instead got {:?}", x),
+    }
+
+    let error = handler.new_channel_tcp_connection(channel1).unwrap_err();
+    match error.kind {
+        NewConnectionErrorKind::ClientDisconnected(id) => assert_eq!(id, client1.id, "Unexpected client id"),
+        x => panic!("Expected ClientDisconnected error, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn resuming_client_gets_session_back_once_challenge_is_answered() {
+    let mut handler = handler_requiring_auth_secret(b"secret");
+    let client1 = authenticated_client(&mut handler, b"secret");
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let _ = handler.dsrp_client_disconnected(client1.id);
+
+    let client2 = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    let answer = HandshakeChallengeAnswer {
+        hmac: compute_challenge_answer(b"secret", &challenge_nonce(&client2)),
+    };
+
+    let resumed_client = handler.handle_challenge_answer(client2.id, answer).unwrap();
+    assert_eq!(resumed_client.id, client1.id, "Expected the same client id");
+    assert!(resumed(&resumed_client), "Expected the session to be resumed");
+
+    let (_, operation) = handler.new_channel_tcp_connection(channel1).unwrap();
+    match operation {
+        ServerOperation::SendMessageToDsrpClient {client, message: ServerMessage::NewIncomingTcpConnection {..}} => {
+            assert_eq!(client, client1.id, "Unexpected client notified");
+        },
+
+        x => panic!("Expected new connection message, instead got {:?}", x),
+    }
+
+    let (_, message) = open_channel_request(ConnectionType::Tcp, 24);
+    let error = handler.handle_client_message(client2.id, message).unwrap_err();
+    match error.kind {
+        ClientMessageHandlingErrorKind::UnknownClientId(id) => assert_eq!(id, client2.id, "Unexpected client id"),
+        x => panic!("Expected UnknownClientId error, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn session_ticket_takes_over_session_that_still_looks_connected() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client_with_identity(HandshakeRequest::new(), "alice".to_owned()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    let request = HandshakeRequest::resuming(session_ticket(&client1));
+    let client2 = handler.add_dsrp_client_with_identity(request, "alice".to_owned()).unwrap();
+    assert_eq!(client2.id, client1.id, "Expected the same client id");
+    assert!(resumed(&client2), "Expected the session to be resumed");
+    assert_vec_contains!(client2.operations, ServerOperation::ResetConnection {connection} => {
+        assert_eq!(*connection, connection1, "Unexpected connection reset");
+    });
+
+    let (_, operation) = handler.new_channel_tcp_connection(channel1).unwrap();
+    match operation {
+        ServerOperation::SendMessageToDsrpClient {client, message: ServerMessage::NewIncomingTcpConnection {..}} => {
+            assert_eq!(client, client1.id, "Unexpected client notified");
+        },
+
+        x => panic!("Expected new connection message, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn session_ticket_alone_cannot_take_over_connected_session() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+
+    let client2 = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    assert_ne!(client2.id, client1.id, "Expected a new client id");
+    assert!(!resumed(&client2), "Expected a new session");
+
+    let (_, operation) = handler.new_channel_tcp_connection(channel1).unwrap();
+    match operation {
+        ServerOperation::SendMessageToDsrpClient {client, message: ServerMessage::NewIncomingTcpConnection {..}} => {
+            assert_eq!(client, client1.id, "Unexpected client notified");
+        },
+
+        x => panic!("Expected new connection message, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn authenticated_client_takes_over_session_that_still_looks_connected() {
+    let mut handler = handler_requiring_auth_secret(b"secret");
+    let client1 = authenticated_client(&mut handler, b"secret");
+
+    let client2 = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    let answer = HandshakeChallengeAnswer {
+        hmac: compute_challenge_answer(b"secret", &challenge_nonce(&client2)),
+    };
+
+    let resumed_client = handler.handle_challenge_answer(client2.id, answer).unwrap();
+    assert_eq!(resumed_client.id, client1.id, "Expected the same client id");
+    assert!(resumed(&resumed_client), "Expected the session to be resumed");
+}
+
+#[test]
+fn session_ticket_cannot_take_over_connected_session_when_grace_period_is_zero() {
+    let config = ServerHandlerConfig {session_grace_period: Duration::from_secs(0), ..Default::default()};
+    let mut handler = ServerHandler::with_config(config, Box::new(TestClock::new()));
+    let client1 = handler.add_dsrp_client_with_identity(HandshakeRequest::new(), "alice".to_owned()).unwrap();
+
+    let request = HandshakeRequest::resuming(session_ticket(&client1));
+    let client2 = handler.add_dsrp_client_with_identity(request, "alice".to_owned()).unwrap();
+    assert_ne!(client2.id, client1.id, "Expected a new client id");
+    assert!(!resumed(&client2), "Expected a new session");
+}
+
+#[test]
+fn registration_answered_while_client_disconnected_is_sent_once_session_resumes() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let (request_id, message) = open_channel_request(ConnectionType::Tcp, 23);
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+    let mut channel1 = ChannelId(u32::MAX);
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port: _, channel} => {
+        channel1 = *channel;
+    });
+
+    let _ = handler.dsrp_client_disconnected(client1.id);
+    let operation = handler.socket_binding_successful(channel1);
+    assert!(operation.is_none(), "Expected no message for a disconnected client but got {:?}", operation);
+
+    let resumed_client = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    assert!(resumed(&resumed_client), "Expected the session to be resumed");
+    assert_vec_contains!(resumed_client.operations, ServerOperation::SendMessageToDsrpClient {
+        client,
+        message: ServerMessage::RegistrationSuccessful {request, created_channel, port}
+    } => {
+        assert_eq!(*client, client1.id, "Unexpected client notified");
+        assert_eq!(*request, request_id, "Unexpected request id");
+        assert_eq!(*created_channel, channel1, "Unexpected channel");
+        assert_eq!(*port, 23, "Unexpected port");
+    });
+}
+
+#[test]
+fn channel_revoked_while_client_disconnected_is_reported_once_session_resumes() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let _ = handler.dsrp_client_disconnected(client1.id);
+
+    let response = handler.socket_failed(channel1);
+    assert_vec_contains!(response, ServerOperation::StopTcpOperations {address: _, port} => {
+        assert_eq!(*port, 23, "Unexpected port stopped");
+    });
+
+    assert!(!response.iter().any(|operation| matches!(operation, ServerOperation::SendMessageToDsrpClient {..})),
+            "Expected no message for a disconnected client but got {:?}", response);
+
+    let resumed_client = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    assert!(resumed(&resumed_client), "Expected the session to be resumed");
+    assert_vec_contains!(resumed_client.operations, ServerOperation::SendMessageToDsrpClient {
+        client,
+        message: ServerMessage::ChannelRevoked {channel, reason}
+    } => {
+        assert_eq!(*client, client1.id, "Unexpected client notified");
+        assert_eq!(*channel, channel1, "Unexpected channel revoked");
+        assert_eq!(*reason, ChannelRevocationReason::SocketFailed, "Unexpected reason");
+    });
+}
+
+#[test]
+fn session_ticket_can_only_be_used_once() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let _ = handler.dsrp_client_disconnected(client1.id);
+    let _ = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    let _ = handler.dsrp_client_disconnected(client1.id);
+
+    let client2 = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    assert_ne!(client2.id, client1.id, "Expected a new client id");
+    assert!(!resumed(&client2), "Expected a new session");
+}
+
+#[test]
+fn session_ticket_requires_same_identity() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client_with_identity(HandshakeRequest::new(), "alice".to_owned()).unwrap();
+    let _ = handler.dsrp_client_disconnected(client1.id);
+
+    let request = HandshakeRequest::resuming(session_ticket(&client1));
+    let client2 = handler.add_dsrp_client_with_identity(request, "bob".to_owned()).unwrap();
+    assert_ne!(client2.id, client1.id, "Expected a new client id");
+    assert!(!resumed(&client2), "Expected a new session");
+}
+
+#[test]
+fn disconnected_client_removed_once_grace_period_expires() {
+    let clock = TestClock::new();
+    let config = ServerHandlerConfig {session_grace_period: Duration::from_secs(10), ..Default::default()};
+    let mut handler = ServerHandler::with_config(config, Box::new(clock.clone()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let _ = handler.dsrp_client_disconnected(client1.id);
+
+    clock.advance(Duration::from_secs(9));
+    let response = handler.tick(clock.now());
+    assert_eq!(response.len(), 0, "Expected no operations during the grace period");
+
+    clock.advance(Duration::from_secs(1));
+    let response = handler.tick(clock.now());
+    assert_vec_contains!(response, ServerOperation::StopTcpOperations {address: _, port} => {
+        assert_eq!(*port, 23, "Unexpected port stopped");
+    });
+
+    let client2 = handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))).unwrap();
+    assert!(!resumed(&client2), "Expected a new session after the grace period");
+}
+
+#[test]
+fn beginning_shutdown_notifies_clients_of_remaining_time() {
+    let clock = TestClock::new();
+    let mut handler = ServerHandler::with_config(ServerHandlerConfig::default(), Box::new(clock.clone()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let client2 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+
+    let response = handler.begin_shutdown(clock.now() + Duration::from_secs(30));
+    assert_eq!(response.len(), 2, "Expected a notice for each client but got {:?}", response);
+    for client_id in &[client1.id, client2.id] {
+        assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+            client,
+            message: ServerMessage::ServerShuttingDown {deadline}
+        } if client == client_id => {
+            assert_eq!(*deadline, Duration::from_secs(30), "Unexpected deadline");
+        });
+    }
+}
+
+#[test]
+fn new_clients_rejected_once_shutdown_begins() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let _ = handler.dsrp_client_disconnected(client1.id);
+    let _ = handler.begin_shutdown(Instant::now() + Duration::from_secs(30));
+
+    match handler.add_dsrp_client(HandshakeRequest::new()) {
+        Err(HandshakeResponse::ServerShuttingDown) => (),
+        x => panic!("Expected shutting down response, instead got {:?}", x),
+    }
+
+    match handler.add_dsrp_client(HandshakeRequest::resuming(session_ticket(&client1))) {
+        Err(HandshakeResponse::ServerShuttingDown) => (),
+        x => panic!("Expected shutting down response, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn challenged_client_turned_away_when_shutdown_begins_before_it_answers() {
+    let mut handler = handler_requiring_auth_secret(b"secret");
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let _ = handler.begin_shutdown(Instant::now() + Duration::from_secs(30));
+
+    let answer = HandshakeChallengeAnswer {
+        hmac: compute_challenge_answer(b"secret", &challenge_nonce(&client1)),
+    };
+
+    match handler.handle_challenge_answer(client1.id, answer) {
+        Err(HandshakeResponse::ServerShuttingDown) => (),
+        x => panic!("Expected shutting down response, instead got {:?}", x),
+    }
+}
+
+#[test]
+fn disconnected_clients_removed_when_shutdown_begins() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let _ = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let _ = handler.dsrp_client_disconnected(client1.id);
+
+    let response = handler.begin_shutdown(Instant::now() + Duration::from_secs(30));
+    assert_vec_contains!(response, ServerOperation::StopTcpOperations {address: _, port} => {
+        assert_eq!(*port, 23, "Unexpected port stopped");
+    });
+
+    assert!(handler.has_shut_down(), "Expected nothing left to shut down");
+}
+
+#[test]
+fn registrations_rejected_once_shutdown_begins() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let _ = handler.begin_shutdown(Instant::now() + Duration::from_secs(30));
+
+    let cause = RegistrationFailureCause::ServerShuttingDown;
+    assert_registration_failed(&mut handler, client1.id, ConnectionType::Tcp, 23, cause);
+
+    let message = ClientMessage::RegisterAnyPort {
+        request: RequestId(27),
+        connection_type: ConnectionType::Tcp,
+        port_range: None,
+        bind_address: None,
+    };
+
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client: _,
+        message: ServerMessage::RegistrationFailed {request: _, cause}
+    } => {
+        assert_eq!(*cause, RegistrationFailureCause::ServerShuttingDown, "Unexpected cause");
+    });
+}
+
+#[test]
+fn new_connections_refused_but_existing_ones_relayed_during_shutdown() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let tcp_channel = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let udp_channel = open_channel(&mut handler, client1.id, ConnectionType::Udp, 24);
+    let (connection1, _) = handler.new_channel_tcp_connection(tcp_channel).unwrap();
+    let _ = open_udp_connection(&mut handler, udp_channel, peer_address());
+    let _ = handler.begin_shutdown(Instant::now() + Duration::from_secs(30));
+
+    let error = handler.new_channel_tcp_connection(tcp_channel).unwrap_err();
+    match error.kind {
+        NewConnectionErrorKind::ServerShuttingDown => (),
+        x => panic!("Expected ServerShuttingDown error, instead got {:?}", x),
+    }
+
+    let response = handler.udp_data_received(udp_channel, second_peer_address(), &[1, 2, 3]);
+    assert_eq!(response.len(), 0, "Expected no operations for a new peer but got {:?}", response);
+
+    let response = handler.udp_data_received(udp_channel, peer_address(), &[1, 2, 3]);
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client: _,
+        message: ServerMessage::DataReceived {..}
+    });
+
+    let response = handler.tcp_data_received(connection1, &[1, 2, 3]);
+    assert!(response.is_some(), "Expected data over an existing connection to be relayed");
+}
+
+#[test]
+fn everything_torn_down_once_shutdown_deadline_passes() {
+    let clock = TestClock::new();
+    let mut handler = ServerHandler::with_config(ServerHandlerConfig::default(), Box::new(clock.clone()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+    let _ = handler.begin_shutdown(clock.now() + Duration::from_secs(30));
+
+    clock.advance(Duration::from_secs(29));
+    let response = handler.tick(clock.now());
+    assert_eq!(response.len(), 0, "Expected no operations before the deadline but got {:?}", response);
+    assert!(!handler.has_shut_down(), "Expected the shutdown to still be in progress");
+
+    clock.advance(Duration::from_secs(1));
+    let response = handler.tick(clock.now());
+    assert_vec_contains!(response, ServerOperation::StopTcpOperations {address: _, port} => {
+        assert_eq!(*port, 23, "Unexpected port stopped");
+    });
+
+    assert_vec_contains!(response, ServerOperation::DisconnectConnection {connection} => {
+        assert_eq!(*connection, connection1, "Unexpected connection disconnected");
+    });
+
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client,
+        message: ServerMessage::ChannelRevoked {channel, reason}
+    } => {
+        assert_eq!(*client, client1.id, "Unexpected client notified");
+        assert_eq!(*channel, channel1, "Unexpected channel revoked");
+        assert_eq!(*reason, ChannelRevocationReason::ServerShuttingDown, "Unexpected reason");
+    });
+
+    assert_vec_contains!(response, ServerOperation::DisconnectDsrpClient {client} => {
+        assert_eq!(*client, client1.id, "Unexpected client disconnected");
+    });
+
+    assert!(handler.has_shut_down(), "Expected the shutdown to be complete");
+}
+
+#[test]
+fn shutdown_completes_before_deadline_once_connections_drain() {
+    let clock = TestClock::new();
+    let mut handler = ServerHandler::with_config(ServerHandlerConfig::default(), Box::new(clock.clone()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+    let _ = handler.begin_shutdown(clock.now() + Duration::from_secs(30));
+
+    clock.advance(Duration::from_secs(5));
+    let response = handler.tick(clock.now());
+    assert_eq!(response.len(), 0, "Expected no operations while a connection is open but got {:?}", response);
+
+    let _ = handler.tcp_connection_disconnected(connection1);
+    let response = handler.tick(clock.now());
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client: _,
+        message: ServerMessage::ChannelRevoked {channel, reason}
+    } => {
+        assert_eq!(*channel, channel1, "Unexpected channel revoked");
+        assert_eq!(*reason, ChannelRevocationReason::ServerShuttingDown, "Unexpected reason");
+    });
+
+    assert_vec_contains!(response, ServerOperation::DisconnectDsrpClient {client} => {
+        assert_eq!(*client, client1.id, "Unexpected client disconnected");
+    });
+
+    assert!(handler.has_shut_down(), "Expected the shutdown to be complete");
+}
+
+#[test]
+fn no_operation_returned_if_udp_data_received_on_unknown_channel() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+
+    let bad_channel = ChannelId(channel1.0 + 1);
+    let received_data = [1, 2, 3, 4, 5, 6];
+    let response = handler.udp_data_received(bad_channel, peer_address(), &received_data);
+    assert_eq!(response.len(), 0, "Expected no operations but got {:?}", response);
+}
+
+#[test]
+fn no_operation_returned_if_tcp_data_received_on_unknown_connection() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    let bad_connection = ConnectionId(connection1.0 + 1);
+    let received_data = [1, 2, 3, 4, 5, 6];
+    match handler.tcp_data_received(bad_connection, &received_data) {
+        None => (),
+        Some(_) => panic!("Expected no operation returned but one came back"),
+    }
+}
+
+#[test]
+fn client_message_of_tcp_connection_closed_returns_disconnect_operation() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    let message = ClientMessage::TcpConnectionDisconnected {
+        channel: channel1,
+        connection: connection1,
+    };
+
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+    assert_vec_contains!(response, ServerOperation::DisconnectConnection {
+        connection: closed_connection
+    } => {
+        assert_eq!(*closed_connection, connection1, "Unexpected connection returned in operation");
+    });
+}
+
+#[test]
+fn no_operation_when_client_reports_disconnection_of_unknown_connection_id() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    let message = ClientMessage::TcpConnectionDisconnected {
+        channel: channel1,
+        connection: ConnectionId(connection1.0 + 1),
+    };
+
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+    assert_eq!(response.len(), 0, "Unexpected number of operations returned");
+}
+
+#[test]
+fn no_operation_when_client_reports_disconnection_of_connection_id_belonging_to_another_client() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let client2 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    let message = ClientMessage::TcpConnectionDisconnected {
+        channel: channel1,
+        connection: connection1,
+    };
+
+    let response = handler.handle_client_message(client2.id, message).unwrap();
+    assert_eq!(response.len(), 0, "Unexpected number of operations returned");
+}
+
+#[test]
+fn no_operation_when_client_reports_disconnection_of_connection_id_belonging_to_another_channel() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let channel2 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 24);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    let message = ClientMessage::TcpConnectionDisconnected {
+        channel: channel2,
+        connection: connection1,
+    };
+
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+    assert_eq!(response.len(), 0, "Unexpected number of operations returned");
+}
+
+#[test]
+fn no_operation_returned_if_tcp_data_received_but_connection_disconnected_by_dsrp_client() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    let message = ClientMessage::TcpConnectionDisconnected {
+        channel: channel1,
+        connection: connection1,
+    };
+
+    let _ = handler.handle_client_message(client1.id, message).unwrap(); // assumes success
+
+    match handler.tcp_data_received(connection1, &[1,2,3,4]) {
+        None => (),
+        Some(_) => panic!("Expected no operation returned but one came back"),
+    }
+}
+
+#[test]
+fn remote_half_close_notifies_dsrp_client() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    let results = handler.tcp_connection_half_closed(connection1);
+    assert_eq!(results.len(), 1, "Unexpected number of operations returned");
+    assert_vec_contains!(results, ServerOperation::SendMessageToDsrpClient {
+        client,
+        message: ServerMessage::TcpConnectionHalfClosed {channel, connection}
+    } => {
+        assert_eq!(*client, client1.id, "Unexpected client id");
+        assert_eq!(*channel, channel1, "Unexpected channel identifier");
+        assert_eq!(*connection, connection1, "Unexpected connection identifier");
+    });
+
+    // The client can still send data back to the remote peer
+    let message = ClientMessage::DataBeingSent {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1,2,3],
+    };
+
+    let results = handler.handle_client_message(client1.id, message).unwrap();
+    assert_vec_contains!(results, ServerOperation::SendByteData {channel: _, connection: _, peer_address: _, data: _});
 }
 
 #[test]
-fn no_operation_returned_if_udp_data_received_on_unknown_channel() {
+fn client_half_close_returns_shutdown_write_operation() {
     let mut handler = ServerHandler::new();
     let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
-    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
 
-    let bad_channel = ChannelId(channel1.0 + 1);
-    let received_data = [1, 2, 3, 4, 5, 6];
-    match handler.udp_data_received(bad_channel, &received_data) {
-        None => (),
-        Some(_) => panic!("Expected no operation but got one"),
-    }
+    let message = ClientMessage::TcpConnectionHalfClosed {channel: channel1, connection: connection1};
+    let results = handler.handle_client_message(client1.id, message).unwrap();
+    assert_eq!(results.len(), 1, "Unexpected number of operations returned");
+    assert_vec_contains!(results, ServerOperation::ShutdownConnectionWrite {connection} => {
+        assert_eq!(*connection, connection1, "Unexpected connection identifier");
+    });
+
+    // The remote peer can still send data to the client
+    let result = handler.tcp_data_received(connection1, &[1,2,3]);
+    assert!(result.is_some(), "Expected data to still be relayed");
 }
 
 #[test]
-fn no_operation_returned_if_tcp_data_received_on_unknown_connection() {
+fn connection_disconnected_once_both_sides_half_close() {
     let mut handler = ServerHandler::new();
     let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
     let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
     let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+    let (connection2, _) = handler.new_channel_tcp_connection(channel1).unwrap();
 
-    let bad_connection = ConnectionId(connection1.0 + 1);
-    let received_data = [1, 2, 3, 4, 5, 6];
-    match handler.tcp_data_received(bad_connection, &received_data) {
-        None => (),
-        Some(_) => panic!("Expected no operation returned but one came back"),
-    }
+    // Remote peer finishes first
+    let _ = handler.tcp_connection_half_closed(connection1);
+    let message = ClientMessage::TcpConnectionHalfClosed {channel: channel1, connection: connection1};
+    let results = handler.handle_client_message(client1.id, message).unwrap();
+    assert_vec_contains!(results, ServerOperation::DisconnectConnection {connection} if *connection == connection1);
+
+    // Client finishes first
+    let message = ClientMessage::TcpConnectionHalfClosed {channel: channel1, connection: connection2};
+    let _ = handler.handle_client_message(client1.id, message).unwrap();
+    let results = handler.tcp_connection_half_closed(connection2);
+    assert_vec_contains!(results, ServerOperation::SendMessageToDsrpClient {
+        client: _,
+        message: ServerMessage::TcpConnectionHalfClosed {channel: _, connection}
+    } if *connection == connection2);
+    assert_vec_contains!(results, ServerOperation::DisconnectConnection {connection} if *connection == connection2);
+
+    assert!(handler.tcp_data_received(connection1, &[1]).is_none(), "Expected connection1 to be closed");
+    assert!(handler.tcp_data_received(connection2, &[1]).is_none(), "Expected connection2 to be closed");
 }
 
 #[test]
-fn client_message_of_tcp_connection_closed_returns_disconnect_operation() {
+fn no_operation_when_client_half_closes_connection_belonging_to_another_client() {
     let mut handler = ServerHandler::new();
     let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let client2 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
     let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
     let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
 
-    let message = ClientMessage::TcpConnectionDisconnected {
-        channel: channel1,
-        connection: connection1,
-    };
-
-    let response = handler.handle_client_message(client1.id, message).unwrap();
-    assert_vec_contains!(response, ServerOperation::DisconnectConnection {
-        connection: closed_connection
-    } => {
-        assert_eq!(*closed_connection, connection1, "Unexpected connection returned in operation");
-    });
+    let message = ClientMessage::TcpConnectionHalfClosed {channel: channel1, connection: connection1};
+    let results = handler.handle_client_message(client2.id, message).unwrap();
+    assert_eq!(results.len(), 0, "Unexpected number of operations returned");
 }
 
 #[test]
-fn no_operation_when_client_reports_disconnection_of_unknown_connection_id() {
+fn client_message_of_tcp_connection_failed_returns_reset_operation() {
     let mut handler = ServerHandler::new();
     let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
     let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
     let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
 
-    let message = ClientMessage::TcpConnectionDisconnected {
+    let message = ClientMessage::TcpConnectionFailed {
         channel: channel1,
-        connection: ConnectionId(connection1.0 + 1),
+        connection: connection1,
+        reason: ConnectionFailureReason::ConnectionRefused,
     };
 
     let response = handler.handle_client_message(client1.id, message).unwrap();
-    assert_eq!(response.len(), 0, "Unexpected number of operations returned");
+    assert_vec_contains!(response, ServerOperation::ResetConnection {
+        connection: reset_connection
+    } => {
+        assert_eq!(*reset_connection, connection1, "Unexpected connection returned in operation");
+    });
+
+    match handler.tcp_data_received(connection1, &[1,2,3,4]) {
+        None => (),
+        Some(_) => panic!("Expected no operation returned but one came back"),
+    }
 }
 
 #[test]
-fn no_operation_when_client_reports_disconnection_of_connection_id_belonging_to_another_client() {
+fn no_operation_when_client_reports_failure_of_connection_id_belonging_to_another_client() {
     let mut handler = ServerHandler::new();
     let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
     let client2 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
     let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
     let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
 
-    let message = ClientMessage::TcpConnectionDisconnected {
+    let message = ClientMessage::TcpConnectionFailed {
         channel: channel1,
         connection: connection1,
+        reason: ConnectionFailureReason::ConnectionRefused,
     };
 
     let response = handler.handle_client_message(client2.id, message).unwrap();
@@ -716,40 +2342,53 @@ fn no_operation_when_client_reports_disconnection_of_connection_id_belonging_to_
 }
 
 #[test]
-fn no_operation_when_client_reports_disconnection_of_connection_id_belonging_to_another_channel() {
+fn client_message_of_udp_connection_failed_stops_tracking_peer() {
     let mut handler = ServerHandler::new();
     let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
-    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
-    let channel2 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 24);
-    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let connection1 = open_udp_connection(&mut handler, channel1, peer_address());
 
-    let message = ClientMessage::TcpConnectionDisconnected {
-        channel: channel2,
+    let message = ClientMessage::UdpConnectionFailed {
+        channel: channel1,
         connection: connection1,
+        reason: ConnectionFailureReason::Other,
     };
 
     let response = handler.handle_client_message(client1.id, message).unwrap();
-    assert_eq!(response.len(), 0, "Unexpected number of operations returned");
+    assert_eq!(response.len(), 0, "Unexpected operations returned: {:?}", response);
+
+    let message = ClientMessage::DataBeingSent {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![1, 2, 3],
+    };
+
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+    assert_eq!(response.len(), 0, "Expected no operations for data sent over failed connection");
+
+    let connection2 = open_udp_connection(&mut handler, channel1, peer_address());
+    assert_ne!(connection2, connection1, "Expected the peer to be announced as a new connection");
+    assert_eq!(handler.stats().connection_counts.failed, 1, "Unexpected failed connection count");
 }
 
 #[test]
-fn no_operation_returned_if_tcp_data_received_but_connection_disconnected_by_dsrp_client() {
+fn no_operation_when_client_reports_udp_failure_of_connection_belonging_to_another_client() {
     let mut handler = ServerHandler::new();
     let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
-    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
-    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+    let client2 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let connection1 = open_udp_connection(&mut handler, channel1, peer_address());
 
-    let message = ClientMessage::TcpConnectionDisconnected {
+    let message = ClientMessage::UdpConnectionFailed {
         channel: channel1,
         connection: connection1,
+        reason: ConnectionFailureReason::Other,
     };
 
-    let _ = handler.handle_client_message(client1.id, message).unwrap(); // assumes success
-
-    match handler.tcp_data_received(connection1, &[1,2,3,4]) {
-        None => (),
-        Some(_) => panic!("Expected no operation returned but one came back"),
-    }
+    let _ = handler.handle_client_message(client2.id, message).unwrap();
+    let response = handler.udp_data_received(channel1, peer_address(), &[1, 2, 3]);
+    assert_eq!(response.len(), 1, "Expected the peer's connection to still be tracked");
 }
 
 #[test]
@@ -762,13 +2401,15 @@ fn send_byte_data_operation_when_data_comes_in_from_dsrp_client_with_valid_tcp_c
     let message = ClientMessage::DataBeingSent {
         channel: channel1,
         connection: Some(connection1),
+        peer_address: None,
         data: vec![1,2,3,4,5],
     };
 
     let response = handler.handle_client_message(client1.id, message).unwrap();
-    assert_vec_contains!(response, ServerOperation::SendByteData {channel, connection, data} => {
+    assert_vec_contains!(response, ServerOperation::SendByteData {channel, connection, peer_address, data} => {
        assert_eq!(*channel, channel1, "Unexpected channel seen");
        assert_eq!(*connection, Some(connection1), "Unexpected connection seen");
+       assert_eq!(*peer_address, None, "Unexpected peer address seen");
        assert_eq!(data, &[1_u8,2,3,4,5], "Unexpected data seen");
     });
 }
@@ -778,17 +2419,20 @@ fn send_byte_data_operation_when_data_comes_in_from_dsrp_client_with_valid_udp_c
     let mut handler = ServerHandler::new();
     let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
     let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let connection1 = open_udp_connection(&mut handler, channel1, peer_address());
 
     let message = ClientMessage::DataBeingSent {
         channel: channel1,
-        connection: None,
+        connection: Some(connection1),
+        peer_address: None,
         data: vec![1,2,3,4,5],
     };
 
     let response = handler.handle_client_message(client1.id, message).unwrap();
-    assert_vec_contains!(response, ServerOperation::SendByteData {channel, connection, data} => {
+    assert_vec_contains!(response, ServerOperation::SendByteData {channel, connection, peer_address: address, data} => {
        assert_eq!(*channel, channel1, "Unexpected channel seen");
-       assert_eq!(*connection, None, "Unexpected connection seen");
+       assert_eq!(*connection, Some(connection1), "Unexpected connection seen");
+       assert_eq!(*address, Some(peer_address()), "Unexpected peer address seen");
        assert_eq!(data, &[1_u8,2,3,4,5], "Unexpected data seen");
     });
 }
@@ -803,6 +2447,7 @@ fn no_operation_when_data_sent_over_unknown_connection() {
     let message = ClientMessage::DataBeingSent {
         channel: channel1,
         connection: Some(ConnectionId(connection1.0 + 1)),
+        peer_address: None,
         data: vec![1,2,3,4,5],
     };
 
@@ -820,6 +2465,7 @@ fn no_operation_when_data_sent_over_unknown_channel() {
     let message = ClientMessage::DataBeingSent {
         channel: ChannelId(channel1.0  +1),
         connection: Some(connection1),
+        peer_address: None,
         data: vec![1,2,3,4,5],
     };
 
@@ -838,6 +2484,7 @@ fn no_operation_when_data_sent_over_connection_not_owned_by_specified_channel()
     let message = ClientMessage::DataBeingSent {
         channel: channel2,
         connection: Some(connection1),
+        peer_address: None,
         data: vec![1,2,3,4,5],
     };
 
@@ -856,6 +2503,7 @@ fn no_operation_when_data_sent_over_channel_not_owned_by_client() {
     let message = ClientMessage::DataBeingSent {
         channel: channel1,
         connection: Some(connection1),
+        peer_address: None,
         data: vec![1,2,3,4,5],
     };
 
@@ -873,6 +2521,7 @@ fn no_operation_when_data_sent_no_connection_specified_for_tcp_channel() {
     let message = ClientMessage::DataBeingSent {
         channel: channel1,
         connection: None,
+        peer_address: None,
         data: vec![1,2,3,4,5],
     };
 
@@ -880,6 +2529,60 @@ fn no_operation_when_data_sent_no_connection_specified_for_tcp_channel() {
     assert_eq!(response.len(), 0, "Unexpected number of operations returned");
 }
 
+#[test]
+fn no_operation_when_data_sent_over_udp_channel_without_connection() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let _ = open_udp_connection(&mut handler, channel1, peer_address());
+
+    let message = ClientMessage::DataBeingSent {
+        channel: channel1,
+        connection: None,
+        peer_address: Some(peer_address()),
+        data: vec![1,2,3,4,5],
+    };
+
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+    assert_eq!(response.len(), 0, "Unexpected number of operations returned");
+}
+
+#[test]
+fn no_operation_when_data_sent_over_udp_connection_with_different_peer_address() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let connection1 = open_udp_connection(&mut handler, channel1, peer_address());
+
+    let message = ClientMessage::DataBeingSent {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: Some(second_peer_address()),
+        data: vec![1,2,3,4,5],
+    };
+
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+    assert_eq!(response.len(), 0, "Unexpected number of operations returned");
+}
+
+#[test]
+fn no_operation_when_data_sent_over_tcp_connection_with_peer_address() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    let message = ClientMessage::DataBeingSent {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: Some(peer_address()),
+        data: vec![1,2,3,4,5],
+    };
+
+    let response = handler.handle_client_message(client1.id, message).unwrap();
+    assert_eq!(response.len(), 0, "Unexpected number of operations returned");
+}
+
 #[test]
 fn no_operation_when_server_receives_udp_data_over_unbound_channel() {
     let mut handler = ServerHandler::new();
@@ -891,20 +2594,18 @@ fn no_operation_when_server_receives_udp_data_over_unbound_channel() {
         connection_type: ConnectionType::Udp,
         port: 23,
         request: request_id,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client1.id, message).unwrap();
-    assert_vec_contains!(response, ServerOperation::StartUdpOperations {port: _, channel}
+    assert_vec_contains!(response, ServerOperation::StartUdpOperations {address: _, port: _, channel}
     => {
         opened_channel = *channel;
     });
 
     let data = vec![1,2,3];
-    let operation = handler.udp_data_received(opened_channel, &data);
-    match operation {
-        None => (),
-        Some(x) => panic!("Expected no operations but got {:?}", x),
-    }
+    let response = handler.udp_data_received(opened_channel, peer_address(), &data);
+    assert_eq!(response.len(), 0, "Expected no operations but got {:?}", response);
 }
 
 #[test]
@@ -918,10 +2619,11 @@ fn error_returned_when_server_receives_tcp_connection_over_unbound_channel() {
         connection_type: ConnectionType::Tcp,
         port: 23,
         request: request_id,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client1.id, message).unwrap();
-    assert_vec_contains!(response, ServerOperation::StartTcpOperations {port: _, channel}
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port: _, channel}
     => {
         opened_channel = *channel;
     });
@@ -936,6 +2638,269 @@ fn error_returned_when_server_receives_tcp_connection_over_unbound_channel() {
     }
 }
 
+#[test]
+fn tcp_traffic_counted_in_both_directions() {
+    let clock = TestClock::new();
+    let mut handler = ServerHandler::with_config(ServerHandlerConfig::default(), Box::new(clock.clone()));
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+
+    let _ = handler.tcp_data_received(connection1, &[1, 2, 3]);
+    let _ = handler.tcp_data_received(connection1, &[4, 5]);
+    let message = ClientMessage::DataBeingSent {
+        channel: channel1,
+        connection: Some(connection1),
+        peer_address: None,
+        data: vec![6, 7, 8, 9],
+    };
+
+    let _ = handler.handle_client_message(client1.id, message).unwrap();
+
+    let stats = handler.stats();
+    assert_eq!(stats.traffic.inbound, TrafficCounters {bytes: 5, packets: 2}, "Unexpected inbound traffic");
+    assert_eq!(stats.traffic.outbound, TrafficCounters {bytes: 4, packets: 1}, "Unexpected outbound traffic");
+    assert_eq!(stats.open_connections, 1, "Unexpected open connection count");
+
+    let client = &stats.clients[0];
+    assert_eq!(client.id, client1.id, "Unexpected client");
+    assert_eq!(client.connected_at, clock.now(), "Unexpected client connection time");
+    assert_eq!(client.traffic, stats.traffic, "Expected the client to have all the traffic");
+
+    let channel = &client.channels[0];
+    assert_eq!(channel.id, channel1, "Unexpected channel");
+    assert_eq!(channel.port, 23, "Unexpected port");
+    assert_eq!(channel.traffic, stats.traffic, "Expected the channel to have all the traffic");
+
+    let connection = &channel.connections[0];
+    assert_eq!(connection.id, connection1, "Unexpected connection");
+    assert_eq!(connection.peer_address, None, "Unexpected peer address");
+    assert_eq!(connection.traffic, stats.traffic, "Expected the connection to have all the traffic");
+}
+
+#[test]
+fn udp_traffic_counted_per_peer() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Udp, 23);
+    let connection1 = open_udp_connection(&mut handler, channel1, peer_address());
+    let _ = open_udp_connection(&mut handler, channel1, second_peer_address());
+    let _ = handler.udp_data_received(channel1, peer_address(), &[1, 2]);
+
+    let stats = handler.stats();
+    assert_eq!(stats.traffic.inbound, TrafficCounters {bytes: 4, packets: 3}, "Unexpected inbound traffic");
+    assert_eq!(stats.connection_counts.opened, 2, "Unexpected opened connection count");
+    assert_eq!(stats.clients[0].open_connections, 2, "Unexpected open connection count");
+
+    let connection = stats.clients[0].channels[0].connections.iter()
+        .find(|connection| connection.id == connection1)
+        .unwrap();
+
+    assert_eq!(connection.peer_address, Some(peer_address()), "Unexpected peer address");
+    assert_eq!(connection.traffic.inbound, TrafficCounters {bytes: 3, packets: 2}, "Unexpected connection traffic");
+}
+
+#[test]
+fn totals_include_closed_connections_and_failures() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+    let (connection2, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+    let _ = handler.tcp_data_received(connection1, &[1, 2, 3]);
+    let _ = handler.tcp_connection_disconnected(connection1);
+
+    let message = ClientMessage::TcpConnectionFailed {
+        channel: channel1,
+        connection: connection2,
+        reason: ConnectionFailureReason::ConnectionRefused,
+    };
+
+    let _ = handler.handle_client_message(client1.id, message).unwrap();
+
+    let stats = handler.stats();
+    let expected_counts = ConnectionCounts {opened: 2, failed: 1};
+    assert_eq!(stats.connection_counts, expected_counts, "Unexpected server connection counts");
+    assert_eq!(stats.clients[0].connection_counts, expected_counts, "Unexpected client connection counts");
+    assert_eq!(stats.clients[0].channels[0].connection_counts, expected_counts, "Unexpected channel connection counts");
+    assert_eq!(stats.clients[0].channels[0].traffic.inbound.bytes, 3, "Unexpected channel traffic");
+    assert_eq!(stats.open_connections, 0, "Expected no open connections");
+}
+
+#[test]
+fn client_traffic_kept_after_its_channel_is_closed() {
+    let mut handler = ServerHandler::new();
+    let client1 = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let channel1 = open_channel(&mut handler, client1.id, ConnectionType::Tcp, 23);
+    let (connection1, _) = handler.new_channel_tcp_connection(channel1).unwrap();
+    let _ = handler.tcp_data_received(connection1, &[1, 2, 3]);
+    let _ = handler.handle_client_message(client1.id, ClientMessage::Unregister {channel: channel1}).unwrap();
+
+    let stats = handler.stats();
+    assert_eq!(stats.clients[0].channels.len(), 0, "Expected no active channels");
+    assert_eq!(stats.clients[0].traffic.inbound.bytes, 3, "Unexpected client traffic");
+    assert_eq!(stats.traffic.inbound.bytes, 3, "Unexpected server traffic");
+}
+
+fn handler_requiring_auth_secret(secret: &[u8]) -> ServerHandler {
+    let mut config = ServerHandlerConfig::default();
+    config.auth_secrets.push(secret.to_vec());
+    ServerHandler::with_config(config, Box::new(TestClock::new()))
+}
+
+fn handler_with_identity_permissions(identity: &str, ports: RangeInclusive<u16>) -> ServerHandler {
+    let mut config = ServerHandlerConfig::default();
+    config.identity_port_permissions.insert(identity.to_owned(), vec![ports]);
+    ServerHandler::with_config(config, Box::new(TestClock::new()))
+}
+
+fn assert_registration_failed(handler: &mut ServerHandler,
+                              client_id: ClientId,
+                              connection_type: ConnectionType,
+                              port: u16,
+                              expected_cause: RegistrationFailureCause) {
+    let request_id = RequestId(26);
+    let message = ClientMessage::Register {
+        connection_type,
+        port,
+        request: request_id,
+        bind_address: None,
+    };
+
+    let response = handler.handle_client_message(client_id, message).unwrap();
+    assert_eq!(response.len(), 1, "Unexpected number of operations returned");
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client: intended_client_id,
+        message: ServerMessage::RegistrationFailed {request: response_request_id, cause}
+    } => {
+        assert_eq!(*response_request_id, request_id, "Unexpected request id in response");
+        assert_eq!(*cause, expected_cause, "Unexpected cause");
+        assert_eq!(*intended_client_id, client_id, "Unexpected client id");
+    });
+}
+
+/// Registers a tcp channel on a port chosen by the server, returning the chosen port after
+/// verifying it's reported back to the client once bound
+fn register_any_port(handler: &mut ServerHandler, client_id: ClientId, port_range: Option<RangeInclusive<u16>>) -> u16 {
+    let message = ClientMessage::RegisterAnyPort {
+        request: RequestId(25),
+        connection_type: ConnectionType::Tcp,
+        port_range,
+        bind_address: None,
+    };
+
+    let response = handler.handle_client_message(client_id, message).unwrap();
+    let mut chosen_port = 0;
+    let mut opened_channel = ChannelId(u32::MAX);
+    assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port, channel} => {
+        chosen_port = *port;
+        opened_channel = *channel;
+    });
+
+    let operation = handler.socket_binding_successful(opened_channel).unwrap();
+    match operation {
+        ServerOperation::SendMessageToDsrpClient {
+            client: _,
+            message: ServerMessage::RegistrationSuccessful {request: _, created_channel, port}
+        } => {
+            assert_eq!(created_channel, opened_channel, "Unexpected channel id");
+            assert_eq!(port, chosen_port, "Unexpected port reported to client");
+        },
+
+        x => panic!("Expected registration success message to dsrp client, instead got: {:?}", x),
+    }
+
+    chosen_port
+}
+
+fn vpn_address() -> IpAddr {
+    "10.8.0.1".parse().unwrap()
+}
+
+fn public_address() -> IpAddr {
+    "203.0.113.7".parse().unwrap()
+}
+
+fn handler_with_bind_addresses() -> ServerHandler {
+    let config = ServerHandlerConfig {
+        default_bind_address: public_address(),
+        bind_addresses: vec![vpn_address()],
+        ..ServerHandlerConfig::default()
+    };
+
+    ServerHandler::with_config(config, Box::new(TestClock::new()))
+}
+
+fn register_on_address(handler: &mut ServerHandler,
+                       client_id: ClientId,
+                       bind_address: Option<IpAddr>,
+                       port: u16) -> Vec<ServerOperation> {
+    let message = ClientMessage::Register {
+        request: RequestId(25),
+        connection_type: ConnectionType::Tcp,
+        port,
+        bind_address,
+    };
+
+    handler.handle_client_message(client_id, message).unwrap()
+}
+
+fn authenticated_client(handler: &mut ServerHandler, secret: &[u8]) -> NewClient {
+    let client = handler.add_dsrp_client(HandshakeRequest::new()).unwrap();
+    let answer = HandshakeChallengeAnswer {
+        hmac: compute_challenge_answer(secret, &challenge_nonce(&client)),
+    };
+
+    handler.handle_challenge_answer(client.id, answer).unwrap()
+}
+
+fn session_ticket(new_client: &NewClient) -> SessionTicket {
+    match new_client.response {
+        HandshakeResponse::Success {session_ticket, resumed: _} => session_ticket,
+        ref x => panic!("Expected success, instead got {:?}", x),
+    }
+}
+
+fn resumed(new_client: &NewClient) -> bool {
+    match new_client.response {
+        HandshakeResponse::Success {session_ticket: _, resumed} => resumed,
+        ref x => panic!("Expected success, instead got {:?}", x),
+    }
+}
+
+fn challenge_nonce(new_client: &NewClient) -> [u8; CHALLENGE_NONCE_LENGTH] {
+    match new_client.response {
+        HandshakeResponse::Challenge {nonce} => nonce,
+        ref x => panic!("Expected challenge, instead got {:?}", x),
+    }
+}
+
+fn open_channel_request(connection_type: ConnectionType, port: u16) -> (RequestId, ClientMessage) {
+    let request = RequestId(25);
+    (request, ClientMessage::Register {bind_address: None, connection_type, port, request})
+}
+
+fn peer_address() -> SocketAddr {
+    "192.168.1.10:5000".parse().unwrap()
+}
+
+fn second_peer_address() -> SocketAddr {
+    "192.168.1.11:5000".parse().unwrap()
+}
+
+fn open_udp_connection(handler: &mut ServerHandler, channel_id: ChannelId, peer: SocketAddr) -> ConnectionId {
+    let response = handler.udp_data_received(channel_id, peer, &[0]);
+    let mut opened_connection = ConnectionId(u32::MAX);
+    assert_vec_contains!(response, ServerOperation::SendMessageToDsrpClient {
+        client: _,
+        message: ServerMessage::NewIncomingUdpConnection {channel: _, new_connection, peer_address: _}
+    } => {
+        opened_connection = *new_connection;
+    });
+
+    opened_connection
+}
+
 fn open_channel(handler: &mut ServerHandler,
                 client_id: ClientId,
                 connection_type: ConnectionType,
@@ -946,19 +2911,20 @@ fn open_channel(handler: &mut ServerHandler,
         connection_type: connection_type.clone(),
         port,
         request: request_id,
+        bind_address: None,
     };
 
     let response = handler.handle_client_message(client_id, message).unwrap();
     match connection_type {
         ConnectionType::Tcp => {
-            assert_vec_contains!(response, ServerOperation::StartTcpOperations {port: _, channel}
+            assert_vec_contains!(response, ServerOperation::StartTcpOperations {address: _, port: _, channel}
             => {
                 opened_channel = *channel;
             });
         },
 
         ConnectionType::Udp => {
-            assert_vec_contains!(response, ServerOperation::StartUdpOperations {port: _, channel}
+            assert_vec_contains!(response, ServerOperation::StartUdpOperations {address: _, port: _, channel}
             => {
                 opened_channel = *channel;
             });
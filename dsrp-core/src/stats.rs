@@ -0,0 +1,53 @@
+/// Amount of data relayed in a single direction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficCounters {
+    pub bytes: u64,
+
+    /// Number of separate payloads the bytes arrived in, such as udp packets or tcp reads
+    pub packets: u64,
+}
+
+/// Amount of data relayed in both directions.  Inbound traffic came from remote peers and is
+/// headed for the application server, while outbound traffic is the application server's
+/// responses headed back to the remote peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub inbound: TrafficCounters,
+    pub outbound: TrafficCounters,
+}
+
+/// Number of connections that have been opened, including ones that have since been closed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionCounts {
+    pub opened: u64,
+
+    /// Connections the DSRP client could not open to the application server
+    pub failed: u64,
+}
+
+impl TrafficCounters {
+    fn record(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        self.packets += 1;
+    }
+}
+
+impl TrafficStats {
+    pub(crate) fn record_inbound(&mut self, bytes: usize) {
+        self.inbound.record(bytes);
+    }
+
+    pub(crate) fn record_outbound(&mut self, bytes: usize) {
+        self.outbound.record(bytes);
+    }
+}
+
+impl ConnectionCounts {
+    pub(crate) fn record_opened(&mut self) {
+        self.opened += 1;
+    }
+
+    pub(crate) fn record_failed(&mut self) {
+        self.failed += 1;
+    }
+}
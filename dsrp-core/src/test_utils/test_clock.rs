@@ -0,0 +1,25 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use clock::Clock;
+
+/// Clock whose time only moves when a test advances it
+#[derive(Clone)]
+pub struct TestClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl TestClock {
+    pub fn new() -> Self {
+        TestClock { now: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
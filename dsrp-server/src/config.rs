@@ -0,0 +1,188 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+use dsrp_core::server_handler::ServerHandlerConfig;
+
+const USAGE: &str = "Usage: dsrp-server [--listen <address:port>] [--channel-ip <ip>] [--bind-address <ip>]... \
+                     [--udp-idle-timeout <seconds>] [--session-grace-period <seconds>] [--shutdown-deadline <seconds>] \
+                     [--auth-secret <secret>]... \
+                     [--tls-cert <cert.pem> --tls-key <key.pem> [--tls-client-ca <ca.pem>]] \
+                     [--identity-ports <identity>=<ports>]... \
+                     [--tcp-allow <ports>]... [--tcp-deny <ports>]... \
+                     [--udp-allow <ports>]... [--udp-deny <ports>]... \
+                     [--max-channels-per-client <count>] [--max-connections-per-channel <count>] \
+                     [--max-connections-per-client <count>] [--ephemeral-ports <start-end>]\n\
+                     where <ports> is a comma separated list of single ports or start-end ranges";
+
+/// Settings the DSRP server is started with
+pub struct ServerConfig {
+    /// Address DSRP clients connect to
+    pub listen_address: SocketAddr,
+
+    /// PEM file holding the certificate chain presented to DSRP clients.  Tls is only used on
+    /// the DSRP client connections when this and the key are provided.
+    pub tls_cert_path: Option<PathBuf>,
+
+    /// PEM file holding the private key for the tls certificate
+    pub tls_key_path: Option<PathBuf>,
+
+    /// PEM file holding the CA certificates that DSRP clients' certificates must be signed by.
+    /// When provided, clients must present a certificate and are identified by it.
+    pub tls_client_ca_path: Option<PathBuf>,
+
+    /// How long existing connections are given to finish once the server is asked to shut down
+    pub shutdown_deadline: Duration,
+
+    /// Settings passed along to the server handler
+    pub handler_config: ServerHandlerConfig,
+}
+
+impl ServerConfig {
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut config = ServerConfig {
+            listen_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 6142),
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_client_ca_path: None,
+            shutdown_deadline: Duration::from_secs(30),
+            handler_config: ServerHandlerConfig::default(),
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--listen" => config.listen_address = parse_value(&arg, args.next())?,
+                "--channel-ip" => config.handler_config.default_bind_address = parse_value(&arg, args.next())?,
+                "--bind-address" => config.handler_config.bind_addresses.push(parse_value(&arg, args.next())?),
+                "--tls-cert" => config.tls_cert_path = Some(parse_value(&arg, args.next())?),
+                "--tls-key" => config.tls_key_path = Some(parse_value(&arg, args.next())?),
+                "--tls-client-ca" => config.tls_client_ca_path = Some(parse_value(&arg, args.next())?),
+                "--identity-ports" => {
+                    let value: String = parse_value(&arg, args.next())?;
+                    let (identity, ports) = parse_identity_ports(&value)?;
+                    config.handler_config.identity_port_permissions
+                        .entry(identity)
+                        .or_default()
+                        .extend(ports);
+                },
+
+                "--tcp-allow" => {
+                    let ranges = parse_port_ranges(&arg, &parse_value::<String>(&arg, args.next())?)?;
+                    config.handler_config.tcp_port_policy.allowed_ranges.extend(ranges);
+                },
+
+                "--tcp-deny" => {
+                    let ranges = parse_port_ranges(&arg, &parse_value::<String>(&arg, args.next())?)?;
+                    config.handler_config.tcp_port_policy.denied_ranges.extend(ranges);
+                },
+
+                "--udp-allow" => {
+                    let ranges = parse_port_ranges(&arg, &parse_value::<String>(&arg, args.next())?)?;
+                    config.handler_config.udp_port_policy.allowed_ranges.extend(ranges);
+                },
+
+                "--udp-deny" => {
+                    let ranges = parse_port_ranges(&arg, &parse_value::<String>(&arg, args.next())?)?;
+                    config.handler_config.udp_port_policy.denied_ranges.extend(ranges);
+                },
+
+                "--auth-secret" => {
+                    let secret: String = parse_value(&arg, args.next())?;
+                    if secret.is_empty() {
+                        return Err(format!("Auth secrets can not be empty\n{}", USAGE));
+                    }
+
+                    config.handler_config.auth_secrets.push(secret.into_bytes());
+                },
+
+                "--ephemeral-ports" => {
+                    let value: String = parse_value(&arg, args.next())?;
+                    let mut ranges = parse_port_ranges(&arg, &value)?;
+                    if ranges.len() != 1 {
+                        return Err(format!("Invalid value '{}' for {}\n{}", value, arg, USAGE));
+                    }
+
+                    config.handler_config.ephemeral_port_range = ranges.remove(0);
+                },
+
+                "--max-channels-per-client" => {
+                    config.handler_config.max_channels_per_client = Some(parse_value(&arg, args.next())?);
+                },
+
+                "--max-connections-per-channel" => {
+                    config.handler_config.max_connections_per_channel = Some(parse_value(&arg, args.next())?);
+                },
+
+                "--max-connections-per-client" => {
+                    config.handler_config.max_connections_per_client = Some(parse_value(&arg, args.next())?);
+                },
+
+                "--udp-idle-timeout" => {
+                    let seconds = parse_value(&arg, args.next())?;
+                    config.handler_config.udp_connection_idle_timeout = Duration::from_secs(seconds);
+                },
+
+                "--session-grace-period" => {
+                    let seconds = parse_value(&arg, args.next())?;
+                    config.handler_config.session_grace_period = Duration::from_secs(seconds);
+                },
+
+                "--shutdown-deadline" => {
+                    let seconds = parse_value(&arg, args.next())?;
+                    config.shutdown_deadline = Duration::from_secs(seconds);
+                },
+
+                "--help" | "-h" => return Err(USAGE.to_owned()),
+                x => return Err(format!("Unknown argument '{}'\n{}", x, USAGE)),
+            }
+        }
+
+        if config.tls_cert_path.is_some() != config.tls_key_path.is_some() {
+            return Err(format!("--tls-cert and --tls-key must be specified together\n{}", USAGE));
+        }
+
+        if config.tls_client_ca_path.is_some() && config.tls_cert_path.is_none() {
+            return Err(format!("--tls-client-ca requires --tls-cert and --tls-key\n{}", USAGE));
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parses an `identity=ports` value
+fn parse_identity_ports(value: &str) -> Result<(String, Vec<RangeInclusive<u16>>), String> {
+    let invalid = || format!("Invalid value '{}' for --identity-ports\n{}", value, USAGE);
+    let mut parts = value.splitn(2, '=');
+    let identity = parts.next().filter(|x| !x.is_empty()).ok_or_else(invalid)?;
+    let ports = parts.next().ok_or_else(invalid)?;
+    let ranges = parse_port_ranges("--identity-ports", ports)?;
+
+    Ok((identity.to_owned(), ranges))
+}
+
+/// Parses a comma separated list of single ports or inclusive `start-end` ranges
+fn parse_port_ranges(name: &str, ports: &str) -> Result<Vec<RangeInclusive<u16>>, String> {
+    let invalid = || format!("Invalid value '{}' for {}\n{}", ports, name, USAGE);
+    let mut ranges = Vec::new();
+    for port_range in ports.split(',') {
+        let mut bounds = port_range.splitn(2, '-');
+        let start: u16 = bounds.next().and_then(|x| x.trim().parse().ok()).ok_or_else(invalid)?;
+        let end: u16 = match bounds.next() {
+            Some(x) => x.trim().parse().map_err(|_| invalid())?,
+            None => start,
+        };
+
+        if start > end {
+            return Err(invalid());
+        }
+
+        ranges.push(start..=end);
+    }
+
+    Ok(ranges)
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("No value provided for {}\n{}", name, USAGE))?;
+    value.parse().map_err(|_| format!("Invalid value '{}' for {}", value, name))
+}
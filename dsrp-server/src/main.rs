@@ -1,65 +1,147 @@
-use tokio::net::{TcpListener, TcpStream};
-use tokio::prelude::*;
-use tokio::io::{BufReader};
-use futures::StreamExt;
+mod config;
+mod relay;
+mod sockets;
+mod tls;
+
 use std::io;
-use futures::io::ErrorKind;
+use std::process;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::Session;
+use crate::config::ServerConfig;
+use crate::relay::{ControlConnectionId, Event, Relay};
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait, once the relay has shut down, for whatever is still queued for DSRP clients
+/// to be written out before exiting anyway
+const CLIENT_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let addr = "127.0.0.1:6142";
-    let server = listen_for_dsrp_clients(addr);
+    let config = match ServerConfig::from_args(std::env::args().skip(1)) {
+        Ok(x) => x,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    };
 
-    println!("DSRP server started running on {}", addr);
-    server.await
-}
+    let tls_acceptor = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let client_ca_path = config.tls_client_ca_path.as_deref();
+            Some(tls::load_acceptor(cert_path, key_path, client_ca_path)?)
+        },
+        _ => None,
+    };
 
-async fn listen_for_dsrp_clients(addr: &str) -> io::Result<()> {
-    let mut listener = TcpListener::bind(addr).await?;
-    let mut incoming = listener.incoming();
-    while let Some(socket_result) = incoming.next().await {
-        match socket_result {
-            Err(err) => {
-                println!("Accept error: {:?}", err);
-            }
+    let (events, event_receiver) = mpsc::unbounded_channel();
+    let relay = Relay::new(config.handler_config.clone(), events.clone());
+    let relay_finished = tokio::spawn(relay.run(event_receiver));
+    tokio::spawn(raise_ticks(events.clone()));
+    tokio::spawn(handle_shutdown_signals(events.clone(), config.shutdown_deadline));
 
-            Ok(socket) => {
-                println!("Accepted connection from {:?}", socket.peer_addr()?);
-                tokio::spawn(async move {
-                    if let Err(e) = handle_client(socket).await {
-                        println!("An error occurred handling client: {:?}", e);
-                    }
-                });
-            }
-        }
+    if config.handler_config.auth_secrets.is_empty() {
+        println!("No auth secrets configured, any client that can reach this server can register ports");
     }
 
+    match tls_acceptor {
+        Some(_) => println!("DSRP server started running on {} with tls", config.listen_address),
+        None => println!("DSRP server started running on {}", config.listen_address),
+    }
+
+    // Every task writing to a DSRP client holds a clone of the guard, so the receiver only
+    // finishes once they've all been flushed
+    let (writer_guard, mut writers_finished) = mpsc::channel::<()>(1);
+    tokio::select! {
+        result = listen_for_dsrp_clients(config, tls_acceptor, events, writer_guard) => return result,
+        _ = relay_finished => (),
+    }
+
+    // The relay dropped its senders for every client, so anything queued for them, such as the
+    // revocation of their channels, is all that's left to write
+    if tokio::time::timeout(CLIENT_FLUSH_TIMEOUT, writers_finished.recv()).await.is_err() {
+        println!("Gave up waiting for messages to DSRP clients to be written");
+    }
+
+    println!("DSRP server shut down");
     Ok(())
 }
 
-async fn handle_client(mut socket: TcpStream) -> io::Result<()> {
-    let (reader, mut writer) = socket.split();
-    let mut reader = BufReader::new(reader);
+/// Starts a graceful shutdown on the first ctrl-c, giving existing connections until the
+/// deadline to finish, and exits right away on the second
+async fn handle_shutdown_signals(events: mpsc::UnboundedSender<Event>, shutdown_deadline: Duration) {
+    if signal::ctrl_c().await.is_err() {
+        return;
+    }
+
+    println!("Shutting down, existing connections have {}s to finish", shutdown_deadline.as_secs());
+    let deadline = Instant::now() + shutdown_deadline;
+    if events.send(Event::ShutdownRequested {deadline}).is_err() {
+        return;
+    }
+
+    if signal::ctrl_c().await.is_ok() {
+        println!("Shutting down immediately");
+        process::exit(1);
+    }
+}
 
-    writer.write(b"Hello there!\n").await?;
+/// Periodically gives the relay a chance to expire anything that's gone idle and to check
+/// that its clients are still responsive
+async fn raise_ticks(events: mpsc::UnboundedSender<Event>) {
+    let mut interval = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if events.send(Event::Tick).is_err() {
+            break;
+        }
+    }
+}
 
+async fn listen_for_dsrp_clients(config: ServerConfig,
+                                 tls_acceptor: Option<TlsAcceptor>,
+                                 events: mpsc::UnboundedSender<Event>,
+                                 writer_guard: mpsc::Sender<()>) -> io::Result<()> {
+    let mut listener = TcpListener::bind(config.listen_address).await?;
+    let mut next_control_connection = 0;
     loop {
-        let mut line = String::new();
-        match reader.read_line(&mut line).await {
-            Ok(0) => {
-                println!("Client disconnected!");
-                break;
+        match listener.accept().await {
+            Err(error) => {
+                println!("Accept error: {:?}", error);
             }
 
-            Ok(_) => {writer.write(line.as_bytes()).await?;},
-            Err(e) if e.kind() == ErrorKind::WouldBlock => (),
-            Err(e) if e.kind() == ErrorKind::Interrupted => (),
-            Err(e) => {
-                println!("Error: {:?}", e);
-                break;
-            },
+            Ok((socket, address)) => {
+                println!("Accepted connection from {:?}", address);
+                let control_connection = ControlConnectionId(next_control_connection);
+                next_control_connection += 1;
+
+                let events = events.clone();
+                let writer_guard = writer_guard.clone();
+                match tls_acceptor.clone() {
+                    None => tokio::spawn(sockets::handle_dsrp_client(socket, control_connection, None, events, writer_guard)),
+                    Some(acceptor) => tokio::spawn(async move {
+                        match acceptor.accept(socket).await {
+                            Ok(stream) => {
+                                let identity = stream.get_ref().1
+                                    .get_peer_certificates()
+                                    .and_then(|certificates| tls::client_identity(&certificates));
+
+                                if let Some(identity) = &identity {
+                                    println!("Client {:?} presented a certificate for {}", address, identity);
+                                }
+
+                                sockets::handle_dsrp_client(stream, control_connection, identity, events, writer_guard).await
+                            },
+
+                            Err(error) => println!("Tls handshake with {:?} failed: {}", address, error),
+                        }
+                    }),
+                };
+            }
         }
     }
-
-    Ok(())
-}
\ No newline at end of file
+}
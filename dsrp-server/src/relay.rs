@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+use dsrp_core::clock::SystemClock;
+use dsrp_core::handshake::{HandshakeChallengeAnswer, HandshakeRequest, HandshakeResponse};
+use dsrp_core::messages::{ChannelId, ClientMessage, ConnectionId};
+use dsrp_core::server_handler::{ClientId, ServerHandler, ServerHandlerConfig, ServerOperation};
+use futures::future::{abortable, AbortHandle};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use crate::sockets;
+
+/// How far a DSRP client got after its handshake was processed
+pub enum HandshakeStatus {
+    Accepted(ClientId),
+    Challenged(ClientId),
+    Rejected,
+}
+
+/// Identifies a DSRP client's control connection, since a session can be taken over by a new
+/// connection while the old one still looks open
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ControlConnectionId(pub u64);
+
+/// Socket activity that needs to be run through the server handler
+pub enum Event {
+    DsrpClientHandshake {
+        control_connection: ControlConnectionId,
+        request: HandshakeRequest,
+        identity: Option<String>,
+        outbound: mpsc::UnboundedSender<Vec<u8>>,
+        response: oneshot::Sender<HandshakeStatus>,
+    },
+
+    DsrpClientChallengeAnswer {
+        client: ClientId,
+        answer: HandshakeChallengeAnswer,
+        response: oneshot::Sender<HandshakeStatus>,
+    },
+
+    DsrpClientMessage {
+        client: ClientId,
+        control_connection: ControlConnectionId,
+        message: ClientMessage,
+    },
+
+    DsrpClientDisconnected {
+        client: ClientId,
+        control_connection: ControlConnectionId,
+    },
+
+    SocketBound {
+        channel: ChannelId,
+    },
+
+    SocketBindingFailed {
+        channel: ChannelId,
+    },
+
+    /// The socket bound for a channel stopped working and was given up on
+    SocketFailed {
+        channel: ChannelId,
+    },
+
+    NewTcpConnection {
+        channel: ChannelId,
+        stream: TcpStream,
+    },
+
+    TcpDataReceived {
+        connection: ConnectionId,
+        data: Vec<u8>,
+    },
+
+    TcpConnectionHalfClosed {
+        connection: ConnectionId,
+    },
+
+    TcpConnectionClosed {
+        connection: ConnectionId,
+    },
+
+    UdpDataReceived {
+        channel: ChannelId,
+        peer: SocketAddr,
+        data: Vec<u8>,
+    },
+
+    ShutdownRequested {
+        deadline: Instant,
+    },
+
+    Tick,
+}
+
+struct DsrpClient {
+    control_connection: ControlConnectionId,
+    outbound: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+struct TcpPort {
+    listener: AbortHandle,
+}
+
+struct UdpPort {
+    channel: ChannelId,
+    socket: AbortHandle,
+    outbound: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+}
+
+struct TcpConnection {
+    reader: AbortHandle,
+    reset: oneshot::Sender<()>,
+
+    /// Cleared once the write side of the connection has been shut down
+    outbound: Option<mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+/// Owns the server handler and carries out the operations it returns against real sockets.
+/// All socket activity is funneled through a single event queue so the handler is only ever
+/// touched from one place.
+pub struct Relay {
+    handler: ServerHandler,
+    events: mpsc::UnboundedSender<Event>,
+    clients: HashMap<ClientId, DsrpClient>,
+    tcp_ports: HashMap<SocketAddr, TcpPort>,
+    udp_ports: HashMap<SocketAddr, UdpPort>,
+    udp_channel_ports: HashMap<ChannelId, SocketAddr>,
+    tcp_connections: HashMap<ConnectionId, TcpConnection>,
+    shutting_down: bool,
+}
+
+impl Relay {
+    pub fn new(handler_config: ServerHandlerConfig,
+               events: mpsc::UnboundedSender<Event>) -> Self {
+        Relay {
+            handler: ServerHandler::with_config(handler_config, Box::new(SystemClock)),
+            events,
+            clients: HashMap::new(),
+            tcp_ports: HashMap::new(),
+            udp_ports: HashMap::new(),
+            udp_channel_ports: HashMap::new(),
+            tcp_connections: HashMap::new(),
+            shutting_down: false,
+        }
+    }
+
+    /// Handles events until the event queue closes or a requested shutdown has completed
+    pub async fn run(mut self, mut events: mpsc::UnboundedReceiver<Event>) {
+        while let Some(event) = events.recv().await {
+            self.handle_event(event);
+            if self.handler.has_shut_down() {
+                break;
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::DsrpClientHandshake {control_connection, request, identity, outbound, response} => {
+                let result = match identity {
+                    Some(identity) => self.handler.add_dsrp_client_with_identity(request, identity),
+                    None => self.handler.add_dsrp_client(request),
+                };
+
+                let (handshake_response, status, operations) = match result {
+                    Ok(new_client) => {
+                        let status = match new_client.response {
+                            HandshakeResponse::Challenge {..} => HandshakeStatus::Challenged(new_client.id),
+                            _ => HandshakeStatus::Accepted(new_client.id),
+                        };
+
+                        (new_client.response, status, new_client.operations)
+                    },
+
+                    Err(failure) => (failure, HandshakeStatus::Rejected, Vec::new()),
+                };
+
+                let resumed = match handshake_response {
+                    HandshakeResponse::Success {resumed, ..} => resumed,
+                    _ => false,
+                };
+
+                send_handshake_response(&outbound, handshake_response);
+                match status {
+                    HandshakeStatus::Accepted(id) => {
+                        if resumed {
+                            println!("DSRP client {} reconnected and resumed its session", id);
+                        } else {
+                            println!("DSRP client {} connected", id);
+                        }
+
+                        self.attach_client(id, DsrpClient {control_connection, outbound});
+                    },
+
+                    HandshakeStatus::Challenged(id) => {
+                        self.clients.insert(id, DsrpClient {control_connection, outbound});
+                    },
+
+                    HandshakeStatus::Rejected => (),
+                }
+
+                self.perform_operations(operations);
+                let _ = response.send(status);
+            },
+
+            Event::DsrpClientChallengeAnswer {client, answer, response} => {
+                let (handshake_response, status, operations) = match self.handler.handle_challenge_answer(client, answer) {
+                    Ok(new_client) => {
+                        if new_client.id == client {
+                            println!("DSRP client {} authenticated", client);
+                        } else {
+                            // The connection now belongs to the session the client resumed
+                            println!("DSRP client {} authenticated and resumed the session of client {}",
+                                     client, new_client.id);
+
+                            if let Some(dsrp_client) = self.clients.remove(&client) {
+                                self.attach_client(new_client.id, dsrp_client);
+                            }
+                        }
+
+                        (new_client.response, HandshakeStatus::Accepted(new_client.id), new_client.operations)
+                    },
+
+                    Err(failure) => {
+                        println!("DSRP client {} failed authentication", client);
+                        (failure, HandshakeStatus::Rejected, Vec::new())
+                    },
+                };
+
+                let accepted_id = match status {
+                    HandshakeStatus::Accepted(id) => id,
+                    _ => client,
+                };
+
+                if let Some(dsrp_client) = self.clients.get(&accepted_id) {
+                    send_handshake_response(&dsrp_client.outbound, handshake_response);
+                }
+
+                // Dropping the outbound sender of a rejected client closes its connection once
+                // the failure has been flushed
+                if let HandshakeStatus::Rejected = status {
+                    self.clients.remove(&client);
+                }
+
+                self.perform_operations(operations);
+                let _ = response.send(status);
+            },
+
+            Event::DsrpClientMessage {client, control_connection, message} => {
+                // Anything still arriving over a connection the session was taken away from is
+                // stale
+                if !self.is_current_connection(client, control_connection) {
+                    return;
+                }
+
+                match self.handler.handle_client_message(client, message) {
+                    Ok(operations) => self.perform_operations(operations),
+                    Err(error) => println!("Failed to handle message from client {}: {}", client, error),
+                }
+            },
+
+            Event::DsrpClientDisconnected {client, control_connection} => {
+                // Nothing to do if the handler already disconnected the client or its session
+                // moved on to a newer connection
+                if !self.is_current_connection(client, control_connection) {
+                    return;
+                }
+
+                // The client's channels may be kept around for a while in case it reconnects
+                println!("DSRP client {} disconnected", client);
+                self.clients.remove(&client);
+                let operations = self.handler.dsrp_client_disconnected(client);
+                self.perform_operations(operations);
+            },
+
+            Event::SocketBound {channel} => {
+                let operation = self.handler.socket_binding_successful(channel);
+                self.perform_operations(operation);
+            },
+
+            Event::SocketBindingFailed {channel} => {
+                let operation = self.handler.socket_binding_failed(channel);
+                self.perform_operations(operation);
+            },
+
+            Event::SocketFailed {channel} => {
+                let operations = self.handler.socket_failed(channel);
+                self.perform_operations(operations);
+            },
+
+            Event::NewTcpConnection {channel, stream} => {
+                match self.handler.new_channel_tcp_connection(channel) {
+                    Ok((connection, operation)) => {
+                        let (reader, outbound, reset) = sockets::spawn_writer(stream, connection, self.events.clone());
+                        let read_future = sockets::read_tcp_connection(reader, connection, self.events.clone());
+                        let (read_future, reader) = abortable(read_future);
+                        tokio::spawn(read_future);
+
+                        self.tcp_connections.insert(connection, TcpConnection {reader, reset, outbound: Some(outbound)});
+                        self.perform_operation(operation);
+                    },
+
+                    Err(error) => println!("Rejected tcp connection: {}", error),
+                }
+            },
+
+            Event::TcpDataReceived {connection, data} => {
+                let operation = self.handler.tcp_data_received(connection, &data);
+                self.perform_operations(operation);
+            },
+
+            Event::TcpConnectionHalfClosed {connection} => {
+                let operations = self.handler.tcp_connection_half_closed(connection);
+                self.perform_operations(operations);
+            },
+
+            Event::TcpConnectionClosed {connection} => {
+                // The reader may still be running when it was the write side that failed
+                if let Some(tcp_connection) = self.tcp_connections.remove(&connection) {
+                    tcp_connection.reader.abort();
+                }
+
+                let operation = self.handler.tcp_connection_disconnected(connection);
+                self.perform_operations(operation);
+            },
+
+            Event::UdpDataReceived {channel, peer, data} => {
+                let operations = self.handler.udp_data_received(channel, peer, &data);
+                self.perform_operations(operations);
+            },
+
+            Event::ShutdownRequested {deadline} => {
+                self.shutting_down = true;
+                let operations = self.handler.begin_shutdown(deadline);
+                self.perform_operations(operations);
+            },
+
+            Event::Tick => {
+                let operations = self.handler.tick(Instant::now());
+                self.perform_operations(operations);
+            },
+        }
+    }
+
+    /// Gives a client's session to the control connection it was accepted on.  Dropping the
+    /// sender of any connection the session was taken over from closes that connection.
+    fn attach_client(&mut self, id: ClientId, dsrp_client: DsrpClient) {
+        if self.clients.insert(id, dsrp_client).is_some() {
+            println!("DSRP client {} took its session over from a stale connection", id);
+        }
+    }
+
+    fn is_current_connection(&self, id: ClientId, control_connection: ControlConnectionId) -> bool {
+        self.clients.get(&id).is_some_and(|x| x.control_connection == control_connection)
+    }
+
+    fn perform_operations<I: IntoIterator<Item = ServerOperation>>(&mut self, operations: I) {
+        for operation in operations {
+            self.perform_operation(operation);
+        }
+    }
+
+    fn perform_operation(&mut self, operation: ServerOperation) {
+        match operation {
+            ServerOperation::StartTcpOperations {address, port, channel} => {
+                let address = SocketAddr::new(address, port);
+                let (future, listener) = abortable(sockets::run_tcp_listener(address, channel, self.events.clone()));
+                tokio::spawn(future);
+                self.tcp_ports.insert(address, TcpPort {listener});
+            },
+
+            ServerOperation::StopTcpOperations {address, port} => {
+                if let Some(tcp_port) = self.tcp_ports.remove(&SocketAddr::new(address, port)) {
+                    tcp_port.listener.abort();
+                }
+            },
+
+            ServerOperation::StartUdpOperations {address, port, channel} => {
+                let address = SocketAddr::new(address, port);
+                let (outbound, outbound_receiver) = mpsc::unbounded_channel();
+                let socket_future = sockets::run_udp_socket(address, channel, outbound_receiver, self.events.clone());
+                let (future, socket) = abortable(socket_future);
+                tokio::spawn(future);
+
+                self.udp_channel_ports.insert(channel, address);
+                self.udp_ports.insert(address, UdpPort {channel, socket, outbound});
+            },
+
+            ServerOperation::StopUdpOperations {address, port} => {
+                if let Some(udp_port) = self.udp_ports.remove(&SocketAddr::new(address, port)) {
+                    udp_port.socket.abort();
+                    self.udp_channel_ports.remove(&udp_port.channel);
+                }
+            },
+
+            ServerOperation::DisconnectConnection {connection} => {
+                // Dropping the outbound sender lets queued data get flushed before the write
+                // side is shut down, while aborting the reader releases the rest of the socket
+                if let Some(tcp_connection) = self.tcp_connections.remove(&connection) {
+                    tcp_connection.reader.abort();
+                }
+            },
+
+            ServerOperation::ShutdownConnectionWrite {connection} => {
+                // Dropping the outbound sender lets queued data get flushed before the write
+                // side is shut down, while the reader keeps relaying what the remote peer sends
+                if let Some(tcp_connection) = self.tcp_connections.get_mut(&connection) {
+                    tcp_connection.outbound = None;
+                }
+            },
+
+            ServerOperation::ResetConnection {connection} => {
+                // The writer sets the socket up to be reset before it releases it
+                if let Some(tcp_connection) = self.tcp_connections.remove(&connection) {
+                    let _ = tcp_connection.reset.send(());
+                    tcp_connection.reader.abort();
+                }
+            },
+
+            ServerOperation::DisconnectDsrpClient {client} => {
+                // Dropping the outbound sender closes the client's connection once anything
+                // queued for it has been written
+                if self.shutting_down {
+                    println!("DSRP client {} was disconnected for the shutdown", client);
+                } else {
+                    println!("DSRP client {} stopped responding and was disconnected", client);
+                }
+
+                self.clients.remove(&client);
+            },
+
+            ServerOperation::SendMessageToDsrpClient {client, message} => {
+                if let Some(dsrp_client) = self.clients.get(&client) {
+                    let _ = dsrp_client.outbound.send(message.into_bytes());
+                }
+            },
+
+            ServerOperation::SendByteData {channel, connection, peer_address, data} => {
+                match (connection, peer_address) {
+                    (_, Some(peer_address)) => {
+                        let udp_port = self.udp_channel_ports.get(&channel)
+                            .and_then(|address| self.udp_ports.get(address));
+
+                        if let Some(udp_port) = udp_port {
+                            let _ = udp_port.outbound.send((data, peer_address));
+                        }
+                    },
+
+                    (Some(connection), None) => {
+                        let outbound = self.tcp_connections.get(&connection)
+                            .and_then(|tcp_connection| tcp_connection.outbound.as_ref());
+
+                        if let Some(outbound) = outbound {
+                            let _ = outbound.send(data);
+                        }
+                    },
+
+                    (None, None) => (),
+                }
+            },
+        }
+    }
+}
+
+fn send_handshake_response(outbound: &mpsc::UnboundedSender<Vec<u8>>, response: HandshakeResponse) {
+    match response.into_bytes() {
+        Ok(bytes) => { let _ = outbound.send(bytes); },
+        Err(error) => println!("Failed to generate handshake response: {}", error),
+    }
+}
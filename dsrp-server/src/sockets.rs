@@ -0,0 +1,310 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use dsrp_core::framing::{FrameDecoder, Frame, DEFAULT_MAX_FRAME_SIZE};
+use dsrp_core::messages::{ChannelId, ConnectionId};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::io;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::prelude::*;
+use tokio::sync::{mpsc, oneshot};
+use crate::relay::{ControlConnectionId, Event, HandshakeStatus};
+
+const READ_BUFFER_SIZE: usize = 8192;
+const MAX_UDP_PACKET_SIZE: usize = 65536;
+
+/// How many errors in a row a channel's listener or udp socket can hit before it's considered
+/// broken and its channel is revoked
+const MAX_CONSECUTIVE_SOCKET_ERRORS: u32 = 10;
+
+/// Writes every chunk of bytes it's given to the socket, until all senders have been dropped
+/// and the write side can be shut down.  Returns the error if the socket could not be written to.
+pub async fn write_outbound<W>(mut writer: W, mut outbound: mpsc::UnboundedReceiver<Vec<u8>>) -> io::Result<()>
+    where W: AsyncWrite + Unpin {
+
+    while let Some(bytes) = outbound.recv().await {
+        if let Err(error) = writer.write_all(&bytes).await {
+            println!("Failed to write to socket: {}", error);
+            return Err(error);
+        }
+    }
+
+    let _ = writer.shutdown().await;
+    Ok(())
+}
+
+/// Runs the control connection of a DSRP client, whether it's plain tcp or wrapped in tls.  The
+/// identity is only known when the client authenticated itself with a tls certificate.  The
+/// writer guard is held until everything queued for the client has been written, so the server
+/// can wait for that before exiting.
+pub async fn handle_dsrp_client<S>(stream: S,
+                                   control_connection: ControlConnectionId,
+                                   identity: Option<String>,
+                                   events: mpsc::UnboundedSender<Event>,
+                                   writer_guard: mpsc::Sender<()>)
+    where S: AsyncRead + AsyncWrite + Send + 'static {
+
+    let (reader, writer) = io::split(stream);
+    let (outbound, outbound_receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let _ = write_outbound(writer, outbound_receiver).await;
+        drop(writer_guard);
+    });
+
+    read_dsrp_client(reader, control_connection, identity, outbound, events).await;
+}
+
+/// Reads handshake and message frames sent by a DSRP client and raises them as relay events
+async fn read_dsrp_client<R>(mut reader: R,
+                             control_connection: ControlConnectionId,
+                             mut identity: Option<String>,
+                             outbound: mpsc::UnboundedSender<Vec<u8>>,
+                             events: mpsc::UnboundedSender<Event>)
+    where R: AsyncRead + Unpin {
+
+    let mut decoder = FrameDecoder::for_server(DEFAULT_MAX_FRAME_SIZE);
+    let mut client_id = None;
+    let mut challenged_client_id = None;
+    let mut outbound = Some(outbound);
+    let mut buffer = [0; READ_BUFFER_SIZE];
+
+    'reading: loop {
+        let bytes_read = match reader.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(x) => x,
+            Err(error) => {
+                println!("Error reading from DSRP client: {}", error);
+                break;
+            },
+        };
+
+        decoder.push_bytes(&buffer[..bytes_read]);
+        loop {
+            let frame = match decoder.next_frame() {
+                Ok(Some(x)) => x,
+                Ok(None) => break,
+                Err(error) => {
+                    println!("Invalid data received from DSRP client: {}", error);
+                    break 'reading;
+                },
+            };
+
+            match (frame, client_id) {
+                (Frame::HandshakeRequest(request), None) => {
+                    let (response_sender, response_receiver) = oneshot::channel();
+                    let event = Event::DsrpClientHandshake {
+                        control_connection,
+                        request,
+                        identity: identity.take(),
+                        outbound: outbound.take().expect("Handshake can only be received once"),
+                        response: response_sender,
+                    };
+
+                    if events.send(event).is_err() {
+                        break 'reading;
+                    }
+
+                    match response_receiver.await {
+                        Ok(HandshakeStatus::Accepted(id)) => client_id = Some(id),
+                        Ok(HandshakeStatus::Challenged(id)) => {
+                            challenged_client_id = Some(id);
+                            decoder.expect_challenge_answer();
+                        },
+
+                        _ => break 'reading, // handshake rejected
+                    }
+                },
+
+                (Frame::HandshakeChallengeAnswer(answer), None) if challenged_client_id.is_some() => {
+                    let client = challenged_client_id.take().expect("Challenged client id checked above");
+                    let (response_sender, response_receiver) = oneshot::channel();
+                    let event = Event::DsrpClientChallengeAnswer {client, answer, response: response_sender};
+                    if events.send(event).is_err() {
+                        break 'reading;
+                    }
+
+                    match response_receiver.await {
+                        Ok(HandshakeStatus::Accepted(id)) => client_id = Some(id),
+                        _ => break 'reading, // challenge failed
+                    }
+                },
+
+                (Frame::ClientMessage(message), Some(client)) => {
+                    if events.send(Event::DsrpClientMessage {client, control_connection, message}).is_err() {
+                        break 'reading;
+                    }
+                },
+
+                (frame, _) => {
+                    println!("Unexpected frame received from DSRP client: {:?}", frame);
+                    break 'reading;
+                },
+            }
+        }
+    }
+
+    if let Some(client) = client_id.or(challenged_client_id) {
+        let _ = events.send(Event::DsrpClientDisconnected {client, control_connection});
+    }
+}
+
+/// Binds the tcp port for a channel and raises an event for each connection accepted on it
+pub async fn run_tcp_listener(address: SocketAddr, channel: ChannelId, events: mpsc::UnboundedSender<Event>) {
+    let mut listener = match TcpListener::bind(address).await {
+        Ok(x) => x,
+        Err(error) => {
+            println!("Failed to bind tcp listener on {}: {}", address, error);
+            let _ = events.send(Event::SocketBindingFailed {channel});
+            return;
+        }
+    };
+
+    if events.send(Event::SocketBound {channel}).is_err() {
+        return;
+    }
+
+    let mut consecutive_errors = 0;
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                consecutive_errors = 0;
+                if events.send(Event::NewTcpConnection {channel, stream}).is_err() {
+                    break;
+                }
+            },
+
+            Err(error) => {
+                println!("Failed to accept tcp connection on {}: {}", address, error);
+                consecutive_errors += 1;
+                if consecutive_errors >= MAX_CONSECUTIVE_SOCKET_ERRORS {
+                    let _ = events.send(Event::SocketFailed {channel});
+                    break;
+                }
+            },
+        }
+    }
+}
+
+/// Reads data off of a connection accepted on a channel's tcp port and raises it as relay events
+pub async fn read_tcp_connection(mut reader: OwnedReadHalf,
+                                 connection: ConnectionId,
+                                 events: mpsc::UnboundedSender<Event>) {
+    let mut buffer = [0; READ_BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buffer).await {
+            Ok(0) => {
+                // The remote peer may still be waiting on data even though it's done sending
+                let _ = events.send(Event::TcpConnectionHalfClosed {connection});
+                return;
+            },
+
+            Ok(x) => {
+                let data = buffer[..x].to_vec();
+                if events.send(Event::TcpDataReceived {connection, data}).is_err() {
+                    return;
+                }
+            },
+
+            Err(error) => {
+                println!("Error reading from tcp connection: {}", error);
+                break;
+            },
+        }
+    }
+
+    let _ = events.send(Event::TcpConnectionClosed {connection});
+}
+
+/// Binds the udp port for a channel, raising received packets as relay events and sending
+/// out any packets handed to it.
+pub async fn run_udp_socket(address: SocketAddr,
+                            channel: ChannelId,
+                            mut outbound: mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>,
+                            events: mpsc::UnboundedSender<Event>) {
+    let socket = match UdpSocket::bind(address).await {
+        Ok(x) => x,
+        Err(error) => {
+            println!("Failed to bind udp socket on {}: {}", address, error);
+            let _ = events.send(Event::SocketBindingFailed {channel});
+            return;
+        }
+    };
+
+    if events.send(Event::SocketBound {channel}).is_err() {
+        return;
+    }
+
+    let (mut receiver, mut sender) = socket.split();
+    tokio::spawn(async move {
+        while let Some((data, target)) = outbound.recv().await {
+            if let Err(error) = sender.send_to(&data, &target).await {
+                println!("Failed to send udp packet to {}: {}", target, error);
+            }
+        }
+    });
+
+    let mut buffer = vec![0; MAX_UDP_PACKET_SIZE];
+    let mut consecutive_errors = 0;
+    loop {
+        match receiver.recv_from(&mut buffer).await {
+            Ok((size, peer)) => {
+                consecutive_errors = 0;
+                let data = buffer[..size].to_vec();
+                if events.send(Event::UdpDataReceived {channel, peer, data}).is_err() {
+                    break;
+                }
+            },
+
+            Err(error) => {
+                println!("Failed to receive udp packet on {}: {}", address, error);
+                consecutive_errors += 1;
+                if consecutive_errors >= MAX_CONSECUTIVE_SOCKET_ERRORS {
+                    let _ = events.send(Event::SocketFailed {channel});
+                    break;
+                }
+            },
+        }
+    }
+}
+
+/// Splits an accepted socket into its reading half and a sender that queues bytes to write to it.
+/// Signaling the returned reset sender aborts the connection instead of shutting it down cleanly.
+pub fn spawn_writer(stream: TcpStream,
+                    connection: ConnectionId,
+                    events: mpsc::UnboundedSender<Event>)
+    -> (OwnedReadHalf, mpsc::UnboundedSender<Vec<u8>>, oneshot::Sender<()>) {
+    let (reader, writer) = stream.into_split();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let (reset, reset_receiver) = oneshot::channel();
+    tokio::spawn(write_tcp_connection(writer, receiver, reset_receiver, connection, events));
+    (reader, sender, reset)
+}
+
+/// Writes queued bytes to a connection accepted on a channel's tcp port, until all senders have
+/// been dropped or the connection is reset.  Failing to write closes the connection, since the
+/// reader stops watching it once the remote peer half-closes, and a reset sent after that is
+/// only noticed here.
+async fn write_tcp_connection(writer: OwnedWriteHalf,
+                              outbound: mpsc::UnboundedReceiver<Vec<u8>>,
+                              reset: oneshot::Receiver<()>,
+                              connection: ConnectionId,
+                              events: mpsc::UnboundedSender<Event>) {
+    let mut writer = Some(writer);
+    tokio::select! {
+        result = write_outbound(writer.as_mut().unwrap(), outbound) => {
+            if result.is_err() {
+                let _ = events.send(Event::TcpConnectionClosed {connection});
+            }
+        },
+
+        Ok(()) = reset => {
+            // Without any linger time the socket sends a reset instead of a fin once both
+            // halves are released, so the write half must not be shut down when it's dropped
+            let writer = writer.take().unwrap();
+            if let Err(error) = writer.as_ref().set_linger(Some(Duration::from_secs(0))) {
+                println!("Failed to reset tcp connection: {}", error);
+            }
+
+            writer.forget();
+        },
+    }
+}
@@ -0,0 +1,91 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey};
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::rustls::internal::pemfile;
+use x509_parser::extensions::GeneralName;
+
+/// Builds the acceptor that wraps DSRP client connections in tls, using a PEM encoded
+/// certificate chain and private key.  When a client CA is given, clients must present a
+/// certificate signed by it to complete the tls handshake.
+pub fn load_acceptor(cert_path: &Path, key_path: &Path, client_ca_path: Option<&Path>) -> io::Result<TlsAcceptor> {
+    let certs = pemfile::certs(&mut open_pem_file(cert_path)?)
+        .map_err(|_| invalid_pem_file(cert_path))?;
+
+    if certs.is_empty() {
+        return Err(invalid_pem_file(cert_path));
+    }
+
+    let key = load_private_key(key_path)?;
+    let mut config = match client_ca_path {
+        None => ServerConfig::new(NoClientAuth::new()),
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots.add_pem_file(&mut open_pem_file(path)?)
+                .map_err(|_| invalid_pem_file(path))?;
+
+            if added == 0 {
+                return Err(invalid_pem_file(path));
+            }
+
+            ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+        },
+    };
+
+    config.set_single_cert(certs, key)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Determines the identity of a client from the end-entity certificate it presented.  The first
+/// DNS subject alternative name is preferred, falling back to the subject's common name.
+pub fn client_identity(certificates: &[Certificate]) -> Option<String> {
+    let certificate = certificates.first()?;
+    let (_, certificate) = x509_parser::parse_x509_certificate(&certificate.0).ok()?;
+
+    if let Ok(Some(alternative_names)) = certificate.subject_alternative_name() {
+        for name in &alternative_names.value.general_names {
+            if let GeneralName::DNSName(dns_name) = name {
+                return Some((*dns_name).to_owned());
+            }
+        }
+    }
+
+    let common_name = certificate.subject()
+        .iter_common_name()
+        .next()
+        .and_then(|common_name| common_name.as_str().ok())
+        .map(|common_name| common_name.to_owned());
+
+    common_name
+}
+
+/// Reads the first private key out of a PEM file, which can be either PKCS#8 or RSA encoded
+fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let pkcs8_keys = pemfile::pkcs8_private_keys(&mut open_pem_file(path)?)
+        .map_err(|_| invalid_pem_file(path))?;
+
+    if let Some(key) = pkcs8_keys.into_iter().next() {
+        return Ok(key);
+    }
+
+    let rsa_keys = pemfile::rsa_private_keys(&mut open_pem_file(path)?)
+        .map_err(|_| invalid_pem_file(path))?;
+
+    rsa_keys.into_iter().next().ok_or_else(|| invalid_pem_file(path))
+}
+
+fn open_pem_file(path: &Path) -> io::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|error| io::Error::new(error.kind(), format!("Failed to open {}: {}", path.display(), error)))
+}
+
+fn invalid_pem_file(path: &Path) -> io::Error {
+    let message = format!("{} does not contain a valid PEM encoded certificate or key", path.display());
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}